@@ -0,0 +1,161 @@
+//! A fake status producer, for developing (and screenshotting) a UI without an amp or MQTT broker.
+
+use common::zone::{ZoneAttribute, ZoneId};
+use crossbeam_channel::Sender;
+
+use crate::{StatusUpdate, ZoneMeta};
+
+const AMP_NAMES: [&str; 3] = ["Downstairs", "Upstairs", "Outside"];
+
+const ZONE_NAMES: [[&str; 6]; 3] = [
+    ["Living Room", "Kitchen", "Dining Room", "Study", "Laundry", "Entry"],
+    ["Main Bedroom", "Ensuite", "Bedroom 2", "Bedroom 3", "Bathroom", "Hallway"],
+    ["Deck", "Patio", "Pool", "Garage", "Garden", "Front Porch"],
+];
+
+/// Emits the status a daemon with `amps` amps would publish, with plausible zone names and attributes.
+///
+/// Sets are accepted and ignored, so the status never changes.
+pub struct DemoStatus {
+    amps: u8,
+}
+
+impl DemoStatus {
+    pub fn new(amps: u8) -> Self {
+        DemoStatus {
+            amps: amps.clamp(1, AMP_NAMES.len() as u8)
+        }
+    }
+
+    /// The system zone, then each amp zone followed by its physical zones.
+    pub fn zones(&self) -> Vec<ZoneId> {
+        std::iter::once(ZoneId::System)
+            .chain((1..=self.amps).flat_map(|amp| std::iter::once(ZoneId::Amp(amp)).chain((1..=6).map(move |zone| ZoneId::Zone { amp, zone }))))
+            .collect()
+    }
+
+    /// The zone list, then the metadata and (for physical zones) attributes of each zone,
+    /// in the same order as `Client::setup_status_handlers` would send them.
+    pub fn status_updates(&self) -> Vec<StatusUpdate> {
+        let zones = self.zones();
+
+        let mut updates = vec![StatusUpdate::AvailableZones(zones.clone())];
+
+        for zone in zones {
+            updates.extend(ZoneMeta::from_zone_id(&zone).into_iter()
+                .chain([ZoneMeta::Name(zone_name(&zone))])
+                .map(|meta| StatusUpdate::ZoneMeta(zone, meta)));
+
+            updates.extend(zone_attributes(&zone).into_iter().map(|attr| StatusUpdate::ZoneAttribute(zone, attr)));
+        }
+
+        updates
+    }
+
+    /// Send the status updates to `updates_send`, as a stand-in for `Client::setup_status_handlers`.
+    pub fn setup_status_handlers(&self, updates_send: Sender<StatusUpdate>) -> Result<(), crossbeam_channel::SendError<StatusUpdate>> {
+        for update in self.status_updates() {
+            updates_send.send(update)?;
+        }
+
+        Ok(())
+    }
+
+    /// Accept (and ignore) a message that would otherwise be published to the broker, i.e. a zone attribute set.
+    pub fn publish(&self, topic: &str, payload: &str) {
+        log::debug!("demo: ignoring publish to {}: {}", topic, payload);
+    }
+}
+
+fn zone_name(zone: &ZoneId) -> String {
+    match *zone {
+        ZoneId::System => "All Zones".to_string(),
+        ZoneId::Amp(amp) => AMP_NAMES[amp as usize - 1].to_string(),
+        ZoneId::Zone { amp, zone } => ZONE_NAMES[amp as usize - 1][zone as usize - 1].to_string(),
+    }
+}
+
+/// a varied, but deterministic, status for a physical zone (amp and system zones have no attributes)
+fn zone_attributes(zone: &ZoneId) -> Vec<ZoneAttribute> {
+    let ZoneId::Zone { amp, zone } = *zone else {
+        return Vec::new();
+    };
+
+    let n = (amp - 1) * 6 + (zone - 1);
+    let power = n % 3 != 2;
+
+    vec![
+        ZoneAttribute::PublicAnnouncement(false),
+        ZoneAttribute::Power(power),
+        ZoneAttribute::Mute(n % 5 == 4),
+        ZoneAttribute::DoNotDisturb(n % 7 == 6),
+        ZoneAttribute::Volume(if power { 8 + (n * 5) % 24 } else { 0 }),
+        ZoneAttribute::Treble(7 + n % 3),
+        ZoneAttribute::Bass(7 + (n + 1) % 3),
+        ZoneAttribute::Balance(10),
+        ZoneAttribute::Source(1 + n % 6),
+        ZoneAttribute::KeypadConnected(zone != 6),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+
+    use common::zone::ZoneAttributeDiscriminants;
+    use strum::IntoEnumIterator;
+
+    use super::*;
+
+    #[test]
+    fn test_demo_status() {
+        let (updates_send, updates_recv) = crossbeam_channel::unbounded();
+
+        let demo = DemoStatus::new(2);
+        demo.setup_status_handlers(updates_send).unwrap();
+
+        let updates = updates_recv.try_iter().collect::<Vec<_>>();
+
+        // the zone list comes first
+        let StatusUpdate::AvailableZones(available) = &updates[0] else {
+            panic!("expected the zone list, got {:?}", updates[0]);
+        };
+        assert_eq!(available.len(), 1 + 2 * 7);
+        assert_eq!(*available, demo.zones());
+
+        let mut names = HashMap::new();
+        let mut attrs = HashMap::<ZoneId, Vec<ZoneAttribute>>::new();
+
+        for update in &updates[1..] {
+            match update {
+                StatusUpdate::ZoneMeta(zone, ZoneMeta::Name(name)) => assert!(names.insert(*zone, name.clone()).is_none()),
+                StatusUpdate::ZoneMeta(zone, meta) => assert!(ZoneMeta::from_zone_id(zone).contains(meta)),
+                StatusUpdate::ZoneAttribute(zone, attr) => attrs.entry(*zone).or_default().push(*attr),
+                update => panic!("unexpected update {:?}", update)
+            }
+        }
+
+        // every listed zone is named, and only listed zones have updates
+        assert_eq!(names.keys().collect::<HashSet<_>>(), available.iter().collect::<HashSet<_>>());
+        assert!(attrs.keys().all(|zone| available.contains(zone)));
+
+        // every physical zone has exactly one valid value for each attribute, other zones have none
+        for zone in available {
+            let zone_attrs = attrs.get(zone).cloned().unwrap_or_default();
+
+            match zone {
+                ZoneId::Zone { .. } => {
+                    let kinds = zone_attrs.iter().map(ZoneAttributeDiscriminants::from).collect::<Vec<_>>();
+                    assert_eq!(kinds, ZoneAttributeDiscriminants::iter().collect::<Vec<_>>());
+                    assert!(zone_attrs.iter().all(|attr| attr.validate().is_ok()), "{}: {:?}", zone, zone_attrs);
+                },
+                _ => assert!(zone_attrs.is_empty())
+            }
+        }
+
+        // and the same status every time
+        let (updates_send, updates_recv) = crossbeam_channel::unbounded();
+        demo.setup_status_handlers(updates_send).unwrap();
+        assert_eq!(format!("{:?}", updates_recv.try_iter().collect::<Vec<_>>()), format!("{:?}", updates));
+    }
+}
@@ -1,8 +1,12 @@
-use std::{collections::HashMap, sync::{Arc, Mutex}, str::FromStr, error::Error};
+use std::{collections::HashSet, sync::{Arc, Mutex}, thread};
 
-use common::{mqtt::MqttConnectionManager, ids::SourceId, zone::{ZoneId, ZoneAttribute, ZoneIdError}};
+use common::{mqtt::{MqttConnectionManager, ReconnectHooks}, zone::{ZoneId, ZoneAttribute, ZoneAttributeDiscriminants, ZoneIdError, ZoneTopic, ZoneTopicFormat}};
 use crossbeam_channel::Sender;
 use rumqttc::{Publish, QoS};
+use strum::IntoEnumIterator;
+
+pub mod demo;
+pub mod settings;
 
 #[derive(Debug)]
 pub enum Connected {
@@ -14,9 +18,38 @@ pub enum SourceMeta {
     Name(String)
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
 pub enum ZoneMeta {
-    Name(String)
+    Name(String),
+
+    /// whether the zone is a physical zone, or a virtual amp/system zone
+    Kind(ZoneKind),
+
+    /// the amp the zone is on (`None` for the system zone)
+    Amp(Option<u8>),
+
+    /// the zone number on its amp (`None` for amp and system zones)
+    Index(Option<u8>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ZoneKind {
+    Zone,
+    Amp,
+    System
+}
+
+impl ZoneMeta {
+    /// The metadata derived from a zone's id (i.e. for grouping zones by amp), rather than published.
+    pub fn from_zone_id(zone: &ZoneId) -> Vec<ZoneMeta> {
+        let (kind, amp, index) = match *zone {
+            ZoneId::Zone { amp, zone } => (ZoneKind::Zone, Some(amp), Some(zone)),
+            ZoneId::Amp(amp) => (ZoneKind::Amp, Some(amp), None),
+            ZoneId::System => (ZoneKind::System, None, None),
+        };
+
+        vec![ZoneMeta::Kind(kind), ZoneMeta::Amp(amp), ZoneMeta::Index(index)]
+    }
 }
 
 #[derive(Debug)]
@@ -95,112 +128,437 @@ pub enum StatusUpdate {
 //     }
 // }
 
+/// The subscription side of an MQTT connection (i.e. a `MqttConnectionManager`).
+pub trait Subscriber: Send + 'static {
+    fn subscribe_handler(&mut self, topic: String, handler: HandlerFn) -> Result<(), rumqttc::ClientError>;
+
+    /// Send the existing subscriptions to the broker again, keeping their handlers.
+    fn resubscribe(&mut self) -> Result<(), rumqttc::ClientError>;
+
+    /// Hooks run after each reconnection to the broker.
+    fn reconnect_hooks(&self) -> &ReconnectHooks;
+}
+
+pub type HandlerFn = Box<dyn Fn(&Publish) + Send>;
+
+impl Subscriber for MqttConnectionManager {
+    fn subscribe_handler(&mut self, topic: String, handler: HandlerFn) -> Result<(), rumqttc::ClientError> {
+        self.subscribe(topic, QoS::AtLeastOnce, handler)
+    }
+
+    fn resubscribe(&mut self) -> Result<(), rumqttc::ClientError> {
+        MqttConnectionManager::resubscribe(self)
+    }
+
+    fn reconnect_hooks(&self) -> &ReconnectHooks {
+        MqttConnectionManager::reconnect_hooks(self)
+    }
+}
+
 pub struct Client {
+    topic_base: String,
+    zone_topic_format: ZoneTopicFormat,
 }
 
 
 impl Client {
-    pub fn new() -> Self {
+    /// `zone_topic_format` must match the daemon's `publish.zone_topic_format` (published to `status/zone-topic-format`).
+    pub fn new(topic_base: &str, zone_topic_format: ZoneTopicFormat) -> Self {
         Client {
+            topic_base: topic_base.to_string(),
+            zone_topic_format
         }
     }
 
     // pub fn set_zone_attribute(&self, )
 
 
-    pub fn setup_status_handlers<>(&self, mqtt: Arc<Mutex<MqttConnectionManager>>, updates_send: Sender<StatusUpdate>) {
-        let topic_base = "mwha/status/";
+    /// Subscribe to the zone list, and the status of each listed zone, sending updates to `updates_send`.
+    ///
+    /// The broker forgets subscriptions when the connection drops, so on reconnect they are re-subscribed (keeping
+    /// their handlers, so updates aren't delivered twice). Zones newly listed since are subscribed once the (retained)
+    /// zone list is received again.
+    pub fn setup_status_handlers<M: Subscriber>(&self, mqtt: Arc<Mutex<M>>, updates_send: Sender<StatusUpdate>) -> Result<(), rumqttc::ClientError> {
+        // zones with subscriptions installed
+        let zones = Arc::new(Mutex::new(HashSet::new()));
 
-        // for source in SourceId::all() {
-        //     mqtt.lock().unwrap().subscribe_json(format!("{}/source/{}/name", topic_base, source), QoS::AtLeastOnce, |publish: Publish, name: String| {
+        subscribe_zones(&mqtt, &self.topic_base, self.zone_topic_format, zones, updates_send)?;
 
-        //         self.status
+        let hook = {
+            let mqtt = mqtt.clone();
 
-        //         println!("{}: name: {}", source, name);
+            move || {
+                if let Err(e) = mqtt.lock().expect("lock mqtt").resubscribe() {
+                    log::error!("failed to re-subscribe to zone status after reconnect: {}", e);
+                }
+            }
+        };
 
-        //     });
-    
-        //     mqtt.subscribe_json(format!("{}/source/{}/enabled", topic_base, source), QoS::AtLeastOnce, |publish: Publish, enabled: bool| {
-                
-        //     });
-        // }
+        mqtt.lock().expect("lock mqtt").reconnect_hooks().add(hook);
 
-        
+        // handle out-of-order zones:  status/zones contains list of active zones, however we may get messages
+        // about zones we dont care about. how to handle?
+        // doesn't matter -- we only install handlers for zones after we get the zone list
+        //  the initial subscibe will only register handlers to get values for zones we care about
+        //  later, if the zone list changes, we can delete items from the zone list
+        //  handlers therefor should never add to the zone list -- it's an error to do so
+
+        Ok(())
+    }
+}
+
+/// subscribe to `status/zones`, installing subscriptions for newly listed zones when it changes.
+///
+/// handlers run on the MQTT notification thread, which is the only thread polling the event loop. subscribing blocks
+/// once the client's request queue is full, so newly listed zones are subscribed on a separate thread.
+fn subscribe_zones<M: Subscriber>(mqtt: &Arc<Mutex<M>>, topic_base: &str, format: ZoneTopicFormat, zones: Arc<Mutex<HashSet<ZoneId>>>, updates_send: Sender<StatusUpdate>) -> Result<(), rumqttc::ClientError> {
+    let (added_send, added_recv) = crossbeam_channel::unbounded::<Vec<ZoneId>>();
+
+    thread::Builder::new()
+        .name("MQTT zone subscriptions".to_string())
+        .spawn({
+            let mqtt = mqtt.clone();
+            let topic_base = topic_base.to_string();
+            let updates_send = updates_send.clone();
+
+            // exits once the handler (and its sender) is dropped
+            move || {
+                for added in added_recv {
+                    for zone in added {
+                        if let Err(e) = subscribe_zone(&mut *mqtt.lock().expect("lock mqtt"), &topic_base, format, zone, &updates_send) {
+                            log::error!("zone {}: failed to subscribe to status: {}", zone, e);
+                        }
+
+                        for meta in ZoneMeta::from_zone_id(&zone) {
+                            updates_send.send(StatusUpdate::ZoneMeta(zone, meta)).expect("send on updates_send");
+                        }
+                    }
+                }
+            }
+        }).expect("spawn MQTT zone subscriptions thread");
+
+    let handler = move |publish: &Publish| {
+        let available = serde_json::from_slice::<Vec<String>>(&publish.payload)
+            .map_err(|e| e.to_string())
+            .and_then(|ids| ids.iter().map(|id| format.parse(id)).collect::<Result<Vec<_>, ZoneIdError>>().map_err(|e| e.to_string()));
+
+        let available = match available {
+            Ok(available) => available,
+            Err(e) => {
+                log::error!("{}: {}", publish.topic, e);
+                updates_send.send(StatusUpdate::Error()).expect("send on updates_send");
+                return;
+            }
+        };
+
+        updates_send.send(StatusUpdate::AvailableZones(available.clone())).expect("send on updates_send");
+
+        // TODO: implement unsubscribe for zones that are no longer in the available zones list
+        let added = {
+            let mut zones = zones.lock().expect("lock zones");
+            available.into_iter().filter(|zone| zones.insert(*zone)).collect::<Vec<_>>()
+        };
+
+        if !added.is_empty() {
+            added_send.send(added).expect("send on added_send");
+        }
+    };
+
+    mqtt.lock().expect("lock mqtt").subscribe_handler(format!("{}status/zones", topic_base), Box::new(handler))
+}
 
-        // mqtt.lock().unwrap().subscribe_json(format!("{}zones", topic_base), QoS::AtLeastOnce, {
-        //     let mqtt = mqtt.clone();
+/// subscribe to a zone's name and (for physical zones) attribute status topics
+fn subscribe_zone<M: Subscriber>(mqtt: &mut M, topic_base: &str, format: ZoneTopicFormat, zone: ZoneId, updates_send: &Sender<StatusUpdate>) -> Result<(), rumqttc::ClientError> {
+    let handler = {
+        let updates_send = updates_send.clone();
 
-        //     move |publish: &Publish, zones: Vec<String>| {
-        //         let zones = zones.into_iter()
-        //             .map(|zone| ZoneId::from_str(&zone))
-        //             .collect::<Result<Vec<ZoneId>, ZoneIdError>>();
+        move |publish: &Publish| {
+            match serde_json::from_slice::<String>(&publish.payload) {
+                Ok(name) => updates_send.send(StatusUpdate::ZoneMeta(zone, ZoneMeta::Name(name))).expect("send on updates_send"),
+                Err(e) => log::error!("{}: {}", publish.topic, e)
+            }
+        }
+    };
 
-        //         let zones = match zones {
-        //             Ok(zones) => zones,
-        //             Err(e) => {
-        //                 log::error!("{}: {}", publish.topic, e);
-        //                 updates_send.send(StatusUpdate::Error()).expect("send on updates_send");
-        //                 return;
-        //             }
-        //         };
+    mqtt.subscribe_handler(ZoneTopic::Status.zone_topic_name(topic_base, format, &zone, "name"), Box::new(handler))?;
 
-        //         updates_send.send(StatusUpdate::AvailableZones(zones.clone())).expect("send on updates_send");
+    // System and Amp zones don't receive attribute status updates
+    let ZoneId::Zone { .. } = zone else {
+        return Ok(());
+    };
 
-        //         // TODO: implement unsubscribe for zones that are no longer in the available zones list
-                
+    for attr in ZoneAttributeDiscriminants::iter() {
+        let handler = {
+            let updates_send = updates_send.clone();
 
-        //         let mut mqtt = mqtt.lock().unwrap();
+            move |publish: &Publish| {
+                match decode_attribute(attr, &publish.payload) {
+                    Ok(attr) => updates_send.send(StatusUpdate::ZoneAttribute(zone, attr)).expect("send on updates_send"),
+                    Err(e) => log::error!("{}: {}", publish.topic, e)
+                }
+            }
+        };
 
-        //         for zone in zones {
-        //             dbg!(zone);
-        //             let topic_base = format!("{}zone/{}/", topic_base, zone);
+        mqtt.subscribe_handler(attr.mqtt_topic_name(ZoneTopic::Status, topic_base, format, &zone), Box::new(handler))?;
+    }
 
-        //             mqtt.subscribe_json(format!("{}name", topic_base), QoS::AtLeastOnce, {
-        //                 let updates_send = updates_send.clone();
+    Ok(())
+}
 
-        //                 move |_publish: &Publish, name: String| {
-        //                     updates_send.send(StatusUpdate::ZoneMeta(zone, ZoneMeta::Name(name)))
-        //                         .expect("send on updates_send");
-        //                 }
-        //             }).unwrap();
+/// decode a zone attribute status payload (booleans may be `ON`/`OFF`, depending on the daemon's `publish.bool_payload`)
+fn decode_attribute(attr: ZoneAttributeDiscriminants, payload: &[u8]) -> Result<ZoneAttribute, serde_json::Error> {
+    use ZoneAttributeDiscriminants::*;
+
+    let de_bool = || match payload {
+        b"ON" => Ok(true),
+        b"OFF" => Ok(false),
+        payload => serde_json::from_slice::<bool>(payload)
+    };
+    let de_u8 = || serde_json::from_slice::<u8>(payload);
+
+    match attr {
+        PublicAnnouncement => de_bool().map(ZoneAttribute::PublicAnnouncement),
+        Power => de_bool().map(ZoneAttribute::Power),
+        Mute => de_bool().map(ZoneAttribute::Mute),
+        DoNotDisturb => de_bool().map(ZoneAttribute::DoNotDisturb),
+        KeypadConnected => de_bool().map(ZoneAttribute::KeypadConnected),
+        Volume => de_u8().map(ZoneAttribute::Volume),
+        Treble => de_u8().map(ZoneAttribute::Treble),
+        Bass => de_u8().map(ZoneAttribute::Bass),
+        Balance => de_u8().map(ZoneAttribute::Balance),
+        Source => de_u8().map(ZoneAttribute::Source),
+    }
+}
 
-        //             // System and Amp zones don't receive attribute status updates
-        //             // is there a way to do if-let-or? or something better
-        //             if let ZoneId::Zone { amp: _, zone: _ } = zone {
-        //             } else {
-        //                 continue;
-        //             }
+/// The set topic and payload to publish to change a zone attribute (the inverse of `decode_attribute`).
+pub fn zone_set_message(topic_base: &str, format: ZoneTopicFormat, zone: &ZoneId, attr: &ZoneAttribute) -> (String, String) {
+    use ZoneAttribute::*;
 
-        //             mqtt.subscribe_json(format!("{}public-announcement", topic_base), QoS::AtLeastOnce, {
-        //                 let updates_send = updates_send.clone();
+    let topic = ZoneAttributeDiscriminants::from(attr).mqtt_topic_name(ZoneTopic::Set, topic_base, format, zone);
 
-        //                 move |_publish: &Publish, pa: bool| {
-        //                     updates_send.send(StatusUpdate::ZoneAttribute(zone, ZoneAttribute::PublicAnnouncement(pa)))
-        //                         .expect("send on updates_send");
-        //                 }
-        //             }).unwrap();
+    let payload = match *attr {
+        PublicAnnouncement(b) | Power(b) | Mute(b) | DoNotDisturb(b) | KeypadConnected(b) => b.to_string(),
+        Volume(v) | Treble(v) | Bass(v) | Balance(v) | Source(v) => v.to_string(),
+    };
 
-        //             mqtt.subscribe_json(format!("{}volume", topic_base), QoS::AtLeastOnce, {
-        //                 let updates_send = updates_send.clone();
+    (topic, payload)
+}
 
-        //                 move |_publish: &Publish, volume: u8| {
-        //                     updates_send.send(StatusUpdate::ZoneAttribute(zone, ZoneAttribute::Volume(volume)))
-        //                         .expect("send on updates_send");
-        //                 }
-        //             }).unwrap();
-        //         }
 
-                
-        //     }
-        // }).unwrap();
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
 
-        // handle out-of-order zones:  status/zones contains list of active zones, however we may get messages
-        // about zones we dont care about. how to handle?
-        // doesn't matter -- we only install handlers for zones after we get the zone list
-        //  the initial subscibe will only register handlers to get values for zones we care about
-        //  later, if the zone list changes, we can delete items from the zone list
-        //  handlers therefor should never add to the zone list -- it's an error to do so
+    use crossbeam_channel::Receiver;
 
+    use super::*;
+
+    /// records subscriptions, and delivers publishes to their handlers
+    #[derive(Default)]
+    struct MockSubscriber {
+        handlers: Arc<Mutex<HashMap<String, Arc<Mutex<HandlerFn>>>>>,
+        subscribes: Vec<String>,
+        resubscribes: usize,
+        hooks: ReconnectHooks,
+    }
+
+    impl Subscriber for MockSubscriber {
+        fn subscribe_handler(&mut self, topic: String, handler: HandlerFn) -> Result<(), rumqttc::ClientError> {
+            self.subscribes.push(topic.clone());
+            self.handlers.lock().unwrap().insert(topic, Arc::new(Mutex::new(handler)));
+            Ok(())
+        }
+
+        fn resubscribe(&mut self) -> Result<(), rumqttc::ClientError> {
+            self.resubscribes += 1;
+            Ok(())
+        }
+
+        fn reconnect_hooks(&self) -> &ReconnectHooks {
+            &self.hooks
+        }
+    }
+
+    fn deliver(mqtt: &Arc<Mutex<MockSubscriber>>, topic: &str, payload: &str) {
+        // don't hold the lock while handling, handlers may subscribe
+        let handler = mqtt.lock().unwrap().handlers.lock().unwrap().get(topic).cloned().expect("subscribed");
+        (handler.lock().unwrap())(&Publish::new(topic, QoS::AtLeastOnce, payload));
+    }
+
+    fn subscribe_count(mqtt: &Arc<Mutex<MockSubscriber>>, topic: &str) -> usize {
+        mqtt.lock().unwrap().subscribes.iter().filter(|t| *t == topic).count()
+    }
+
+    fn drain(recv: &Receiver<StatusUpdate>) -> Vec<StatusUpdate> {
+        recv.try_iter().collect()
+    }
+
+    /// wait for `zones` newly listed zones to be subscribed (on the zone subscriptions thread), returning the updates
+    /// received meanwhile. each zone's metadata is sent once it's subscribed
+    fn wait_subscribed(recv: &Receiver<StatusUpdate>, zones: usize) -> Vec<StatusUpdate> {
+        let mut updates = Vec::new();
+
+        while updates.iter().filter(|update| matches!(update, StatusUpdate::ZoneMeta(_, ZoneMeta::Index(_)))).count() < zones {
+            updates.push(recv.recv_timeout(std::time::Duration::from_secs(5)).expect("zone subscribed"));
+        }
+
+        updates
+    }
+
+    #[test]
+    fn test_reconnect_resubscribes() {
+        let mqtt = Arc::new(Mutex::new(MockSubscriber::default()));
+        let (updates_send, updates_recv) = crossbeam_channel::unbounded();
+
+        Client::new("mwha/", ZoneTopicFormat::Numeric).setup_status_handlers(mqtt.clone(), updates_send).unwrap();
+        assert_eq!(mqtt.lock().unwrap().subscribes, vec!["mwha/status/zones"]);
+
+        let hooks = mqtt.lock().unwrap().hooks.clone();
+        assert!(hooks.connected().is_none()); // initial connection
+
+        // zone subscriptions follow the zone list
+        deliver(&mqtt, "mwha/status/zones", r#"["11", "10"]"#);
+        wait_subscribed(&updates_recv, 2);
+        assert_eq!(subscribe_count(&mqtt, "mwha/status/zone/11/volume"), 1);
+        assert_eq!(subscribe_count(&mqtt, "mwha/status/zone/10/name"), 1);
+        assert_eq!(subscribe_count(&mqtt, "mwha/status/zone/10/volume"), 0); // amp zones only have a name
+
+        drain(&updates_recv);
+        deliver(&mqtt, "mwha/status/zone/11/volume", "20");
+        assert!(matches!(drain(&updates_recv)[..], [StatusUpdate::ZoneAttribute(ZoneId::Zone { amp: 1, zone: 1 }, ZoneAttribute::Volume(20))]));
+
+        // an unchanged zone list doesn't re-subscribe
+        deliver(&mqtt, "mwha/status/zones", r#"["11", "10"]"#);
+        assert_eq!(subscribe_count(&mqtt, "mwha/status/zone/11/volume"), 1);
+
+        // reconnect re-subscribes, keeping the existing handlers...
+        hooks.connected().unwrap().join().unwrap();
+        assert_eq!(mqtt.lock().unwrap().resubscribes, 1);
+        assert_eq!(subscribe_count(&mqtt, "mwha/status/zones"), 1);
+
+        // ...and only newly listed zones are subscribed when the zone list is received again
+        deliver(&mqtt, "mwha/status/zones", r#"["11", "10", "12"]"#);
+        wait_subscribed(&updates_recv, 1);
+        assert_eq!(subscribe_count(&mqtt, "mwha/status/zone/11/volume"), 1);
+        assert_eq!(subscribe_count(&mqtt, "mwha/status/zone/10/name"), 1);
+        assert_eq!(subscribe_count(&mqtt, "mwha/status/zone/12/volume"), 1);
+
+        drain(&updates_recv);
+        deliver(&mqtt, "mwha/status/zone/12/power", "true");
+        assert!(matches!(drain(&updates_recv)[..], [StatusUpdate::ZoneAttribute(ZoneId::Zone { amp: 1, zone: 2 }, ZoneAttribute::Power(true))]));
+    }
+
+    #[test]
+    fn test_zone_meta() {
+        let mqtt = Arc::new(Mutex::new(MockSubscriber::default()));
+        let (updates_send, updates_recv) = crossbeam_channel::unbounded();
+
+        Client::new("mwha/", ZoneTopicFormat::Numeric).setup_status_handlers(mqtt.clone(), updates_send).unwrap();
+
+        deliver(&mqtt, "mwha/status/zones", r#"["00", "20", "23"]"#);
+
+        let metas = wait_subscribed(&updates_recv, 3).into_iter()
+            .filter_map(|update| match update {
+                StatusUpdate::ZoneMeta(zone, meta) => Some((zone, meta)),
+                _ => None
+            })
+            .collect::<Vec<_>>();
+
+        let system = ZoneId::System;
+        let amp = ZoneId::Amp(2);
+        let zone = ZoneId::Zone { amp: 2, zone: 3 };
+
+        assert_eq!(metas, vec![
+            (system, ZoneMeta::Kind(ZoneKind::System)), (system, ZoneMeta::Amp(None)), (system, ZoneMeta::Index(None)),
+            (amp, ZoneMeta::Kind(ZoneKind::Amp)), (amp, ZoneMeta::Amp(Some(2))), (amp, ZoneMeta::Index(None)),
+            (zone, ZoneMeta::Kind(ZoneKind::Zone)), (zone, ZoneMeta::Amp(Some(2))), (zone, ZoneMeta::Index(Some(3))),
+        ]);
+
+        // names are published
+        deliver(&mqtt, "mwha/status/zone/20/name", r#""Upstairs""#);
+        assert_eq!(drain(&updates_recv).into_iter().filter_map(|update| match update {
+            StatusUpdate::ZoneMeta(zone, meta) => Some((zone, meta)),
+            _ => None
+        }).collect::<Vec<_>>(), vec![(amp, ZoneMeta::Name("Upstairs".to_string()))]);
+
+        // only emitted for newly listed zones
+        deliver(&mqtt, "mwha/status/zones", r#"["00", "20", "23"]"#);
+        assert!(matches!(drain(&updates_recv)[..], [StatusUpdate::AvailableZones(_)]));
     }
-}
 
+    #[test]
+    fn test_decode_attribute() {
+        use ZoneAttributeDiscriminants::*;
+
+        assert_eq!(decode_attribute(Power, b"true").unwrap(), ZoneAttribute::Power(true));
+        assert_eq!(decode_attribute(Power, b"ON").unwrap(), ZoneAttribute::Power(true));
+        assert_eq!(decode_attribute(Mute, b"OFF").unwrap(), ZoneAttribute::Mute(false));
+        assert_eq!(decode_attribute(Volume, b"20").unwrap(), ZoneAttribute::Volume(20));
+        assert!(decode_attribute(Volume, b"ON").is_err());
+    }
+
+    #[test]
+    fn test_zone_set_message() {
+        let zone = ZoneId::Zone { amp: 1, zone: 2 };
+
+        assert_eq!(zone_set_message("mwha/", ZoneTopicFormat::Numeric, &zone, &ZoneAttribute::Mute(true)), ("mwha/set/zone/12/mute".to_string(), "true".to_string()));
+        assert_eq!(zone_set_message("mwha/", ZoneTopicFormat::Dashed, &zone, &ZoneAttribute::Volume(20)), ("mwha/set/zone/1-2/volume".to_string(), "20".to_string()));
+
+        // the daemon decodes set payloads as the status is published
+        assert_eq!(decode_attribute(ZoneAttributeDiscriminants::Source, zone_set_message("mwha/", ZoneTopicFormat::Numeric, &zone, &ZoneAttribute::Source(3)).1.as_bytes()).unwrap(), ZoneAttribute::Source(3));
+    }
+
+    #[test]
+    fn test_path_zone_topic_format() {
+        let mqtt = Arc::new(Mutex::new(MockSubscriber::default()));
+        let (updates_send, updates_recv) = crossbeam_channel::unbounded();
+
+        Client::new("mwha/", ZoneTopicFormat::Path).setup_status_handlers(mqtt.clone(), updates_send).unwrap();
+
+        deliver(&mqtt, "mwha/status/zones", r#"["amp1/zone2", "amp1"]"#);
+        assert!(matches!(wait_subscribed(&updates_recv, 2)[0], StatusUpdate::AvailableZones(ref zones) if zones == &[ZoneId::Zone { amp: 1, zone: 2 }, ZoneId::Amp(1)]));
+        assert_eq!(subscribe_count(&mqtt, "mwha/status/zone/amp1/zone2/volume"), 1);
+        assert_eq!(subscribe_count(&mqtt, "mwha/status/zone/amp1/name"), 1);
+        assert_eq!(subscribe_count(&mqtt, "mwha/status/zone/12/volume"), 0);
+
+        deliver(&mqtt, "mwha/status/zone/amp1/zone2/volume", "20");
+        assert!(matches!(drain(&updates_recv)[..], [StatusUpdate::ZoneAttribute(ZoneId::Zone { amp: 1, zone: 2 }, ZoneAttribute::Volume(20))]));
+
+        // numeric ids don't parse in another format
+        deliver(&mqtt, "mwha/status/zones", r#"["12"]"#);
+        assert!(matches!(drain(&updates_recv)[..], [StatusUpdate::Error()]));
+    }
+
+    #[test]
+    fn test_zone_list_handler_does_not_subscribe() {
+        let mqtt = Arc::new(Mutex::new(MockSubscriber::default()));
+        let (updates_send, updates_recv) = crossbeam_channel::unbounded();
+
+        Client::new("mwha/", ZoneTopicFormat::Numeric).setup_status_handlers(mqtt.clone(), updates_send).unwrap();
+
+        let handler = mqtt.lock().unwrap().handlers.lock().unwrap().get("mwha/status/zones").cloned().unwrap();
+
+        // the handler runs on the MQTT notification thread, so it must not wait on subscribing (i.e. while the
+        // reconnect hooks hold the connection to re-subscribe, or the client's request queue is full)
+        {
+            let _mqtt = mqtt.lock().unwrap();
+            (handler.lock().unwrap())(&Publish::new("mwha/status/zones", QoS::AtLeastOnce, r#"["11", "12"]"#));
+        }
+
+        wait_subscribed(&updates_recv, 2);
+        assert_eq!(subscribe_count(&mqtt, "mwha/status/zone/11/volume"), 1);
+        assert_eq!(subscribe_count(&mqtt, "mwha/status/zone/12/volume"), 1);
+    }
+
+    #[test]
+    fn test_invalid_zone_list() {
+        let mqtt = Arc::new(Mutex::new(MockSubscriber::default()));
+        let (updates_send, updates_recv) = crossbeam_channel::unbounded();
+
+        Client::new("mwha/", ZoneTopicFormat::Numeric).setup_status_handlers(mqtt.clone(), updates_send).unwrap();
+
+        deliver(&mqtt, "mwha/status/zones", r#"["11", "99"]"#);
+        assert!(matches!(drain(&updates_recv)[..], [StatusUpdate::Error()]));
+        assert_eq!(mqtt.lock().unwrap().subscribes.len(), 1);
+    }
+}
@@ -0,0 +1,140 @@
+use std::{fs, io, path::Path};
+
+use serde::{Serialize, Deserialize};
+
+/// MQTT broker connection settings (i.e. for a mixer's preferences), persisted as TOML.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConnectionSettings {
+    /// broker URL, i.e. `mqtt://localhost:1883`
+    pub url: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+
+    /// prefix of the `mwha2mqttd` topics, i.e. `mwha/`
+    pub topic_base: String,
+}
+
+impl Default for ConnectionSettings {
+    fn default() -> Self {
+        Self {
+            url: "mqtt://localhost:1883".to_string(),
+            username: None,
+            password: None,
+            topic_base: "mwha/".to_string(),
+        }
+    }
+}
+
+impl ConnectionSettings {
+    pub fn from_toml(s: &str) -> Result<Self, toml::de::Error> {
+        toml::from_str(s)
+    }
+
+    pub fn to_toml(&self) -> Result<String, toml::ser::Error> {
+        toml::to_string(self)
+    }
+
+    /// Load settings from `path`, or the defaults if it doesn't exist.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(s) => Self::from_toml(&s).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e)
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let s = self.to_toml().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        fs::write(path, s)
+    }
+
+    /// Normalize values entered in the preferences dialog (empty credentials are unset, the topic base ends with `/`).
+    pub fn normalized(mut self) -> Self {
+        self.url = self.url.trim().to_string();
+        self.username = self.username.filter(|u| !u.is_empty());
+        self.password = self.password.filter(|p| !p.is_empty());
+
+        if !self.topic_base.is_empty() && !self.topic_base.ends_with('/') {
+            self.topic_base.push('/');
+        }
+
+        self
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let settings = ConnectionSettings {
+            url: "mqtts://broker.local:8883".to_string(),
+            username: Some("mixer".to_string()),
+            password: Some("hunter2".to_string()),
+            topic_base: "home/mwha/".to_string(),
+        };
+
+        assert_eq!(ConnectionSettings::from_toml(&settings.to_toml().unwrap()).unwrap(), settings);
+
+        // unset credentials are omitted
+        let toml = ConnectionSettings::default().to_toml().unwrap();
+        assert!(!toml.contains("username"));
+        assert!(!toml.contains("password"));
+        assert_eq!(ConnectionSettings::from_toml(&toml).unwrap(), ConnectionSettings::default());
+    }
+
+    #[test]
+    fn test_defaults() {
+        let settings = ConnectionSettings::from_toml(r#"url = "mqtt://pi:1883""#).unwrap();
+
+        assert_eq!(settings.url, "mqtt://pi:1883");
+        assert_eq!(settings.username, None);
+        assert_eq!(settings.topic_base, "mwha/");
+
+        assert_eq!(ConnectionSettings::from_toml("").unwrap(), ConnectionSettings::default());
+
+        assert!(ConnectionSettings::from_toml("url = 1883").is_err());
+        assert!(ConnectionSettings::from_toml("url = ").is_err());
+    }
+
+    #[test]
+    fn test_normalized() {
+        let settings = ConnectionSettings {
+            url: " mqtt://pi ".to_string(),
+            username: Some(String::new()),
+            password: Some(String::new()),
+            topic_base: "mwha".to_string(),
+        }.normalized();
+
+        assert_eq!(settings, ConnectionSettings { url: "mqtt://pi".to_string(), topic_base: "mwha/".to_string(), ..Default::default() });
+    }
+
+    #[test]
+    fn test_load_save() {
+        let path = std::env::temp_dir().join(format!("mwha-client-settings-test-{}", std::process::id())).join("settings.toml");
+
+        // missing files are the defaults
+        assert_eq!(ConnectionSettings::load(&path).unwrap(), ConnectionSettings::default());
+
+        let settings = ConnectionSettings { username: Some("mixer".to_string()), ..Default::default() };
+        settings.save(&path).unwrap();
+        assert_eq!(ConnectionSettings::load(&path).unwrap(), settings);
+
+        fs::write(&path, "url = [").unwrap();
+        assert_eq!(ConnectionSettings::load(&path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,63 @@
+use std::ops::RangeInclusive;
+
+/// Render a value as a bar filled from the start of `range`, followed by the value (i.e. `[███░░░] (3/6)`).
+///
+/// Values outside of `range` are clamped.
+pub fn bar(value: u8, range: RangeInclusive<u8>) -> String {
+    let value = value.clamp(*range.start(), *range.end());
+
+    let filled = usize::from(value - range.start());
+    let empty = usize::from(range.end() - value);
+
+    format!("[{}{}] ({}/{})", "█".repeat(filled), "░".repeat(empty), value, range.end())
+}
+
+/// Render a value as a mark on a track spanning `range`, followed by the value (i.e. `[──◉────] (2/6)`).
+///
+/// Used for attributes that are centered (i.e. treble, bass and balance). Values outside of `range` are clamped.
+pub fn slider(value: u8, range: RangeInclusive<u8>) -> String {
+    let value = value.clamp(*range.start(), *range.end());
+
+    let left = usize::from(value - range.start());
+    let right = usize::from(range.end() - value);
+
+    format!("[{}◉{}] ({}/{})", "─".repeat(left), "─".repeat(right), value, range.end())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::zone::ranges;
+
+    use super::*;
+
+    #[test]
+    fn test_bar() {
+        assert_eq!(bar(0, ranges::VOLUME), format!("[{}] (0/38)", "░".repeat(38)));
+        assert_eq!(bar(19, ranges::VOLUME), format!("[{}{}] (19/38)", "█".repeat(19), "░".repeat(19)));
+        assert_eq!(bar(38, ranges::VOLUME), format!("[{}] (38/38)", "█".repeat(38)));
+
+        // ranges that don't start at 0
+        assert_eq!(bar(1, ranges::SOURCE), "[░░░░░] (1/6)");
+        assert_eq!(bar(3, ranges::SOURCE), "[██░░░] (3/6)");
+        assert_eq!(bar(6, ranges::SOURCE), "[█████] (6/6)");
+
+        // clamped
+        assert_eq!(bar(0, ranges::SOURCE), "[░░░░░] (1/6)");
+        assert_eq!(bar(99, ranges::VOLUME), bar(38, ranges::VOLUME));
+    }
+
+    #[test]
+    fn test_slider() {
+        assert_eq!(slider(0, ranges::TREBLE), format!("[◉{}] (0/14)", "─".repeat(14)));
+        assert_eq!(slider(7, ranges::TREBLE), format!("[{0}◉{0}] (7/14)", "─".repeat(7)));
+        assert_eq!(slider(14, ranges::TREBLE), format!("[{}◉] (14/14)", "─".repeat(14)));
+
+        assert_eq!(slider(0, ranges::BALANCE), format!("[◉{}] (0/20)", "─".repeat(20)));
+        assert_eq!(slider(10, ranges::BALANCE), format!("[{0}◉{0}] (10/20)", "─".repeat(10)));
+        assert_eq!(slider(20, ranges::BALANCE), format!("[{}◉] (20/20)", "─".repeat(20)));
+
+        // clamped
+        assert_eq!(slider(99, ranges::BALANCE), slider(20, ranges::BALANCE));
+    }
+}
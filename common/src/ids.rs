@@ -1,22 +1,49 @@
-use std::{str::FromStr, fmt::Display, num::ParseIntError, ops::RangeInclusive};
+use std::{str::FromStr, fmt::Display, num::ParseIntError, ops::RangeInclusive, hash::Hash};
 
 use serde::{Serialize, Deserialize};
 use thiserror::Error;
 
+use crate::zone::ZoneAttribute;
+
 pub const SOURCES: RangeInclusive<u8> = 1..=6;
 
+/// An id addressed by a single value in the amp protocol (i.e. `ZoneId`, `SourceId`),
+/// and written as that value in config keys, CLI args and MQTT topics.
+pub trait ProtocolId: Copy + Eq + Hash + Display {
+    type Error: std::error::Error + Send + Sync + 'static;
+
+    /// Parse an id from its string form (the inverse of `Display`).
+    fn parse_id(s: &str) -> Result<Self, Self::Error>;
+
+    /// Decode and validate an id from its protocol value.
+    fn decode(value: u8) -> Result<Self, Self::Error>;
+
+    /// The protocol value of the id.
+    fn encode(&self) -> u8;
+
+    fn is_valid(value: u8) -> bool {
+        Self::decode(value).is_ok()
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum SourceIdError {
     #[error("source id {0} is out of range [1,6]")]
     OutOfRange(u8),
 
-    #[error("cannot parse \"{value}\" as source id ({source})")]
+    #[error("source id is empty")]
+    Empty,
+
+    #[error("cannot parse \"{value}\" as source id, expected a number ({source})")]
     ParseFailure {
         value: String,
 
          #[source]
          source: ParseIntError,
-    }
+    },
+
+    #[error("zone attribute {0:?} is not a source")]
+    NotASource(ZoneAttribute),
 }
 
 
@@ -25,7 +52,17 @@ pub struct SourceId(u8);
 
 impl SourceId {
     pub fn all() -> Vec<SourceId> {
-        (1..=6).into_iter().map(SourceId).collect()
+        Self::up_to(*SOURCES.end()).collect()
+    }
+
+    /// Sources 1 through `count` (clamped to the amp's 6 inputs), in order.
+    pub fn up_to(count: u8) -> impl Iterator<Item = SourceId> {
+        (*SOURCES.start()..=count.min(*SOURCES.end())).map(SourceId)
+    }
+
+    /// The 1-based value used for this source by the amp protocol (i.e. in `ZoneAttribute::Source`).
+    pub fn as_zone_source_value(&self) -> u8 {
+        self.0
     }
 }
 
@@ -33,6 +70,11 @@ impl FromStr for SourceId {
     type Err = SourceIdError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Err(SourceIdError::Empty);
+        }
+
         let i = s.parse::<u8>().map_err(|e| SourceIdError::ParseFailure{ value: s.to_string(), source: e })?;
         SourceId::try_from(i)
     }
@@ -50,9 +92,48 @@ impl TryFrom<u8> for SourceId {
     }
 }
 
+impl TryFrom<ZoneAttribute> for SourceId {
+    type Error = SourceIdError;
+
+    fn try_from(value: ZoneAttribute) -> Result<Self, Self::Error> {
+        match value {
+            ZoneAttribute::Source(source) => SourceId::try_from(source),
+            other => Err(SourceIdError::NotASource(other))
+        }
+    }
+}
+
 impl From<&SourceId> for u8 {
     fn from(value: &SourceId) -> Self {
-        value.0
+        value.as_zone_source_value()
+    }
+}
+
+impl From<SourceId> for u8 {
+    fn from(value: SourceId) -> Self {
+        value.as_zone_source_value()
+    }
+}
+
+impl From<SourceId> for ZoneAttribute {
+    fn from(value: SourceId) -> Self {
+        ZoneAttribute::Source(value.as_zone_source_value())
+    }
+}
+
+impl ProtocolId for SourceId {
+    type Error = SourceIdError;
+
+    fn parse_id(s: &str) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+
+    fn decode(value: u8) -> Result<Self, Self::Error> {
+        SourceId::try_from(value)
+    }
+
+    fn encode(&self) -> u8 {
+        self.into()
     }
 }
 
@@ -60,4 +141,75 @@ impl Display for SourceId {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", self.0)
     }
-}
\ No newline at end of file
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fmt::Debug;
+
+    use crate::zone::ZoneId;
+
+    use super::*;
+
+    /// Check the `ProtocolId` contract: encode/decode and parse/display round trip, and invalid values are rejected.
+    fn assert_protocol_id<T: ProtocolId + Debug>(ids: &[T], invalid: &[u8]) {
+        for id in ids {
+            assert_eq!(T::decode(id.encode()).unwrap(), *id);
+            assert_eq!(T::parse_id(&id.to_string()).unwrap(), *id);
+            assert!(T::is_valid(id.encode()));
+        }
+
+        for value in invalid {
+            assert!(!T::is_valid(*value));
+            assert!(T::decode(*value).is_err());
+            assert!(T::parse_id(&value.to_string()).is_err());
+        }
+
+        assert!(T::parse_id("x").is_err());
+        assert!(T::parse_id("256").is_err());
+    }
+
+    #[test]
+    fn test_source_id_from_str() {
+        assert_eq!(" 2 ".parse::<SourceId>().unwrap(), SourceId(2));
+
+        assert!(matches!("".parse::<SourceId>(), Err(SourceIdError::Empty)));
+        assert!(matches!(" ".parse::<SourceId>(), Err(SourceIdError::Empty)));
+        assert!(matches!("1a".parse::<SourceId>(), Err(SourceIdError::ParseFailure { value, .. }) if value == "1a"));
+    }
+
+    #[test]
+    fn test_source_id_up_to() {
+        assert_eq!(SourceId::up_to(0).count(), 0);
+        assert_eq!(SourceId::up_to(2).collect::<Vec<_>>(), vec![SourceId(1), SourceId(2)]);
+        assert_eq!(SourceId::up_to(6).collect::<Vec<_>>(), SourceId::all());
+        assert_eq!(SourceId::up_to(255).collect::<Vec<_>>(), SourceId::all());
+    }
+
+    #[test]
+    fn test_protocol_ids() {
+        assert_protocol_id(&SourceId::all(), &[0, 7, 255]);
+
+        let zones: Vec<ZoneId> = ZoneId::System.to_zones().into_iter()
+            .chain(ZoneId::System.to_amps())
+            .chain([ZoneId::System])
+            .collect();
+        assert_protocol_id(&zones, &[7, 9, 17, 40, 255]);
+    }
+
+    #[test]
+    fn test_zone_source_round_trip() {
+        for source_id in SourceId::all() {
+            let attr = ZoneAttribute::from(source_id);
+
+            assert_eq!(attr, ZoneAttribute::Source(source_id.as_zone_source_value()));
+            assert_eq!(SourceId::try_from(attr).unwrap(), source_id);
+            assert_eq!(SourceId::try_from(u8::from(source_id)).unwrap(), source_id);
+        }
+
+        assert!(matches!(SourceId::try_from(ZoneAttribute::Source(0)), Err(SourceIdError::OutOfRange(0))));
+        assert!(matches!(SourceId::try_from(ZoneAttribute::Source(7)), Err(SourceIdError::OutOfRange(7))));
+        assert!(matches!(SourceId::try_from(ZoneAttribute::Volume(1)), Err(SourceIdError::NotASource(ZoneAttribute::Volume(1)))));
+    }
+}
@@ -1,3 +1,6 @@
+pub mod display;
+pub mod duration;
 pub mod ids;
 pub mod mqtt;
+pub mod payload;
 pub mod zone;
\ No newline at end of file
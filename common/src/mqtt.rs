@@ -1,19 +1,26 @@
 use std::{sync::{Arc, Mutex}, collections::HashMap, thread::{self, JoinHandle}, fs::File, io::{BufReader}, env, path::{Path, PathBuf}, any, str::Utf8Error, fmt::Display};
 use std::str;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
 use anyhow::{bail, Context};
 use bytes::Bytes;
 use crossbeam_channel::{Sender, Receiver, select};
 use log::{warn, error, info};
 use rumqttc::{Client, Publish, Connection, Event, Packet, MqttOptions, tokio_rustls::rustls::{RootCertStore, Certificate, ClientConfig, PrivateKey}, ConnectionError, Subscribe};
 use serde_json::Value;
-use serde::{Deserialize, de::DeserializeOwned};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
 use figment::value::magic::RelativePathBuf;
 
+use crate::duration;
+
 
 pub trait PublishJson {
     fn publish_json<S>(&mut self, topic: S, qos: rumqttc::QoS, retain: bool, value: Value) -> Result<(), rumqttc::ClientError> where 
         S: Into<String>;
-    
+
+    /// Clear a retained topic (publish an empty retained message).
+    fn clear_retained<S>(&mut self, topic: S, qos: rumqttc::QoS) -> Result<(), rumqttc::ClientError> where
+        S: Into<String>;
 }
 
 impl PublishJson for Client {
@@ -22,6 +29,202 @@ impl PublishJson for Client {
     {
         self.publish(topic, qos, retain, value.to_string())
     }
+
+    fn clear_retained<S>(&mut self, topic: S, qos: rumqttc::QoS) -> Result<(), rumqttc::ClientError> where
+        S: Into<String>
+    {
+        self.publish(topic, qos, true, Vec::new())
+    }
+}
+
+/// Estimates the number of publishes queued with the client that the MQTT event loop has yet to send.
+///
+/// Only publishes made via a `BacklogClient` are counted as queued, and resends after a reconnection are counted
+/// as sent again, so the estimate is approximate.
+#[derive(Clone, Default)]
+pub struct PublishBacklog {
+    queued: Arc<AtomicUsize>,
+    sent: Arc<AtomicUsize>,
+}
+
+impl PublishBacklog {
+    fn record_queued(&self) {
+        self.queued.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn record_sent(&self) {
+        self.sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn len(&self) -> usize {
+        self.queued.load(Ordering::Relaxed).saturating_sub(self.sent.load(Ordering::Relaxed))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// non-retained publishes queued for a mirror beyond this are dropped, oldest first
+const MIRROR_MAX_QUEUED_EVENTS: usize = 1000;
+
+enum MirrorRequest {
+    Publish(Publish),
+    Disconnect
+}
+
+/// Publishes waiting to be forwarded to a mirror broker.
+///
+/// Retained publishes are coalesced by topic (a queued retained publish is replaced by a newer one to the same topic),
+/// so the mirror's retained state is brought up to date once it's reachable, however long it was down, without the queue
+/// growing. Other publishes are dropped once `MIRROR_MAX_QUEUED_EVENTS` are queued.
+#[derive(Default)]
+struct MirrorQueue {
+    requests: Mutex<std::collections::VecDeque<MirrorRequest>>,
+    queued: std::sync::Condvar,
+}
+
+impl MirrorQueue {
+    /// Forward the queued requests to `client` from a new thread, which exits after forwarding a disconnect.
+    fn spawn(client: Client) -> Arc<MirrorQueue> {
+        let queue = Arc::new(MirrorQueue::default());
+
+        thread::Builder::new()
+            .name("MQTT mirror".to_string())
+            .spawn({
+                let queue = queue.clone();
+                move || queue.forward(client)
+            }).expect("spawn MQTT mirror thread");
+
+        queue
+    }
+
+    fn push(&self, request: MirrorRequest) {
+        let mut requests = self.requests.lock().expect("lock mirror requests");
+
+        match request {
+            MirrorRequest::Publish(publish) if publish.retain => {
+                match requests.iter_mut().find(|r| matches!(r, MirrorRequest::Publish(p) if p.retain && p.topic == publish.topic)) {
+                    Some(queued) => *queued = MirrorRequest::Publish(publish),
+                    None => requests.push_back(MirrorRequest::Publish(publish))
+                }
+            },
+            MirrorRequest::Publish(publish) => {
+                let events = requests.iter().filter(|r| matches!(r, MirrorRequest::Publish(p) if !p.retain)).count();
+
+                if events >= MIRROR_MAX_QUEUED_EVENTS {
+                    let oldest = requests.iter().position(|r| matches!(r, MirrorRequest::Publish(p) if !p.retain)).expect("queued event");
+                    requests.remove(oldest);
+
+                    log::debug!("mirror queue full, dropped oldest non-retained publish");
+                }
+
+                requests.push_back(MirrorRequest::Publish(publish));
+            },
+            MirrorRequest::Disconnect => requests.push_back(MirrorRequest::Disconnect)
+        }
+
+        self.queued.notify_one();
+    }
+
+    fn pop(&self) -> MirrorRequest {
+        let mut requests = self.requests.lock().expect("lock mirror requests");
+
+        loop {
+            match requests.pop_front() {
+                Some(request) => return request,
+                None => requests = self.queued.wait(requests).expect("lock mirror requests")
+            }
+        }
+    }
+
+    /// blocks while the mirror's client queue is full (i.e. the mirror is unreachable), publishes keep coalescing meanwhile
+    fn forward(&self, mut client: Client) {
+        loop {
+            let result = match self.pop() {
+                MirrorRequest::Publish(publish) => client.publish(publish.topic, publish.qos, publish.retain, publish.payload.to_vec()),
+                MirrorRequest::Disconnect => return client.disconnect().unwrap_or_else(|e| warn!("failed to disconnect from mirror MQTT broker: {}", e))
+            };
+
+            if let Err(e) = result {
+                error!("mirror MQTT client stopped: {}", e);
+                return
+            }
+        }
+    }
+}
+
+/// A `Client` that records its publishes in a `PublishBacklog`, and copies them to any mirror brokers.
+#[derive(Clone)]
+pub struct BacklogClient {
+    client: Client,
+    backlog: PublishBacklog,
+
+    /// queues of the mirror brokers that every publish is copied to
+    mirrors: Vec<Arc<MirrorQueue>>,
+}
+
+impl BacklogClient {
+    pub fn new(client: Client, backlog: PublishBacklog) -> Self {
+        Self { client, backlog, mirrors: Vec::new() }
+    }
+
+    /// Also publish to the broker of `mirror`.
+    ///
+    /// Publishes are forwarded to the mirror from another thread (see `MirrorQueue`), so an unreachable mirror never
+    /// holds up publishing, and mirrored publishes aren't counted in the backlog.
+    pub fn mirror(&mut self, mirror: &MqttConnectionManager) {
+        self.mirrors.push(MirrorQueue::spawn(mirror.client.clone()));
+    }
+
+    pub fn publish<S, V>(&mut self, topic: S, qos: rumqttc::QoS, retain: bool, payload: V) -> Result<(), rumqttc::ClientError>
+    where
+        S: Into<String>,
+        V: Into<Vec<u8>>
+    {
+        let topic = topic.into();
+        let payload = payload.into();
+
+        for mirror in &self.mirrors {
+            let mut publish = Publish::new(&topic, qos, payload.clone());
+            publish.retain = retain;
+
+            mirror.push(MirrorRequest::Publish(publish));
+        }
+
+        self.client.publish(topic, qos, retain, payload)?;
+        self.backlog.record_queued();
+
+        Ok(())
+    }
+
+    /// Disconnect from the broker, and from any mirrors once their queued publishes have been forwarded.
+    pub fn disconnect(&mut self) -> Result<(), rumqttc::ClientError> {
+        for mirror in &self.mirrors {
+            mirror.push(MirrorRequest::Disconnect);
+        }
+
+        self.client.disconnect()
+    }
+
+    /// Estimated number of publishes yet to be sent.
+    pub fn backlog(&self) -> usize {
+        self.backlog.len()
+    }
+}
+
+impl PublishJson for BacklogClient {
+    fn publish_json<S>(&mut self, topic: S, qos: rumqttc::QoS, retain: bool, value: Value) -> Result<(), rumqttc::ClientError> where
+        S: Into<String>
+    {
+        self.publish(topic, qos, retain, value.to_string())
+    }
+
+    fn clear_retained<S>(&mut self, topic: S, qos: rumqttc::QoS) -> Result<(), rumqttc::ClientError> where
+        S: Into<String>
+    {
+        self.publish(topic, qos, true, Vec::new())
+    }
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -42,14 +245,8 @@ pub enum PayloadDecodeError {
 
 impl Display for PayloadDecodeError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        fn printable_payload<'A>(p: &Bytes) -> String {
-            let mut p = String::from_utf8_lossy(p);
-
-            // if p.len() > 50 {
-            //     p = 
-            // }
-
-            p.escape_default().to_string()
+        fn printable_payload(p: &Bytes) -> String {
+            crate::payload::preview_payload(p, 50)
         }
 
         match self {
@@ -67,118 +264,319 @@ impl Display for PayloadDecodeError {
 
 type HandlerFn = Box<dyn Fn(&Publish) + Send>;
 
+type HookFn = Box<dyn Fn() + Send>;
+
 type CoHashMap<A, B> = Arc<Mutex<HashMap<A, B>>>;
 
+/// Hooks that are run after each successful reconnection to the broker (i.e. to republish retained state).
+#[derive(Clone, Default)]
+pub struct ReconnectHooks {
+    hooks: Arc<Mutex<Vec<HookFn>>>,
+    connections: Arc<AtomicUsize>,
+}
+
+impl ReconnectHooks {
+    pub fn add<F>(&self, hook: F)
+    where
+        F: Fn() + Send + 'static
+    {
+        self.hooks.lock().expect("lock hooks").push(Box::new(hook));
+    }
+
+    /// Record a successful connection.
+    /// 
+    /// If it's a reconnection the hooks are run on a separate thread (so that they may publish without blocking the
+    /// MQTT notification handler), which is returned.
+    pub fn connected(&self) -> Option<JoinHandle<()>> {
+        if self.connections.fetch_add(1, Ordering::SeqCst) == 0 {
+            return None; // initial connection
+        }
+
+        info!("reconnected to MQTT broker");
+
+        let hooks = self.hooks.clone();
+
+        let thread = thread::Builder::new()
+            .name("MQTT reconnect hooks".to_string())
+            .spawn(move || {
+                for hook in hooks.lock().expect("lock hooks").iter() {
+                    hook();
+                }
+            }).expect("spawn MQTT reconnect hooks thread");
+
+        Some(thread)
+    }
+}
+
+/// The state of the MQTT notification handler thread.
+///
+/// Topic handlers are owned by the handler (keyed by topic) for the life of the `MqttConnectionManager`, not the
+/// connection, so handlers and the state they capture stay valid across reconnections.
+/// A topic may have multiple handlers, each publish is delivered to every handler of its topic, once.
+struct NotificationHandler {
+    outgoing_topic_handlers_recv: Receiver<(String, HandlerFn)>,
+
+    /// handlers of subscriptions sent but not yet acknowledged, by packet id
+    pending_topic_handlers: HashMap<u16, (String, HandlerFn)>,
+
+    topic_handlers: CoHashMap<String, Vec<HandlerFn>>,
+    connected_send: Sender<()>,
+    disconnected_send: Sender<()>,
+    errors_send: Sender<ConnectionError>,
+    reconnect_hooks: ReconnectHooks,
+    publish_backlog: PublishBacklog,
+
+    /// cleared when the handler is dropped, i.e. its thread has exited (or panicked)
+    alive: Arc<AtomicBool>
+}
+
+impl Drop for NotificationHandler {
+    fn drop(&mut self) {
+        self.alive.store(false, Ordering::SeqCst);
+    }
+}
+
+impl NotificationHandler {
+    /// Handle a notification from the MQTT event loop. Returns false once the connection has been disconnected.
+    fn handle(&mut self, notification: Result<Event, ConnectionError>) -> bool {
+        log::debug!("mqtt notif: {:?}", notification);
+
+        match notification {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                // only the initial connection is waited on, ignore if nobody is waiting
+                let _ = self.connected_send.try_send(());
+
+                // subscriptions in flight when the connection dropped are discarded by the client and never
+                // acknowledged, keep their handlers rather than leaving them pending forever
+                for (_, (topic, handler_fn)) in std::mem::take(&mut self.pending_topic_handlers) {
+                    log::debug!("subscription to MQTT topic {} was not acknowledged before reconnecting", topic);
+                    self.register(topic, handler_fn);
+                }
+
+                self.reconnect_hooks.connected();
+            },
+            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                // incoming message for a subscription
+
+                // todo: handle wildcards
+                match self.topic_handlers.lock().expect("lock topic_handlers").get(&publish.topic) {
+                    Some(handlers) => handlers.iter().for_each(|handler| handler(&publish)),
+                    None => log::warn!("received MQTT Publish packet for unknown subscription. topic = {}", publish.topic),
+                }
+            },
+            Ok(Event::Outgoing(rumqttc::Outgoing::Publish(_))) => {
+                self.publish_backlog.record_sent();
+            },
+            Ok(Event::Outgoing(rumqttc::Outgoing::Disconnect)) => {
+                // requests are sent in order, so everything published before the disconnect has been sent.
+                // ignore if nobody is waiting
+                let _ = self.disconnected_send.try_send(());
+                return false
+            },
+
+            // deferred topic handler registration on suback
+            Ok(Event::Outgoing(rumqttc::Outgoing::Subscribe(pkid))) => {
+                // `subscribe` queues the handler before the subscription is sent, so there is nothing to wait for.
+                // subscriptions made without a handler (i.e. `resubscribe`) keep the existing handlers
+                match self.outgoing_topic_handlers_recv.try_recv() {
+                    Ok(handler) => { self.pending_topic_handlers.insert(pkid, handler); },
+                    Err(_) => log::debug!("no handler queued for MQTT Subscribe packet (pkid = {})", pkid),
+                }
+            },
+            Ok(Event::Incoming(Packet::SubAck(suback))) => {
+                // TODO: handle suback.return_codes
+
+                let handler = self.pending_topic_handlers.remove(&suback.pkid);
+
+                match handler {
+                    Some((topic, handler_fn)) => self.register(topic, handler_fn),
+                    None => log::warn!("received MQTT SubAck packet for unknown subscription"),
+                }
+            }
+
+            Ok(_) => {},
+            Err(e) => {
+                log::error!("mqtt error: {}", e);
+
+                // only errors while waiting for the initial connection are received, ignore if nobody is waiting
+                let _ = self.errors_send.try_send(e);
+            },
+        }
+
+        true
+    }
+
+    fn register(&self, topic: String, handler_fn: HandlerFn) {
+        add_topic_handler(&self.topic_handlers, topic, handler_fn);
+    }
+}
+
+/// Add a handler for a topic, alongside any existing handlers.
+fn add_topic_handler(topic_handlers: &CoHashMap<String, Vec<HandlerFn>>, topic: String, handler_fn: HandlerFn) {
+    topic_handlers.lock().expect("lock topic_handlers").entry(topic).or_default().push(handler_fn);
+}
+
+/// The error for a subscription made after the notification handler thread has stopped.
+///
+/// Like a dead event loop, a dead handler thread can't service the subscription, so it's reported the same way.
+fn handler_stopped(topic: String, qos: rumqttc::QoS) -> rumqttc::ClientError {
+    log::error!("cannot subscribe to MQTT topic {}, the notification handler has stopped", topic);
+
+    rumqttc::ClientError::Request(rumqttc::Request::Subscribe(Subscribe::new(topic, qos)))
+}
+
+fn registered_topics(topic_handlers: &CoHashMap<String, Vec<HandlerFn>>) -> Vec<String> {
+    let mut topics = topic_handlers.lock().expect("lock topic_handlers").keys().cloned().collect::<Vec<_>>();
+    topics.sort();
+    topics
+}
+
+/// delay between reconnection attempts to the broker
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
 /// handles MQTT notifications and topic subscriptions, delegating incoming packets to regestered topic handlers 
 pub struct MqttConnectionManager {
     client: Client,
     outgoing_topic_handlers_send: Sender<(String, HandlerFn)>,
-    topic_handlers: CoHashMap<String, HandlerFn>,
+    topic_handlers: CoHashMap<String, Vec<HandlerFn>>,
+
+    /// topics subscribed to with the broker, and their QoS
+    subscriptions: HashMap<String, rumqttc::QoS>,
+
     handler_thread: JoinHandle<()>,
     connected_recv: Receiver<()>,
-    errors_recv: Receiver<ConnectionError>
+    disconnected_recv: Receiver<()>,
+    errors_recv: Receiver<ConnectionError>,
+    reconnect_hooks: ReconnectHooks,
+    publish_backlog: PublishBacklog,
+    handler_alive: Arc<AtomicBool>
 }
 
 impl MqttConnectionManager {
     pub fn new(client: Client, connection: Connection) -> MqttConnectionManager {
+        MqttConnectionManager::with_handler_thread(client, |handler| MqttConnectionManager::spawn_handler_thread(connection, handler))
+    }
+
+    /// Create a manager whose notification handler is run by `spawn_handler`.
+    fn with_handler_thread<F>(client: Client, spawn_handler: F) -> MqttConnectionManager
+    where
+        F: FnOnce(NotificationHandler) -> JoinHandle<()>
+    {
         let (outgoing_topic_handlers_send, outgoing_topic_handlers_recv) = crossbeam_channel::unbounded();
         let topic_handlers = Arc::new(Mutex::new(HashMap::new()));
 
         let (connected_send, connected_recv) = crossbeam_channel::bounded(1);
+        let (disconnected_send, disconnected_recv) = crossbeam_channel::bounded(1);
         let (errors_send, errors_recv) = crossbeam_channel::bounded(1);
 
-        let handler_thread = MqttConnectionManager::spawn_handler_thread(
-            connection,
+        let reconnect_hooks = ReconnectHooks::default();
+        let publish_backlog = PublishBacklog::default();
+        let handler_alive = Arc::new(AtomicBool::new(true));
+
+        let handler_thread = spawn_handler(NotificationHandler {
             outgoing_topic_handlers_recv,
-            topic_handlers.clone(),
+            pending_topic_handlers: HashMap::new(),
+            topic_handlers: topic_handlers.clone(),
             connected_send,
-            errors_send
-        );
+            disconnected_send,
+            errors_send,
+            reconnect_hooks: reconnect_hooks.clone(),
+            publish_backlog: publish_backlog.clone(),
+            alive: handler_alive.clone()
+        });
 
         MqttConnectionManager {
             client,
             outgoing_topic_handlers_send,
             topic_handlers,
+            subscriptions: HashMap::new(),
             handler_thread,
             connected_recv,
-            errors_recv
+            disconnected_recv,
+            errors_recv,
+            reconnect_hooks,
+            publish_backlog,
+            handler_alive
         }
     }
 
-    fn spawn_handler_thread(mut connection: Connection,
-        outgoing_topic_handlers_recv: Receiver<(String, HandlerFn)>,
-        topic_handlers: CoHashMap<String, HandlerFn>,
-        connected_send: Sender<()>,
-        errors_send: Sender<ConnectionError>
-    ) -> JoinHandle<()> {
+    fn spawn_handler_thread(mut connection: Connection, mut handler: NotificationHandler) -> JoinHandle<()> {
         thread::Builder::new()
             .name("MQTT notification handler".to_string())
             .spawn(move || {
-                let mut pending_topic_handlers = HashMap::new();
-
                 for notification in connection.iter() {
-                    log::debug!("mqtt notif: {:?}", notification);
-
-                    match notification {
-                        Ok(Event::Incoming(Packet::ConnAck(_))) => {
-                            connected_send.send(()).expect("send on connected_send");
-                        },
-                        Ok(Event::Incoming(Packet::Publish(publish))) => {
-                            // incoming message for a subscription
-
-                            // todo: handle wildcards
-                            match topic_handlers.lock().expect("lock topic_handlers").get(&publish.topic) {
-                                Some(handler) => handler(&publish),
-                                None => log::warn!("received MQTT Publish packet for unknown subscription. topic = {}", publish.topic),
-                            }
-                        },
-                        Ok(Event::Outgoing(rumqttc::Outgoing::Disconnect)) => {
-                            // TODO: notify anyone waiting for disconnect
-                            return
-                        },
-
-                        // deferred topic handler registration on suback
-                        Ok(Event::Outgoing(rumqttc::Outgoing::Subscribe(pkid))) => {
-                            let handler = outgoing_topic_handlers_recv.recv().expect("recv from outgoing_topic_handlers_recv");
+                    let failed = notification.is_err();
 
-                            pending_topic_handlers.insert(pkid, handler);
-                        },
-                        Ok(Event::Incoming(Packet::SubAck(suback))) => {
-                            // TODO: handle suback.return_codes
-
-                            let handler = pending_topic_handlers.remove(&suback.pkid);
-
-                            match handler {
-                                Some((topic, handler_fn)) => {
-                                    topic_handlers.lock().expect("lock topic_handlers")
-                                        .insert(topic, handler_fn);
-                                },
-                                None => log::warn!("received MQTT SubAck packet for unknown subscription"),
-                            }
-                        }
+                    if !handler.handle(notification) {
+                        return
+                    }
 
-                        Ok(_) => {},
-                        Err(e) => {
-                            log::error!("mqtt error: {}", e);
-                            errors_send.send(e).expect("send on errors_send");
-                        },
+                    // the event loop reconnects on the next poll, don't spin while the broker is unreachable
+                    if failed {
+                        thread::sleep(RECONNECT_DELAY);
                     }
                 }
             }).expect("spawn MQTT notification handler thread")
     }
 
+    /// Whether the notification handler thread is still running.
+    ///
+    /// Once it has stopped, nothing services the connection: subscriptions and waits fail rather than hang.
+    pub fn handler_alive(&self) -> bool {
+        self.handler_alive.load(Ordering::SeqCst)
+    }
+
     pub fn wait_connected(&self) -> anyhow::Result<()> {
+        if !self.handler_alive() {
+            bail!("MQTT notification handler has stopped");
+        }
+
         // wait for a established connection or a connection error
         select! {
-            recv(self.connected_recv) -> msg => Ok(msg?),
-            recv(self.errors_recv) -> err => Err(err?.into())
+            recv(self.connected_recv) -> msg => msg.context("MQTT notification handler has stopped"),
+            recv(self.errors_recv) -> err => Err(err.context("MQTT notification handler has stopped")?.into())
         }
     }
 
-    pub fn wait_disconnected(&self) -> anyhow::Result<()> {
-        todo!()
+    /// Like `wait_connected`, but retries up to `retries` more times if the initial connection fails,
+    /// waiting `backoff` (doubled after each failed attempt) in between.
+    pub fn wait_connected_retry(&self, retries: u32, backoff: Duration) -> anyhow::Result<()> {
+        retry_with_backoff(retries, backoff, thread::sleep, || {
+            // errors from attempts made while backing off are stale
+            while self.errors_recv.try_recv().is_ok() {}
+
+            self.wait_connected()
+        })
+    }
+
+    /// Hooks run after each successful reconnection to the broker.
+    pub fn reconnect_hooks(&self) -> &ReconnectHooks {
+        &self.reconnect_hooks
+    }
+
+    /// A client that shares this connection and counts its publishes towards the outgoing publish backlog.
+    pub fn backlog_client(&self) -> BacklogClient {
+        BacklogClient::new(self.client.clone(), self.publish_backlog.clone())
+    }
+
+    /// The topic filters with a registered handler, sorted.
+    ///
+    /// Subscriptions are registered once acknowledged by the broker, so recent subscriptions may not be listed yet.
+    pub fn subscriptions(&self) -> Vec<String> {
+        registered_topics(&self.topic_handlers)
     }
 
+    /// Wait up to `timeout` for a requested disconnect to be sent to the broker, after any publishes made before it.
+    ///
+    /// Returns false if the disconnect wasn't sent in time (e.g. the broker is unreachable).
+    pub fn wait_disconnected(&self, timeout: Duration) -> bool {
+        self.disconnected_recv.recv_timeout(timeout).is_ok()
+    }
+
+    /// Subscribe to a topic, delivering its publishes to `handler`.
+    ///
+    /// A topic may be subscribed to more than once, each handler receives every publish. Only the first subscription
+    /// to a topic is sent to the broker, later subscriptions just add their handler (and keep the original QoS).
     pub fn subscribe<F, S>(&mut self, topic: S, qos: rumqttc::QoS, handler: F) -> anyhow::Result<(), rumqttc::ClientError>
     where
         F: Fn(&Publish) + Send + 'static,
@@ -186,10 +584,65 @@ impl MqttConnectionManager {
     {
         let topic = topic.into();
 
+        if !self.handler_alive() {
+            return Err(handler_stopped(topic, qos));
+        }
+
+        if self.subscriptions.contains_key(&topic) {
+            log::debug!("adding handler for already subscribed MQTT topic {}", topic);
+
+            add_topic_handler(&self.topic_handlers, topic, Box::new(handler));
+            return Ok(());
+        }
+
         log::info!("subscribing to MQTT topic {}", topic);
 
-        self.outgoing_topic_handlers_send.send((topic.clone(), Box::new(handler))).expect("send on outgoing_topic_handlers_send");
-        self.client.subscribe(topic, qos)
+        if self.outgoing_topic_handlers_send.send((topic.clone(), Box::new(handler))).is_err() {
+            return Err(handler_stopped(topic, qos));
+        }
+
+        self.client.subscribe(topic.clone(), qos)?;
+
+        self.subscriptions.insert(topic, qos);
+
+        Ok(())
+    }
+
+    /// Send the subscriptions to the broker again, keeping their handlers
+    /// (i.e. after reconnecting with a clean session, which forgets them).
+    pub fn resubscribe(&mut self) -> Result<(), rumqttc::ClientError> {
+        let mut subscriptions = self.subscriptions.iter().collect::<Vec<_>>();
+        subscriptions.sort_by_key(|&(topic, _)| topic);
+
+        for (topic, &qos) in subscriptions {
+            if !self.handler_alive() {
+                return Err(handler_stopped(topic.clone(), qos));
+            }
+
+            log::debug!("re-subscribing to MQTT topic {}", topic);
+
+            self.client.subscribe(topic, qos)?;
+        }
+
+        Ok(())
+    }
+
+    /// Re-subscribe after each reconnection to the broker, which forgets the subscriptions of a clean session.
+    ///
+    /// The hook holds the lock on `mqtt` while re-subscribing, so topic handlers must not lock it (the notification
+    /// handler they run on sends the subscriptions).
+    pub fn resubscribe_on_reconnect(mqtt: &Arc<Mutex<MqttConnectionManager>>) {
+        let hook = {
+            let mqtt = mqtt.clone();
+
+            move || {
+                if let Err(e) = mqtt.lock().expect("lock mqtt").resubscribe() {
+                    log::error!("failed to re-subscribe after reconnecting to MQTT broker: {}", e);
+                }
+            }
+        };
+
+        mqtt.lock().expect("lock mqtt").reconnect_hooks().add(hook);
     }
 
     pub fn subscribe_utf8<F, S>(&mut self, topic: S, qos: rumqttc::QoS, handler: F) -> Result<(), rumqttc::ClientError>
@@ -254,32 +707,87 @@ impl MqttConnectionManager {
 }
 
 
-#[derive(Clone, Deserialize, Debug)]
+#[derive(Clone, Deserialize, Serialize, Debug)]
 pub struct MqttConfig {
     pub url: url::Url,
 
     #[serde(default = "MqttConfig::default_srv_lookup")]
     pub srv_lookup: bool,
 
+    #[serde(serialize_with = "ser_relative_path", skip_serializing_if = "Option::is_none")]
     pub ca_certs: Option<RelativePathBuf>,
 
+    #[serde(serialize_with = "ser_relative_path", skip_serializing_if = "Option::is_none")]
     pub client_certs: Option<RelativePathBuf>,
+    #[serde(serialize_with = "ser_relative_path", skip_serializing_if = "Option::is_none")]
     pub client_key: Option<RelativePathBuf>,
+
+    #[serde(default = "MqttConfig::default_connect_retries")]
+    pub connect_retries: u32,
+
+    #[serde(deserialize_with = "duration::deserialize", serialize_with = "duration::serialize", default = "MqttConfig::default_connect_backoff")]
+    pub connect_backoff: Duration,
+}
+
+/// serialize a config path as resolved (relative to the config file), so it's the same wherever it's read from
+fn ser_relative_path<S>(path: &Option<RelativePathBuf>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer
+{
+    path.as_ref().map(RelativePathBuf::relative).serialize(serializer)
 }
 
 impl MqttConfig {
     fn default_srv_lookup() -> bool { false }
 
+    fn default_connect_retries() -> u32 { 0 }
+
+    fn default_connect_backoff() -> Duration { Duration::from_secs(1) }
+
+    /// The topic base from the URL path (i.e. `home/audio/` for `mqtt://localhost/home/audio`), or `None` if there's no path.
+    ///
+    /// Topics are built by appending to the base, so a non-empty base always ends with `/`.
+    /// An empty base (`mqtt://localhost/`) puts the topics at the root.
     pub fn topic_base(&self) -> Option<String> {
         match self.url.path() {
             "" => None,
             other => {
-                Some(other.strip_prefix("/").unwrap_or(other).to_string())
+                let mut base = other.strip_prefix("/").unwrap_or(other).to_string();
+
+                if !base.is_empty() && !base.ends_with('/') {
+                    base.push('/');
+                }
+
+                Some(base)
             }
         }
     }
 }
 
+/// Call `f` until it succeeds, at most `retries + 1` times, sleeping between attempts (doubling `backoff` each time).
+fn retry_with_backoff<T, S, F>(retries: u32, mut backoff: Duration, mut sleep: S, mut f: F) -> anyhow::Result<T>
+where
+    S: FnMut(Duration),
+    F: FnMut() -> anyhow::Result<T>
+{
+    let mut attempt = 0;
+
+    loop {
+        match f() {
+            Ok(v) => return Ok(v),
+            Err(e) if attempt < retries => {
+                attempt += 1;
+
+                warn!("{:#}, retrying in {:?} (attempt {} of {})", e, backoff, attempt, retries);
+
+                sleep(backoff);
+                backoff = backoff.saturating_mul(2);
+            },
+            Err(e) => return Err(e)
+        }
+    }
+}
+
 fn resolve_credentials_path(path: &RelativePathBuf) -> anyhow::Result<PathBuf> {
     let path = path.relative();
 
@@ -445,6 +953,205 @@ pub fn options_from_config(config: &MqttConfig, default_client_id: &str) -> anyh
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_handlers_across_reconnect() {
+        use rumqttc::{ConnAck, ConnectReturnCode, Outgoing, QoS, SubAck, SubscribeReasonCode};
+
+        let (handlers_send, outgoing_topic_handlers_recv) = crossbeam_channel::unbounded();
+        let (connected_send, _connected_recv) = crossbeam_channel::bounded(1);
+        let (disconnected_send, disconnected_recv) = crossbeam_channel::bounded(1);
+        let (errors_send, _errors_recv) = crossbeam_channel::bounded(1);
+
+        let mut handler = NotificationHandler {
+            outgoing_topic_handlers_recv,
+            pending_topic_handlers: HashMap::new(),
+            topic_handlers: Arc::new(Mutex::new(HashMap::new())),
+            connected_send,
+            disconnected_send,
+            errors_send,
+            reconnect_hooks: ReconnectHooks::default(),
+            publish_backlog: PublishBacklog::default(),
+            alive: Arc::new(AtomicBool::new(true))
+        };
+
+        let connack = || Event::Incoming(Packet::ConnAck(ConnAck::new(ConnectReturnCode::Success, false)));
+        let subscribe = |pkid| Event::Outgoing(Outgoing::Subscribe(pkid));
+        let suback = |pkid| Event::Incoming(Packet::SubAck(SubAck::new(pkid, vec![SubscribeReasonCode::Success(QoS::AtLeastOnce)])));
+        let publish = |topic: &str, payload: &str| Event::Incoming(Packet::Publish(Publish::new(topic, QoS::AtLeastOnce, payload)));
+
+        // a handler that captures shared state and a channel (like the zone and shairport handlers)
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let (send, recv) = crossbeam_channel::unbounded();
+
+        let stateful_handler = |name: &'static str| -> HandlerFn {
+            let received = received.clone();
+            let send = send.clone();
+
+            Box::new(move |publish: &Publish| {
+                received.lock().unwrap().push((name, publish.payload.clone()));
+                send.send(()).unwrap();
+            })
+        };
+
+        assert!(handler.handle(Ok(connack())));
+
+        handlers_send.send(("mwha/set/volume".to_string(), stateful_handler("first"))).unwrap();
+        handler.handle(Ok(subscribe(1)));
+        handler.handle(Ok(suback(1)));
+
+        handler.handle(Ok(publish("mwha/set/volume", "1")));
+
+        // reconnect, the handler and its state survive
+        handler.handle(Err(ConnectionError::RequestsDone));
+        handler.handle(Ok(connack()));
+        handler.handle(Ok(publish("mwha/set/volume", "2")));
+
+        assert_eq!(*received.lock().unwrap(), vec![("first", Bytes::from("1")), ("first", Bytes::from("2"))]);
+        assert_eq!(recv.try_iter().count(), 2);
+
+        // an additional handler receives publishes alongside the first, each handler exactly once
+        handlers_send.send(("mwha/set/volume".to_string(), stateful_handler("second"))).unwrap();
+        handler.handle(Ok(subscribe(2)));
+        handler.handle(Ok(suback(2)));
+
+        received.lock().unwrap().clear();
+        handler.handle(Ok(publish("mwha/set/volume", "3")));
+
+        assert_eq!(*received.lock().unwrap(), vec![("first", Bytes::from("3")), ("second", Bytes::from("3"))]);
+        assert_eq!(recv.try_iter().count(), 2);
+
+        // a subscribe sent without a handler (i.e. `resubscribe`) doesn't block or drop the existing handlers
+        handler.handle(Ok(subscribe(3)));
+        handler.handle(Ok(suback(3)));
+
+        received.lock().unwrap().clear();
+        handler.handle(Ok(publish("mwha/set/volume", "4")));
+        assert_eq!(*received.lock().unwrap(), vec![("first", Bytes::from("4")), ("second", Bytes::from("4"))]);
+
+        // a subscription in flight when the connection dropped keeps its handler
+        handlers_send.send(("mwha/set/mute".to_string(), stateful_handler("mute"))).unwrap();
+        handler.handle(Ok(subscribe(4)));
+        handler.handle(Ok(connack()));
+
+        received.lock().unwrap().clear();
+        handler.handle(Ok(publish("mwha/set/mute", "true")));
+        assert_eq!(*received.lock().unwrap(), vec![("mute", Bytes::from("true"))]);
+
+        // anyone waiting for the disconnect is notified
+        assert!(disconnected_recv.try_recv().is_err());
+        assert!(!handler.handle(Ok(Event::Outgoing(Outgoing::Disconnect))));
+        assert!(disconnected_recv.try_recv().is_ok());
+    }
+
+    #[test]
+    fn test_subscriptions() {
+        use rumqttc::{ConnAck, ConnectReturnCode, Outgoing, QoS, SubAck, SubscribeReasonCode};
+
+        let (handlers_send, outgoing_topic_handlers_recv) = crossbeam_channel::unbounded();
+        let (connected_send, _connected_recv) = crossbeam_channel::bounded(1);
+        let (disconnected_send, _disconnected_recv) = crossbeam_channel::bounded(1);
+        let (errors_send, _errors_recv) = crossbeam_channel::bounded(1);
+
+        let topic_handlers = Arc::new(Mutex::new(HashMap::new()));
+
+        let mut handler = NotificationHandler {
+            outgoing_topic_handlers_recv,
+            pending_topic_handlers: HashMap::new(),
+            topic_handlers: topic_handlers.clone(),
+            connected_send,
+            disconnected_send,
+            errors_send,
+            reconnect_hooks: ReconnectHooks::default(),
+            publish_backlog: PublishBacklog::default(),
+            alive: Arc::new(AtomicBool::new(true))
+        };
+
+        handler.handle(Ok(Event::Incoming(Packet::ConnAck(ConnAck::new(ConnectReturnCode::Success, false)))));
+
+        let topics = ["mwha/set/zone/11/volume", "mwha/set/zone/11/mute", "mwha/set/zone/12/volume"];
+
+        for (pkid, topic) in (1..).zip(topics) {
+            handlers_send.send((topic.to_string(), Box::new(|_: &Publish| {}) as HandlerFn)).unwrap();
+            handler.handle(Ok(Event::Outgoing(Outgoing::Subscribe(pkid))));
+        }
+
+        // not registered until acknowledged
+        assert!(registered_topics(&topic_handlers).is_empty());
+
+        for pkid in 1..=3 {
+            handler.handle(Ok(Event::Incoming(Packet::SubAck(SubAck::new(pkid, vec![SubscribeReasonCode::Success(QoS::AtLeastOnce)])))));
+        }
+
+        assert_eq!(registered_topics(&topic_handlers), vec!["mwha/set/zone/11/mute", "mwha/set/zone/11/volume", "mwha/set/zone/12/volume"]);
+    }
+
+    #[test]
+    fn test_duplicate_subscriptions() {
+        use rumqttc::{Outgoing, QoS, SubAck, SubscribeReasonCode};
+
+        let (client, _connection) = Client::new(MqttOptions::new("test", "localhost", 1883), 10);
+
+        // run the notification handler by hand
+        let (handler_send, handler_recv) = crossbeam_channel::bounded(1);
+        let mut mqtt = MqttConnectionManager::with_handler_thread(client, |handler| {
+            handler_send.send(handler).unwrap();
+            thread::spawn(|| {})
+        });
+        let mut handler = handler_recv.recv().unwrap();
+
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let recording_handler = |name: &'static str| {
+            let received = received.clone();
+            move |publish: &Publish| received.lock().unwrap().push((name, publish.payload.clone()))
+        };
+
+        let topic = "mwha/cmd/redetect-baud";
+
+        mqtt.subscribe(topic, QoS::AtLeastOnce, recording_handler("first")).unwrap();
+        mqtt.subscribe(topic, QoS::AtMostOnce, recording_handler("second")).unwrap();
+
+        // only the first subscription is sent to the broker, keeping its QoS
+        assert_eq!(mqtt.subscriptions, HashMap::from([(topic.to_string(), QoS::AtLeastOnce)]));
+
+        handler.handle(Ok(Event::Outgoing(Outgoing::Subscribe(1))));
+        assert!(handler.outgoing_topic_handlers_recv.is_empty());
+        handler.handle(Ok(Event::Incoming(Packet::SubAck(SubAck::new(1, vec![SubscribeReasonCode::Success(QoS::AtLeastOnce)])))));
+
+        assert_eq!(mqtt.subscriptions(), vec![topic]);
+
+        // every handler receives the publish
+        handler.handle(Ok(Event::Incoming(Packet::Publish(Publish::new(topic, QoS::AtLeastOnce, "true")))));
+
+        let mut received = received.lock().unwrap().clone();
+        received.sort();
+        assert_eq!(received, vec![("first", Bytes::from("true")), ("second", Bytes::from("true"))]);
+
+        mqtt.resubscribe().unwrap();
+    }
+
+    #[test]
+    fn test_handler_stopped() {
+        use rumqttc::QoS;
+
+        let (client, _connection) = Client::new(MqttOptions::new("test", "localhost", 1883), 10);
+
+        // the handler thread exits straight away
+        let mut mqtt = MqttConnectionManager::with_handler_thread(client, |handler| {
+            drop(handler);
+            thread::spawn(|| {})
+        });
+
+        assert!(!mqtt.handler_alive());
+
+        // subscriptions and waits fail, rather than panic or hang
+        assert!(mqtt.subscribe("mwha/set/zone/11/volume", QoS::AtLeastOnce, |_: &Publish| {}).is_err());
+        assert!(mqtt.subscribe_json("mwha/set/zone/11/power", QoS::AtLeastOnce, |_: &Publish, _: Result<bool, PayloadDecodeError>| {}).is_err());
+        assert!(mqtt.subscriptions.is_empty());
+
+        assert!(mqtt.wait_connected().is_err());
+        assert!(mqtt.wait_connected_retry(2, Duration::ZERO).is_err());
+    }
+
     #[test]
     fn test_resolve_credentials_path() {
         assert_eq!(resolve_credentials_path(&RelativePathBuf::from(Path::new("credentials"))).unwrap(), PathBuf::from("credentials"));
@@ -456,6 +1163,30 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_retry_with_backoff() {
+        let attempt = |failures: u32, retries: u32| {
+            let mut attempts = 0;
+            let mut sleeps = Vec::new();
+
+            let result = retry_with_backoff(retries, Duration::from_secs(1), |d| sleeps.push(d), || {
+                attempts += 1;
+                if attempts > failures { Ok(attempts) } else { bail!("connection refused") }
+            });
+
+            (result.ok(), attempts, sleeps)
+        };
+
+        // fail-fast by default
+        assert_eq!(attempt(1, 0), (None, 1, vec![]));
+
+        // gives up once the retries are exhausted
+        assert_eq!(attempt(5, 3), (None, 4, vec![Duration::from_secs(1), Duration::from_secs(2), Duration::from_secs(4)]));
+
+        // stops retrying once connected
+        assert_eq!(attempt(1, 3), (Some(2), 2, vec![Duration::from_secs(1)]));
+    }
+
     #[test]
     fn test_config_topic_base() {
         fn config_with_url(url: &str) -> MqttConfig {
@@ -465,13 +1196,267 @@ mod tests {
                 ca_certs: None,
                 client_certs: None,
                 client_key: None,
+                connect_retries: 0,
+                connect_backoff: Duration::from_secs(1),
             }
         }
 
         assert_eq!(config_with_url("mqtt://localhost").topic_base(), None);
         assert_eq!(config_with_url("mqtt://localhost/").topic_base(), Some("".to_string()));
-        assert_eq!(config_with_url("mqtt://localhost/base").topic_base(), Some("base".to_string()));
+        assert_eq!(config_with_url("mqtt://localhost/base").topic_base(), Some("base/".to_string()));
         assert_eq!(config_with_url("mqtt://localhost/base/").topic_base(), Some("base/".to_string()));
         assert_eq!(config_with_url("mqtt://localhost//base/").topic_base(), Some("/base/".to_string()));
+
+        // multiple levels
+        assert_eq!(config_with_url("mqtt://localhost/home/audio").topic_base(), Some("home/audio/".to_string()));
+        assert_eq!(config_with_url("mqtt://localhost/home/audio/").topic_base(), Some("home/audio/".to_string()));
+    }
+
+    #[test]
+    fn test_mirror_queue() {
+        use rumqttc::QoS;
+
+        let queue = MirrorQueue::default();
+
+        let publish = |topic: &str, retain: bool, payload: &str| {
+            let mut publish = Publish::new(topic, QoS::AtLeastOnce, payload);
+            publish.retain = retain;
+            MirrorRequest::Publish(publish)
+        };
+
+        let queued = |queue: &MirrorQueue| queue.requests.lock().unwrap().iter()
+            .map(|r| match r {
+                MirrorRequest::Publish(p) => format!("{}={}", p.topic, str::from_utf8(&p.payload).unwrap()),
+                MirrorRequest::Disconnect => "disconnect".to_string()
+            })
+            .collect::<Vec<_>>();
+
+        // retained publishes are coalesced by topic, keeping their place in the queue
+        queue.push(publish("mwha/status/zone/11/volume", true, "10"));
+        queue.push(publish("mwha/status/zone/11/mute", true, "false"));
+        queue.push(publish("mwha/debug/responses", false, "a"));
+        queue.push(publish("mwha/status/zone/11/volume", true, "12"));
+        queue.push(publish("mwha/debug/responses", false, "b"));
+
+        assert_eq!(queued(&queue), vec!["mwha/status/zone/11/volume=12", "mwha/status/zone/11/mute=false", "mwha/debug/responses=a", "mwha/debug/responses=b"]);
+
+        // only non-retained publishes are dropped once the queue is full, oldest first
+        for i in 0..MIRROR_MAX_QUEUED_EVENTS {
+            queue.push(publish("mwha/debug/responses", false, &i.to_string()));
+        }
+        queue.push(publish("mwha/connected", true, "0"));
+        queue.push(MirrorRequest::Disconnect);
+
+        let queued = queued(&queue);
+        assert_eq!(queued.len(), 2 + MIRROR_MAX_QUEUED_EVENTS + 2);
+        assert_eq!(queued[..3], ["mwha/status/zone/11/volume=12", "mwha/status/zone/11/mute=false", "mwha/debug/responses=0"]);
+        assert_eq!(queued[queued.len() - 2..], ["mwha/connected=0", "disconnect"]);
+
+        assert!(matches!(queue.pop(), MirrorRequest::Publish(p) if p.topic == "mwha/status/zone/11/volume"));
+    }
+
+    /// a minimal broker on a local port, that accepts a single client and forwards the packets it sends
+    fn fake_broker() -> (u16, Receiver<Packet>) {
+        use std::io::{Read, Write};
+        use bytes::BytesMut;
+        use rumqttc::{ConnAck, ConnectReturnCode};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let (packets_send, packets_recv) = crossbeam_channel::unbounded();
+
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut read = BytesMut::new();
+            let mut buf = [0; 1024];
+
+            loop {
+                match rumqttc::mqttbytes::v4::read(&mut read, 1024 * 1024) {
+                    Ok(packet) => {
+                        // publishes are only acknowledged if QoS > 0, so tests publish with QoS 0 (the client closes the
+                        // connection straight after a disconnect, an unread ack would reset it before everything is read)
+                        if let Packet::Connect(_) = packet {
+                            let mut connack = BytesMut::new();
+                            ConnAck::new(ConnectReturnCode::Success, false).write(&mut connack).unwrap();
+                            stream.write_all(&connack).unwrap();
+                        }
+
+                        let _ = packets_send.send(packet);
+                    },
+                    Err(_) => match stream.read(&mut buf) {
+                        Ok(0) | Err(_) => return,
+                        Ok(len) => read.extend_from_slice(&buf[..len])
+                    }
+                }
+            }
+        });
+
+        (port, packets_recv)
+    }
+
+    /// a minimal broker on a local port, that accepts clients (one at a time) and forwards the packets they send along with
+    /// the index of their connection. subscriptions are acknowledged, and the current connection is closed on request
+    fn reconnecting_broker() -> (u16, Receiver<(usize, Packet)>, Sender<()>) {
+        use std::io::{Read, Write};
+        use bytes::BytesMut;
+        use rumqttc::{ConnAck, ConnectReturnCode, QoS, SubAck, SubscribeReasonCode};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let (packets_send, packets_recv) = crossbeam_channel::unbounded();
+        let (close_send, close_recv) = crossbeam_channel::unbounded();
+
+        thread::spawn(move || {
+            for (connection, stream) in listener.incoming().enumerate() {
+                let mut stream = stream.unwrap();
+                stream.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+
+                let mut read = BytesMut::new();
+                let mut buf = [0; 1024];
+
+                while close_recv.try_recv().is_err() {
+                    match rumqttc::mqttbytes::v4::read(&mut read, 1024 * 1024) {
+                        Ok(packet) => {
+                            let mut reply = BytesMut::new();
+
+                            match &packet {
+                                Packet::Connect(_) => { ConnAck::new(ConnectReturnCode::Success, false).write(&mut reply).unwrap(); },
+                                Packet::Subscribe(subscribe) => { SubAck::new(subscribe.pkid, vec![SubscribeReasonCode::Success(QoS::AtLeastOnce)]).write(&mut reply).unwrap(); },
+                                _ => {}
+                            }
+
+                            stream.write_all(&reply).unwrap();
+                            let _ = packets_send.send((connection, packet));
+                        },
+                        Err(_) => match stream.read(&mut buf) {
+                            Ok(0) => break,
+                            Ok(len) => read.extend_from_slice(&buf[..len]),
+                            Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => {},
+                            Err(_) => break
+                        }
+                    }
+                }
+            }
+        });
+
+        (port, packets_recv, close_send)
+    }
+
+    #[test]
+    fn test_resubscribe_on_reconnect() {
+        use rumqttc::QoS;
+
+        let (port, packets, close) = reconnecting_broker();
+
+        let (client, connection) = Client::new(MqttOptions::new("test", "127.0.0.1", port), 10);
+        let mqtt = Arc::new(Mutex::new(MqttConnectionManager::new(client, connection)));
+        mqtt.lock().unwrap().wait_connected().unwrap();
+
+        MqttConnectionManager::resubscribe_on_reconnect(&mqtt);
+
+        let topics = ["mwha/set/zone/11/volume", "mwha/cmd/redetect-baud"];
+
+        for topic in topics {
+            mqtt.lock().unwrap().subscribe(topic, QoS::AtLeastOnce, |_: &Publish| {}).unwrap();
+        }
+
+        // the topics subscribed to on a connection, once it has received them all
+        let subscribed = |connection: usize| {
+            let mut subscribed = Vec::new();
+
+            while subscribed.len() < topics.len() {
+                match packets.recv_timeout(Duration::from_secs(10)).expect("broker packet") {
+                    (c, Packet::Subscribe(subscribe)) if c == connection => subscribed.extend(subscribe.filters.into_iter().map(|f| f.path)),
+                    _ => {}
+                }
+            }
+
+            subscribed.sort();
+            subscribed
+        };
+
+        let mut expected = topics.map(String::from).to_vec();
+        expected.sort();
+
+        assert_eq!(subscribed(0), expected);
+
+        // the broker drops the connection, and forgets the subscriptions. they're sent again once reconnected
+        close.send(()).unwrap();
+        assert_eq!(subscribed(1), expected);
+    }
+
+    #[test]
+    fn test_mirror() {
+        use rumqttc::QoS;
+
+        let connect = |port: u16| {
+            let (client, connection) = Client::new(MqttOptions::new("test", "127.0.0.1", port), 10);
+            let mgr = MqttConnectionManager::new(client, connection);
+            mgr.wait_connected().unwrap();
+            mgr
+        };
+
+        let (primary_port, primary_packets) = fake_broker();
+        let (mirror_port, mirror_packets) = fake_broker();
+
+        let primary = connect(primary_port);
+        let mirror = connect(mirror_port);
+
+        // publishes (after the connect) in the order they reach a broker, and whether it was disconnected
+        let received = |packets: &Receiver<Packet>| {
+            let mut publishes = Vec::new();
+
+            loop {
+                match packets.recv_timeout(Duration::from_secs(5)).expect("broker packet") {
+                    Packet::Publish(p) => publishes.push((p.topic, p.payload)),
+                    Packet::Disconnect => return publishes,
+                    _ => {}
+                }
+            }
+        };
+
+        let mut mqtt = primary.backlog_client();
+        mqtt.mirror(&mirror);
+
+        // a status publish reaches both brokers
+        mqtt.publish_json("mwha/status/zone/11/volume", QoS::AtMostOnce, true, serde_json::json!(20)).unwrap();
+
+        // including a burst larger than the client queues (i.e. the startup metadata)
+        for zone in 0..100 {
+            mqtt.publish(format!("mwha/status/zone/{}/name", zone), QoS::AtMostOnce, true, "Zone").unwrap();
+        }
+
+        mqtt.publish("mwha/connected", QoS::AtMostOnce, true, "0").unwrap();
+        mqtt.disconnect().unwrap();
+
+        let primary_received = received(&primary_packets);
+        assert_eq!(primary_received.len(), 102);
+        assert_eq!(primary_received[0], ("mwha/status/zone/11/volume".to_string(), Bytes::from("20")));
+        assert_eq!(primary_received[101], ("mwha/connected".to_string(), Bytes::from("0")));
+
+        assert_eq!(received(&mirror_packets), primary_received);
+    }
+
+    #[test]
+    fn test_mirror_unreachable() {
+        use rumqttc::QoS;
+
+        let (client, _connection) = Client::new(MqttOptions::new("test", "localhost", 1883), 10);
+        let mut mqtt = BacklogClient::new(client, PublishBacklog::default());
+
+        // nothing is listening on the mirror's port, so its client queue is never drained
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let (mirror_client, mirror_connection) = Client::new(MqttOptions::new("mirror", "127.0.0.1", port), 1);
+        let mirror = MqttConnectionManager::new(mirror_client, mirror_connection);
+        assert!(mirror.wait_connected().is_err());
+
+        mqtt.mirror(&mirror);
+
+        // publishing doesn't block on the mirror (only on the primary's client queue)
+        for _ in 0..10 {
+            mqtt.publish("mwha/status/zone/11/volume", QoS::AtLeastOnce, true, "20").unwrap();
+        }
     }
 }
\ No newline at end of file
@@ -0,0 +1,136 @@
+/// Make a printable preview of a (possibly binary or invalid UTF-8) payload for logging.
+///
+/// The payload is lossy-decoded as UTF-8, truncated to at most `max` bytes (on a char boundary) and escaped.
+/// Truncated previews end with `…`.
+pub fn preview_payload(bytes: &[u8], max: usize) -> String {
+    let s = String::from_utf8_lossy(bytes);
+
+    let mut end = max.min(s.len());
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    let mut preview = s[..end].escape_default().to_string();
+
+    if end < s.len() {
+        preview.push('…');
+    }
+
+    preview
+}
+
+/// Longer names are truncated by `sanitize_name`.
+pub const MAX_NAME_LEN: usize = 64;
+
+/// Make a (config-provided) name safe to publish, for consumers that don't expect multi-line or huge labels.
+///
+/// Terminal escape sequences (i.e. ANSI colors) are removed whole, other control characters are removed (runs of line
+/// breaks and tabs become a single space), surrounding whitespace is trimmed, and the name is truncated to at most
+/// `MAX_NAME_LEN` chars.
+pub fn sanitize_name(name: &str) -> String {
+    let name = strip_escape_sequences(name);
+
+    let name = name.split(|c: char| c.is_control() && c.is_whitespace())
+        .filter(|s| !s.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+        .replace(char::is_control, "");
+
+    name.trim().chars().take(MAX_NAME_LEN).collect::<String>().trim_end().to_string()
+}
+
+/// remove ECMA-48 escape sequences: CSI (`ESC [`, i.e. `\x1b[31m`), OSC/DCS-style strings (terminated by `BEL` or
+/// `ESC \`), and other `ESC`-prefixed sequences. An unterminated sequence is removed up to the end of `s`.
+fn strip_escape_sequences(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        let csi = match c {
+            '\u{1b}' if chars.peek() == Some(&'[') => { chars.next(); true },
+            '\u{9b}' => true,
+            '\u{1b}' => false,
+            _ => {
+                out.push(c);
+                continue;
+            }
+        };
+
+        if csi {
+            // parameter and intermediate bytes, then a final byte
+            for c in chars.by_ref() {
+                if !('\u{20}'..='\u{3f}').contains(&c) { break }
+            }
+
+            continue;
+        }
+
+        match chars.next() {
+            // string sequences (OSC, DCS, SOS, PM, APC) run until the string terminator
+            Some(']' | 'P' | 'X' | '^' | '_') => {
+                while let Some(c) = chars.next() {
+                    if c == '\u{7}' { break }
+                    if c == '\u{1b}' && chars.peek() == Some(&'\\') {
+                        chars.next();
+                        break;
+                    }
+                }
+            },
+
+            // intermediate bytes, then a final byte
+            Some(c) if ('\u{20}'..='\u{2f}').contains(&c) => {
+                for c in chars.by_ref() {
+                    if !('\u{20}'..='\u{2f}').contains(&c) { break }
+                }
+            },
+
+            _ => {}
+        }
+    }
+
+    out
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sanitize_name() {
+        assert_eq!(sanitize_name("Living Room"), "Living Room");
+        assert_eq!(sanitize_name("Living\nRoom"), "Living Room");
+        assert_eq!(sanitize_name("Whole\r\nHouse"), "Whole House");
+        assert_eq!(sanitize_name(" Study\t\u{7}\u{1b}[31m\r\n"), "Study");
+        assert_eq!(sanitize_name("\u{1b}[1;31mKitchen\u{1b}[0m"), "Kitchen");
+        assert_eq!(sanitize_name("\u{1b}]0;title\u{7}Deck"), "Deck");
+        assert_eq!(sanitize_name("\u{1b}]8;;http://x\u{1b}\\Patio"), "Patio");
+        assert_eq!(sanitize_name("\u{1b}(BPool\u{1b}="), "Pool");
+        assert_eq!(sanitize_name("\u{9b}2JGarage"), "Garage");
+        assert_eq!(sanitize_name("\u{1b}[31m"), "");
+        assert_eq!(sanitize_name("Porch\u{1b}["), "Porch");
+        assert_eq!(sanitize_name("Café €"), "Café €");
+
+        // truncated by chars, not bytes
+        assert_eq!(sanitize_name(&"é".repeat(100)), "é".repeat(MAX_NAME_LEN));
+        assert_eq!(sanitize_name(&format!("{} b", "a".repeat(MAX_NAME_LEN - 1))), "a".repeat(MAX_NAME_LEN - 1));
+    }
+
+    #[test]
+    fn test_preview_payload() {
+        assert_eq!(preview_payload(b"true", 50), "true");
+        assert_eq!(preview_payload(b"a\r\n\"b\"", 50), r#"a\r\n\"b\""#);
+        assert_eq!(preview_payload(b"abcdef", 3), "abc…");
+
+        // multi-byte chars straddling the truncation point are dropped entirely
+        let s = "aé€";  // 1, 2 and 3 byte chars
+        assert_eq!(preview_payload(s.as_bytes(), 1), "a…");
+        assert_eq!(preview_payload(s.as_bytes(), 2), "a…");
+        assert_eq!(preview_payload(s.as_bytes(), 3), r"a\u{e9}…");
+        assert_eq!(preview_payload(s.as_bytes(), 5), r"a\u{e9}…");
+        assert_eq!(preview_payload(s.as_bytes(), 6), r"a\u{e9}\u{20ac}");
+
+        // invalid UTF-8 is replaced
+        assert_eq!(preview_payload(b"a\xffb", 50), r"a\u{fffd}b");
+    }
+}
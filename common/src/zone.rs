@@ -4,12 +4,15 @@ use std::fmt::Display;
 use std::str::FromStr;
 
 use serde::{Serialize, Deserialize};
+use strum::IntoEnumIterator;
 use strum_macros::{EnumDiscriminants, Display, EnumVariantNames, EnumIter};
 
 use thiserror::Error;
 
 use heck::ToKebabCase;
 
+use crate::ids::ProtocolId;
+
 pub const MAX_AMPS: u8 = 3;
 pub const MAX_ZONES_PER_AMP: u8 = 6;
 
@@ -20,6 +23,8 @@ pub mod ranges {
     pub const TREBLE: RangeInclusive<u8> = 0..=14;
     pub const BASS: RangeInclusive<u8> = 0..=14;
     pub const BALANCE: RangeInclusive<u8> = 0..=20;
+    pub const BALANCE_TRIM: RangeInclusive<u8> = 0..=10;
+    pub const VOLUME_PERCENT: RangeInclusive<u8> = 0..=100;
     pub const SOURCE: RangeInclusive<u8> = 1..=6;
 }
 
@@ -47,19 +52,100 @@ pub enum ZoneAttributeError {
     }
 }
 
+/// The valid ranges of numeric zone attributes, for amps whose firmware differs from the defaults in `ranges`.
+///
+/// Each range deserializes from a string, either inclusive (`"0..=79"`) or exclusive (`"0..80"`).
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct AttributeRanges {
+    #[serde(deserialize_with = "de_range", serialize_with = "ser_range")]
+    pub volume: RangeInclusive<u8>,
+
+    #[serde(deserialize_with = "de_range", serialize_with = "ser_range")]
+    pub treble: RangeInclusive<u8>,
+
+    #[serde(deserialize_with = "de_range", serialize_with = "ser_range")]
+    pub bass: RangeInclusive<u8>,
+
+    #[serde(deserialize_with = "de_range", serialize_with = "ser_range")]
+    pub balance: RangeInclusive<u8>,
+
+    #[serde(deserialize_with = "de_range", serialize_with = "ser_range")]
+    pub source: RangeInclusive<u8>,
+}
+
+impl Default for AttributeRanges {
+    fn default() -> Self {
+        Self {
+            volume: ranges::VOLUME,
+            treble: ranges::TREBLE,
+            bass: ranges::BASS,
+            balance: ranges::BALANCE,
+            source: ranges::SOURCE,
+        }
+    }
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+#[error("cannot parse \"{0}\" as a range (expected \"start..=end\" or \"start..end\")")]
+pub struct RangeParseError(String);
+
+/// Parse an inclusive (`"0..=79"`) or exclusive (`"0..80"`) range.
+pub fn parse_range(s: &str) -> Result<RangeInclusive<u8>, RangeParseError> {
+    let err = || RangeParseError(s.to_string());
+
+    let (start, end) = s.split_once("..").ok_or_else(err)?;
+
+    let (end, inclusive) = match end.strip_prefix('=') {
+        Some(end) => (end, true),
+        None => (end, false)
+    };
+
+    let start = start.trim().parse::<u8>().map_err(|_| err())?;
+    let end = end.trim().parse::<u8>().map_err(|_| err())?;
+
+    let end = match inclusive {
+        true => end,
+        false => end.checked_sub(1).ok_or_else(err)?
+    };
+
+    match start <= end {
+        true => Ok(start..=end),
+        false => Err(err())
+    }
+}
+
+fn de_range<'de, D>(deserializer: D) -> Result<RangeInclusive<u8>, D::Error>
+where
+    D: serde::Deserializer<'de>
+{
+    let s = String::deserialize(deserializer)?;
+    parse_range(&s).map_err(serde::de::Error::custom)
+}
+
+fn ser_range<S>(range: &RangeInclusive<u8>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer
+{
+    serializer.serialize_str(&format!("{}..={}", range.start(), range.end()))
+}
+
 impl ZoneAttribute {
     pub fn validate(&self) -> Result<(), ZoneAttributeError> {
+        self.validate_in(&AttributeRanges::default())
+    }
+
+    /// Like `validate`, but against the given ranges.
+    pub fn validate_in(&self, ranges: &AttributeRanges) -> Result<(), ZoneAttributeError> {
         use ZoneAttribute::*;
 
-        let (v, range) = match self {
-            Volume(v) => (v, ranges::VOLUME),
-            Treble(v) => (v, ranges::TREBLE),
-            Bass(v) => (v, ranges::BASS),
-            Balance(v) => (v, ranges::BALANCE),
-            Source(v) => (v, ranges::SOURCE),
+        let v = match self {
+            Volume(v) | Treble(v) | Bass(v) | Balance(v) | Source(v) => v,
             _ => return Ok(()) // boolean attributes are always valid
         };
 
+        let range = ZoneAttributeDiscriminants::from(self).io_range_in(ranges).expect("numeric attributes have a range");
+
         if !range.contains(&v) {
             Err(ZoneAttributeError::ValueOutOfRange{ attr: *self, range: range })
             
@@ -67,14 +153,180 @@ impl ZoneAttribute {
             Ok(())
         }
     }
+
+    /// This attribute with numeric values clamped to their valid range (boolean attributes are returned unchanged).
+    pub fn clamped(&self) -> ZoneAttribute {
+        self.clamped_in(&AttributeRanges::default())
+    }
+
+    /// Like `clamped`, but to the given ranges.
+    pub fn clamped_in(&self, ranges: &AttributeRanges) -> ZoneAttribute {
+        use ZoneAttribute::*;
+
+        let clamp = |v: u8| {
+            let range = ZoneAttributeDiscriminants::from(self).io_range_in(ranges).expect("numeric attributes have a range");
+            v.clamp(*range.start(), *range.end())
+        };
+
+        match *self {
+            Volume(v) => Volume(clamp(v)),
+            Treble(v) => Treble(clamp(v)),
+            Bass(v) => Bass(clamp(v)),
+            Balance(v) => Balance(clamp(v)),
+            Source(v) => Source(clamp(v)),
+            other => other
+        }
+    }
 }
 
+/// Balance as a trim from center towards one side (`ranges::BALANCE_TRIM`), for clients that find the centered balance awkward.
+///
+/// Only one side is ever trimmed: trimming one side resets the other to 0 (and a trim of 0 on either side centers the balance).
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BalanceTrim {
+    Left(u8),
+    Right(u8),
+}
+
+impl BalanceTrim {
+    const CENTER: u8 = 10;
+
+    /// The equivalent `Balance` attribute (out of range trims are clamped).
+    pub fn to_balance(&self) -> ZoneAttribute {
+        let trim = |t: u8| t.min(*ranges::BALANCE_TRIM.end());
+
+        match *self {
+            BalanceTrim::Left(t) => ZoneAttribute::Balance(Self::CENTER - trim(t)),
+            BalanceTrim::Right(t) => ZoneAttribute::Balance(Self::CENTER + trim(t)),
+        }
+    }
+
+    /// The (left, right) trims for a balance value.
+    pub fn from_balance(balance: u8) -> (u8, u8) {
+        (Self::CENTER.saturating_sub(balance), balance.saturating_sub(Self::CENTER))
+    }
+}
+
+/// A zone volume expressed as a percentage (0-100) of the full `Volume` range.
+///
+/// Both directions round to the nearest step, with halves rounded up, so every raw volume survives a round trip.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct VolumePercent(pub u8);
+
+impl VolumePercent {
+    const MAX_VOLUME: u16 = *ranges::VOLUME.end() as u16;
+
+    /// The equivalent `Volume` attribute (out of range percentages are clamped).
+    pub fn to_volume(&self) -> ZoneAttribute {
+        let percent = self.0.min(*ranges::VOLUME_PERCENT.end()) as u16;
+
+        ZoneAttribute::Volume(((percent * Self::MAX_VOLUME + 50) / 100) as u8)
+    }
+
+    /// The percentage for a volume value (out of range volumes are clamped).
+    pub fn from_volume(volume: u8) -> Self {
+        let volume = (volume as u16).min(Self::MAX_VOLUME);
+
+        VolumePercent(((volume * 100 + Self::MAX_VOLUME / 2) / Self::MAX_VOLUME) as u8)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum ZoneTopic {
     Set,
+    /// like `Set`, but the adjustment is always sent to the amp, even if the zone status already matches
+    ForceSet,
     Status,
 }
 
+impl ZoneTopic {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ZoneTopic::Set => "set",
+            ZoneTopic::ForceSet => "force-set",
+            ZoneTopic::Status => "status",
+        }
+    }
+
+    /// The topic for a zone attribute (or pseudo-attribute, i.e. `source-name`).
+    pub fn zone_topic_name(&self, topic_base: &str, format: ZoneTopicFormat, zone: &ZoneId, attr_name: &str) -> String {
+        let topic_name = self.name();
+        let zone = format.format(zone);
+
+        format!("{topic_base}{topic_name}/zone/{zone}/{attr_name}")
+    }
+}
+
 impl ZoneAttributeDiscriminants {
+    /// The valid range of values for numeric attributes, or `None` for boolean attributes.
+    pub fn io_range(&self) -> Option<RangeInclusive<u8>> {
+        self.io_range_in(&AttributeRanges::default())
+    }
+
+    /// Like `io_range`, but from the given ranges.
+    pub fn io_range_in(&self, ranges: &AttributeRanges) -> Option<RangeInclusive<u8>> {
+        use ZoneAttributeDiscriminants::*;
+
+        match self {
+            Volume => Some(ranges.volume.clone()),
+            Treble => Some(ranges.treble.clone()),
+            Bass => Some(ranges.bass.clone()),
+            Balance => Some(ranges.balance.clone()),
+            Source => Some(ranges.source.clone()),
+            PublicAnnouncement | Power | Mute | DoNotDisturb | KeypadConnected => None,
+        }
+    }
+
+    /// The number of digits of the attribute's value in the amp protocol (i.e. `20` in the `<11VO20` set command).
+    ///
+    /// Values are zero-padded to two digits, or as many digits as the largest value in `ranges` needs.
+    pub fn field_width_in(&self, ranges: &AttributeRanges) -> usize {
+        let max = self.io_range_in(ranges).map_or(1, |range| *range.end());
+
+        max.to_string().len().max(2)
+    }
+
+    /// The two-letter code of the attribute in the amp protocol (i.e. `VO` in the `<11VO20` set command).
+    pub fn protocol_code(&self) -> &'static str {
+        use ZoneAttributeDiscriminants::*;
+
+        match self {
+            PublicAnnouncement => "PA",
+            Power => "PR",
+            Mute => "MU",
+            DoNotDisturb => "DT",
+            Volume => "VO",
+            Treble => "TR",
+            Bass => "BS",
+            Balance => "BL",
+            Source => "CH",
+            KeypadConnected => "LS",
+        }
+    }
+
+    /// The attribute with the given two-letter protocol code (see `protocol_code`).
+    pub fn from_protocol_code(code: &str) -> Option<Self> {
+        Self::iter().find(|attr| attr.protocol_code() == code)
+    }
+
+    /// The attribute with the given protocol value (boolean attributes are `0` or `1`).
+    pub fn with_value(&self, value: u8) -> ZoneAttribute {
+        use ZoneAttributeDiscriminants::*;
+
+        match self {
+            PublicAnnouncement => ZoneAttribute::PublicAnnouncement(value != 0),
+            Power => ZoneAttribute::Power(value != 0),
+            Mute => ZoneAttribute::Mute(value != 0),
+            DoNotDisturb => ZoneAttribute::DoNotDisturb(value != 0),
+            Volume => ZoneAttribute::Volume(value),
+            Treble => ZoneAttribute::Treble(value),
+            Bass => ZoneAttribute::Bass(value),
+            Balance => ZoneAttribute::Balance(value),
+            Source => ZoneAttribute::Source(value),
+            KeypadConnected => ZoneAttribute::KeypadConnected(value != 0),
+        }
+    }
+
     pub fn read_only(&self) -> bool {
         use ZoneAttributeDiscriminants::*;
 
@@ -85,15 +337,13 @@ impl ZoneAttributeDiscriminants {
         }
     }
 
-    pub fn mqtt_topic_name(&self, topic: ZoneTopic, topic_base: &str, zone: &ZoneId) -> String {
-        let topic_name = match topic {
-            ZoneTopic::Set => "set",
-            ZoneTopic::Status => "status",
-        };
-
-        let attr_name = self.to_string().to_kebab_case();
+    /// The attribute name used in zone topics (i.e. `do-not-disturb`).
+    pub fn mqtt_name(&self) -> String {
+        self.to_string().to_kebab_case()
+    }
 
-        format!("{topic_base}{topic_name}/zone/{zone}/{attr_name}")
+    pub fn mqtt_topic_name(&self, topic: ZoneTopic, topic_base: &str, format: ZoneTopicFormat, zone: &ZoneId) -> String {
+        topic.zone_topic_name(topic_base, format, zone, &self.mqtt_name())
     }
 }
 
@@ -106,13 +356,19 @@ pub enum ZoneIdError {
     #[error("zone is out of range ([1, {}]) for zone id {0:02}", MAX_ZONES_PER_AMP)]
     ZoneOutOfRange(u8),
 
-    #[error("cannot parse \"{value}\" as zone id ({source})")]
+    #[error("zone id is empty")]
+    Empty,
+
+    #[error("cannot parse \"{value}\" as zone id, expected a number ({source})")]
     ParseFailure {
         value: String,
 
          #[source]
          source: ParseIntError,
-    }
+    },
+
+    #[error("cannot parse \"{0}\" as {1} zone id")]
+    FormatMismatch(String, ZoneTopicFormat),
 }
 
 
@@ -145,6 +401,11 @@ impl FromStr for ZoneId {
     type Err = ZoneIdError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Err(ZoneIdError::Empty);
+        }
+
         let i = s.parse::<u8>().map_err(|e| ZoneIdError::ParseFailure{ value: s.to_string(), source: e })?;
         ZoneId::try_from(i)
     }
@@ -186,6 +447,22 @@ impl From<&ZoneId> for u8 {
     }
 }
 
+impl ProtocolId for ZoneId {
+    type Error = ZoneIdError;
+
+    fn parse_id(s: &str) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+
+    fn decode(value: u8) -> Result<Self, Self::Error> {
+        ZoneId::try_from(value)
+    }
+
+    fn encode(&self) -> u8 {
+        self.into()
+    }
+}
+
 impl Display for ZoneId {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let id: u8 = self.into();
@@ -194,6 +471,85 @@ impl Display for ZoneId {
     }
 }
 
+/// How a `ZoneId` is written in MQTT topics (and the `status/zones` list).
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Deserialize, Serialize, Display)]
+#[serde(rename_all = "kebab-case")]
+#[strum(serialize_all = "kebab-case")]
+pub enum ZoneTopicFormat {
+    /// `12`, `10` (amp), `00` (system), i.e. `ZoneId`'s `Display`
+    #[default]
+    Numeric,
+
+    /// `1-2`, `1-0` (amp), `0-0` (system)
+    Dashed,
+
+    /// `amp1/zone2`, `amp1` (amp), `system` (system)
+    Path,
+}
+
+impl ZoneTopicFormat {
+    pub fn format(&self, zone: &ZoneId) -> String {
+        match self {
+            ZoneTopicFormat::Numeric => zone.to_string(),
+            ZoneTopicFormat::Dashed => {
+                let id = u8::from(zone);
+                format!("{}-{}", id / 10, id % 10)
+            },
+            ZoneTopicFormat::Path => match zone {
+                ZoneId::Zone { amp, zone } => format!("amp{amp}/zone{zone}"),
+                ZoneId::Amp(amp) => format!("amp{amp}"),
+                ZoneId::System => "system".to_string(),
+            },
+        }
+    }
+
+    /// Parse a zone id written in this format (the inverse of `format`).
+    pub fn parse(&self, s: &str) -> Result<ZoneId, ZoneIdError> {
+        let mismatch = || ZoneIdError::FormatMismatch(s.to_string(), *self);
+
+        let digit = |d: &str| match d.len() {
+            1 => d.parse::<u8>().map_err(|_| mismatch()),
+            _ => Err(mismatch())
+        };
+
+        let (amp, zone) = match self {
+            ZoneTopicFormat::Numeric => return s.parse(),
+            ZoneTopicFormat::Dashed => {
+                let (amp, zone) = s.split_once('-').ok_or_else(mismatch)?;
+                (digit(amp)?, digit(zone)?)
+            },
+            ZoneTopicFormat::Path => {
+                if s == "system" {
+                    return Ok(ZoneId::System);
+                }
+
+                let (amp, zone) = match s.split_once('/') {
+                    Some((amp, zone)) => (amp, Some(zone)),
+                    None => (s, None)
+                };
+
+                let amp = digit(amp.strip_prefix("amp").ok_or_else(mismatch)?)?;
+                let zone = match zone {
+                    Some(zone) => match digit(zone.strip_prefix("zone").ok_or_else(mismatch)?)? {
+                        0 => return Err(mismatch()),
+                        zone => zone
+                    },
+                    None => 0
+                };
+
+                // amp 0 is reserved for the system zone
+                if amp == 0 {
+                    return Err(ZoneIdError::AmpOutOfRange(amp * 10 + zone));
+                }
+
+                (amp, zone)
+            },
+        };
+
+        ZoneId::try_from(amp * 10 + zone)
+    }
+}
+
 impl Ord for ZoneId {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
         u8::from(self).cmp(&other.into())
@@ -284,3 +640,242 @@ impl <'de>Deserialize<'de> for ZoneId {
 
 // }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_zone_id_from_str() {
+        assert_eq!(" 12 ".parse::<ZoneId>().unwrap(), ZoneId::Zone { amp: 1, zone: 2 });
+        assert_eq!("\t00\n".parse::<ZoneId>().unwrap(), ZoneId::System);
+
+        assert!(matches!("".parse::<ZoneId>(), Err(ZoneIdError::Empty)));
+        assert!(matches!("  ".parse::<ZoneId>(), Err(ZoneIdError::Empty)));
+        assert!(matches!("1a".parse::<ZoneId>(), Err(ZoneIdError::ParseFailure { value, .. }) if value == "1a"));
+        assert!(matches!("1 2".parse::<ZoneId>(), Err(ZoneIdError::ParseFailure { .. })));
+    }
+
+    #[test]
+    fn test_protocol_codes() {
+        for attr in ZoneAttributeDiscriminants::iter() {
+            assert_eq!(ZoneAttributeDiscriminants::from_protocol_code(attr.protocol_code()), Some(attr));
+            assert_eq!(ZoneAttributeDiscriminants::from(attr.with_value(1)), attr);
+        }
+
+        assert_eq!(ZoneAttributeDiscriminants::from_protocol_code("BA"), None);
+        assert_eq!(ZoneAttributeDiscriminants::Volume.with_value(20), ZoneAttribute::Volume(20));
+        assert_eq!(ZoneAttributeDiscriminants::Mute.with_value(0), ZoneAttribute::Mute(false));
+    }
+
+    #[test]
+    fn test_io_range() {
+        use ZoneAttributeDiscriminants::*;
+
+        assert_eq!(Volume.io_range(), Some(0..=38));
+        assert_eq!(Treble.io_range(), Some(0..=14));
+        assert_eq!(Bass.io_range(), Some(0..=14));
+        assert_eq!(Balance.io_range(), Some(0..=20));
+        assert_eq!(Source.io_range(), Some(1..=6));
+
+        for attr in [PublicAnnouncement, Power, Mute, DoNotDisturb, KeypadConnected] {
+            assert_eq!(attr.io_range(), None);
+        }
+
+        // validation uses the same ranges
+        assert!(ZoneAttribute::Source(6).validate().is_ok());
+        assert!(ZoneAttribute::Source(0).validate().is_err());
+        assert!(ZoneAttribute::Balance(21).validate().is_err());
+    }
+
+    #[test]
+    fn test_clamped() {
+        use ZoneAttribute::*;
+
+        let numeric = [
+            (Volume as fn(u8) -> ZoneAttribute, ranges::VOLUME),
+            (Treble, ranges::TREBLE),
+            (Bass, ranges::BASS),
+            (Balance, ranges::BALANCE),
+            (Source, ranges::SOURCE),
+        ];
+
+        for (attr, range) in numeric {
+            let (min, max) = (*range.start(), *range.end());
+
+            assert_eq!(attr(max + 1).clamped(), attr(max));
+            assert_eq!(attr(u8::MAX).clamped(), attr(max));
+            assert_eq!(attr(max).clamped(), attr(max));
+            assert_eq!(attr(min).clamped(), attr(min));
+            assert_eq!(attr((min + max) / 2).clamped(), attr((min + max) / 2));
+
+            if min > 0 {
+                assert_eq!(attr(min - 1).clamped(), attr(min));
+            }
+
+            assert!(attr(u8::MAX).clamped().validate().is_ok());
+        }
+
+        assert_eq!(Power(true).clamped(), Power(true));
+        assert_eq!(Mute(false).clamped(), Mute(false));
+    }
+
+    #[test]
+    fn test_attribute_ranges() {
+        assert_eq!(parse_range("0..=79"), Ok(0..=79));
+        assert_eq!(parse_range("0..80"), Ok(0..=79));
+        assert_eq!(parse_range(" 1 ..= 4 "), Ok(1..=4));
+        assert!(parse_range("0-79").is_err());
+        assert!(parse_range("5..=2").is_err());
+        assert!(parse_range("0..0").is_err());
+        assert!(parse_range("0..=256").is_err());
+
+        let ranges = AttributeRanges { volume: 0..=79, source: 1..=4, ..Default::default() };
+
+        assert_eq!(ZoneAttributeDiscriminants::Volume.io_range_in(&ranges), Some(0..=79));
+        assert_eq!(ZoneAttributeDiscriminants::Treble.io_range_in(&ranges), Some(ranges::TREBLE));
+
+        // configured ranges affect validation
+        assert!(ZoneAttribute::Volume(60).validate().is_err());
+        assert!(ZoneAttribute::Volume(60).validate_in(&ranges).is_ok());
+        assert!(ZoneAttribute::Volume(80).validate_in(&ranges).is_err());
+        assert!(ZoneAttribute::Source(5).validate_in(&ranges).is_err());
+        assert!(ZoneAttribute::Power(true).validate_in(&ranges).is_ok());
+
+        // ...and the width of values in the amp protocol
+        assert_eq!(ZoneAttributeDiscriminants::Volume.field_width_in(&ranges), 2);
+        assert_eq!(ZoneAttributeDiscriminants::Power.field_width_in(&ranges), 2);
+        assert_eq!(ZoneAttributeDiscriminants::Volume.field_width_in(&AttributeRanges { volume: 0..=100, ..Default::default() }), 3);
+        assert_eq!(ZoneAttributeDiscriminants::Source.field_width_in(&AttributeRanges { source: 1..=255, ..Default::default() }), 3);
+
+        // ...and clamping
+        assert_eq!(ZoneAttribute::Volume(100).clamped_in(&ranges), ZoneAttribute::Volume(79));
+        assert_eq!(ZoneAttribute::Source(6).clamped_in(&ranges), ZoneAttribute::Source(4));
+    }
+
+    #[test]
+    fn test_balance_trim() {
+        use BalanceTrim::*;
+
+        assert_eq!(Left(0).to_balance(), ZoneAttribute::Balance(10));
+        assert_eq!(Right(0).to_balance(), ZoneAttribute::Balance(10));
+        assert_eq!(Left(10).to_balance(), ZoneAttribute::Balance(0));
+        assert_eq!(Right(10).to_balance(), ZoneAttribute::Balance(20));
+        assert_eq!(Left(3).to_balance(), ZoneAttribute::Balance(7));
+        assert_eq!(Right(3).to_balance(), ZoneAttribute::Balance(13));
+
+        // clamped
+        assert_eq!(Left(11).to_balance(), ZoneAttribute::Balance(0));
+        assert_eq!(Right(200).to_balance(), ZoneAttribute::Balance(20));
+
+        assert_eq!(BalanceTrim::from_balance(10), (0, 0));
+        assert_eq!(BalanceTrim::from_balance(0), (10, 0));
+        assert_eq!(BalanceTrim::from_balance(7), (3, 0));
+        assert_eq!(BalanceTrim::from_balance(20), (0, 10));
+        assert_eq!(BalanceTrim::from_balance(13), (0, 3));
+
+        // round trip
+        for balance in ranges::BALANCE {
+            let (left, right) = BalanceTrim::from_balance(balance);
+            let trim = if left > 0 { Left(left) } else { Right(right) };
+
+            assert_eq!(trim.to_balance(), ZoneAttribute::Balance(balance));
+        }
+    }
+
+    #[test]
+    fn test_volume_percent() {
+        assert_eq!(VolumePercent(0).to_volume(), ZoneAttribute::Volume(0));
+        assert_eq!(VolumePercent(50).to_volume(), ZoneAttribute::Volume(19));
+        assert_eq!(VolumePercent(100).to_volume(), ZoneAttribute::Volume(38));
+
+        assert_eq!(VolumePercent::from_volume(0), VolumePercent(0));
+        assert_eq!(VolumePercent::from_volume(19), VolumePercent(50));
+        assert_eq!(VolumePercent::from_volume(38), VolumePercent(100));
+
+        // rounding edges: 1% = 0.38 rounds down, 2% = 0.76 rounds up
+        assert_eq!(VolumePercent(1).to_volume(), ZoneAttribute::Volume(0));
+        assert_eq!(VolumePercent(2).to_volume(), ZoneAttribute::Volume(1));
+        // 25% = 9.5 rounds half up, 75% = 28.5 likewise
+        assert_eq!(VolumePercent(25).to_volume(), ZoneAttribute::Volume(10));
+        assert_eq!(VolumePercent(75).to_volume(), ZoneAttribute::Volume(29));
+        // raw 1 = 2.63% rounds up, raw 37 = 97.37% rounds down
+        assert_eq!(VolumePercent::from_volume(1), VolumePercent(3));
+        assert_eq!(VolumePercent::from_volume(37), VolumePercent(97));
+
+        // clamped
+        assert_eq!(VolumePercent(101).to_volume(), ZoneAttribute::Volume(38));
+        assert_eq!(VolumePercent(255).to_volume(), ZoneAttribute::Volume(38));
+        assert_eq!(VolumePercent::from_volume(200), VolumePercent(100));
+
+        // round trip
+        for volume in ranges::VOLUME {
+            assert_eq!(VolumePercent::from_volume(volume).to_volume(), ZoneAttribute::Volume(volume));
+        }
+    }
+
+    #[test]
+    fn test_zone_topic_base() {
+        use ZoneAttributeDiscriminants::*;
+
+        let zone = ZoneId::Zone { amp: 1, zone: 2 };
+
+        let cases = [
+            ("mwha/", "mwha/set/zone/12/volume", "mwha/status/zone/12/volume", "mwha/status/zone/10/name"),
+            ("home/audio/", "home/audio/set/zone/12/volume", "home/audio/status/zone/12/volume", "home/audio/status/zone/10/name"),
+            ("", "set/zone/12/volume", "status/zone/12/volume", "status/zone/10/name"),
+        ];
+
+        for (base, set, status, name) in cases {
+            assert_eq!(Volume.mqtt_topic_name(ZoneTopic::Set, base, ZoneTopicFormat::Numeric, &zone), set);
+            assert_eq!(Volume.mqtt_topic_name(ZoneTopic::Status, base, ZoneTopicFormat::Numeric, &zone), status);
+            assert_eq!(ZoneTopic::Status.zone_topic_name(base, ZoneTopicFormat::Numeric, &ZoneId::Amp(1), "name"), name);
+
+            // every attribute topic is a well-formed topic, below the base
+            for attr in ZoneAttributeDiscriminants::iter() {
+                for topic in [ZoneTopic::Set, ZoneTopic::Status] {
+                    let topic = attr.mqtt_topic_name(topic, base, ZoneTopicFormat::Path, &zone);
+
+                    assert!(topic.starts_with(base));
+                    assert!(!topic.starts_with('/') && !topic.contains("//"), "{}", topic);
+                    assert_eq!(topic.split('/').count(), base.matches('/').count() + 5, "{}", topic);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_zone_topic_format() {
+        use ZoneTopicFormat::*;
+
+        let zone = ZoneId::Zone { amp: 1, zone: 2 };
+
+        let cases = [
+            (Numeric, "mwha/status/zone/12/volume", ["12", "10", "00"]),
+            (Dashed, "mwha/status/zone/1-2/volume", ["1-2", "1-0", "0-0"]),
+            (Path, "mwha/status/zone/amp1/zone2/volume", ["amp1/zone2", "amp1", "system"]),
+        ];
+
+        for (format, topic, ids) in cases {
+            assert_eq!(ZoneAttributeDiscriminants::Volume.mqtt_topic_name(ZoneTopic::Status, "mwha/", format, &zone), topic);
+
+            for (zone_id, s) in [zone, ZoneId::Amp(1), ZoneId::System].iter().zip(ids) {
+                assert_eq!(format.format(zone_id), s);
+                assert_eq!(format.parse(s).unwrap(), *zone_id);
+            }
+
+            // every valid zone id round-trips
+            for zone_id in ZoneId::System.to_zones().into_iter().chain(ZoneId::System.to_amps()) {
+                assert_eq!(format.parse(&format.format(&zone_id)).unwrap(), zone_id);
+            }
+        }
+
+        // the format must match
+        assert!(Dashed.parse("12").is_err());
+        assert!(Path.parse("1-2").is_err());
+        assert!(Path.parse("amp1/2").is_err());
+        assert!(Path.parse("amp0/zone1").is_err());
+        assert!(Dashed.parse("1-7").is_err());
+        assert!(Dashed.parse("12-1").is_err());
+    }
+}
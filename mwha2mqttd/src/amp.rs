@@ -1,28 +1,81 @@
 
-use std::ascii::escape_default;
 use std::io::Read;
 use std::io::Write;
 
 use std::net::TcpStream;
 use std::str;
+use std::sync::Arc;
+use std::sync::RwLock;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
 
 use anyhow::bail;
 use itertools::Itertools;
+use strum::IntoEnumIterator;
 use log::debug;
+use log::info;
+use log::warn;
 
 use anyhow::{Context, Result};
 
+use common::payload::preview_payload;
+use common::zone::AttributeRanges;
+use common::zone::MAX_ZONES_PER_AMP;
 use common::zone::ZoneId;
 use common::zone::ZoneAttribute;
+use common::zone::ZoneAttributeDiscriminants;
 
+use crate::config::WritePacing;
 
 
-pub trait Port: Read + Write + Send {}
 
-impl Port for TcpStream {}
+pub trait Port: Read + Write + Send {
+    /// Re-detect (and re-adjust) the baud rate of the connection, returning the new baud rate.
+    ///
+    /// `None` if the port has no baud rate (i.e. TCP).
+    fn redetect_baud(&mut self) -> Result<Option<u32>> {
+        Ok(None)
+    }
+
+    /// Discard any received data that hasn't been read yet, without waiting for more to arrive.
+    fn clear_input(&mut self) -> Result<()>;
+
+    /// A function that releases this port's hold on the device from another thread (leaving this handle open), so that
+    /// the device can be reopened while this handle is still in use (i.e. by a wedged worker).
+    ///
+    /// `None` if an open handle doesn't prevent reopening (i.e. TCP).
+    fn release_handle(&self) -> Result<Option<ReleasePortFn>> {
+        Ok(None)
+    }
+}
+
+/// Releases a port's hold on its device, see `Port::release_handle`.
+pub type ReleasePortFn = Box<dyn FnOnce() + Send>;
+
+impl Port for TcpStream {
+    fn clear_input(&mut self) -> Result<()> {
+        self.set_nonblocking(true)?;
+
+        let mut buffer = [0; 256];
+
+        let result = loop {
+            match self.read(&mut buffer) {
+                Ok(0) => break Ok(()), // closed, the next command will fail
+                Ok(_) => continue,
+                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => break Ok(()),
+                Err(err) => break Err(anyhow::Error::new(err).context("failed to drain tcp stream"))
+            }
+        };
+
+        self.set_nonblocking(false)?;
+
+        result
+    }
+}
 
 
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub struct ZoneStatus {
     pub zone_id: ZoneId,
     pub attributes: Vec<ZoneAttribute>,
@@ -32,45 +85,187 @@ impl ZoneStatus {
     pub fn matches(&self, match_attr: ZoneAttribute) -> bool {
         self.attributes.iter().any(|attr| *attr == match_attr)
     }
+
+    pub fn get(&self, attr: ZoneAttributeDiscriminants) -> Option<ZoneAttribute> {
+        self.attributes.iter().find(|a| ZoneAttributeDiscriminants::from(*a) == attr).copied()
+    }
+
+    /// Iterate over the zone's attributes in canonical (`ZoneAttributeDiscriminants`) order.
+    pub fn iter(&self) -> impl Iterator<Item = (ZoneAttributeDiscriminants, ZoneAttribute)> + '_ {
+        ZoneAttributeDiscriminants::iter()
+            .filter_map(|d| self.get(d).map(|attr| (d, attr)))
+    }
+}
+
+/// The most recently polled zone statuses, shared between the amp worker and other readers (i.e. the shairport handlers).
+/// 
+/// The worker replaces the whole snapshot after each poll, while readers take a cheap clone of the current snapshot.
+/// The lock is only held long enough to swap/clone the `Arc`, so a slow reader never blocks the worker.
+#[derive(Clone, Default)]
+pub struct SharedZonesStatus(Arc<RwLock<Arc<Vec<ZoneStatus>>>>);
+
+impl SharedZonesStatus {
+    pub fn snapshot(&self) -> Arc<Vec<ZoneStatus>> {
+        self.0.read().expect("read lock zones status").clone()
+    }
+
+    pub fn update(&self, statuses: Vec<ZoneStatus>) {
+        *self.0.write().expect("write lock zones status") = Arc::new(statuses);
+    }
+}
+
+
+#[derive(thiserror::Error, Debug)]
+pub enum AmpError {
+    #[error("amp responded with command error while executing command")]
+    CommandError,
+
+    #[error("unexpected response from the amp (not a zone status): \"{0}\"")]
+    Unexpected(String),
+
+    #[error("timed out waiting for a response from the amp")]
+    Timeout,
+
+    #[error("incomplete response from the amp (no end of response marker received before timing out): \"{0}\"")]
+    Framing(String),
+}
+
+impl AmpError {
+    /// Recognize the error response sent by the amp in place of a command response.
+    fn from_response(response: &[u8]) -> Option<AmpError> {
+        match response.trim_ascii() {
+            b"Command Error." => Some(AmpError::CommandError),
+            _ => None
+        }
+    }
+}
+
+
+/// The amp operations used by the worker, so that alternate backends (i.e. an in-memory mock, for testing) can be used.
+///
+/// There is no operation to set public announcement mode, as PA is triggered by hardware (12V trigger) and can't be
+/// changed over the serial interface.
+pub trait AmpController: Send {
+    /// Get the status of a zone, or all the zones of an amp/system.
+    ///
+    /// Zones that don't respond (i.e. their amp isn't present) are omitted from the result.
+    fn zone_enquiry(&mut self, id: ZoneId) -> Result<Vec<ZoneStatus>>;
+
+    fn set_zone_attribute(&mut self, id: ZoneId, attr: ZoneAttribute) -> Result<()>;
+
+    /// Get any zone status the amp has sent unsolicited since the last call (if listening for unsolicited status).
+    ///
+    /// May block for up to the port read timeout waiting for status to arrive.
+    fn unsolicited_statuses(&mut self) -> Result<Vec<ZoneStatus>> {
+        Ok(Vec::new())
+    }
+
+    /// Re-detect the baud rate of the amp connection, returning the new baud rate (`None` if the connection has no baud rate).
+    fn redetect_baud(&mut self) -> Result<Option<u32>> {
+        Ok(None)
+    }
+
+    /// A function that releases the amp connection's hold on its port, see `Port::release_handle`.
+    fn release_handle(&self) -> Result<Option<ReleasePortFn>> {
+        Ok(None)
+    }
+}
+
+impl AmpController for Amp {
+    fn zone_enquiry(&mut self, id: ZoneId) -> Result<Vec<ZoneStatus>> {
+        Amp::zone_enquiry(self, id)
+    }
+
+    fn set_zone_attribute(&mut self, id: ZoneId, attr: ZoneAttribute) -> Result<()> {
+        Amp::set_zone_attribute(self, id, attr)
+    }
+
+    fn unsolicited_statuses(&mut self) -> Result<Vec<ZoneStatus>> {
+        Amp::unsolicited_statuses(self)
+    }
+
+    fn redetect_baud(&mut self) -> Result<Option<u32>> {
+        Amp::redetect_baud(self)
+    }
+
+    fn release_handle(&self) -> Result<Option<ReleasePortFn>> {
+        self.port.release_handle()
+    }
 }
 
 
 pub struct Amp {
-	port: Box<dyn Port>
+	port: Box<dyn Port>,
+
+    /// number of amps connected on the expansion bus
+    amps: u8,
+
+    /// whether the amp supports the system zone enquiry command, `None` until first attempted
+    system_enquiry: Option<bool>,
+
+    resync_marker: ResyncMarkerFn,
+
+    /// unsolicited zone status received so far, `None` if not listening for unsolicited status
+    unsolicited: Option<Vec<ZoneStatus>>,
+
+    /// called with every raw response frame read (for debugging)
+    response_observer: Option<ResponseObserverFn>,
+
+    /// valid ranges of numeric zone attributes, checked before setting them
+    ranges: AttributeRanges,
+
+    /// write commands in paced chunks, rather than all at once
+    write_pacing: Option<WritePacing>,
+
+    /// clear the port input before a command if the connection has been idle for at least this long
+    drain_after_idle: Option<Duration>,
+
+    /// when the last command (or resync) completed
+    last_activity: Instant,
 }
 
-fn escape(s: &String) -> String {
-    String::from_utf8(
-        s.bytes()
-            .flat_map(|b| std::ascii::escape_default(b))
-            .collect::<Vec<u8>>(),
-    )
-    .unwrap()
+/// Called with each raw response frame read from the amp, including the end of response marker.
+pub type ResponseObserverFn = Box<dyn FnMut(&[u8]) + Send>;
+
+/// Generates the unique part of the marker used to resync the serial stream.
+pub type ResyncMarkerFn = Box<dyn FnMut() -> String + Send>;
+
+fn random_resync_marker() -> String {
+    use rand::distributions::{Alphanumeric, DistString};
+
+    Alphanumeric.sample_string(&mut rand::thread_rng(), 8)
 }
 
 pub fn print_buffer(buffer: &[u8]) {
-    let foo = &buffer.iter()
-            .flat_map(|b| escape_default(*b))
-            .collect::<Vec<u8>>();
-
-        let s = String::from_utf8_lossy(
-            &foo
-        );
-        print!("{}, {:?}", s, buffer);
+    print!("{}, {:?}", preview_payload(buffer, buffer.len()), buffer);
 }
 
 impl Amp {
     const END_OF_RESPONSE_MARKER: &[u8] = b"\r\n#";
 
-	pub fn new(port: Box<dyn Port>) -> Result<Self> {
+	pub fn new(port: Box<dyn Port>, amps: u8) -> Result<Self> {
+        Self::with_resync_marker(port, amps, Box::new(random_resync_marker))
+	}
+
+    /// Like `new`, but with a custom resync marker generator (i.e. a fixed marker, for testing).
+    pub fn with_resync_marker(port: Box<dyn Port>, amps: u8, resync_marker: ResyncMarkerFn) -> Result<Self> {
         let mut amp = Self {
-			port
+			port,
+            amps,
+            system_enquiry: None,
+            resync_marker,
+            unsolicited: None,
+            response_observer: None,
+            ranges: AttributeRanges::default(),
+            write_pacing: None,
+            drain_after_idle: None,
+            last_activity: Instant::now(),
 		};
 
         amp.resync().context("failed to resync amp connection")?;
 
 		Ok( amp )
-	}
+    }
 
     fn read_until(&mut self, marker: &[u8]) -> Result<Vec<u8>> {
         let mut buffer = Vec::with_capacity(256);
@@ -80,8 +275,18 @@ impl Amp {
         while !buffer.ends_with(marker) {
             let mut ch = [0; 1];
 
-            self.port.read(&mut ch)
-                .context("failed to read from port")?;
+            match self.port.read(&mut ch) {
+                Ok(_) => {},
+                // a timeout before any data is received leaves the stream in sync
+                Err(err) if matches!(err.kind(), std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock) => {
+                    if buffer.is_empty() {
+                        return Err(AmpError::Timeout.into());
+                    }
+
+                    return Err(AmpError::Framing(preview_payload(&buffer, 50)).into());
+                },
+                Err(err) => return Err(anyhow::Error::new(err).context("failed to read from port"))
+            }
             
             buffer.extend_from_slice(&ch);
         }
@@ -92,31 +297,88 @@ impl Amp {
     fn read_command_response(&mut self) -> Result<Vec<u8>> {
         let mut buffer = self.read_until(Self::END_OF_RESPONSE_MARKER)?;
 
+        if let Some(observer) = self.response_observer.as_mut() {
+            observer(&buffer);
+        }
+
         buffer.truncate(buffer.len() - Self::END_OF_RESPONSE_MARKER.len());
 
-        if buffer == b"\r\nCommand Error." {
-            bail!("amp responded with command error while executing command.");
+        if let Some(err) = AmpError::from_response(&buffer) {
+            return Err(err.into());
         }
 
         Ok(buffer)
     }
 
+    /// Write to the port, in chunks with a delay between each if write pacing is enabled.
+    fn write_paced(&mut self, buf: &[u8]) -> Result<()> {
+        let Some(WritePacing { chunk_size, delay }) = self.write_pacing else {
+            self.port.write_all(buf)?;
+            return Ok(());
+        };
+
+        for (i, chunk) in buf.chunks(chunk_size.max(1)).enumerate() {
+            if i > 0 {
+                thread::sleep(delay);
+            }
+
+            self.port.write_all(chunk)?;
+            self.port.flush()?;
+        }
+
+        Ok(())
+    }
+
 	fn exec_command(&mut self, command: &[u8], expected_responses: usize) -> Result<Vec<Vec<u8>>> {
+        let result = self.exec_command_inner(command, expected_responses);
+
+        self.last_activity = Instant::now();
+
+        result
+    }
+
+	fn exec_command_inner(&mut self, command: &[u8], expected_responses: usize) -> Result<Vec<Vec<u8>>> {
+        // stray data (i.e. gateway keepalives) may have arrived while idle, which would break the echoback check
+        if self.drain_after_idle.is_some_and(|threshold| self.last_activity.elapsed() >= threshold) {
+            debug!("connection idle for {:?}, clearing input", self.last_activity.elapsed());
+            self.port.clear_input().context("failed to clear port input")?;
+        }
+
 		// write command
-        self.port.write(command)?;
-		self.port.write(b"\r")?;
+        self.write_paced(&[command, b"\r"].concat())?;
 		self.port.flush()?;
 		
         // read echoback
-		let echo = self.read_command_response()?;
+		let mut echo = self.read_command_response()?;
+
+        // unsolicited status may arrive before the echoback
+        while let Some(unsolicited) = self.unsolicited.as_mut() {
+            if echo == command || !echo.starts_with(b">") {
+                break;
+            }
+
+            unsolicited.push(Self::parse_zone_status(&echo, &self.ranges).context("failed to parse unsolicited zone status")?);
+            echo = self.read_command_response()?;
+        }
+
         if echo != command {
             bail!("serial echoback was not the expected value. got = {:?}, expected = {:?}", str::from_utf8(&echo), str::from_utf8(command));
         }
 
-        // read responses
+        // read responses.
+        // zones that aren't present (i.e. amp not connected) don't respond, so fewer responses may be received
         let mut responses = Vec::with_capacity(expected_responses);
         for _i in 0..expected_responses {
-            responses.push(self.read_command_response()?);
+            match self.read_command_response() {
+                // any other error framing (i.e. from a gateway) would otherwise be misparsed as a zone status
+                Ok(response) if !response.trim_ascii().starts_with(b">") => return Err(AmpError::Unexpected(preview_payload(&response, 50)).into()),
+                Ok(response) => responses.push(response),
+                Err(err) if matches!(err.downcast_ref::<AmpError>(), Some(AmpError::Timeout)) => {
+                    debug!("{:?}: received {} of {} expected responses", str::from_utf8(command), responses.len(), expected_responses);
+                    break;
+                },
+                Err(err) => return Err(err)
+            }
         }
 
 		Ok(responses)
@@ -130,69 +392,224 @@ impl Amp {
     fn resync(&mut self) -> Result<()> {
         debug!("resyncing serial connection...");
 
-        use rand::distributions::{Alphanumeric, DistString};
-        let marker = Alphanumeric.sample_string(&mut rand::thread_rng(), 8);
-        let marker = format!("resync{}", marker);
+        let marker = format!("resync{}", (self.resync_marker)());
 
         let cmd = format!("{}\r", marker);
         let reply = format!("{}\r\n#\r\nCommand Error.\r\n#", marker);
 
-        println!("cmd: '{}', expected reply: '{}'", escape(&cmd), escape(&reply));
+        debug!("resync cmd: '{}', expected reply: '{}'", preview_payload(cmd.as_bytes(), cmd.len()), preview_payload(reply.as_bytes(), reply.len()));
 
         self.port.write(cmd.as_bytes())?;
         self.read_until(reply.as_bytes())?;
 
+        self.last_activity = Instant::now();
+
         Ok(())
     }
 
+    /// Get the status of a zone, or all the zones of an amp/system.
+    ///
+    /// Zones that don't respond (i.e. their amp isn't present) are omitted from the result.
     pub fn zone_enquiry(&mut self, id: ZoneId) -> Result<Vec<ZoneStatus>> {
-        if let ZoneId::System = id {
-            return id.to_amps().into_iter()
-                .map(|amp| self.zone_enquiry(amp))
-                .flatten_ok()
-                .collect();
-        }
+        let expected_responses = match id {
+            ZoneId::Zone { .. } => 1,
+            ZoneId::Amp(_) => MAX_ZONES_PER_AMP.into(),
+            ZoneId::System => {
+                // try the single system enquiry command, falling back to per-amp enquiries if the amp doesn't support it
+                if self.system_enquiry != Some(false) {
+                    match self.enquiry_command(id, usize::from(self.amps * MAX_ZONES_PER_AMP)) {
+                        Ok(statuses) if !statuses.is_empty() => {
+                            self.system_enquiry = Some(true);
+                            return Ok(statuses);
+                        },
+                        // an amp may echo the command without replying, rather than reporting a command error
+                        Ok(_) if self.system_enquiry.is_none() => {
+                            info!("no zones responded to the system zone enquiry, falling back to per-amp enquiries");
+                            self.system_enquiry = Some(false);
+                        },
+                        Ok(statuses) => return Ok(statuses),
+                        Err(err) if self.system_enquiry.is_none() && matches!(err.downcast_ref::<AmpError>(), Some(AmpError::CommandError)) => {
+                            info!("system zone enquiry not supported by amp, falling back to per-amp enquiries");
+                            self.system_enquiry = Some(false);
+                        },
+                        Err(err) => return Err(err)
+                    }
+                }
 
-        let (amp, zone, expected_responses) = match id {
-            ZoneId::Zone { amp, zone } => (amp, zone, 1),
-            ZoneId::Amp(amp) => (amp, 0, 6),
-            ZoneId::System => unreachable!()
+                return (1..=self.amps).map(ZoneId::Amp)
+                    .map(|amp| self.zone_enquiry(amp))
+                    .flatten_ok()
+                    .collect();
+            }
         };
 
-        let cmd = format!("?{:}{:}", amp, zone);
+        self.enquiry_command(id, expected_responses)
+    }
 
-        self.exec_command(cmd.as_bytes(), expected_responses)?
+    /// Enquire the status of `id`, discarding the status of any zones that weren't enquired (i.e. from another amp on the bus).
+    fn enquiry_command(&mut self, id: ZoneId, expected_responses: usize) -> Result<Vec<ZoneStatus>> {
+        let cmd = format!("?{}", id);
+
+        let statuses = self.exec_command(cmd.as_bytes(), expected_responses)?
             .into_iter()
-            .map(|resp| -> Result<ZoneStatus> {
-            let values = resp[1..] // skip leading '>'
-                .chunks_exact(2)
-                .map(|c| -> Result<u8> {
-                    let s = str::from_utf8(c).context("response string not valid UTF-8")?;
-
-                    Ok(str::parse::<u8>(s).context("failed to parse u8")?)
-                })
-                .collect::<Result<Vec<_>>>()?;
-
-            {
-                use ZoneAttribute::*;
-
-                Ok(ZoneStatus {
-                    zone_id: ZoneId::try_from(values[0]).context("invalid zone id received from amp")?,
-                    attributes: vec![
-                        PublicAnnouncement(values[1] != 0),
-                        Power(values[2] != 0),
-                        Mute(values[3] != 0),
-                        DoNotDisturb(values[4] != 0),
-                        Volume(values[5]),
-                        Treble(values[6]),
-                        Bass(values[7]),
-                        Balance(values[8]),
-                        Source(values[9]),
-                        KeypadConnected(values[10] != 0)
-                    ] 
-                })
+            .map(|resp| Self::parse_zone_status(&resp, &self.ranges))
+            .collect::<Result<Vec<_>>>()?;
+
+        let zone_ids = id.to_zones();
+
+        Ok(statuses.into_iter()
+            .filter(|status| {
+                let expected = zone_ids.contains(&status.zone_id);
+
+                if !expected {
+                    warn!("enquiry {}: discarding status for unexpected zone {}", id, status.zone_id);
+                }
+
+                expected
+            })
+            .collect())
+    }
+
+    /// Get a single attribute of a zone, which is cheaper than a full `zone_enquiry`.
+    ///
+    /// Not used by the worker yet (it always polls whole zones), so only built for tests.
+    #[cfg(test)]
+    pub fn zone_attribute_enquiry(&mut self, id: ZoneId, attr: ZoneAttributeDiscriminants) -> Result<ZoneAttribute> {
+        if !matches!(id, ZoneId::Zone { .. }) {
+            bail!("attribute enquiry requires a single zone, got zone id {}", id);
+        }
+
+        let cmd = format!("?{}{}", id, attr.protocol_code());
+
+        let response = self.exec_command(cmd.as_bytes(), 1)?.into_iter().next()
+            .with_context(|| format!("zone {} did not respond to attribute enquiry", id))?;
+
+        let (zone_id, attribute) = Self::parse_zone_attribute(&response, &self.ranges)?;
+
+        if zone_id != id || ZoneAttributeDiscriminants::from(attribute) != attr {
+            bail!("expected {} {} in attribute enquiry response, got {} {}", id, attr, zone_id, attribute);
+        }
+
+        Ok(attribute)
+    }
+
+    /// Parse a zone attribute response (i.e. `>11VO20`).
+    ///
+    /// The value is as wide as `ranges` requires (see `ZoneAttributeDiscriminants::field_width_in`).
+    #[cfg(test)]
+    fn parse_zone_attribute(resp: &[u8], ranges: &AttributeRanges) -> Result<(ZoneId, ZoneAttribute)> {
+        let resp = str::from_utf8(resp).context("response string not valid UTF-8")?;
+
+        let (zone_id, code, value) = match resp.strip_prefix('>') {
+            Some(values) if values.len() > 4 && values.is_ascii() => (&values[0..2], &values[2..4], &values[4..]),
+            _ => bail!("expected a zone attribute response, got {:?}", resp)
+        };
+
+        let zone_id = zone_id.parse::<ZoneId>().context("invalid zone id received from amp")?;
+
+        let attr = ZoneAttributeDiscriminants::from_protocol_code(code)
+            .with_context(|| format!("unknown attribute code {:?} received from amp", code))?;
+
+        let width = attr.field_width_in(ranges);
+        if value.len() != width {
+            bail!("expected a {}-digit {} value in zone attribute response, got {:?}", width, attr, resp);
+        }
+
+        let value = value.parse::<u8>().context("failed to parse u8")?;
+
+        Ok((zone_id, attr.with_value(value)))
+    }
+
+    /// Parse a zone status response (i.e. `>1100010000200707100101`).
+    ///
+    /// The zone id and each attribute value are two digits, or wider if `ranges` requires
+    /// (see `ZoneAttributeDiscriminants::field_width_in`).
+    fn parse_zone_status(resp: &[u8], ranges: &AttributeRanges) -> Result<ZoneStatus> {
+        let resp = str::from_utf8(resp.get(1..).unwrap_or_default()) // skip leading '>'
+            .context("response string not valid UTF-8")?;
+
+        let widths = std::iter::once(2)
+            .chain(ZoneAttributeDiscriminants::iter().map(|attr| attr.field_width_in(ranges)))
+            .collect::<Vec<_>>();
+
+        let expected_len = widths.iter().sum::<usize>();
+        if resp.len() != expected_len || !resp.is_ascii() {
+            bail!("expected {} values ({} digits) in zone status response, got {:?}", widths.len(), expected_len, resp);
+        }
+
+        let mut fields = widths.into_iter().scan(0, |start, width| {
+            let field = &resp[*start..*start + width];
+            *start += width;
+
+            Some(str::parse::<u8>(field).context("failed to parse u8"))
+        });
+
+        let zone_id = fields.next().expect("zone id field")?;
+
+        Ok(ZoneStatus {
+            zone_id: ZoneId::try_from(zone_id).context("invalid zone id received from amp")?,
+            attributes: ZoneAttributeDiscriminants::iter().zip(fields)
+                .map(|(attr, value)| Ok(attr.with_value(value?)))
+                .collect::<Result<Vec<_>>>()?
+        })
+    }
+
+    /// Re-detect the baud rate of the amp connection (see `Port::redetect_baud`), then resync.
+    pub fn redetect_baud(&mut self) -> Result<Option<u32>> {
+        let baud = self.port.redetect_baud()?;
+
+        self.resync().context("failed to resync amp connection")?;
+
+        Ok(baud)
+    }
+
+    /// Call `observer` with every raw response frame read from the amp.
+    pub fn observe_responses(&mut self, observer: ResponseObserverFn) {
+        self.response_observer = Some(observer);
+    }
+
+    /// Listen for zone status sent unsolicited by the amp (or a gateway), in between command responses.
+    pub fn listen_unsolicited(&mut self, listen: bool) {
+        self.unsolicited = if listen { Some(Vec::new()) } else { None };
+    }
+
+    /// Set the valid ranges of numeric zone attributes, for amps whose firmware differs from the defaults.
+    pub fn set_ranges(&mut self, ranges: AttributeRanges) {
+        self.ranges = ranges;
+    }
+
+    /// Write commands in chunks with a delay between each (`None` writes each command all at once).
+    pub fn set_write_pacing(&mut self, pacing: Option<WritePacing>) {
+        self.write_pacing = pacing;
+    }
+
+    /// Clear the port input before a command if the connection has been idle for at least `threshold` (`None` disables).
+    pub fn set_drain_after_idle(&mut self, threshold: Option<Duration>) {
+        self.drain_after_idle = threshold;
+    }
+
+    /// Get the zone status the amp has sent unsolicited since the last call.
+    ///
+    /// Reads any pending status, blocking for up to the port read timeout if there is none.
+    /// Always empty unless listening for unsolicited status.
+    pub fn unsolicited_statuses(&mut self) -> Result<Vec<ZoneStatus>> {
+        if self.unsolicited.is_none() {
+            return Ok(Vec::new());
+        }
+
+        loop {
+            match self.read_command_response() {
+                Ok(resp) if resp.starts_with(b">") => {
+                    let status = Self::parse_zone_status(&resp, &self.ranges).context("failed to parse unsolicited zone status")?;
+                    self.unsolicited.get_or_insert_with(Vec::new).push(status);
+                },
+                Ok(resp) => debug!("ignoring unexpected data from amp: {}", preview_payload(&resp, 50)),
+                Err(err) if matches!(err.downcast_ref::<AmpError>(), Some(AmpError::Timeout)) => break,
+                Err(err) => return Err(err)
             }
-        }).collect()
+        }
+
+        Ok(self.unsolicited.as_mut().map(std::mem::take).unwrap_or_default())
     }
 
     pub fn set_zone_attribute(&mut self, id: ZoneId, attr: ZoneAttribute) -> Result<()> {
@@ -202,7 +619,9 @@ impl Amp {
                 .collect();
         }
 
-        attr.validate()?;
+        attr.validate_in(&self.ranges)?;
+
+        let width = ZoneAttributeDiscriminants::from(attr).field_width_in(&self.ranges);
 
         let (attr, val) = {
             use ZoneAttribute::*;
@@ -221,10 +640,435 @@ impl Amp {
         };
 
 
-        let cmd = format!("<{}{}{:02}", id, attr, val);
+        let cmd = format!("<{}{}{:0width$}", id, attr, val);
 
         self.exec_command(cmd.as_bytes(), 0)?;
 
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use std::net::TcpListener;
+    use std::sync::Mutex;
+
+    /// a `MockPort` buffer, shared with the test that inspects it
+    pub(crate) type Shared<T> = Arc<Mutex<T>>;
+
+    /// a port that replies with canned data and records everything written to it
+    #[derive(Clone, Default)]
+    pub(crate) struct MockPort {
+        pub(crate) read: Shared<std::collections::VecDeque<u8>>,
+        pub(crate) written: Shared<Vec<u8>>,
+
+        /// each write, and when it was made
+        pub(crate) writes: Shared<Vec<(std::time::Instant, Vec<u8>)>>,
+
+        /// replies that only arrive once the next command is written
+        pub(crate) deferred: Shared<std::collections::VecDeque<Vec<u8>>>,
+    }
+
+    impl MockPort {
+        pub(crate) fn with_reply(reply: &[u8]) -> Self {
+            let port = Self::default();
+            port.reply(reply);
+            port
+        }
+
+        pub(crate) fn reply(&self, reply: &[u8]) {
+            self.read.lock().unwrap().extend(reply);
+        }
+
+        /// reply once the next command is written (i.e. after any input is cleared)
+        pub(crate) fn reply_after_write(&self, reply: &[u8]) {
+            self.deferred.lock().unwrap().push_back(reply.to_vec());
+        }
+
+        pub(crate) fn written(&self) -> Vec<u8> {
+            self.written.lock().unwrap().clone()
+        }
+    }
+
+    impl Read for MockPort {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let mut read = self.read.lock().unwrap();
+
+            if read.is_empty() {
+                return Err(std::io::ErrorKind::TimedOut.into());
+            }
+
+            let n = buf.len().min(read.len());
+            for (b, r) in buf.iter_mut().zip(read.drain(..n)) {
+                *b = r;
+            }
+
+            Ok(n)
+        }
+    }
+
+    impl Write for MockPort {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.written.lock().unwrap().extend_from_slice(buf);
+            self.writes.lock().unwrap().push((std::time::Instant::now(), buf.to_vec()));
+
+            if let Some(reply) = self.deferred.lock().unwrap().pop_front() {
+                self.reply(&reply);
+            }
+
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Port for MockPort {
+        fn clear_input(&mut self) -> Result<()> {
+            self.read.lock().unwrap().clear();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_resync_handshake() {
+        let port = MockPort::with_reply(b"stale data\r\n#resyncTEST\r\n#\r\nCommand Error.\r\n#");
+
+        let amp = Amp::with_resync_marker(Box::new(port.clone()), 1, Box::new(|| "TEST".to_string()));
+
+        assert!(amp.is_ok());
+        assert_eq!(port.written(), b"resyncTEST\r");
+
+        // the stale data and the resync reply are consumed
+        assert!(port.read.lock().unwrap().is_empty());
+
+        // an unexpected reply fails the resync
+        let port = MockPort::with_reply(b"resyncOTHER\r\n#\r\nCommand Error.\r\n#");
+        assert!(Amp::with_resync_marker(Box::new(port), 1, Box::new(|| "TEST".to_string())).is_err());
+    }
+
+    #[test]
+    fn test_error_responses() {
+        let enquiry = |reply: &[u8]| {
+            let port = MockPort::with_reply(b"resyncTEST\r\n#\r\nCommand Error.\r\n#");
+            let mut amp = Amp::with_resync_marker(Box::new(port.clone()), 1, Box::new(|| "TEST".to_string())).unwrap();
+
+            port.reply(reply);
+
+            amp.zone_enquiry(ZoneId::Zone { amp: 1, zone: 1 }).unwrap_err()
+        };
+
+        // as sent by the emulator
+        assert!(matches!(enquiry(b"?11\r\n#\r\nCommand Error.\r\n#").downcast_ref(), Some(AmpError::CommandError)));
+
+        // anything else that isn't a zone status
+        assert!(matches!(enquiry(b"?11\r\n#\r\nERROR\r\n#").downcast_ref(), Some(AmpError::Unexpected(received)) if received == "\\r\\nERROR"));
+
+        // gateways may mangle the line endings
+        assert!(matches!(enquiry(b"?11\r\n#\nCommand Error.\r\n#").downcast_ref(), Some(AmpError::CommandError)));
+
+        // a response cut short before the end of response marker
+        assert!(matches!(enquiry(b"?11\r\n#\r\n>1100000").downcast_ref(), Some(AmpError::Framing(received)) if received == "\\r\\n>1100000"));
+    }
+
+    #[test]
+    fn test_zone_attribute_enquiry() {
+        const STUDY: ZoneId = ZoneId::Zone { amp: 1, zone: 1 };
+
+        let mut emu = mwhaemu::emu::Amp::new(1);
+        emu.zone_set(STUDY, ZoneAttribute::Volume(20));
+        emu.zone_set(STUDY, ZoneAttribute::Source(3));
+        emu.zone_set(STUDY, ZoneAttribute::Mute(true));
+
+        let (mut amp, _emu) = emulated_amp(emu, 1);
+
+        let status = amp.zone_enquiry(STUDY).unwrap().remove(0);
+
+        // each attribute matches the full zone status
+        for attr in ZoneAttributeDiscriminants::iter() {
+            assert_eq!(amp.zone_attribute_enquiry(STUDY, attr).unwrap(), status.get(attr).unwrap(), "{attr}");
+        }
+
+        assert_eq!(amp.zone_attribute_enquiry(STUDY, ZoneAttributeDiscriminants::Volume).unwrap(), ZoneAttribute::Volume(20));
+
+        // only single zones
+        assert!(amp.zone_attribute_enquiry(ZoneId::Amp(1), ZoneAttributeDiscriminants::Volume).is_err());
+        assert!(amp.zone_attribute_enquiry(ZoneId::System, ZoneAttributeDiscriminants::Volume).is_err());
+    }
+
+    #[test]
+    fn test_enquiry_unexpected_zone() {
+        let port = MockPort::with_reply(b"resyncTEST\r\n#\r\nCommand Error.\r\n#");
+        let mut amp = Amp::with_resync_marker(Box::new(port.clone()), 1, Box::new(|| "TEST".to_string())).unwrap();
+
+        // a zone from amp 2 in the reply to an amp 1 enquiry
+        port.reply(b"?10\r\n#>1100010000200707100101\r\n#>2200010000200707100101\r\n#>1300010000200707100101\r\n#");
+
+        let statuses = amp.zone_enquiry(ZoneId::Amp(1)).unwrap();
+        assert_eq!(statuses.iter().map(|s| s.zone_id).collect::<Vec<_>>(), vec![ZoneId::Zone { amp: 1, zone: 1 }, ZoneId::Zone { amp: 1, zone: 3 }]);
+
+        // another zone in the reply to a single zone enquiry
+        port.reply(b"?12\r\n#>1300010000200707100101\r\n#");
+
+        assert!(amp.zone_enquiry(ZoneId::Zone { amp: 1, zone: 2 }).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_custom_range_field_widths() {
+        const STUDY: ZoneId = ZoneId::Zone { amp: 1, zone: 1 };
+
+        let port = MockPort::with_reply(b"resyncTEST\r\n#\r\nCommand Error.\r\n#");
+        let mut amp = Amp::with_resync_marker(Box::new(port.clone()), 1, Box::new(|| "TEST".to_string())).unwrap();
+
+        // the default ranges are all two digits
+        port.reply(b"?11\r\n#>1100010000200707100101\r\n#");
+        assert_eq!(amp.zone_enquiry(STUDY).unwrap()[0].get(ZoneAttributeDiscriminants::Volume), Some(ZoneAttribute::Volume(20)));
+
+        // a volume range above 99 widens the volume field to three digits
+        amp.set_ranges(AttributeRanges { volume: 0..=150, ..Default::default() });
+
+        port.reply(b"?11\r\n#>11000100001500707100101\r\n#");
+        let status = amp.zone_enquiry(STUDY).unwrap().remove(0);
+        assert_eq!(status.get(ZoneAttributeDiscriminants::Volume), Some(ZoneAttribute::Volume(150)));
+        assert_eq!(status.get(ZoneAttributeDiscriminants::Treble), Some(ZoneAttribute::Treble(7)));
+        assert_eq!(status.get(ZoneAttributeDiscriminants::KeypadConnected), Some(ZoneAttribute::KeypadConnected(true)));
+
+        // small values are zero-padded to the full width
+        port.reply(b"?11\r\n#>11000100000200707100101\r\n#");
+        assert_eq!(amp.zone_enquiry(STUDY).unwrap()[0].get(ZoneAttributeDiscriminants::Volume), Some(ZoneAttribute::Volume(20)));
+
+        // two-digit responses no longer fit
+        port.reply(b"?11\r\n#>1100010000200707100101\r\n#");
+        assert!(amp.zone_enquiry(STUDY).is_err());
+
+        // attribute enquiries
+        port.reply(b"?11VO\r\n#>11VO150\r\n#");
+        assert_eq!(amp.zone_attribute_enquiry(STUDY, ZoneAttributeDiscriminants::Volume).unwrap(), ZoneAttribute::Volume(150));
+
+        port.reply(b"?11TR\r\n#>11TR07\r\n#");
+        assert_eq!(amp.zone_attribute_enquiry(STUDY, ZoneAttributeDiscriminants::Treble).unwrap(), ZoneAttribute::Treble(7));
+
+        port.reply(b"?11VO\r\n#>11VO99\r\n#");
+        assert!(amp.zone_attribute_enquiry(STUDY, ZoneAttributeDiscriminants::Volume).is_err());
+
+        // sets are written at the same width
+        port.reply(b"<11VO005\r\n#");
+        amp.set_zone_attribute(STUDY, ZoneAttribute::Volume(5)).unwrap();
+        assert!(port.written().ends_with(b"<11VO005\r"));
+
+        port.reply(b"<11TR03\r\n#");
+        amp.set_zone_attribute(STUDY, ZoneAttribute::Treble(3)).unwrap();
+        assert!(port.written().ends_with(b"<11TR03\r"));
+    }
+
+    #[test]
+    fn test_drain_after_idle() {
+        const IDLE: Duration = Duration::from_millis(20);
+
+        let enquiry = |drain_after_idle| {
+            let port = MockPort::with_reply(b"resyncTEST\r\n#\r\nCommand Error.\r\n#");
+            let mut amp = Amp::with_resync_marker(Box::new(port.clone()), 1, Box::new(|| "TEST".to_string())).unwrap();
+            amp.set_drain_after_idle(drain_after_idle);
+
+            thread::sleep(IDLE);
+
+            // a gateway keepalive received while idle
+            port.reply(b"KEEPALIVE\r\n#");
+            port.reply_after_write(b"?11\r\n#>1100010000200707100101\r\n#");
+
+            amp.zone_enquiry(ZoneId::Zone { amp: 1, zone: 1 })
+        };
+
+        // the stray bytes are read as the echoback
+        assert!(enquiry(None).is_err());
+
+        let statuses = enquiry(Some(IDLE)).unwrap();
+        assert_eq!(statuses.len(), 1);
+
+        // not idle long enough, nothing is cleared
+        assert!(enquiry(Some(Duration::from_secs(60))).is_err());
+    }
+
+    #[test]
+    fn test_write_pacing() {
+        const DELAY: Duration = Duration::from_millis(20);
+
+        let enquiry_writes = |pacing| {
+            let port = MockPort::with_reply(b"resyncTEST\r\n#\r\nCommand Error.\r\n#");
+            let mut amp = Amp::with_resync_marker(Box::new(port.clone()), 1, Box::new(|| "TEST".to_string())).unwrap();
+            amp.set_write_pacing(pacing);
+
+            port.writes.lock().unwrap().clear();
+            port.reply(b"?11\r\n#>1100010000200707100101\r\n#");
+
+            amp.zone_enquiry(ZoneId::Zone { amp: 1, zone: 1 }).unwrap();
+
+            let writes = port.writes.lock().unwrap().clone();
+            writes
+        };
+
+        // unpaced, the command is written all at once
+        let writes = enquiry_writes(None);
+        assert_eq!(writes.into_iter().map(|(_, buf)| buf).collect::<Vec<_>>(), vec![b"?11\r".to_vec()]);
+
+        // paced, the command is written in chunks with a delay between each
+        let writes = enquiry_writes(Some(WritePacing { chunk_size: 3, delay: DELAY }));
+        assert_eq!(writes.iter().map(|(_, buf)| buf.clone()).collect::<Vec<_>>(), vec![b"?11".to_vec(), b"\r".to_vec()]);
+        assert!(writes[1].0.duration_since(writes[0].0) >= DELAY);
+    }
+
+    #[test]
+    fn test_unsolicited_status() {
+        const STATUS: &[u8] = b">1100010000330707100101\r\n#";
+
+        let port = MockPort::with_reply(b"resyncTEST\r\n#\r\nCommand Error.\r\n#");
+        let mut amp = Amp::with_resync_marker(Box::new(port.clone()), 1, Box::new(|| "TEST".to_string())).unwrap();
+
+        // ignored unless listening
+        port.reply(STATUS);
+        assert!(amp.unsolicited_statuses().unwrap().is_empty());
+        port.read.lock().unwrap().clear();
+
+        amp.listen_unsolicited(true);
+
+        // between commands
+        port.reply(STATUS);
+        let statuses = amp.unsolicited_statuses().unwrap();
+        assert_eq!(statuses.len(), 1);
+        assert_eq!(statuses[0].zone_id, ZoneId::Zone { amp: 1, zone: 1 });
+        assert!(statuses[0].matches(ZoneAttribute::Volume(33)));
+
+        assert!(amp.unsolicited_statuses().unwrap().is_empty());
+
+        // before a command echoback
+        port.reply(STATUS);
+        port.reply(b"?12\r\n#>1200010000200707100101\r\n#");
+
+        let statuses = amp.zone_enquiry(ZoneId::Zone { amp: 1, zone: 2 }).unwrap();
+        assert_eq!(statuses.len(), 1);
+        assert_eq!(statuses[0].zone_id, ZoneId::Zone { amp: 1, zone: 2 });
+
+        let statuses = amp.unsolicited_statuses().unwrap();
+        assert_eq!(statuses.len(), 1);
+        assert_eq!(statuses[0].zone_id, ZoneId::Zone { amp: 1, zone: 1 });
+    }
+
+    /// spawn an emulated amp and connect to it
+    pub(crate) fn emulated_amp(emu: mwhaemu::emu::Amp, amps: u8) -> (Amp, Arc<Mutex<mwhaemu::emu::Amp>>) {
+        let emu = Arc::new(Mutex::new(emu));
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        std::thread::spawn({
+            let emu = emu.clone();
+
+            move || {
+                let (stream, _) = listener.accept().unwrap();
+                mwhaemu::serial::run(emu, stream).unwrap();
+            }
+        });
+
+        let stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(std::time::Duration::from_millis(200))).unwrap();
+
+        let amp = Amp::new(Box::new(stream), amps).unwrap();
+
+        (amp, emu)
+    }
+
+    #[test]
+    fn test_system_zone_enquiry() {
+        let zone_ids = |statuses: Vec<ZoneStatus>| statuses.into_iter().map(|s| s.zone_id).collect::<Vec<_>>();
+        let expected = ZoneId::Amp(1).to_zones().into_iter().chain(ZoneId::Amp(2).to_zones()).collect::<Vec<_>>();
+
+        // bulk
+        {
+            let mut emu = mwhaemu::emu::Amp::new(2);
+            emu.system_enquiry = true;
+
+            let (mut amp, _) = emulated_amp(emu, 2);
+
+            assert_eq!(zone_ids(amp.zone_enquiry(ZoneId::System).unwrap()), expected);
+            assert_eq!(amp.system_enquiry, Some(true));
+        }
+
+        // fallback
+        {
+            let (mut amp, _) = emulated_amp(mwhaemu::emu::Amp::new(2), 2);
+
+            assert_eq!(zone_ids(amp.zone_enquiry(ZoneId::System).unwrap()), expected);
+            assert_eq!(amp.system_enquiry, Some(false));
+
+            // subsequent enquiries go straight to the fallback
+            assert_eq!(zone_ids(amp.zone_enquiry(ZoneId::System).unwrap()), expected);
+        }
+
+        // fallback, when the system enquiry is echoed but nothing replies
+        {
+            let port = MockPort::with_reply(b"resyncTEST\r\n#\r\nCommand Error.\r\n#");
+            let mut amp = Amp::with_resync_marker(Box::new(port.clone()), 1, Box::new(|| "TEST".to_string())).unwrap();
+
+            port.reply_after_write(b"?00\r\n#");
+            port.reply_after_write(b"?10\r\n#>1100010000200707100101\r\n#>1200010000200707100101\r\n#");
+
+            let statuses = amp.zone_enquiry(ZoneId::System).unwrap();
+            assert_eq!(zone_ids(statuses), vec![ZoneId::Zone { amp: 1, zone: 1 }, ZoneId::Zone { amp: 1, zone: 2 }]);
+            assert_eq!(amp.system_enquiry, Some(false));
+        }
+    }
+
+    #[test]
+    fn test_zone_status_iter() {
+        use ZoneAttribute::*;
+
+        // attributes in non-canonical order
+        let status = ZoneStatus {
+            zone_id: ZoneId::Zone { amp: 1, zone: 1 },
+            attributes: vec![
+                KeypadConnected(true), Source(2), Balance(10), Bass(7), Treble(7),
+                Volume(20), DoNotDisturb(false), Mute(false), Power(true), PublicAnnouncement(false)
+            ]
+        };
+
+        let attrs = status.iter().collect::<Vec<_>>();
+
+        assert_eq!(attrs.iter().map(|(d, _)| *d).collect::<Vec<_>>(), ZoneAttributeDiscriminants::iter().collect::<Vec<_>>());
+        assert_eq!(attrs.iter().map(|(_, a)| *a).collect::<Vec<_>>(), vec![
+            PublicAnnouncement(false), Power(true), Mute(false), DoNotDisturb(false), Volume(20),
+            Treble(7), Bass(7), Balance(10), Source(2), KeypadConnected(true)
+        ]);
+        assert!(attrs.iter().all(|(d, a)| *d == ZoneAttributeDiscriminants::from(a)));
+    }
+
+    #[test]
+    fn test_shared_zones_status_readers_dont_block_writer() {
+        let status = |zone| ZoneStatus { zone_id: ZoneId::Zone { amp: 1, zone }, attributes: vec![ZoneAttribute::Volume(zone)] };
+
+        let shared = SharedZonesStatus::default();
+        shared.update(vec![status(1)]);
+
+        // a reader holding on to a snapshot (on this thread, so a blocking writer would deadlock)...
+        let snapshot = shared.snapshot();
+
+        // ...doesn't prevent the writer from publishing a new one
+        let writer = {
+            let shared = shared.clone();
+            std::thread::spawn(move || shared.update(vec![status(1), status(2)]))
+        };
+        writer.join().unwrap();
+
+        // the reader's snapshot is unaffected by the update
+        assert_eq!(snapshot.len(), 1);
+        assert!(snapshot[0].matches(ZoneAttribute::Volume(1)));
+
+        // new readers see the update
+        let snapshot = shared.snapshot();
+        assert_eq!(snapshot.len(), 2);
+        assert_eq!(snapshot[1].zone_id, ZoneId::Zone { amp: 1, zone: 2 });
+    }
+}
@@ -0,0 +1,200 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{RecvTimeoutError, TryRecvError};
+
+use crate::config::ChannelOverflow;
+use crate::worker::AmpControlChannelMessage;
+
+
+/// Create a channel for messages from the MQTT handlers to the amp worker, holding at most `capacity` zone attribute
+/// adjustments (0 is unbounded).
+///
+/// Adjustments sent while the channel is full are handled according to `overflow`. Other messages (i.e. `Poison`)
+/// are never dropped, and don't count towards the capacity.
+pub fn control_channel(capacity: usize, overflow: ChannelOverflow) -> (ControlSender, ControlReceiver) {
+    let shared = Arc::new(Shared {
+        queue: Mutex::new(VecDeque::new()),
+        available: Condvar::new(),
+        capacity,
+        overflow
+    });
+
+    (ControlSender(shared.clone()), ControlReceiver(shared))
+}
+
+struct Shared {
+    queue: Mutex<VecDeque<AmpControlChannelMessage>>,
+    available: Condvar,
+    capacity: usize,
+    overflow: ChannelOverflow,
+}
+
+#[derive(Clone)]
+pub struct ControlSender(Arc<Shared>);
+
+impl ControlSender {
+    /// Queue a message for the worker, dropping an adjustment if the channel is full.
+    pub fn send(&self, msg: AmpControlChannelMessage) {
+        let Shared { queue, available, capacity, overflow } = &*self.0;
+
+        let mut queue = queue.lock().expect("lock control channel");
+
+        let queued_adjustments = queue.iter().filter(|queued| queued.adjustment().is_some()).count();
+
+        match msg.adjustment() {
+            Some((zone_id, attr, force)) if *capacity > 0 && queued_adjustments >= *capacity => {
+                match overflow {
+                    ChannelOverflow::DropSuperseded => {
+                        // coalescing would discard the older adjustment of the same zone attribute anyway
+                        let superseded = queue.iter().position(|queued| queued.adjustment().is_some_and(|(queued_zone_id, queued_attr, _)| {
+                            queued_zone_id == zone_id && std::mem::discriminant(&queued_attr) == std::mem::discriminant(&attr)
+                        }));
+
+                        let Some(superseded) = superseded.and_then(|i| queue.remove(i)) else {
+                            log::warn!("control channel full ({} adjustments), dropped adjust {} = {:?}", capacity, zone_id, attr);
+                            return;
+                        };
+
+                        log::debug!("control channel full, adjust {} = {:?} superseded by {:?}", zone_id, superseded.adjustment().map(|(_, attr, _)| attr), attr);
+
+                        // stay forced if the superseded adjustment was
+                        let force = force || superseded.adjustment().is_some_and(|(_, _, force)| force);
+
+                        queue.push_back(match force {
+                            true => AmpControlChannelMessage::ForceZoneAttribute(zone_id, attr),
+                            false => AmpControlChannelMessage::ChangeZoneAttribute(zone_id, attr)
+                        });
+                    },
+                    ChannelOverflow::DropNewest => {
+                        log::warn!("control channel full ({} adjustments), dropped adjust {} = {:?}", capacity, zone_id, attr);
+                        return;
+                    }
+                }
+            },
+            _ => queue.push_back(msg)
+        }
+
+        available.notify_one();
+    }
+}
+
+/// The receiving end of a control channel, with the same receive operations as a `crossbeam_channel::Receiver`.
+///
+/// The channel is never disconnected.
+#[derive(Clone)]
+pub struct ControlReceiver(Arc<Shared>);
+
+impl ControlReceiver {
+    pub fn try_recv(&self) -> Result<AmpControlChannelMessage, TryRecvError> {
+        self.0.queue.lock().expect("lock control channel").pop_front().ok_or(TryRecvError::Empty)
+    }
+
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<AmpControlChannelMessage, RecvTimeoutError> {
+        let deadline = Instant::now() + timeout;
+
+        let mut queue = self.0.queue.lock().expect("lock control channel");
+
+        loop {
+            if let Some(msg) = queue.pop_front() {
+                return Ok(msg);
+            }
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(RecvTimeoutError::Timeout);
+            }
+
+            queue = self.0.available.wait_timeout(queue, remaining).expect("wait on control channel").0;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use common::zone::{ZoneAttribute, ZoneId};
+
+    use super::*;
+
+    const STUDY: ZoneId = ZoneId::Zone { amp: 1, zone: 1 };
+    const LIVING_ROOM: ZoneId = ZoneId::Zone { amp: 1, zone: 2 };
+
+    fn drain(recv: &ControlReceiver) -> Vec<(ZoneId, ZoneAttribute, bool)> {
+        std::iter::from_fn(|| recv.try_recv().ok()).filter_map(|msg| msg.adjustment()).collect()
+    }
+
+    #[test]
+    fn test_flood_drops_superseded() {
+        let (send, recv) = control_channel(2, ChannelOverflow::DropSuperseded);
+
+        // a flood of volume changes, far beyond the capacity
+        for volume in 0..=38 {
+            send.send(AmpControlChannelMessage::ChangeZoneAttribute(STUDY, ZoneAttribute::Volume(volume)));
+        }
+
+        // the latest volumes are kept, superseded volumes are dropped
+        assert_eq!(drain(&recv), vec![
+            (STUDY, ZoneAttribute::Volume(37), false),
+            (STUDY, ZoneAttribute::Volume(38), false),
+        ]);
+
+        // without an adjustment of the same zone attribute to supersede, the new adjustment is dropped
+        send.send(AmpControlChannelMessage::ForceZoneAttribute(STUDY, ZoneAttribute::Volume(10)));
+        send.send(AmpControlChannelMessage::ChangeZoneAttribute(STUDY, ZoneAttribute::Mute(true)));
+        send.send(AmpControlChannelMessage::ChangeZoneAttribute(LIVING_ROOM, ZoneAttribute::Volume(5)));
+        send.send(AmpControlChannelMessage::ChangeZoneAttribute(STUDY, ZoneAttribute::Volume(20)));
+
+        // control messages are never dropped
+        send.send(AmpControlChannelMessage::Poison);
+
+        let queued = std::iter::from_fn(|| recv.try_recv().ok()).collect::<Vec<_>>();
+        assert!(matches!(queued.last(), Some(AmpControlChannelMessage::Poison)));
+
+        // the superseding adjustment stays forced
+        assert_eq!(queued.iter().filter_map(AmpControlChannelMessage::adjustment).collect::<Vec<_>>(), vec![
+            (STUDY, ZoneAttribute::Mute(true), false),
+            (STUDY, ZoneAttribute::Volume(20), true),
+        ]);
+    }
+
+    #[test]
+    fn test_drop_newest() {
+        let (send, recv) = control_channel(2, ChannelOverflow::DropNewest);
+
+        for volume in 0..=38 {
+            send.send(AmpControlChannelMessage::ChangeZoneAttribute(STUDY, ZoneAttribute::Volume(volume)));
+        }
+
+        assert_eq!(drain(&recv), vec![
+            (STUDY, ZoneAttribute::Volume(0), false),
+            (STUDY, ZoneAttribute::Volume(1), false),
+        ]);
+    }
+
+    #[test]
+    fn test_unbounded() {
+        let (send, recv) = control_channel(0, ChannelOverflow::DropSuperseded);
+
+        for volume in 0..=38 {
+            send.send(AmpControlChannelMessage::ChangeZoneAttribute(STUDY, ZoneAttribute::Volume(volume)));
+        }
+
+        assert_eq!(drain(&recv).len(), 39);
+    }
+
+    #[test]
+    fn test_recv_timeout() {
+        let (send, recv) = control_channel(0, ChannelOverflow::DropSuperseded);
+
+        assert!(matches!(recv.recv_timeout(Duration::from_millis(10)), Err(RecvTimeoutError::Timeout)));
+
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(10));
+            send.send(AmpControlChannelMessage::RedetectBaud);
+        });
+
+        assert!(matches!(recv.recv_timeout(Duration::from_secs(10)), Ok(AmpControlChannelMessage::RedetectBaud)));
+    }
+}
@@ -0,0 +1,331 @@
+//! An optional HTTP JSON API for clients that don't speak MQTT, enabled with `[http] listen`.
+//!
+//! - `GET /zones`: the status of every zone
+//! - `GET /zones/<id>`: the status of a zone
+//! - `POST /zones/<id>`: set zone attributes, from a JSON object of attribute names and values (i.e. `{"volume": 20}`)
+//!
+//! Status is read from the worker's shared status cache, sets are queued on the control channel like MQTT sets. Each
+//! `POST` counts as one set towards the zone's `amp.max_sets_per_second` limit, and its attributes are recorded in the
+//! command log (with the "topic" `POST /zones/<id>`).
+
+use std::collections::HashSet;
+use std::io::Read;
+use std::net::SocketAddr;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Instant;
+
+use anyhow::Result;
+use anyhow::anyhow;
+use common::zone::AttributeRanges;
+use common::zone::ZoneAttribute;
+use common::zone::ZoneAttributeDiscriminants;
+use common::zone::ZoneId;
+use serde_json::Map;
+use serde_json::Value;
+use serde_json::json;
+use strum::IntoEnumIterator;
+use tiny_http::Header;
+use tiny_http::Method;
+use tiny_http::Response;
+use tiny_http::Server;
+
+use crate::SetContext;
+use crate::amp::SharedZonesStatus;
+use crate::amp::ZoneStatus;
+use crate::channel::ControlSender;
+use crate::config::BoolPayload;
+use crate::worker::AmpControlChannelMessage;
+
+/// larger request bodies are rejected
+const MAX_BODY_LEN: u64 = 4096;
+
+/// Handles API requests, independent of the HTTP server (so the handlers can be tested without one).
+pub struct HttpApi {
+    zones_status: SharedZonesStatus,
+
+    /// configured zones, sets for other zones are rejected
+    zones: HashSet<ZoneId>,
+
+    /// sets are rate limited and logged like MQTT sets, or rejected when the daemon is readonly (`None`)
+    sets: Option<(SetContext, ControlSender)>,
+}
+
+impl HttpApi {
+    pub(crate) fn new(zones_status: SharedZonesStatus, zones: HashSet<ZoneId>, sets: Option<(SetContext, ControlSender)>) -> Self {
+        HttpApi { zones_status, zones, sets }
+    }
+
+    /// Handle a request, returning the response status code and JSON body.
+    pub fn handle(&self, method: &Method, url: &str, body: &[u8]) -> (u16, Value) {
+        let path = url.split('?').next().unwrap_or_default().trim_end_matches('/');
+
+        let result = match (method, path.strip_prefix("/zones")) {
+            (Method::Get, Some("")) => Ok(self.get_zones()),
+            (method, Some(id)) if id.starts_with('/') => {
+                let zone_id = id[1..].parse::<ZoneId>().map_err(|e| (400, e.to_string()));
+
+                zone_id.and_then(|zone_id| match method {
+                    Method::Get => self.get_zone(zone_id),
+                    Method::Post => self.post_zone(zone_id, body),
+                    _ => Err((405, format!("method {} not allowed", method)))
+                })
+            },
+            (_, Some("")) => Err((405, format!("method {} not allowed", method))),
+            _ => Err((404, format!("{} not found", path)))
+        };
+
+        result.unwrap_or_else(|(status, error)| (status, json!({ "error": error })))
+    }
+
+    fn get_zones(&self) -> (u16, Value) {
+        (200, Value::Array(self.zones_status.snapshot().iter().map(zone_json).collect()))
+    }
+
+    fn get_zone(&self, zone_id: ZoneId) -> Result<(u16, Value), (u16, String)> {
+        self.zones_status.snapshot().iter()
+            .find(|status| status.zone_id == zone_id)
+            .map(|status| (200, zone_json(status)))
+            .ok_or_else(|| (404, format!("no status for zone {}", zone_id)))
+    }
+
+    /// decode and validate every attribute before queuing any, so that a bad request has no effect
+    fn post_zone(&self, zone_id: ZoneId, body: &[u8]) -> Result<(u16, Value), (u16, String)> {
+        let Some((set_context, send)) = &self.sets else {
+            return Err((403, "readonly: zone attributes can't be set".to_string()));
+        };
+
+        if !self.zones.contains(&zone_id) {
+            return Err((404, format!("zone {} is not configured", zone_id)));
+        }
+
+        if !set_context.limiter.allow(zone_id, Instant::now()) {
+            log::warn!("http: set zone {} rate limit exceeded, dropped", zone_id);
+            return Err((429, format!("set rate limit exceeded for zone {}", zone_id)));
+        }
+
+        let values = serde_json::from_slice::<Map<String, Value>>(body)
+            .map_err(|e| (400, format!("body is not a JSON object: {}", e)))?;
+
+        let attrs = values.iter()
+            .map(|(name, value)| self.decode_attribute(&set_context.ranges, name, value).map_err(|e| (400, format!("{}: {:#}", name, e))))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let log_topic = format!("POST /zones/{}", zone_id);
+
+        for &attr in &attrs {
+            log::info!("http: set zone {} {:?}", zone_id, attr);
+
+            let msg = AmpControlChannelMessage::ChangeZoneAttribute(zone_id, attr);
+
+            set_context.command_log.record(&log_topic, &msg);
+            send.send(msg);
+        }
+
+        Ok((202, json!({
+            "zone": zone_id.to_string(),
+            "queued": attrs.iter().map(|attr| (ZoneAttributeDiscriminants::from(attr).mqtt_name(), attribute_json(attr))).collect::<Map<_, _>>()
+        })))
+    }
+
+    /// decode an attribute value like an MQTT set payload, in the configured range
+    fn decode_attribute(&self, ranges: &AttributeRanges, name: &str, value: &Value) -> Result<ZoneAttribute> {
+        let attr = ZoneAttributeDiscriminants::iter()
+            .find(|attr| attr.mqtt_name() == name)
+            .ok_or_else(|| anyhow!("unknown attribute"))?;
+
+        if attr.read_only() {
+            return Err(anyhow!("attribute is read-only"));
+        }
+
+        Ok(crate::decode_set_payload(attr, value.to_string().as_bytes(), ranges, BoolPayload::Json)?)
+    }
+}
+
+fn attribute_json(attr: &ZoneAttribute) -> Value {
+    use ZoneAttribute::*;
+
+    match *attr {
+        PublicAnnouncement(b) | Power(b) | Mute(b) | DoNotDisturb(b) | KeypadConnected(b) => json!(b),
+        Volume(v) | Treble(v) | Bass(v) | Balance(v) | Source(v) => json!(v)
+    }
+}
+
+/// a zone status as a JSON object, keyed by the attributes' MQTT names (i.e. `{"zone": "11", "volume": 20, ...}`)
+fn zone_json(status: &ZoneStatus) -> Value {
+    let mut zone = Map::new();
+
+    zone.insert("zone".to_string(), json!(status.zone_id.to_string()));
+    zone.extend(status.attributes.iter().map(|attr| (ZoneAttributeDiscriminants::from(attr).mqtt_name(), attribute_json(attr))));
+
+    Value::Object(zone)
+}
+
+/// Serve the API on `listen`, on a new thread.
+pub fn spawn_http_server(listen: SocketAddr, api: HttpApi) -> Result<JoinHandle<()>> {
+    let server = Server::http(listen).map_err(|e| anyhow!("failed to listen on {}: {}", listen, e))?;
+
+    log::info!("http: listening on {}", listen);
+
+    let thread = thread::Builder::new()
+        .name("http".to_string())
+        .spawn(move || {
+            for mut request in server.incoming_requests() {
+                let mut body = Vec::new();
+
+                let (status, value) = match request.as_reader().take(MAX_BODY_LEN + 1).read_to_end(&mut body) {
+                    Ok(len) if len as u64 > MAX_BODY_LEN => (413, json!({ "error": "request body too large" })),
+                    Ok(_) => api.handle(request.method(), request.url(), &body),
+                    Err(e) => (400, json!({ "error": format!("failed to read request body: {}", e) }))
+                };
+
+                let response = Response::from_string(value.to_string())
+                    .with_status_code(status)
+                    .with_header(Header::from_bytes("Content-Type", "application/json").expect("valid header"));
+
+                if let Err(e) = request.respond(response) {
+                    log::warn!("http: failed to send response: {}", e);
+                }
+            }
+        })?;
+
+    Ok(thread)
+}
+
+#[cfg(test)]
+mod tests {
+    use common::zone::ZoneTopicFormat;
+
+    use crate::CommandLog;
+    use crate::SetErrors;
+    use crate::channel::ControlReceiver;
+    use crate::channel::control_channel;
+    use crate::config::ChannelOverflow;
+    use crate::worker::SetRateLimiter;
+    use crate::worker::tests::Published;
+
+    use super::*;
+
+    const STUDY: ZoneId = ZoneId::Zone { amp: 1, zone: 1 };
+    const LIVING_ROOM: ZoneId = ZoneId::Zone { amp: 1, zone: 2 };
+
+    fn seeded_api(readonly: bool) -> (HttpApi, ControlReceiver) {
+        let (api, recv, _) = seeded_api_with(readonly, 0);
+
+        (api, recv)
+    }
+
+    /// an api that allows `max_sets_per_second` for each zone, recording its command log
+    fn seeded_api_with(readonly: bool, max_sets_per_second: u32) -> (HttpApi, ControlReceiver, Published) {
+        let zones_status = SharedZonesStatus::default();
+        zones_status.update(vec![
+            ZoneStatus { zone_id: STUDY, attributes: vec![ZoneAttribute::Power(true), ZoneAttribute::Volume(20)] },
+            ZoneStatus { zone_id: LIVING_ROOM, attributes: vec![ZoneAttribute::Power(false), ZoneAttribute::DoNotDisturb(true)] },
+        ]);
+
+        let (send, recv) = control_channel(0, ChannelOverflow::DropSuperseded);
+
+        let published = Published::default();
+
+        let set_context = SetContext {
+            limiter: SetRateLimiter::new(max_sets_per_second),
+            command_log: CommandLog::new(published.clone(), "mwha/", ZoneTopicFormat::Numeric),
+            set_errors: SetErrors::default(),
+            ranges: AttributeRanges::default(),
+            bool_payload: BoolPayload::Json
+        };
+
+        let api = HttpApi::new(zones_status, HashSet::from([STUDY, LIVING_ROOM]), (!readonly).then_some((set_context, send)));
+
+        (api, recv, published)
+    }
+
+    fn queued(recv: &ControlReceiver) -> Vec<(ZoneId, ZoneAttribute, bool)> {
+        std::iter::from_fn(|| recv.try_recv().ok()).filter_map(|msg| msg.adjustment()).collect()
+    }
+
+    #[test]
+    fn test_get() {
+        let (api, _) = seeded_api(false);
+
+        assert_eq!(api.handle(&Method::Get, "/zones", b""), (200, json!([
+            { "zone": "11", "power": true, "volume": 20 },
+            { "zone": "12", "power": false, "do-not-disturb": true },
+        ])));
+
+        assert_eq!(api.handle(&Method::Get, "/zones/12/", b""), (200, json!({ "zone": "12", "power": false, "do-not-disturb": true })));
+
+        assert_eq!(api.handle(&Method::Get, "/zones/13", b"").0, 404);
+        assert_eq!(api.handle(&Method::Get, "/zones/99", b"").0, 400);
+        assert_eq!(api.handle(&Method::Get, "/sources", b"").0, 404);
+        assert_eq!(api.handle(&Method::Delete, "/zones/11", b"").0, 405);
+    }
+
+    #[test]
+    fn test_post() {
+        let (api, recv) = seeded_api(false);
+
+        let (status, body) = api.handle(&Method::Post, "/zones/11", br#"{"volume": 25, "mute": true}"#);
+        assert_eq!(status, 202);
+        assert_eq!(body["queued"], json!({ "volume": 25, "mute": true }));
+        assert_eq!(queued(&recv), vec![(STUDY, ZoneAttribute::Mute(true), false), (STUDY, ZoneAttribute::Volume(25), false)]);
+
+        // any invalid attribute rejects the whole request
+        for body in [r#"{"volume": 25, "treble": 99}"#, r#"{"volume": 25, "keypad-connected": true}"#, r#"{"loudness": 1}"#, r#"{"power": "yes"}"#, "[]", "nope"] {
+            assert_eq!(api.handle(&Method::Post, "/zones/11", body.as_bytes()).0, 400, "{}", body);
+        }
+        assert!(queued(&recv).is_empty());
+
+        // only configured zones
+        assert_eq!(api.handle(&Method::Post, "/zones/13", br#"{"power": true}"#).0, 404);
+        assert!(queued(&recv).is_empty());
+
+        // nothing is set when readonly
+        let (api, recv) = seeded_api(true);
+        assert_eq!(api.handle(&Method::Post, "/zones/11", br#"{"power": true}"#).0, 403);
+        assert!(queued(&recv).is_empty());
+    }
+
+    #[test]
+    fn test_post_rate_limit() {
+        let (api, recv, _) = seeded_api_with(false, 2);
+
+        // each request counts as one set, per zone
+        assert_eq!(api.handle(&Method::Post, "/zones/11", br#"{"volume": 25, "mute": true}"#).0, 202);
+        assert_eq!(api.handle(&Method::Post, "/zones/11", br#"{"volume": 26}"#).0, 202);
+        assert_eq!(queued(&recv).len(), 3);
+
+        let (status, body) = api.handle(&Method::Post, "/zones/11", br#"{"volume": 27}"#);
+        assert_eq!(status, 429);
+        assert!(body["error"].as_str().unwrap().contains("rate limit"), "{body}");
+        assert!(queued(&recv).is_empty());
+
+        assert_eq!(api.handle(&Method::Post, "/zones/12", br#"{"volume": 27}"#).0, 202);
+        assert_eq!(queued(&recv), vec![(LIVING_ROOM, ZoneAttribute::Volume(27), false)]);
+    }
+
+    #[test]
+    fn test_post_command_log() {
+        let (api, _recv, published) = seeded_api_with(false, 0);
+
+        api.handle(&Method::Post, "/zones/11", br#"{"volume": 25, "mute": true}"#);
+
+        let entries = published.take().into_iter()
+            .map(|(topic, _, payload)| {
+                assert_eq!(topic, "mwha/log/commands");
+
+                let entry = serde_json::from_str::<Value>(&payload).unwrap();
+                (entry["topic"].clone(), entry["attribute"].clone(), entry["value"].clone())
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(entries, vec![
+            (json!("POST /zones/11"), json!("mute"), json!(true)),
+            (json!("POST /zones/11"), json!("volume"), json!(25)),
+        ]);
+
+        // rejected requests aren't logged
+        api.handle(&Method::Post, "/zones/11", br#"{"volume": 99}"#);
+        assert!(published.take().is_empty());
+    }
+}
@@ -1,58 +1,98 @@
 mod config;
 mod amp;
+mod channel;
+mod http;
 mod serial;
 mod shairport;
+mod worker;
 
 use std::collections::HashMap;
-use std::collections::HashSet;
 use std::net::TcpStream;
+use std::ops::RangeInclusive;
+use std::io::Write;
 use std::path::PathBuf;
 use std::sync::Arc;
 use std::sync::Mutex;
-use std::sync::mpsc;
-use std::sync::mpsc::Receiver;
-use std::sync::mpsc::Sender;
-use std::thread;
-use std::thread::JoinHandle;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
 
 use amp::Amp;
 use amp::Port;
-use amp::ZoneStatus;
+use amp::ResponseObserverFn;
+use amp::SharedZonesStatus;
 use anyhow::bail;
+use common::mqtt::BacklogClient;
 use common::mqtt::MqttConfig;
 use common::mqtt::MqttConnectionManager;
+use common::mqtt::ReconnectHooks;
 use common::mqtt::PayloadDecodeError;
+use common::ids::SourceId;
+use common::payload::preview_payload;
+use common::zone::BalanceTrim;
+use common::zone::VolumePercent;
 use common::zone::ZoneAttribute;
 use common::zone::ZoneAttributeDiscriminants;
+use common::zone::ZoneAttributeError;
+use common::zone::AttributeRanges;
 
 use clap::Parser;
+use clap::Subcommand;
 use clap::command;
 
+use common::zone::MAX_AMPS;
 use common::zone::ZoneId;
 use common::zone::ZoneTopic;
+use common::zone::ZoneTopicFormat;
+use common::zone::ranges;
 use config::AmpConfig;
+use config::BoolPayload;
 use config::Config;
+use config::MetadataTiming;
+use config::PortConfig;
+use config::PublishConfig;
+use config::SerialPortConfig;
+use config::SourceMetadata;
 use config::ZoneConfig;
+use humantime_serde::re::humantime;
 
 use log::LevelFilter;
 use rumqttc::Client;
 use rumqttc::LastWill;
+use rumqttc::MqttOptions;
 use rumqttc::Publish;
 use serde_json::json;
+use serde_json::Value;
 use serial::AmpSerialPort;
 
 use signal_hook::consts::TERM_SIGNALS;
+use signal_hook::iterator::Handle;
 use signal_hook::iterator::Signals;
 use simplelog::SimpleLogger;
 use strum::IntoEnumIterator;
 
 use std::str;
+use std::str::Utf8Error;
+
+use thiserror::Error;
 
 use anyhow::{Context, Result};
 
 use common::mqtt::PublishJson;
 
+use crate::channel::ControlReceiver;
+use crate::channel::ControlSender;
+use crate::channel::control_channel;
+use crate::http::HttpApi;
+use crate::http::spawn_http_server;
 use crate::shairport::install_source_shairport_handlers;
+use crate::worker::AmpControlChannelMessage;
+use crate::worker::AmpWorkerHandle;
+use crate::worker::FirstPollHook;
+use crate::worker::SetRateLimiter;
+use crate::worker::Watchdog;
+use crate::worker::WorkerHooks;
+use crate::worker::spawn_amp_worker;
 
 
 const DEFAULT_CONFIG_FILE_PATH: &str = match option_env!("DEFAULT_CONFIG_FILE_PATH") {
@@ -65,44 +105,147 @@ const DEFAULT_CONFIG_FILE_PATH: &str = match option_env!("DEFAULT_CONFIG_FILE_PA
 };
 
 
-#[derive(Parser)]
+const EXAMPLE_CONFIG: &str = include_str!("../mwha2mqttd.toml");
+
+/// how long to wait on shutdown for the final publishes to be sent to the broker
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+
+#[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    #[arg[long, default_value=DEFAULT_CONFIG_FILE_PATH]]
-    config_file: PathBuf
+    #[arg[long, global = true, default_value=DEFAULT_CONFIG_FILE_PATH]]
+    config_file: PathBuf,
+
+    /// Only poll and publish zone status, never set zone attributes on the amp (overrides `amp.readonly`)
+    #[arg(long, global = true)]
+    readonly: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>
+}
+
+#[derive(Subcommand, Debug, PartialEq, Eq)]
+enum Command {
+    /// Run the daemon (the default when no command is given)
+    Run,
+
+    /// Check the config file for errors and print a summary of it
+    Check,
+
+    /// Print an example config file, with all the available settings documented
+    ExampleConfig,
+
+    /// Print the effective config, with defaults filled in (and URL credentials redacted), as TOML
+    DumpConfig {
+        /// Print as JSON, rather than TOML
+        #[arg(long)]
+        json: bool
+    },
+
+    /// Connect to the amp, print the status of all zones and exit (without connecting to MQTT)
+    Probe {
+        /// Serial device the amp is connected to (the baud rate is detected).
+        /// If not given, the port from the config file is used
+        #[arg(long)]
+        device: Option<String>,
+
+        /// Number of amps connected on the expansion bus.
+        /// If not given, inferred from the zones in the config file (or 1 when --device is given)
+        #[arg(long, value_parser = clap::value_parser!(u8).range(1..=3))]
+        amps: Option<u8>
+    },
 }
 
-fn connect_mqtt(config: &MqttConfig) -> Result<(Client, MqttConnectionManager, String)> {
+/// the will published by the broker on behalf of mwha2mqttd if it disconnects uncleanly.
+///
+/// a connection only has one will: the death message on `status` if the birth message is enabled (so `status` never
+/// stays `online` after a crash), otherwise `connected`
+fn last_will(topic_base: &str, publish_config: &PublishConfig) -> LastWill {
+    if publish_config.birth {
+        let (topic, payload) = birth_message(topic_base, false);
+
+        return LastWill::new(topic, payload.to_string(), rumqttc::QoS::AtLeastOnce, true);
+    }
+
+    let payload = match publish_config.structured_will {
+        true => json!({"connected": false, "reason": "unexpected"}).to_string(),
+        false => "0".to_string()
+    };
+
+    LastWill::new(format!("{}connected", topic_base), payload, rumqttc::QoS::AtLeastOnce, true)
+}
+
+/// build the MQTT client options (and get the topic base)
+fn mqtt_options(config: &MqttConfig, publish_config: &PublishConfig) -> Result<(MqttOptions, String)> {
     let mut options = common::mqtt::options_from_config(config, "mwha2mqttd")?;
 
     let topic_base = config.topic_base().unwrap_or("mwha/".to_string());
 
-    options.set_last_will(LastWill::new(format!("{}connected", topic_base), "0", rumqttc::QoS::AtLeastOnce, true));
+    options.set_last_will(last_will(&topic_base, publish_config));
+
+    // the broker only publishes the will after 1.5x the keep alive passes without hearing from the client,
+    // so a longer keep alive hides brief network drops (the reconnect hooks restore `connected` straight away)
+    if !publish_config.online_grace.is_zero() {
+        options.set_keep_alive(publish_config.online_grace);
+    }
+
+    Ok((options, topic_base))
+}
+
+/// mirror clients queue more requests, so subscribing before a mirror has connected doesn't block
+const MIRROR_REQUEST_CAPACITY: usize = 1000;
+
+fn connect_broker(config: &MqttConfig, publish_config: &PublishConfig, capacity: usize) -> Result<(MqttConnectionManager, String)> {
+    let (options, topic_base) = mqtt_options(config, publish_config)?;
+
+    let (client, connection) = Client::new(options, capacity);
+
+    let mgr = MqttConnectionManager::new(client, connection);
+
+    mgr.wait_connected_retry(config.connect_retries, config.connect_backoff).with_context(|| format!("failed to connect to MQTT broker {}", config.url))?;
 
-    let (client, connection) = Client::new(options, 10);
+    Ok((mgr, topic_base))
+}
+
+/// connect to the primary broker, and any mirrors (the primary is first).
+/// publishes made with the returned client go to every broker.
+///
+/// a mirror that can't be reached at startup doesn't stop the daemon, it's retried in the background
+fn connect_mqtt(config: &Config) -> Result<(BacklogClient, Vec<MqttConnectionManager>, String)> {
+    let (primary, topic_base) = connect_broker(&config.mqtt, &config.publish, 10)?;
+
+    let mut client = primary.backlog_client();
+    let mut mgrs = vec![primary];
+
+    for mirror_config in &config.mqtt_mirrors {
+        let (options, _) = mqtt_options(mirror_config, &config.publish)?;
+        let (mirror_client, connection) = Client::new(options, MIRROR_REQUEST_CAPACITY);
 
-    let mgr = MqttConnectionManager::new(client.clone(), connection);
+        let mirror = MqttConnectionManager::new(mirror_client, connection);
 
-    mgr.wait_connected().with_context(|| format!("failed to connect to MQTT broker {}", config.url))?;
+        if let Err(e) = mirror.wait_connected_retry(mirror_config.connect_retries, mirror_config.connect_backoff) {
+            log::warn!("failed to connect to MQTT mirror {}, retrying in the background: {:#}", mirror_config.url.host_str().unwrap_or_default(), e);
+        }
+
+        client.mirror(&mirror);
+        mgrs.push(mirror);
+    }
 
-    Ok((
-        client.clone(),
-        mgr,
-        topic_base
-    ))
+    Ok((client, mgrs, topic_base))
 }
 
 
 /// establish a connection to the amp, via either serial or TCP
-fn connect_amp(config: &Config) -> Result<Amp> {
-    let port: Box<dyn Port> = match &config.port {
-        config::PortConfig::Serial(serial) => {
+fn connect_amp(port_config: &PortConfig, amps: u8) -> Result<Amp> {
+    let port: Box<dyn Port> = match port_config {
+        PortConfig::Serial(serial) => {
             let serial = AmpSerialPort::new(serial)
                 .with_context(|| format!("failed to establish serial port connection: {}", serial.device))?;
 
             Box::new(serial)
         },
-        config::PortConfig::Tcp(tcp) => {
+        PortConfig::Tcp(tcp) => {
             let url = &tcp.url;
             match url.scheme() {
                 "raw" => {
@@ -128,93 +271,279 @@ fn connect_amp(config: &Config) -> Result<Amp> {
         },
     };
 
-    Ok(Amp::new(port)?)
+    Amp::new(port, amps)
 }
 
-pub enum AmpControlChannelMessage {
-    ChangeZoneAttribute(ZoneId, ZoneAttribute),
-    Poison
+/// publishes each raw amp response frame (escaped) to `debug/responses`
+fn debug_response_observer<M>(mut mqtt: M, topic_base: &str) -> ResponseObserverFn
+where
+    M: PublishJson + Send + 'static
+{
+    let topic = format!("{}debug/responses", topic_base);
+
+    Box::new(move |frame: &[u8]| {
+        if let Err(err) = mqtt.publish_json(topic.clone(), rumqttc::QoS::AtMostOnce, false, json!(preview_payload(frame, frame.len()))) {
+            log::error!("failed to publish amp response: {}", err);
+        }
+    })
+}
+
+/// Publishes JSON values (not retained) from MQTT handlers, which share it.
+type HandlerPublishFn = Arc<Mutex<Box<dyn FnMut(String, Value) + Send>>>;
+
+fn handler_publisher<M>(mut mqtt: M) -> HandlerPublishFn
+where
+    M: PublishJson + Send + 'static
+{
+    Arc::new(Mutex::new(Box::new(move |topic: String, value: Value| {
+        if let Err(err) = mqtt.publish_json(topic.clone(), rumqttc::QoS::AtLeastOnce, false, value) {
+            log::error!("failed to publish {}: {}", topic, err);
+        }
+    })))
+}
+
+/// Publishes each accepted zone set to `log/commands` (not retained), as an audit trail of changes made via MQTT.
+///
+/// Sets are recorded before they're queued for the amp worker. Disabled (records nothing) unless created with `new`.
+#[derive(Clone, Default)]
+struct CommandLog(Option<(HandlerPublishFn, String, ZoneTopicFormat)>);
+
+impl CommandLog {
+    fn new<M>(mqtt: M, topic_base: &str, format: ZoneTopicFormat) -> CommandLog
+    where
+        M: PublishJson + Send + 'static
+    {
+        CommandLog(Some((handler_publisher(mqtt), format!("{}log/commands", topic_base), format)))
+    }
+
+    /// record a zone set received on `topic`
+    fn record(&self, topic: &str, msg: &AmpControlChannelMessage) {
+        let (Some((publish, log_topic, format)), Some((zone_id, attr, force))) = (&self.0, msg.adjustment()) else { return };
+
+        let value = {
+            use ZoneAttribute::*;
+
+            match attr {
+                PublicAnnouncement(b) | Power(b) | Mute(b) | DoNotDisturb(b) | KeypadConnected(b) => json!(b),
+                Volume(v) | Treble(v) | Bass(v) | Balance(v) | Source(v) => json!(v)
+            }
+        };
+
+        let entry = json!({
+            "timestamp": humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+            "topic": topic,
+            "zone": format.format(&zone_id),
+            "attribute": ZoneAttributeDiscriminants::from(attr).mqtt_name(),
+            "value": value,
+            "force": force
+        });
+
+        (publish.lock().expect("lock command log"))(log_topic.clone(), entry);
+    }
 }
 
+/// Why a zone attribute set payload was rejected.
+#[derive(Error, Debug)]
+enum SetRejection {
+    #[error("payload is not valid UTF-8: {0}")]
+    InvalidUtf8(#[from] Utf8Error),
+
+    #[error("payload is not a valid value: {0}")]
+    InvalidJson(#[from] serde_json::Error),
+
+    #[error(transparent)]
+    OutOfRange(#[from] ZoneAttributeError),
+
+    #[error("{value} is out of range {range:?}")]
+    ValueOutOfRange {
+        value: u8,
+        range: RangeInclusive<u8>
+    },
+
+    #[error("unknown source \"{0}\"")]
+    UnknownSource(String),
+}
+
+impl SetRejection {
+    /// A short, machine-readable reason.
+    fn reason(&self) -> &'static str {
+        match self {
+            SetRejection::InvalidUtf8(_) => "invalid-utf8",
+            SetRejection::InvalidJson(_) => "invalid-json",
+            SetRejection::OutOfRange(_) | SetRejection::ValueOutOfRange { .. } => "out-of-range",
+            SetRejection::UnknownSource(_) => "unknown-source",
+        }
+    }
+}
+
+/// decode a zone attribute set payload, which must be in the configured range
+fn decode_set_payload(attr: ZoneAttributeDiscriminants, payload: &[u8], ranges: &AttributeRanges, bool_payload: BoolPayload) -> Result<ZoneAttribute, SetRejection> {
+    let payload = str::from_utf8(payload)?;
+
+    let de_bool = || bool_payload.decode(payload);
+    let de_u8 = || serde_json::from_str::<u8>(payload);
+
+    let attr = {
+        use ZoneAttributeDiscriminants::*;
+
+        match attr {
+            Power => de_bool().map(ZoneAttribute::Power),
+            Mute => de_bool().map(ZoneAttribute::Mute),
+            DoNotDisturb => de_bool().map(ZoneAttribute::DoNotDisturb),
+            Volume => de_u8().map(ZoneAttribute::Volume),
+            Treble => de_u8().map(ZoneAttribute::Treble),
+            Bass => de_u8().map(ZoneAttribute::Bass),
+            Balance => de_u8().map(ZoneAttribute::Balance),
+            Source => de_u8().map(ZoneAttribute::Source),
+            _ => unreachable!("read-only attributes should never have subscription handlers")
+        }
+    }?;
+
+    attr.validate_in(ranges)?;
+
+    Ok(attr)
+}
+
+/// decode a set payload for an attribute that isn't sent to the amp as-is (i.e. `volume-percent`), which must be in `range`
+fn decode_set_value(payload: &[u8], range: RangeInclusive<u8>) -> Result<u8, SetRejection> {
+    let value = serde_json::from_str::<u8>(str::from_utf8(payload)?)?;
+
+    if !range.contains(&value) {
+        return Err(SetRejection::ValueOutOfRange { value, range });
+    }
+
+    Ok(value)
+}
+
+/// decode a `source-name` set payload, either a JSON string or the bare name (or id) of a source
+fn decode_source_name(payload: &[u8], amp_config: &AmpConfig) -> Result<ZoneAttribute, SetRejection> {
+    let payload = str::from_utf8(payload)?;
+
+    let name = serde_json::from_str::<String>(payload).unwrap_or_else(|_| payload.to_string());
+
+    let source_id = amp_config.resolve_source(&name).ok_or(SetRejection::UnknownSource(name))?;
+
+    Ok(ZoneAttribute::from(source_id))
+}
+
+/// Publishes why zone sets were rejected to `error/zone/<id>/<attr>` (not retained), so that clients can show why
+/// a set was ignored. Disabled (reports nothing) unless created with `new`.
+#[derive(Clone, Default)]
+struct SetErrors(Option<(HandlerPublishFn, String, ZoneTopicFormat)>);
+
+impl SetErrors {
+    fn new<M>(mqtt: M, topic_base: &str, format: ZoneTopicFormat) -> SetErrors
+    where
+        M: PublishJson + Send + 'static
+    {
+        SetErrors(Some((handler_publisher(mqtt), topic_base.to_string(), format)))
+    }
+
+    /// report a rejected set of `attr_name` received on `topic`
+    fn report(&self, topic: &str, zone_id: ZoneId, attr_name: &str, payload: &[u8], rejection: &SetRejection) {
+        let Some((publish, topic_base, format)) = &self.0 else { return };
+
+        let error = json!({
+            "topic": topic,
+            "payload": preview_payload(payload, 50),
+            "reason": rejection.reason(),
+            "error": rejection.to_string()
+        });
+
+        (publish.lock().expect("lock set errors"))(format!("{}error/zone/{}/{}", topic_base, format.format(&zone_id), attr_name), error);
+    }
+}
+
+/// State and config shared by the zone set handlers.
+#[derive(Clone)]
+struct SetContext {
+    limiter: SetRateLimiter,
+    command_log: CommandLog,
+    set_errors: SetErrors,
+    ranges: AttributeRanges,
+    bool_payload: BoolPayload,
+}
+
+/// connect to the amp, configured for the daemon
+fn open_amp(config: &Config, mqtt: &BacklogClient, topic_base: &str) -> Result<Amp> {
+    let mut amp = connect_amp(&config.port, config.amp.amp_count())?;
+
+    amp.listen_unsolicited(config.amp.unsolicited_status);
+    amp.set_ranges(config.amp.ranges.clone());
+
+    if let PortConfig::Serial(serial) = &config.port {
+        amp.set_write_pacing(serial.write_pacing);
+    }
+
+    amp.set_drain_after_idle(match &config.port {
+        PortConfig::Serial(serial) => serial.common.drain_after_idle,
+        PortConfig::Tcp(tcp) => tcp.common.drain_after_idle,
+    });
+
+    if config.publish.debug_responses {
+        amp.observe_responses(debug_response_observer(mqtt.clone(), topic_base));
+    }
+
+    Ok(amp)
+}
 
 /// install zone attribute mqtt subscriptons
-fn install_zone_attribute_subscription_handers(zones_config: &HashMap<ZoneId, ZoneConfig>, mqtt: &mut MqttConnectionManager, topic_base: &str, send: Sender<AmpControlChannelMessage>) -> Result<()> {
+fn install_zone_attribute_subscription_handers(zones_config: &HashMap<ZoneId, ZoneConfig>, mqtt: &mut MqttConnectionManager, topic_base: &str, format: ZoneTopicFormat, set_context: &SetContext, send: ControlSender) -> Result<()> {
     for (&zone_id, _) in zones_config {
         for attr in ZoneAttributeDiscriminants::iter() {
             // don't subscribe/install handlers for read-only attributes
             if attr.read_only() { continue };
 
-            let topic = attr.mqtt_topic_name(ZoneTopic::Set, topic_base, &zone_id);
-
-            // {
-            //     use ZoneAttributeDiscriminants::*;
-
-            //     match attr {
-            //         Power | Mute | DoNotDisturb => {
-            //             mqtt.subscribe_json(topic, rumqttc::QoS::AtLeastOnce, |publish: &Publish, payload: Result<bool, PayloadDecodeError>| {
-
-            //             })
-            //         },
-            //         Volume | Treble | Bass | Balance | Source => {
-            //             mqtt.subscribe_json(topic, rumqttc::QoS::AtLeastOnce, |publish: &Publish, payload: Result<u8, PayloadDecodeError>| {
-            //                 //payload
-            //                 //payload.map(a)
-            //             })
-            //         },
-            //         other => unreachable!("{other}: read-only attributes should never have subscription handlers")
-            //     };
-            // }
-
-
-
-            // todo: maybe invert this so the enum match is on the outside?
-            let handler = {
-                let topic = topic.clone();
-                let send = send.clone();
-
-                move |publish: &Publish| {
-                    let payload = match str::from_utf8(&publish.payload) {
-                        Ok(s) => s,
-                        Err(err) => {
-                            let mut s = String::from_utf8_lossy(&publish.payload);
-                            let payload = s.to_mut();
-                            payload.truncate(50);
-
-                            log::error!("{}: received payload \"{}\" is not valid UTF-8: {}", topic, payload.escape_default(), err);
-                            return;
-                        },
-                    };
-
-                    let de_bool = || serde_json::from_str::<bool>(payload);
-                    let de_u8 = || serde_json::from_str::<u8>(payload);
-
-                    let attr = {
-                        use ZoneAttributeDiscriminants::*;
-
-                        match attr {
-                            Power => de_bool().map(ZoneAttribute::Power),
-                            Mute => de_bool().map(ZoneAttribute::Mute),
-                            DoNotDisturb => de_bool().map(ZoneAttribute::DoNotDisturb),
-                            Volume => de_u8().map(ZoneAttribute::Volume),
-                            Treble => de_u8().map(ZoneAttribute::Treble),
-                            Bass => de_u8().map(ZoneAttribute::Bass),
-                            Balance => de_u8().map(ZoneAttribute::Balance),
-                            Source => de_u8().map(ZoneAttribute::Source),
-                            _ => unreachable!("read-only attributes should never have subscription handlers")
-                        }
-                    };
-
-                    let attr = match attr {
-                        Ok(attr) => attr,
-                        Err(err) => {
-                            log::error!("{}: unable to decode payload \"{}\": {}", topic, payload.escape_default(), err);
-                            return;
-                        }
-                    };
-
-                    send.send(AmpControlChannelMessage::ChangeZoneAttribute(zone_id, attr)).unwrap(); // todo: handle channel send error?
-                }
-            };
+            for zone_topic in [ZoneTopic::Set, ZoneTopic::ForceSet] {
+                let topic = attr.mqtt_topic_name(zone_topic, topic_base, format, &zone_id);
+
+                // {
+                //     use ZoneAttributeDiscriminants::*;
+
+                //     match attr {
+                //         Power | Mute | DoNotDisturb => {
+                //             mqtt.subscribe_json(topic, rumqttc::QoS::AtLeastOnce, |publish: &Publish, payload: Result<bool, PayloadDecodeError>| {
+
+                //             })
+                //         },
+                //         Volume | Treble | Bass | Balance | Source => {
+                //             mqtt.subscribe_json(topic, rumqttc::QoS::AtLeastOnce, |publish: &Publish, payload: Result<u8, PayloadDecodeError>| {
+                //                 //payload
+                //                 //payload.map(a)
+                //             })
+                //         },
+                //         other => unreachable!("{other}: read-only attributes should never have subscription handlers")
+                //     };
+                // }
+
+
+
+                // todo: maybe invert this so the enum match is on the outside?
+                let handler = zone_set_handler(topic.clone(), zone_id, zone_topic, &attr.mqtt_name(), set_context, &send, {
+                    let set_context = set_context.clone();
+
+                    move |payload| decode_set_payload(attr, payload, &set_context.ranges, set_context.bool_payload)
+                });
+
+                mqtt.subscribe(topic, rumqttc::QoS::AtLeastOnce, handler)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// install zone `source-name` mqtt subscriptions, which select a zone source by name (or id)
+fn install_zone_source_name_handlers(amp_config: &AmpConfig, mqtt: &mut MqttConnectionManager, topic_base: &str, format: ZoneTopicFormat, set_context: &SetContext, send: ControlSender) -> Result<()> {
+    for &zone_id in amp_config.zones.keys() {
+        for zone_topic in [ZoneTopic::Set, ZoneTopic::ForceSet] {
+            let topic = zone_topic.zone_topic_name(topic_base, format, &zone_id, "source-name");
+
+            let handler = zone_set_handler(topic.clone(), zone_id, zone_topic, "source-name", set_context, &send, {
+                let amp_config = amp_config.clone();
+
+                move |payload| decode_source_name(payload, &amp_config)
+            });
 
             mqtt.subscribe(topic, rumqttc::QoS::AtLeastOnce, handler)?;
         }
@@ -223,8 +552,187 @@ fn install_zone_attribute_subscription_handers(zones_config: &HashMap<ZoneId, Zo
     Ok(())
 }
 
-fn publish_metadata(mqtt: &mut Client, config: &Config, topic_base: &str) -> Result<()> {
-    mqtt.publish(format!("{}connected", topic_base), rumqttc::QoS::AtLeastOnce, true, "2")?;
+/// install zone `balance-left`/`balance-right` mqtt subscriptions, which set the zone balance as a trim towards one side
+fn install_zone_balance_trim_handlers(zones_config: &HashMap<ZoneId, ZoneConfig>, mqtt: &mut MqttConnectionManager, topic_base: &str, format: ZoneTopicFormat, set_context: &SetContext, send: ControlSender) -> Result<()> {
+    for &zone_id in zones_config.keys() {
+        for zone_topic in [ZoneTopic::Set, ZoneTopic::ForceSet] {
+            for (attr_name, side) in [("balance-left", BalanceTrim::Left as fn(u8) -> BalanceTrim), ("balance-right", BalanceTrim::Right)] {
+                let topic = zone_topic.zone_topic_name(topic_base, format, &zone_id, attr_name);
+
+                let handler = zone_set_handler(topic.clone(), zone_id, zone_topic, attr_name, set_context, &send, move |payload| {
+                    decode_set_value(payload, ranges::BALANCE_TRIM).map(|trim| side(trim).to_balance())
+                });
+
+                mqtt.subscribe(topic, rumqttc::QoS::AtLeastOnce, handler)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// install zone `volume-percent` mqtt subscriptions, which set the zone volume as a percentage of its full range
+fn install_zone_volume_percent_handlers(zones_config: &HashMap<ZoneId, ZoneConfig>, mqtt: &mut MqttConnectionManager, topic_base: &str, format: ZoneTopicFormat, set_context: &SetContext, send: ControlSender) -> Result<()> {
+    for &zone_id in zones_config.keys() {
+        for zone_topic in [ZoneTopic::Set, ZoneTopic::ForceSet] {
+            let topic = zone_topic.zone_topic_name(topic_base, format, &zone_id, "volume-percent");
+
+            let handler = zone_set_handler(topic.clone(), zone_id, zone_topic, "volume-percent", set_context, &send, |payload| {
+                decode_set_value(payload, ranges::VOLUME_PERCENT).map(|percent| VolumePercent(percent).to_volume())
+            });
+
+            mqtt.subscribe(topic, rumqttc::QoS::AtLeastOnce, handler)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// a zone `set`/`force-set` mqtt handler for `topic`: rate limits, `decode`s the payload (reporting rejections to the
+/// set errors topic) and sends the attribute to the worker, logging the command
+fn zone_set_handler<D>(topic: String, zone_id: ZoneId, zone_topic: ZoneTopic, attr_name: &str, set_context: &SetContext, send: &ControlSender, decode: D) -> impl Fn(&Publish) + Send + 'static
+    where D: Fn(&[u8]) -> Result<ZoneAttribute, SetRejection> + Send + 'static
+{
+    let attr_name = attr_name.to_string();
+    let set_context = set_context.clone();
+    let send = send.clone();
+
+    move |publish: &Publish| {
+        if !set_context.limiter.allow(zone_id, Instant::now()) {
+            log::warn!("{}: set rate limit exceeded, dropped payload \"{}\"", topic, preview_payload(&publish.payload, 50));
+            return;
+        }
+
+        let attr = match decode(&publish.payload) {
+            Ok(attr) => attr,
+            Err(rejection) => {
+                log::error!("{}: rejected payload \"{}\": {}", topic, preview_payload(&publish.payload, 50), rejection);
+                set_context.set_errors.report(&topic, zone_id, &attr_name, &publish.payload, &rejection);
+                return;
+            }
+        };
+
+        let msg = match zone_topic {
+            ZoneTopic::ForceSet => AmpControlChannelMessage::ForceZoneAttribute(zone_id, attr),
+            _ => AmpControlChannelMessage::ChangeZoneAttribute(zone_id, attr)
+        };
+
+        set_context.command_log.record(&topic, &msg);
+        send.send(msg);
+    }
+}
+
+/// install zone `set`/`force-set` mqtt subscriptions that only log that writes are disabled (readonly mode)
+fn install_readonly_set_handlers(zones_config: &HashMap<ZoneId, ZoneConfig>, mqtt: &mut MqttConnectionManager, topic_base: &str, format: ZoneTopicFormat, balance_trims: bool, volume_percent: bool) -> Result<()> {
+    for &zone_id in zones_config.keys() {
+        for zone_topic in [ZoneTopic::Set, ZoneTopic::ForceSet] {
+            let topics = ZoneAttributeDiscriminants::iter()
+                .filter(|attr| !attr.read_only())
+                .map(|attr| attr.mqtt_topic_name(zone_topic, topic_base, format, &zone_id))
+                .chain([zone_topic.zone_topic_name(topic_base, format, &zone_id, "source-name")])
+                .chain(["balance-left", "balance-right"].into_iter()
+                    .filter(|_| balance_trims)
+                    .map(|attr_name| zone_topic.zone_topic_name(topic_base, format, &zone_id, attr_name)))
+                .chain(Some("volume-percent")
+                    .filter(|_| volume_percent)
+                    .map(|attr_name| zone_topic.zone_topic_name(topic_base, format, &zone_id, attr_name)));
+
+            for topic in topics {
+                let handler = {
+                    let topic = topic.clone();
+
+                    move |publish: &Publish| {
+                        log::warn!("{}: ignoring payload \"{}\", writes to the amp are disabled (readonly)", topic, preview_payload(&publish.payload, 50));
+                    }
+                };
+
+                mqtt.subscribe(topic, rumqttc::QoS::AtLeastOnce, handler)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// install zone `enabled` mqtt subscriptions, which enable/disable zone status publishing at runtime
+fn install_zone_enabled_handlers(zones_config: &HashMap<ZoneId, ZoneConfig>, mqtt: &mut MqttConnectionManager, topic_base: &str, format: ZoneTopicFormat, send: ControlSender) -> Result<()> {
+    for &zone_id in zones_config.keys() {
+        let topic = ZoneTopic::Set.zone_topic_name(topic_base, format, &zone_id, "enabled");
+
+        let handler = {
+            let send = send.clone();
+
+            move |_publish: &Publish, payload: Result<bool, PayloadDecodeError>| {
+                match payload {
+                    Ok(enabled) => send.send(AmpControlChannelMessage::SetZoneEnabled(zone_id, enabled)),
+                    Err(e) => log::error!("{e}")
+                }
+            }
+        };
+
+        mqtt.subscribe_json(topic, rumqttc::QoS::AtLeastOnce, handler)?;
+    }
+
+    Ok(())
+}
+
+/// install `cmd` mqtt subscriptions, which trigger one-off actions on the amp
+fn install_command_handlers(mqtt: &mut MqttConnectionManager, topic_base: &str, send: ControlSender) -> Result<()> {
+    let handler = move |_publish: &Publish| {
+        send.send(AmpControlChannelMessage::RedetectBaud);
+    };
+
+    mqtt.subscribe(format!("{}cmd/redetect-baud", topic_base), rumqttc::QoS::AtLeastOnce, handler)?;
+
+    Ok(())
+}
+
+/// the birth (`online`) or death (`offline`) message published to the `status` topic, following the Homie/Tasmota convention
+fn birth_message(topic_base: &str, online: bool) -> (String, Value) {
+    (format!("{}status", topic_base), json!(if online { "online" } else { "offline" }))
+}
+
+/// The `connected` status last published, so that a reconnect restores it rather than assuming the daemon is online.
+#[derive(Clone, Default)]
+struct ConnectedStatus(Arc<Mutex<Option<u8>>>);
+
+impl ConnectedStatus {
+    /// publish (and remember) the `connected` status
+    fn publish(&self, mqtt: &mut impl PublishJson, topic_base: &str, connected: u8) -> Result<()> {
+        let mut status = self.0.lock().expect("lock connected status");
+        *status = Some(connected);
+
+        mqtt.publish_json(format!("{}connected", topic_base), rumqttc::QoS::AtLeastOnce, true, json!(connected))?;
+
+        Ok(())
+    }
+
+    /// republish the last `connected` status. returns false if it hasn't been published yet
+    fn republish(&self, mqtt: &mut impl PublishJson, topic_base: &str) -> Result<bool> {
+        let status = self.0.lock().expect("lock connected status");
+
+        let Some(connected) = *status else { return Ok(false) };
+
+        mqtt.publish_json(format!("{}connected", topic_base), rumqttc::QoS::AtLeastOnce, true, json!(connected))?;
+
+        Ok(true)
+    }
+}
+
+/// publish `connected` = `2`, followed by the metadata
+fn publish_online(mqtt: &mut impl PublishJson, config: &Config, topic_base: &str, connected: &ConnectedStatus) -> Result<()> {
+    connected.publish(mqtt, topic_base, 2)?;
+
+    publish_metadata(mqtt, config, topic_base)
+}
+
+fn publish_metadata(mqtt: &mut impl PublishJson, config: &Config, topic_base: &str) -> Result<()> {
+    if config.publish.birth {
+        let (topic, payload) = birth_message(topic_base, true);
+        mqtt.publish_json(topic, rumqttc::QoS::AtLeastOnce, true, payload)?;
+
+        mqtt.publish_json(format!("{}status/version", topic_base), rumqttc::QoS::AtLeastOnce, true, json!(env!("CARGO_PKG_VERSION")))?;
+    }
 
     // amp metadata
     if let Some(model) = &config.amp.model {
@@ -237,122 +745,405 @@ fn publish_metadata(mqtt: &mut Client, config: &Config, topic_base: &str) -> Res
         mqtt.publish_json(format!("{}status/amp/serial", topic_base), rumqttc::QoS::AtLeastOnce, true, json!(serial))?;
     }
 
-    // source metadata
-    for (source_id, source_config) in config.amp.sources() {
+    // system and amp labels (cleared if not configured)
+    let names = std::iter::once((format!("{}status/amp/name", topic_base), config.amp.system_name()))
+        .chain((1..=MAX_AMPS).map(|amp| (format!("{}status/amp/{}/name", topic_base, amp), config.amp.amp_name(amp))));
+
+    for (topic, name) in names {
+        match name {
+            Some(name) => mqtt.publish_json(topic, rumqttc::QoS::AtLeastOnce, true, json!(name))?,
+            None => mqtt.clear_retained(topic, rumqttc::QoS::AtLeastOnce)?
+        }
+    }
+
+    // source metadata, for each of the amp's `source_ids` (cleared for sources that aren't published or don't exist)
+    let sources = config.amp.sources();
+    let mut published_sources = serde_json::Map::new();
+
+    for source_id in SourceId::all() {
         let topic_base = format!("{}status/source/{}/", topic_base, source_id);
 
-        mqtt.publish_json(format!("{}name", topic_base), rumqttc::QoS::AtLeastOnce, true, json!(source_config.name))?;
-        mqtt.publish_json(format!("{}enabled", topic_base), rumqttc::QoS::AtLeastOnce, true, json!(source_config.enabled))?;
+        let source_config = sources.get(&source_id).filter(|source_config| match config.publish.sources {
+            SourceMetadata::All => true,
+            SourceMetadata::Configured => config.amp.is_configured_source(&source_id),
+            SourceMetadata::Enabled => config.amp.is_configured_source(&source_id) && source_config.enabled,
+        });
+
+        match source_config {
+            Some(source_config) => {
+                mqtt.publish_json(format!("{}name", topic_base), rumqttc::QoS::AtLeastOnce, true, json!(source_config.name))?;
+                mqtt.publish_json(format!("{}enabled", topic_base), rumqttc::QoS::AtLeastOnce, true, json!(source_config.enabled))?;
+
+                published_sources.insert(source_id.to_string(), json!({
+                    "name": source_config.name,
+                    "enabled": source_config.enabled,
+                }));
+            },
+            None => {
+                mqtt.clear_retained(format!("{}name", topic_base), rumqttc::QoS::AtLeastOnce)?;
+                mqtt.clear_retained(format!("{}enabled", topic_base), rumqttc::QoS::AtLeastOnce)?;
+            }
+        }
     }
 
+    // map of published sources, for clients to fetch in one go
+    mqtt.publish_json(format!("{}status/sources", topic_base), rumqttc::QoS::AtLeastOnce, true, json!(published_sources))?;
+
+    // how zone ids are written in topics, for clients to parse `status/zones` with
+    mqtt.publish_json(format!("{}status/zone-topic-format", topic_base), rumqttc::QoS::AtLeastOnce, true, json!(config.publish.zone_topic_format))?;
+
     // list of active zones
-    mqtt.publish_json(format!("{}status/zones", topic_base), rumqttc::QoS::AtLeastOnce, true, json!(config.amp.zones.keys().map(|z| z.to_string()).collect::<Vec<_>>()))?;
+    mqtt.publish_json(format!("{}status/zones", topic_base), rumqttc::QoS::AtLeastOnce, true, json!(config.amp.zones.keys().map(|z| config.publish.zone_topic_format.format(z)).collect::<Vec<_>>()))?;
 
     // zone metadata
     for (zone_id, zone_config) in &config.amp.zones {
-        let topic_base = format!("{}status/zone/{}/", topic_base, zone_id);
+        let topic_base = format!("{}status/zone/{}/", topic_base, config.publish.zone_topic_format.format(zone_id));
 
         mqtt.publish_json(format!("{}name", topic_base), rumqttc::QoS::AtLeastOnce, true, json!(zone_config.name))?;
     }
 
+    // sanitized config summary
+    if config.publish.config {
+        mqtt.publish_json(format!("{}status/config", topic_base), rumqttc::QoS::AtLeastOnce, true, config.summary())?;
+    }
+
+    Ok(())
+}
+
+/// every retained status topic published for the config, other than `connected` and the birth message
+fn status_topics(config: &Config, topic_base: &str) -> Vec<String> {
+    let format = config.publish.zone_topic_format;
+
+    let daemon = ["status/version", "status/amp/model", "status/amp/manufacturer", "status/amp/serial", "status/amp/name", "status/amp/baud",
+            "status/sources", "status/zone-topic-format", "status/zones", "status/config", "status/last-error", "status/diag/commands_total"].into_iter()
+        .map(|topic| format!("{}{}", topic_base, topic))
+        .chain((1..=MAX_AMPS).map(|amp| format!("{}status/amp/{}/name", topic_base, amp)));
+
+    let sources = SourceId::all().into_iter()
+        .flat_map(|source_id| ["name", "enabled"].map(|attr_name| format!("{}status/source/{}/{}", topic_base, source_id, attr_name)));
+
+    let zones = config.amp.zones.keys().flat_map(|zone_id| {
+        let companions = ["name", "available"].into_iter()
+            .chain(["balance-left", "balance-right"].into_iter().filter(|_| config.publish.balance_trims))
+            .chain(Some("volume-percent").filter(|_| config.publish.volume_percent))
+            .map(|attr_name| ZoneTopic::Status.zone_topic_name(topic_base, format, zone_id, attr_name));
+
+        ZoneAttributeDiscriminants::iter()
+            .map(|attr| attr.mqtt_topic_name(ZoneTopic::Status, topic_base, format, zone_id))
+            .chain(companions)
+            .collect::<Vec<_>>()
+    });
+
+    daemon.chain(sources).chain(zones).collect()
+}
+
+/// clear every retained status topic, so a stopped daemon doesn't leave stale values in the broker
+fn clear_status(mqtt: &mut impl PublishJson, config: &Config, topic_base: &str) -> Result<()> {
+    for topic in status_topics(config, topic_base) {
+        mqtt.clear_retained(topic, rumqttc::QoS::AtLeastOnce)?;
+    }
+
     Ok(())
 }
 
-/// spawn a worker thread that processes incoming zone attribute adjustments and periodically polls the amp for status updates
-fn spawn_amp_worker(config: &AmpConfig, mut amp: Amp, mqtt: rumqttc::Client, topic_base: &str, recv: Receiver<AmpControlChannelMessage>, zones_status: Arc<Mutex<Vec<ZoneStatus>>>) -> JoinHandle<()> {
-    // get the zones specifically configured for publish (ignore amp and system zones)
-    let zone_ids = config.zones.keys().filter_map(|z| match z {
-        ZoneId::Zone { amp, zone } => Some(ZoneId::Zone { amp: *amp, zone: *zone }),
-        _ => None,
-    }).collect::<HashSet<_>>();
+/// publish the shutdown status, ending with `connected` = `0`.
+///
+/// the will is only published by the broker on unclean disconnects, so a clean shutdown must publish it explicitly
+fn publish_shutdown(mqtt: &mut impl PublishJson, config: &Config, topic_base: &str, connected: &ConnectedStatus) -> Result<()> {
+    if config.publish.clear_on_shutdown {
+        clear_status(mqtt, config, topic_base)?;
+    }
+
+    if config.publish.birth {
+        let (topic, payload) = birth_message(topic_base, false);
+        mqtt.publish_json(topic, rumqttc::QoS::AtLeastOnce, true, payload)?;
+    }
 
-    // coalesce zone ids into amp ids (for bulk query)
-    let amp_ids = zone_ids.iter().flat_map(ZoneId::to_amps).collect::<HashSet<_>>();
+    connected.publish(mqtt, topic_base, 0)?;
+
+    Ok(())
+}
+
+/// stop the amp worker, then publish the shutdown status.
+///
+/// the worker is stopped first so that none of its publishes follow the shutdown status. the status is still published
+/// if the worker panicked, which is then returned as an error
+fn shutdown(watchdog: Watchdog, send: &ControlSender, mqtt: &mut impl PublishJson, config: &Config, topic_base: &str, connected: &ConnectedStatus) -> Result<()> {
+    // the worker may have already exited
+    send.send(AmpControlChannelMessage::Poison);
+
+    let worker = watchdog.stop().join();
+
+    publish_shutdown(mqtt, config, topic_base, connected)?;
+
+    if worker.is_err() {
+        bail!("amp worker panicked");
+    }
+
+    Ok(())
+}
+
+/// a hook that publishes metadata after the worker's first poll, if the config defers it until then
+fn first_poll_metadata<M>(mut mqtt: M, config: &Config, topic_base: &str, connected: &ConnectedStatus) -> Option<FirstPollHook>
+where
+    M: PublishJson + Send + 'static
+{
+    match config.publish.metadata {
+        MetadataTiming::Startup => None,
+        MetadataTiming::FirstPoll => {
+            let config = config.clone();
+            let topic_base = topic_base.to_string();
+            let connected = connected.clone();
+
+            Some(Box::new(move || {
+                log::info!("first poll complete, publishing metadata");
+
+                if let Err(err) = publish_online(&mut mqtt, &config, &topic_base, &connected) {
+                    log::error!("failed to publish metadata: {:#}", err);
+                }
+            }))
+        }
+    }
+}
 
-    let poll_interval = config.poll_interval;
+/// republish the `connected` status and all metadata after each reconnection to the broker, in case the broker lost
+/// the retained values.
+///
+/// nothing is republished until `connected` has first been published (i.e. while metadata waits for the first poll)
+fn republish_metadata_on_reconnect<M>(hooks: &ReconnectHooks, mqtt: M, config: &Config, topic_base: &str, connected: &ConnectedStatus)
+where
+    M: PublishJson + Clone + Send + 'static
+{
+    let config = config.clone();
     let topic_base = topic_base.to_string();
+    let connected = connected.clone();
 
-    let mut mqtt = mqtt.clone();
+    hooks.add(move || {
+        let mut mqtt = mqtt.clone();
 
-    thread::spawn(move || {
-        let mut previous_statuses: HashMap<ZoneId, amp::ZoneStatus> = HashMap::new();
+        let result = connected.republish(&mut mqtt, &topic_base).and_then(|published| match published {
+            true => {
+                log::info!("republishing metadata");
+                publish_metadata(&mut mqtt, &config, &topic_base)
+            },
+            false => Ok(())
+        });
 
-        loop {
-            let mut adjustments = HashMap::new();
+        if let Err(err) = result {
+            log::error!("failed to republish metadata: {:#}", err);
+        }
+    });
+}
 
-            {
-                // wait for an incoming zone attribute adjustment with a timeout.
-                // if a timeout occurs do a zone status refresh anyway (poll the amp)
-                let mut msg = match recv.recv_timeout(poll_interval) {
-                    Ok(msg) => Some(msg),
-                    Err(std::sync::mpsc::RecvTimeoutError::Timeout) => None, // timeout waiting for message, refresh zone status anyway
-                    Err(other) => panic!("recv_timeout error: {:?}", other)
-                };
+fn check(config: &Config) -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(&config.summary())?);
 
-                // drain the channel.
-                // mqtt can deliver faster than the serialport can handle and multiple adjustments may have come while processing the last request.
-                // there is no point adjusting the same attribute multiple times.
-                // newer attribute adjustments queued for the same zone overwrite earlier ones.
-                loop {
-                    match msg {
-                        Some(AmpControlChannelMessage::ChangeZoneAttribute(zone_id, attr)) => { adjustments.insert((zone_id, std::mem::discriminant(&attr)), (zone_id, attr)); }
-                        Some(AmpControlChannelMessage::Poison) => { return },
-                        None => break
-                    }
+    Ok(())
+}
+
+/// enquire the status of all zones and print it as a table
+fn probe(amp: &mut Amp, out: &mut impl Write) -> Result<()> {
+    let statuses = amp.zone_enquiry(ZoneId::System).context("failed to enquire zone status")?;
 
-                    msg = match recv.try_recv() {
-                        Ok(msg) => Some(msg),
-                        Err(std::sync::mpsc::TryRecvError::Empty) => None,
-                        Err(other) => panic!("try_recv error: {:?}", other)
-                    };
+    let header = std::iter::once("Zone".to_string())
+        .chain(ZoneAttributeDiscriminants::iter().map(|attr| attr.to_string()))
+        .collect::<Vec<_>>();
+
+    let rows = statuses.iter().map(|zone_status| {
+        std::iter::once(zone_status.zone_id.to_string())
+            .chain(zone_status.iter().map(|(_, attr)| {
+                use ZoneAttribute::*;
+
+                match attr {
+                    PublicAnnouncement(b) | Power(b) | Mute(b) | DoNotDisturb(b) | KeypadConnected(b) => b.to_string(),
+                    Volume(v) | Treble(v) | Bass(v) | Balance(v) | Source(v) => v.to_string()
                 }
-            }
+            }))
+            .collect::<Vec<_>>()
+    }).collect::<Vec<_>>();
 
-            // apply zone attribute adjustments, if any
-            for (zone_id, attr) in adjustments.values().into_iter() {
-                log::debug!("adjust {} = {:?}", zone_id, attr);
-                amp.set_zone_attribute(*zone_id, *attr).unwrap(); // TODO: handle error more gracefully
-            }
+    let widths = header.iter().enumerate()
+        .map(|(i, h)| rows.iter().map(|row| row.get(i).map_or(0, String::len)).max().unwrap_or(0).max(h.len()))
+        .collect::<Vec<_>>();
 
-            // get zone statuses from active amps
-            let mut zones_status = zones_status.lock().expect("lock zones_status");
-            zones_status.clear();
-            for amp_id in &amp_ids {
-                let enquiry_result = amp.zone_enquiry(*amp_id).unwrap(); // TODO: handle error more gracefully
+    for row in std::iter::once(&header).chain(&rows) {
+        let line = row.iter().zip(&widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect::<Vec<_>>()
+            .join("  ");
+
+        writeln!(out, "{}", line.trim_end())?;
+    }
+
+    if statuses.is_empty() {
+        writeln!(out, "no zones responded")?;
+    }
 
-                // exclude disabled zones
-                zones_status.extend(enquiry_result.into_iter().filter(|z| zone_ids.contains(&z.zone_id))); 
+    Ok(())
+}
+
+/// replace a stalled amp worker with a new one (and a new amp connection), publishing a degraded `connected` status until it first polls
+fn restart_amp_worker(config: &Config, mqtt: &mut BacklogClient, topic_base: &str, connected: &ConnectedStatus, recv: ControlReceiver, zones_status: SharedZonesStatus, signals_handle: Handle) -> Result<AmpWorkerHandle> {
+    connected.publish(mqtt, topic_base, 1)?;
+
+    let amp = open_amp(config, mqtt, topic_base).context("failed to re-establish amp connection")?;
+
+    let after_first_poll: FirstPollHook = {
+        let mut mqtt = mqtt.clone();
+        let topic_base = topic_base.to_string();
+        let connected = connected.clone();
+
+        Box::new(move || {
+            log::info!("restarted amp worker is polling");
+
+            if let Err(err) = connected.publish(&mut mqtt, &topic_base, 2) {
+                log::error!("failed to publish connected status: {:#}", err);
             }
-    
-            for zone_status in zones_status.iter() {
-                let previous_status = previous_statuses.get(&zone_status.zone_id);
-
-                for attr in &zone_status.attributes {
-                    // don't publish if zone attribute hasn't changed
-                    if previous_status.map_or(false, |prev_status| prev_status.attributes.iter().any(|prev_attr| *prev_attr == *attr)) {
-                        continue;
-                    }
+        })
+    };
+
+    Ok(spawn_amp_worker(config, amp, mqtt.clone(), topic_base, recv, zones_status, WorkerHooks {
+        initial_sync: false,
+        apply_startup: false,
+        after_first_poll: Some(after_first_poll),
+        on_panic: move || signals_handle.close()
+    }))
+}
 
-                    let topic = ZoneAttributeDiscriminants::from(attr).mqtt_topic_name(ZoneTopic::Status, &topic_base, &zone_status.zone_id);
+fn run(config: Config) -> Result<(), Box<dyn std::error::Error>> {
+    let (mut mqtt_client, mut mqtt_cms, topic_base) = connect_mqtt(&config).context("failed to establish MQTT connection")?;
 
-                    let value = {
-                        use ZoneAttribute::*;
+    let amp = open_amp(&config, &mqtt_client, &topic_base).context("failed to establish amp connection")?;
 
-                        match attr {
-                            PublicAnnouncement(b) | Power(b) | Mute(b) | DoNotDisturb(b) | KeypadConnected(b) => json!(b),
-                            Volume(v) | Treble(v) | Bass(v) | Balance(v) | Source(v) => json!(v)
-                        }
-                    };
+    let (amp_ctrl_ch_send, amp_ctl_ch_recv) = control_channel(config.amp.channel_capacity, config.amp.channel_overflow);
+    let zones_status = SharedZonesStatus::default();
+    let connected = ConnectedStatus::default();
 
-                    log::debug!("set {} = {}", topic, value);
-        
-                    mqtt.publish_json(topic, rumqttc::QoS::AtLeastOnce, true, value).unwrap(); // TODO: handle error more gracefully
-                }
+    // shared by the MQTT set handlers and the HTTP API, so sets via either are limited and logged alike
+    let set_context = SetContext {
+        limiter: SetRateLimiter::new(config.amp.max_sets_per_second),
+        command_log: match config.publish.command_log {
+            true => CommandLog::new(mqtt_client.clone(), &topic_base, config.publish.zone_topic_format),
+            false => CommandLog::default()
+        },
+        set_errors: match config.publish.set_errors {
+            true => SetErrors::new(mqtt_client.clone(), &topic_base, config.publish.zone_topic_format),
+            false => SetErrors::default()
+        },
+        ranges: config.amp.ranges.clone(),
+        bool_payload: config.publish.bool_payload
+    };
 
-                previous_statuses.insert(zone_status.zone_id, zone_status.clone());
+    // the amp is connected before any set subscriptions are installed, so retained sets queue up for the worker
+    // (which buffers them until the amp first responds to a poll)
+    if config.amp.readonly {
+        log::info!("readonly: zone attributes will not be set on the amp");
+
+        for mqtt_cm in &mut mqtt_cms {
+            install_readonly_set_handlers(&config.amp.zones, mqtt_cm, &topic_base, config.publish.zone_topic_format, config.publish.balance_trims, config.publish.volume_percent)?;
+        }
+
+    } else {
+        // sets are accepted from every broker
+        for mqtt_cm in &mut mqtt_cms {
+            install_zone_attribute_subscription_handers(&config.amp.zones, mqtt_cm, &topic_base, config.publish.zone_topic_format, &set_context, amp_ctrl_ch_send.clone())?;
+            install_zone_source_name_handlers(&config.amp, mqtt_cm, &topic_base, config.publish.zone_topic_format, &set_context, amp_ctrl_ch_send.clone())?;
+
+            if config.publish.balance_trims {
+                install_zone_balance_trim_handlers(&config.amp.zones, mqtt_cm, &topic_base, config.publish.zone_topic_format, &set_context, amp_ctrl_ch_send.clone())?;
+            }
+
+            if config.publish.volume_percent {
+                install_zone_volume_percent_handlers(&config.amp.zones, mqtt_cm, &topic_base, config.publish.zone_topic_format, &set_context, amp_ctrl_ch_send.clone())?;
             }
+
+            install_command_handlers(mqtt_cm, &topic_base, amp_ctrl_ch_send.clone())?;
         }
-    })
+
+        // shairport-sync publishes its metadata to the local (primary) broker
+        install_source_shairport_handlers(&config.shairport, &config.amp.zones, &config.amp.sources(), &config.amp.ranges, &mut mqtt_cms[0], zones_status.clone(), amp_ctrl_ch_send.clone())?;
+    }
+
+    for mqtt_cm in &mut mqtt_cms {
+        install_zone_enabled_handlers(&config.amp.zones, mqtt_cm, &topic_base, config.publish.zone_topic_format, amp_ctrl_ch_send.clone())?;
+    }
+
+    // shared with the reconnect hooks, which re-subscribe since the brokers forget the subscriptions of a clean session
+    let mqtt_cms: Vec<_> = mqtt_cms.into_iter().map(|mqtt_cm| Arc::new(Mutex::new(mqtt_cm))).collect();
+
+    for mqtt_cm in &mqtt_cms {
+        MqttConnectionManager::resubscribe_on_reconnect(mqtt_cm);
+    }
+
+    // serves until the daemon exits
+    if let Some(http_config) = &config.http {
+        let sets = (!config.amp.readonly).then(|| (set_context.clone(), amp_ctrl_ch_send.clone()));
+        let api = HttpApi::new(zones_status.clone(), config.amp.zones.keys().copied().collect(), sets);
+
+        spawn_http_server(http_config.listen, api)?;
+    }
+
+    let mut signals = Signals::new(TERM_SIGNALS)?;
+
+    // stop waiting for signals if the worker panics, so the daemon exits rather than running without a worker
+    let amp_worker = {
+        let signals_handle = signals.handle();
+
+        let after_first_poll = first_poll_metadata(mqtt_client.clone(), &config, &topic_base, &connected);
+
+        spawn_amp_worker(&config, amp, mqtt_client.clone(), &topic_base, amp_ctl_ch_recv.clone(), zones_status.clone(), WorkerHooks {
+            initial_sync: true,
+            apply_startup: true,
+            after_first_poll,
+            on_panic: move || signals_handle.close()
+        })
+    };
+
+    // the initial sync has populated the zone status topics, so clients never see `connected` without them
+    if config.publish.metadata == MetadataTiming::Startup {
+        publish_online(&mut mqtt_client, &config, &topic_base, &connected)?;
+    }
+
+    let watchdog = Watchdog::spawn(amp_worker, config.amp.watchdog_timeout, {
+        let config = config.clone();
+        let mut mqtt_client = mqtt_client.clone();
+        let topic_base = topic_base.clone();
+        let zones_status = zones_status.clone();
+        let connected = connected.clone();
+        let signals_handle = signals.handle();
+
+        move |_stalled| restart_amp_worker(&config, &mut mqtt_client, &topic_base, &connected, amp_ctl_ch_recv.clone(), zones_status.clone(), signals_handle.clone())
+    });
+
+    // only to the broker that reconnected
+    for mqtt_cm in &mqtt_cms {
+        let mqtt_cm = mqtt_cm.lock().expect("lock mqtt");
+        republish_metadata_on_reconnect(mqtt_cm.reconnect_hooks(), mqtt_cm.backlog_client(), &config, &topic_base, &connected);
+    }
+
+    log::info!("running");
+
+    match signals.forever().next() { // wait for a signal
+        Some(_) => log::info!("caught shutdown signal"),
+        None => log::error!("amp worker stopped unexpectedly, shutting down")
+    }
+
+    let shutdown_result = shutdown(watchdog, &amp_ctrl_ch_send, &mut mqtt_client, &config, &topic_base, &connected);
+    mqtt_client.disconnect()?;
+
+    // the process exiting would drop any publishes the event loop has yet to send
+    for (mqtt_config, mqtt_cm) in std::iter::once(&config.mqtt).chain(&config.mqtt_mirrors).zip(&mqtt_cms) {
+        if !mqtt_cm.lock().expect("lock mqtt").wait_disconnected(SHUTDOWN_TIMEOUT) {
+            log::warn!("timed out waiting to disconnect from MQTT broker {}, shutdown status may not have been published", mqtt_config.url.host_str().unwrap_or_default());
+        }
+    }
+
+    shutdown_result?;
+
+
+    // exit due to: signal, mqtt error/disconnect, 
+
+    Ok(())
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -360,36 +1151,729 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     SimpleLogger::init(LevelFilter::Info, simplelog::Config::default()).unwrap();
 
-    let config = config::load_config(&args.config_file).context("failed to load config")?;
+    let Args { config_file, readonly, command } = args;
 
-    let (mut mqtt_client, mut mqtt_cm, topic_base) = connect_mqtt(&config.mqtt).context("failed to establish MQTT connection")?;
+    let load_config = || config::load_config(&config_file).context("failed to load config");
 
-    let amp = connect_amp(&config).context("failed to establish amp connection")?;
+    match command.unwrap_or(Command::Run) {
+        Command::Run => {
+            let mut config = load_config()?;
+            config.amp.readonly |= readonly;
 
-    let (amp_ctrl_ch_send, amp_ctl_ch_recv) = mpsc::channel::<AmpControlChannelMessage>();
-    let zones_status = Arc::new(Mutex::new(Vec::new()));
+            run(config)
+        },
+        Command::Check => Ok(check(&load_config()?)?),
+        Command::ExampleConfig => {
+            print!("{}", EXAMPLE_CONFIG);
+            Ok(())
+        },
+        Command::DumpConfig { json } => {
+            let mut config = load_config()?;
+            config.amp.readonly |= readonly;
 
-    install_zone_attribute_subscription_handers(&config.amp.zones, &mut mqtt_cm, &topic_base, amp_ctrl_ch_send.clone())?;
-    install_source_shairport_handlers(&config.shairport, &config.amp.zones, &config.amp.sources(), &mut mqtt_cm, zones_status.clone(), amp_ctrl_ch_send.clone())?;
+            println!("{}", config.dump(json)?);
+            Ok(())
+        },
+        Command::Probe { device, amps } => {
+            let (port_config, config_amps) = match device {
+                Some(device) => (PortConfig::Serial(SerialPortConfig::with_device(&device)), 1),
+                None => {
+                    let config = load_config()?;
+                    (config.port, config.amp.amp_count())
+                }
+            };
 
-    let amp_worker_thread = spawn_amp_worker(&config.amp, amp, mqtt_client.clone(), &topic_base, amp_ctl_ch_recv, zones_status.clone());
+            let mut amp = connect_amp(&port_config, amps.unwrap_or(config_amps)).context("failed to establish amp connection")?;
 
-    publish_metadata(&mut mqtt_client, &config, &topic_base)?;
+            Ok(probe(&mut amp, &mut std::io::stdout())?)
+        }
+    }
+}
 
-    log::info!("running");
 
-    let mut signals = Signals::new(TERM_SIGNALS)?;
-    signals.forever().next(); // wait for a signal
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    log::info!("caught shutdown signal");
+    #[test]
+    fn test_args() {
+        let parse = |args: &[&str]| Args::try_parse_from([&["mwha2mqttd"], args].concat()).unwrap();
 
-    mqtt_client.disconnect()?;
+        // no command runs the daemon, for backwards compatibility
+        let args = parse(&[]);
+        assert_eq!(args.command, None);
+        assert_eq!(args.config_file, PathBuf::from(DEFAULT_CONFIG_FILE_PATH));
 
-    amp_ctrl_ch_send.send(AmpControlChannelMessage::Poison)?;
-    amp_worker_thread.join().unwrap();
+        let args = parse(&["--config-file", "test.toml"]);
+        assert_eq!(args.command, None);
+        assert_eq!(args.config_file, PathBuf::from("test.toml"));
 
+        assert_eq!(parse(&["run"]).command, Some(Command::Run));
+        assert_eq!(parse(&["check"]).command, Some(Command::Check));
+        assert_eq!(parse(&["example-config"]).command, Some(Command::ExampleConfig));
+        assert_eq!(parse(&["dump-config"]).command, Some(Command::DumpConfig { json: false }));
+        assert_eq!(parse(&["dump-config", "--json"]).command, Some(Command::DumpConfig { json: true }));
+        assert_eq!(parse(&["probe"]).command, Some(Command::Probe { device: None, amps: None }));
+        assert_eq!(parse(&["probe", "--device", "/dev/ttyUSB0", "--amps", "2"]).command, Some(Command::Probe { device: Some("/dev/ttyUSB0".to_string()), amps: Some(2) }));
+        assert!(Args::try_parse_from(["mwha2mqttd", "probe", "--amps", "4"]).is_err());
 
-    // exit due to: signal, mqtt error/disconnect, 
+        // the config file can be given before or after the command
+        assert_eq!(parse(&["--config-file", "test.toml", "check"]).config_file, PathBuf::from("test.toml"));
+        assert_eq!(parse(&["check", "--config-file", "test.toml"]).config_file, PathBuf::from("test.toml"));
 
-    Ok(())
-}
\ No newline at end of file
+        assert!(!parse(&[]).readonly);
+        assert!(parse(&["--readonly"]).readonly);
+        assert!(parse(&["run", "--readonly"]).readonly);
+
+        assert!(Args::try_parse_from(["mwha2mqttd", "unknown"]).is_err());
+    }
+
+    #[test]
+    fn test_probe() {
+        let mut emu = mwhaemu::emu::Amp::new(2);
+        emu.zone_set(ZoneId::Zone { amp: 2, zone: 3 }, ZoneAttribute::Volume(25));
+
+        let (mut amp, _emu) = crate::amp::tests::emulated_amp(emu, 2);
+
+        let mut out = Vec::new();
+        probe(&mut amp, &mut out).unwrap();
+
+        let out = String::from_utf8(out).unwrap();
+        let lines = out.lines().collect::<Vec<_>>();
+
+        assert_eq!(lines.len(), 1 + 12); // header + zones
+        assert!(lines[0].starts_with("Zone  PublicAnnouncement  Power"));
+        assert!(lines[1].starts_with("11 "));
+
+        let zone_23 = lines.iter().find(|l| l.starts_with("23 ")).unwrap().split_whitespace().collect::<Vec<_>>();
+        assert_eq!(zone_23[5], "25"); // volume
+    }
+
+    #[test]
+    fn test_last_will() {
+        let mut config = crate::config::tests::config_from_str(crate::config::tests::TEST_CONFIG);
+
+        let will = last_will("mwha/", &config.publish);
+        assert_eq!(will.topic, "mwha/connected");
+        assert_eq!(&will.message[..], b"0");
+        assert!(will.retain);
+
+        config.publish.structured_will = true;
+
+        let will = last_will("mwha/", &config.publish);
+        assert_eq!(will.topic, "mwha/connected");
+        assert_eq!(serde_json::from_slice::<serde_json::Value>(&will.message).unwrap(), json!({"connected": false, "reason": "unexpected"}));
+        assert!(will.retain);
+
+        // with the birth message, the will is the death message (matching the one published on clean shutdown)
+        config.publish.birth = true;
+
+        let will = last_will("mwha/", &config.publish);
+        let (topic, payload) = birth_message("mwha/", false);
+        assert_eq!(will.topic, topic);
+        assert_eq!(serde_json::from_slice::<serde_json::Value>(&will.message).unwrap(), payload);
+        assert!(will.retain);
+    }
+
+    #[test]
+    fn test_metadata_republished_on_reconnect() {
+        let config = crate::config::tests::config_from_str(crate::config::tests::TEST_CONFIG);
+
+        let hooks = ReconnectHooks::default();
+        let published = crate::worker::tests::Published::default();
+        let connected = ConnectedStatus::default();
+
+        republish_metadata_on_reconnect(&hooks, published.clone(), &config, "mwha/", &connected);
+
+        // initial connection
+        assert!(hooks.connected().is_none());
+        assert!(published.take().is_empty());
+
+        // nothing is republished until metadata has first been published (i.e. waiting for the first poll)
+        hooks.connected().unwrap().join().unwrap();
+        assert!(published.take().is_empty());
+
+        publish_online(&mut published.clone(), &config, "mwha/", &connected).unwrap();
+        published.take();
+
+        // simulated reconnect
+        hooks.connected().unwrap().join().unwrap();
+
+        let republished = published.take();
+        let topics = republished.iter().map(|(topic, _, _)| topic.as_str()).collect::<Vec<_>>();
+
+        assert!(republished.iter().all(|(_, retain, _)| *retain));
+        assert_eq!(republished.first(), Some(&("mwha/connected".to_string(), true, "2".to_string())));
+        assert!(topics.contains(&"mwha/status/zones"));
+        assert!(topics.contains(&"mwha/status/zone/11/name"));
+        assert!(topics.contains(&"mwha/status/source/1/name"));
+
+        // the current status is restored, rather than assuming the daemon is online (i.e. degraded by the watchdog)
+        connected.publish(&mut published.clone(), "mwha/", 1).unwrap();
+        published.take();
+
+        hooks.connected().unwrap().join().unwrap();
+
+        let republished = published.take();
+        assert_eq!(republished.iter().filter(|(topic, _, _)| topic == "mwha/connected").collect::<Vec<_>>(), vec![&("mwha/connected".to_string(), true, "1".to_string())]);
+    }
+
+    #[test]
+    fn test_publish_shutdown() {
+        let mut config = crate::config::tests::config_from_str(crate::config::tests::TEST_CONFIG);
+
+        for (birth, clear_on_shutdown) in [(false, false), (true, false), (true, true)] {
+            config.publish.birth = birth;
+            config.publish.clear_on_shutdown = clear_on_shutdown;
+
+            let published = crate::worker::tests::Published::default();
+            publish_shutdown(&mut published.clone(), &config, "mwha/", &ConnectedStatus::default()).unwrap();
+
+            let published = published.take();
+
+            // `connected` = 0 is published last, so it's sent before the disconnect and after everything else
+            assert_eq!(published.last(), Some(&("mwha/connected".to_string(), true, "0".to_string())));
+            assert_eq!(published.iter().filter(|(topic, _, _)| topic == "mwha/connected").count(), 1);
+
+            assert_eq!(published.contains(&("mwha/status".to_string(), true, "\"offline\"".to_string())), birth);
+            assert_eq!(published.iter().any(|(topic, _, _)| topic == "mwha/status/zone/11/volume"), clear_on_shutdown);
+        }
+    }
+
+    /// a running worker for zone 11 that publishes a volume change every poll, until the returned flag is set
+    fn running_worker(config: &Config) -> (Watchdog, ControlSender, crate::worker::tests::Published, Arc<std::sync::atomic::AtomicBool>) {
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let study = ZoneId::Zone { amp: 1, zone: 1 };
+
+        let amp = crate::worker::tests::MockAmp::with_zones(&[study]);
+        let published = crate::worker::tests::Published::default();
+        let (send, recv) = crate::channel::control_channel(0, crate::config::ChannelOverflow::DropSuperseded);
+
+        let worker = crate::worker::tests::spawn_test_worker(config, amp.clone(), &published, recv);
+        let watchdog = Watchdog::spawn(worker, Duration::ZERO, |_| unreachable!());
+
+        let stop = Arc::new(AtomicBool::new(false));
+
+        std::thread::spawn({
+            let stop = stop.clone();
+
+            move || {
+                for volume in (0..=38).cycle() {
+                    if stop.load(Ordering::SeqCst) { break }
+
+                    amp.set(study, ZoneAttribute::Volume(volume));
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+            }
+        });
+
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(published.take().iter().any(|(topic, _, _)| topic == "mwha/status/zone/11/volume"));
+
+        (watchdog, send, published, stop)
+    }
+
+    #[test]
+    fn test_shutdown_stops_worker() {
+        let mut config = crate::config::tests::config_from_str(crate::config::tests::TEST_CONFIG);
+        config.amp.poll_interval = Duration::from_millis(1);
+        config.publish.clear_on_shutdown = true;
+
+        let (watchdog, send, published, stop) = running_worker(&config);
+
+        shutdown(watchdog, &send, &mut published.clone(), &config, "mwha/", &ConnectedStatus::default()).unwrap();
+
+        // the cleared status isn't overwritten, as the worker has stopped
+        std::thread::sleep(Duration::from_millis(20));
+
+        let published = published.take();
+        let volume = published.iter().rposition(|(topic, _, _)| topic == "mwha/status/zone/11/volume").unwrap();
+        assert_eq!(published[volume].2, "");
+        assert_eq!(published.last(), Some(&("mwha/connected".to_string(), true, "0".to_string())));
+
+        stop.store(true, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    #[test]
+    fn test_shutdown_connected_last() {
+        let mut config = crate::config::tests::config_from_str(crate::config::tests::TEST_CONFIG);
+        config.amp.poll_interval = Duration::from_millis(1);
+
+        let (watchdog, send, published, stop) = running_worker(&config);
+
+        let connected = ConnectedStatus::default();
+        publish_online(&mut published.clone(), &config, "mwha/", &connected).unwrap();
+
+        shutdown(watchdog, &send, &mut published.clone(), &config, "mwha/", &connected).unwrap();
+
+        // `connected` = 0 is the last publish before the disconnect, with no zone status from the worker after it
+        std::thread::sleep(Duration::from_millis(20));
+
+        let published = published.take();
+        assert_eq!(published.last(), Some(&("mwha/connected".to_string(), true, "0".to_string())));
+
+        assert_eq!(published.iter().filter(|(topic, _, payload)| topic == "mwha/connected" && payload == "0").count(), 1);
+
+        stop.store(true, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    #[test]
+    fn test_clear_status() {
+        use std::collections::HashSet;
+
+        let mut config = crate::config::tests::config_from_str(crate::config::tests::TEST_CONFIG);
+        config.publish.birth = true;
+        config.publish.volume_percent = true;
+
+        // everything published at startup...
+        let published = crate::worker::tests::Published::default();
+        publish_metadata(&mut published.clone(), &config, "mwha/").unwrap();
+
+        let startup = published.take().into_iter()
+            .map(|(topic, _, _)| topic)
+            .filter(|topic| topic != "mwha/connected" && topic != "mwha/status")
+            .collect::<HashSet<_>>();
+
+        // ...is cleared on shutdown, along with the zone status
+        clear_status(&mut published.clone(), &config, "mwha/").unwrap();
+
+        let cleared = published.take();
+        assert!(cleared.iter().all(|(_, retain, payload)| *retain && payload.is_empty()));
+
+        let cleared = cleared.into_iter().map(|(topic, _, _)| topic).collect::<HashSet<_>>();
+        assert!(startup.is_subset(&cleared), "not cleared: {:?}", startup.difference(&cleared));
+
+        for topic in ["mwha/status/zone/11/volume", "mwha/status/zone/12/power", "mwha/status/zone/11/available", "mwha/status/zone/12/volume-percent"] {
+            assert!(cleared.contains(topic), "{topic} not cleared");
+        }
+
+        // `connected` and the death message are published instead
+        assert!(!cleared.contains("mwha/connected"));
+        assert!(!cleared.contains("mwha/status"));
+        assert!(!cleared.contains("mwha/status/zone/11/balance-left"));
+    }
+
+    #[test]
+    fn test_metadata_source_count() {
+        use std::collections::HashSet;
+
+        let mut config = crate::config::tests::config_from_str(crate::config::tests::TEST_CONFIG);
+        config.amp.source_count = 2;
+
+        let mut published = crate::worker::tests::Published::default();
+        publish_metadata(&mut published, &config, "mwha/").unwrap();
+
+        let published = published.take();
+        let sources = published.iter()
+            .filter(|(_, _, payload)| !payload.is_empty())
+            .filter_map(|(topic, _, _)| topic.strip_prefix("mwha/status/source/")?.strip_suffix("/name"))
+            .collect::<HashSet<_>>();
+
+        // only sources up to the count are listed
+        assert_eq!(sources, HashSet::from(["1", "2"]));
+
+        assert_eq!(config.amp.resolve_source("Source 2").map(|id| u8::from(&id)), Some(2));
+        assert_eq!(config.amp.resolve_source("Source 3"), None);
+
+        // the count must be in range, and cover every configured source
+        assert!(config.validate().is_ok());
+        config.amp.source_count = 7;
+        assert!(config.validate().is_err());
+        config.amp.source_count = 0;
+        assert!(config.validate().is_err());
+
+        let config = crate::config::tests::config_from_str(&crate::config::tests::TEST_CONFIG
+            .replace(r#"poll_interval = "100 ms""#, "poll_interval = \"100 ms\"\nsource_count = 2")
+            .replace("[amp.sources]", "[amp.sources]\n5 = \"Vinyl\""));
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_metadata_matches_source_ids() {
+        use std::collections::HashSet;
+
+        let mut config = crate::config::tests::config_from_str(crate::config::tests::TEST_CONFIG);
+
+        for count in common::ids::SOURCES {
+            config.amp.source_count = count;
+
+            let mut published = crate::worker::tests::Published::default();
+            publish_metadata(&mut published, &config, "mwha/").unwrap();
+
+            let published = published.take().into_iter()
+                .filter(|(_, _, payload)| !payload.is_empty())
+                .filter_map(|(topic, _, _)| Some(topic.strip_prefix("mwha/status/source/")?.strip_suffix("/name")?.parse::<SourceId>().unwrap()))
+                .collect::<HashSet<_>>();
+
+            let source_ids = config.amp.source_ids().collect::<HashSet<_>>();
+
+            assert_eq!(source_ids.len(), count as usize);
+            assert_eq!(config.amp.sources().keys().copied().collect::<HashSet<_>>(), source_ids);
+            assert_eq!(published, source_ids);
+        }
+    }
+
+    #[test]
+    fn test_sanitized_names() {
+        let long_name = "x".repeat(100);
+
+        let mut config = crate::config::tests::config_from_str(&crate::config::tests::TEST_CONFIG
+            .replace(r#"11 = "Study""#, r#"11 = "Study\nRoom\u0007""#)
+            .replace(r#"1 = "Public Announcement""#, &format!(r#"1 = "{}"
+            2 = "Turntable\t""#, long_name))
+            .replace("[amp.sources]", r#"name = "Whole\r\nHouse"
+            [amp.sources]"#));
+
+        config.sanitize_names();
+
+        let mut published = crate::worker::tests::Published::default();
+        publish_metadata(&mut published, &config, "mwha/").unwrap();
+
+        let published = published.take().into_iter()
+            .map(|(topic, _, payload)| (topic, payload))
+            .collect::<HashMap<_, _>>();
+
+        let expected_source = format!("\"{}\"", "x".repeat(common::payload::MAX_NAME_LEN));
+
+        assert_eq!(published["mwha/status/zone/11/name"], r#""Study Room""#);
+        assert_eq!(published["mwha/status/zone/12/name"], r#""Living Room""#);
+        assert_eq!(published["mwha/status/amp/name"], r#""Whole House""#);
+        assert_eq!(published["mwha/status/source/1/name"], expected_source);
+        assert_eq!(published["mwha/status/source/2/name"], r#""Turntable""#);
+
+        // including the source map and config summary
+        let sources = serde_json::from_str::<Value>(&published["mwha/status/sources"]).unwrap();
+        assert_eq!(sources["1"]["name"].to_string(), expected_source);
+
+        let summary = serde_json::from_str::<Value>(&published["mwha/status/config"]).unwrap();
+        assert_eq!(summary["amp"]["zones"]["11"]["name"], "Study Room");
+
+        // and names are resolved by their sanitized value
+        assert_eq!(config.amp.resolve_source("turntable"), Some("2".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_amp_names() {
+        use std::collections::HashMap;
+
+        let amp_names = |config: &str| {
+            let config = crate::config::tests::config_from_str(config);
+
+            let mut published = crate::worker::tests::Published::default();
+            publish_metadata(&mut published, &config, "mwha/").unwrap();
+
+            published.take().into_iter()
+                .filter(|(topic, _, _)| topic.starts_with("mwha/status/amp/") && topic.ends_with("name"))
+                .map(|(topic, retain, payload)| {
+                    assert!(retain);
+                    (topic, payload)
+                })
+                .collect::<HashMap<_, _>>()
+        };
+
+        // none configured, all cleared
+        let names = amp_names(crate::config::tests::TEST_CONFIG);
+        assert_eq!(names.len(), 4);
+        assert!(names.values().all(String::is_empty));
+
+        let names = amp_names(&crate::config::tests::TEST_CONFIG.replace("[amp.sources]", r#"name = "Whole House"
+
+            [amp.amp_names]
+            1 = "Downstairs"
+            2 = "Upstairs"
+
+            [amp.sources]"#));
+
+        assert_eq!(names["mwha/status/amp/name"], r#""Whole House""#);
+        assert_eq!(names["mwha/status/amp/1/name"], r#""Downstairs""#);
+        assert_eq!(names["mwha/status/amp/2/name"], r#""Upstairs""#);
+        assert_eq!(names["mwha/status/amp/3/name"], "");
+
+        // falls back to the system and amp zone names
+        let names = amp_names(&crate::config::tests::TEST_CONFIG.replace(r#"11 = "Study""#, r#"11 = "Study"
+            00 = "House"
+            30 = "Garden""#));
+
+        assert_eq!(names["mwha/status/amp/name"], r#""House""#);
+        assert_eq!(names["mwha/status/amp/1/name"], "");
+        assert_eq!(names["mwha/status/amp/3/name"], r#""Garden""#);
+    }
+
+    #[test]
+    fn test_sources_map() {
+        let mut config = crate::config::tests::config_from_str(&crate::config::tests::TEST_CONFIG
+            .replace("[amp.sources]", "[amp.sources]\n3 = { name = \"Unused\", enabled = false }"));
+
+        let sources_map = |config: &Config| {
+            let mut published = crate::worker::tests::Published::default();
+            publish_metadata(&mut published, config, "mwha/").unwrap();
+
+            let (_, retain, payload) = published.take().into_iter().find(|(topic, _, _)| topic == "mwha/status/sources").unwrap();
+            assert!(retain);
+
+            serde_json::from_str::<Value>(&payload).unwrap()
+        };
+
+        let expected = config.amp.sources().into_iter()
+            .map(|(id, source)| (id.to_string(), json!({ "name": source.name, "enabled": source.enabled })))
+            .collect::<serde_json::Map<_, _>>();
+
+        assert_eq!(sources_map(&config), Value::Object(expected));
+        assert_eq!(sources_map(&config)["3"], json!({ "name": "Unused", "enabled": false }));
+
+        // only published sources are included
+        config.publish.sources = SourceMetadata::Enabled;
+        assert_eq!(sources_map(&config), json!({ "1": { "name": "Public Announcement", "enabled": true } }));
+    }
+
+    #[test]
+    fn test_source_metadata() {
+        use std::collections::HashMap;
+
+        let mut config = crate::config::tests::config_from_str(&crate::config::tests::TEST_CONFIG
+            .replace("[amp.sources]", "[amp.sources]\n3 = { name = \"Unused\", enabled = false }"));
+
+        let source_names = |config: &Config| {
+            let mut published = crate::worker::tests::Published::default();
+            publish_metadata(&mut published, config, "mwha/").unwrap();
+
+            published.take().into_iter()
+                .filter_map(|(topic, retain, payload)| {
+                    assert!(retain);
+                    Some((topic.strip_prefix("mwha/status/source/")?.strip_suffix("/name")?.to_string(), payload))
+                })
+                .collect::<HashMap<_, _>>()
+        };
+
+        // all sources, including defaults
+        let names = source_names(&config);
+        assert_eq!(names.len(), 6);
+        assert_eq!(names["4"], "\"Source 4\"");
+
+        // only configured sources, the rest are cleared
+        config.publish.sources = SourceMetadata::Configured;
+        let names = source_names(&config);
+        assert_eq!(names["1"], "\"Public Announcement\"");
+        assert_eq!(names["3"], "\"Unused\"");
+        assert!(["2", "4", "5", "6"].iter().all(|id| names[*id].is_empty()));
+
+        // only enabled configured sources
+        config.publish.sources = SourceMetadata::Enabled;
+        let names = source_names(&config);
+        assert_eq!(names["1"], "\"Public Announcement\"");
+        assert!(["2", "3", "4", "5", "6"].iter().all(|id| names[*id].is_empty()));
+    }
+
+    #[test]
+    fn test_online_grace() {
+        let mut config = crate::config::tests::config_from_str(crate::config::tests::TEST_CONFIG);
+        config.mqtt.url = url::Url::parse("mqtt://localhost/mwha/").unwrap();
+
+        let (options, _) = mqtt_options(&config.mqtt, &config.publish).unwrap();
+        assert_eq!(options.keep_alive(), MqttOptions::new("test", "localhost", 1883).keep_alive());
+
+        config.publish.online_grace = std::time::Duration::from_secs(120);
+
+        let (options, _) = mqtt_options(&config.mqtt, &config.publish).unwrap();
+        assert_eq!(options.keep_alive(), std::time::Duration::from_secs(120));
+
+        // a brief disconnect: the broker may have published the will, but `connected` is restored first thing on reconnect
+        let hooks = ReconnectHooks::default();
+        let published = crate::worker::tests::Published::default();
+        let connected = ConnectedStatus::default();
+
+        republish_metadata_on_reconnect(&hooks, published.clone(), &config, "mwha/", &connected);
+        connected.publish(&mut published.clone(), "mwha/", 2).unwrap();
+        published.take();
+
+        assert!(hooks.connected().is_none());
+        hooks.connected().unwrap().join().unwrap();
+
+        let published = published.take();
+        assert_eq!(published.first(), Some(&("mwha/connected".to_string(), true, "2".to_string())));
+    }
+
+    #[test]
+    fn test_birth_message() {
+        assert_eq!(birth_message("mwha/", true), ("mwha/status".to_string(), json!("online")));
+        assert_eq!(birth_message("mwha/", false), ("mwha/status".to_string(), json!("offline")));
+
+        let mut config = crate::config::tests::config_from_str(crate::config::tests::TEST_CONFIG);
+
+        let status_topics = |config: &Config| {
+            let mut published = crate::worker::tests::Published::default();
+            publish_metadata(&mut published, config, "mwha/").unwrap();
+
+            published.take().into_iter()
+                .filter(|(topic, _, _)| topic == "mwha/status" || topic == "mwha/status/version")
+                .collect::<Vec<_>>()
+        };
+
+        // disabled by default
+        assert!(status_topics(&config).is_empty());
+
+        config.publish.birth = true;
+        assert_eq!(status_topics(&config), vec![
+            ("mwha/status".to_string(), true, r#""online""#.to_string()),
+            ("mwha/status/version".to_string(), true, format!(r#""{}""#, env!("CARGO_PKG_VERSION"))),
+        ]);
+    }
+
+    #[test]
+    fn test_debug_responses() {
+        let (mut amp, _emu) = crate::amp::tests::emulated_amp(mwhaemu::emu::Amp::new(1), 1);
+
+        let published = crate::worker::tests::Published::default();
+        amp.observe_responses(debug_response_observer(published.clone(), "mwha/"));
+
+        amp.zone_enquiry(ZoneId::Zone { amp: 1, zone: 1 }).unwrap();
+        amp.set_zone_attribute(ZoneId::Zone { amp: 1, zone: 1 }, ZoneAttribute::Volume(25)).unwrap();
+
+        // the echo and response of each command, escaped
+        assert_eq!(published.take(), vec![
+            ("mwha/debug/responses".to_string(), false, r#""?11\\r\\n#""#.to_string()),
+            ("mwha/debug/responses".to_string(), false, r#"">1100000000000707100100\\r\\n#""#.to_string()),
+            ("mwha/debug/responses".to_string(), false, r#""<11VO25\\r\\n#""#.to_string()),
+        ]);
+    }
+
+    #[test]
+    fn test_command_log() {
+        let published = crate::worker::tests::Published::default();
+        let command_log = CommandLog::new(published.clone(), "mwha/", ZoneTopicFormat::Numeric);
+
+        let study = ZoneId::Zone { amp: 1, zone: 1 };
+
+        command_log.record("mwha/set/zone/11/volume", &AmpControlChannelMessage::ChangeZoneAttribute(study, ZoneAttribute::Volume(20)));
+        command_log.record("mwha/force-set/zone/11/do-not-disturb", &AmpControlChannelMessage::ForceZoneAttribute(study, ZoneAttribute::DoNotDisturb(true)));
+
+        // only zone sets are recorded
+        command_log.record("mwha/cmd/redetect-baud", &AmpControlChannelMessage::RedetectBaud);
+
+        let entries = published.take().into_iter()
+            .map(|(topic, retain, payload)| {
+                assert_eq!(topic, "mwha/log/commands");
+                assert!(!retain);
+
+                let mut entry = serde_json::from_str::<Value>(&payload).unwrap();
+                let timestamp = entry.as_object_mut().unwrap().remove("timestamp").unwrap();
+                assert!(humantime::parse_rfc3339(timestamp.as_str().unwrap()).is_ok());
+
+                entry
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(entries, vec![
+            json!({"topic": "mwha/set/zone/11/volume", "zone": "11", "attribute": "volume", "value": 20, "force": false}),
+            json!({"topic": "mwha/force-set/zone/11/do-not-disturb", "zone": "11", "attribute": "do-not-disturb", "value": true, "force": true}),
+        ]);
+
+        // disabled records nothing
+        CommandLog::default().record("mwha/set/zone/11/volume", &AmpControlChannelMessage::ChangeZoneAttribute(study, ZoneAttribute::Volume(20)));
+        assert!(published.take().is_empty());
+    }
+
+    #[test]
+    fn test_bool_payload() {
+        use ZoneAttributeDiscriminants::*;
+
+        let ranges = AttributeRanges::default();
+        let decode = |payload: &[u8], bool_payload| decode_set_payload(Mute, payload, &ranges, bool_payload).map_err(|e| e.reason());
+
+        assert_eq!(decode(b"true", BoolPayload::Json), Ok(ZoneAttribute::Mute(true)));
+        assert_eq!(decode(b"false", BoolPayload::Json), Ok(ZoneAttribute::Mute(false)));
+        assert_eq!(decode(b"ON", BoolPayload::Json), Err("invalid-json"));
+
+        // ON/OFF (any case) as well as true/false
+        assert_eq!(decode(b"ON", BoolPayload::OnOff), Ok(ZoneAttribute::Mute(true)));
+        assert_eq!(decode(b"off", BoolPayload::OnOff), Ok(ZoneAttribute::Mute(false)));
+        assert_eq!(decode(b"true", BoolPayload::OnOff), Ok(ZoneAttribute::Mute(true)));
+        assert_eq!(decode(b"\"ON\"", BoolPayload::OnOff), Err("invalid-json"));
+        assert_eq!(decode(b"yes", BoolPayload::OnOff), Err("invalid-json"));
+
+        // numeric attributes are unaffected
+        assert_eq!(decode_set_payload(Volume, b"20", &ranges, BoolPayload::OnOff).unwrap(), ZoneAttribute::Volume(20));
+        assert!(decode_set_payload(Volume, b"ON", &ranges, BoolPayload::OnOff).is_err());
+    }
+
+    #[test]
+    fn test_set_errors() {
+        use ZoneAttributeDiscriminants::*;
+
+        let ranges = AttributeRanges::default();
+
+        assert_eq!(decode_set_payload(Volume, b"20", &ranges, BoolPayload::Json).unwrap(), ZoneAttribute::Volume(20));
+        assert_eq!(decode_set_payload(Power, b"true", &ranges, BoolPayload::Json).unwrap(), ZoneAttribute::Power(true));
+
+        let published = crate::worker::tests::Published::default();
+        let set_errors = SetErrors::new(published.clone(), "mwha/", ZoneTopicFormat::Numeric);
+
+        let study = ZoneId::Zone { amp: 1, zone: 1 };
+
+        let reject = |attr: ZoneAttributeDiscriminants, payload: &[u8]| {
+            let rejection = decode_set_payload(attr, payload, &ranges, BoolPayload::Json).unwrap_err();
+            set_errors.report(&attr.mqtt_topic_name(ZoneTopic::Set, "mwha/", ZoneTopicFormat::Numeric, &study), study, &attr.mqtt_name(), payload, &rejection);
+
+            let [(topic, retain, payload)] = &published.take()[..] else { panic!("expected one error") };
+            assert!(!retain);
+
+            (topic.clone(), serde_json::from_str::<Value>(payload).unwrap())
+        };
+
+        let (topic, error) = reject(Volume, b"\xff20");
+        assert_eq!(topic, "mwha/error/zone/11/volume");
+        assert_eq!(error["topic"], "mwha/set/zone/11/volume");
+        assert_eq!(error["reason"], "invalid-utf8");
+        assert!(error["error"].as_str().unwrap().contains("UTF-8"));
+
+        let (topic, error) = reject(DoNotDisturb, b"maybe");
+        assert_eq!(topic, "mwha/error/zone/11/do-not-disturb");
+        assert_eq!(error["payload"], "maybe");
+        assert_eq!(error["reason"], "invalid-json");
+
+        // values that don't fit the attribute's type are invalid
+        assert_eq!(reject(Volume, b"300").1["reason"], "invalid-json");
+
+        let (_, error) = reject(Volume, b"39");
+        assert_eq!(error["reason"], "out-of-range");
+        assert!(error["error"].as_str().unwrap().contains("0..=38"), "{error}");
+
+        // against the configured ranges
+        let ranges = AttributeRanges { volume: 0..=60, ..AttributeRanges::default() };
+        assert_eq!(decode_set_payload(Volume, b"39", &ranges, BoolPayload::Json).unwrap(), ZoneAttribute::Volume(39));
+
+        // disabled reports nothing
+        let rejection = decode_set_payload(Volume, b"39", &AttributeRanges::default(), BoolPayload::Json).unwrap_err();
+        SetErrors::default().report("mwha/set/zone/11/volume", study, "volume", b"39", &rejection);
+        assert!(published.take().is_empty());
+    }
+
+    #[test]
+    fn test_decode_set_value() {
+        assert_eq!(decode_set_value(b"100", ranges::VOLUME_PERCENT).unwrap(), 100);
+
+        let reason = |payload: &[u8]| decode_set_value(payload, ranges::BALANCE_TRIM).unwrap_err().reason();
+        assert_eq!(reason(b"11"), "out-of-range");
+        assert_eq!(reason(b"-1"), "invalid-json");
+        assert_eq!(reason(b"\xff"), "invalid-utf8");
+
+        assert_eq!(decode_set_value(b"11", ranges::BALANCE_TRIM).unwrap_err().to_string(), "11 is out of range 0..=10");
+    }
+
+    #[test]
+    fn test_decode_source_name() {
+        let config = crate::config::tests::config_from_str(crate::config::tests::TEST_CONFIG);
+
+        let decode = |payload: &[u8]| decode_source_name(payload, &config.amp).map_err(|e| e.reason());
+
+        assert_eq!(decode(b"\"Public Announcement\""), Ok(ZoneAttribute::Source(1)));
+        assert_eq!(decode(b"public announcement"), Ok(ZoneAttribute::Source(1)));
+        assert_eq!(decode(b"6"), Ok(ZoneAttribute::Source(6)));
+
+        assert_eq!(decode(b"Vinyl"), Err("unknown-source"));
+        assert_eq!(decode(b"\xffVinyl"), Err("invalid-utf8"));
+
+        assert_eq!(decode_source_name(b"\"Vinyl\"", &config.amp).unwrap_err().to_string(), "unknown source \"Vinyl\"");
+    }
+}
@@ -7,22 +7,42 @@ use delegate::delegate;
 
 use anyhow::{Context, Result, bail};
 
-use crate::{amp::Port, config::{SerialPortConfig, BaudConfig, AdjustBaudConfig, BAUD_RATES}};
+use common::payload::preview_payload;
+
+use crate::{amp::{Port, ReleasePortFn}, config::{SerialPortConfig, Baud, BaudConfig, AdjustBaudConfig, BAUD_RATES}};
 
 
 
 pub struct AmpSerialPort {
     port: Box<dyn SerialPort>,
 
+    /// the configured baud adjustment, re-applied after re-detection
+    adjust_baud: AdjustBaudConfig,
+
+    /// check the amp responds at the new baud rate after an adjustment
+    verify_adjust_baud: bool,
+
     previous_baud: Option<u32>
 }
 
 const BAUD_DETECT_TEST_DATA: &[u8] = b"baudrate detect\r";
 
+/// a harmless enquiry (zone 11 power) that any amp responds to, and its expected response prefix
+const BAUD_VERIFY_ENQUIRY: &[u8] = b"?11PR\r";
+const BAUD_VERIFY_RESPONSE: &[u8] = b"#>11PR";
+
 impl AmpSerialPort {
     pub fn new(config: &SerialPortConfig) -> Result<Self> {
+        Self::with_sleep(config, std::thread::sleep)
+    }
+
+    /// Like `new`, but with a custom `sleep` function for the open settle delay (i.e. to record it, for testing).
+    fn with_sleep<S>(config: &SerialPortConfig, sleep: S) -> Result<Self>
+    where
+        S: FnOnce(Duration)
+    {
         let default_baud = match config.baud {
-            BaudConfig::Rate(baud) => baud,
+            BaudConfig::Rate(baud) => baud.rate(),
             BaudConfig::Auto => 9600,
         };
 
@@ -32,43 +52,89 @@ impl AmpSerialPort {
             .open()
             .with_context(|| format!("failed to open serial port: {}", config.device))?;
 
+        // give the adapter time to settle before the first write
+        if !config.open_settle.is_zero() {
+            debug!("waiting {:?} for serial port to settle", config.open_settle);
+            sleep(config.open_settle);
+        }
+
         // detect the baud rate
         let detected_baud = match config.baud {
-            BaudConfig::Rate(baud) => baud,
+            BaudConfig::Rate(baud) => baud.rate(),
             BaudConfig::Auto => AmpSerialPort::detect_baud(&mut port)
                 .context("failed to detect baud")?,
         };
 
         // adjust the baud rate
-        let previous_baud = {
-            let new_baud = match config.adjust_baud {
-                AdjustBaudConfig::Rate(baud) => Some(baud),
-                AdjustBaudConfig::Max => Some(BAUD_RATES[BAUD_RATES.len()-1]),
-                AdjustBaudConfig::Off => None,
-            };
-
-            if let Some(baud) = new_baud {
-                if baud != detected_baud {
-                    AmpSerialPort::adjust_baud(&mut port, baud)?;
-
-                    if config.reset_baud { Some(detected_baud) } else { None }
-
-                } else {
-                    // no point in changing baud to the same value
-                    None
-                }
-
-            } else {
-                None
-            }
+        let previous_baud = match AmpSerialPort::apply_adjust_baud(&mut port, config.adjust_baud, config.verify_adjust_baud, detected_baud)? {
+            Some(_) if config.reset_baud => Some(detected_baud),
+            _ => None
         };
-        
+
         Ok(AmpSerialPort {
             port,
+            adjust_baud: config.adjust_baud,
+            verify_adjust_baud: config.verify_adjust_baud,
             previous_baud
         })
     }
 
+    /// Adjust the baud rate of the amp as configured, from the current (`detected_baud`) rate.
+    ///
+    /// Returns the new baud rate, or `None` if the baud rate was left unchanged.
+    /// If `verify`, fails unless the amp responds at the new baud rate.
+    fn apply_adjust_baud(port: &mut Box<dyn SerialPort>, adjust_baud: AdjustBaudConfig, verify: bool, detected_baud: u32) -> Result<Option<u32>> {
+        let new_baud = match adjust_baud {
+            AdjustBaudConfig::Rate(baud) => baud.rate(),
+            AdjustBaudConfig::Max => Baud::MAX.rate(),
+            AdjustBaudConfig::Off => return Ok(None),
+        };
+
+        // no point in changing baud to the same value
+        if new_baud == detected_baud {
+            return Ok(None);
+        }
+
+        AmpSerialPort::adjust_baud(port, new_baud)?;
+
+        if verify {
+            AmpSerialPort::verify_baud(port)
+                .with_context(|| format!("amp did not respond after adjusting baud rate to {}", new_baud))?;
+        }
+
+        Ok(Some(new_baud))
+    }
+
+    /// Check the amp responds to an enquiry at the current baud rate.
+    ///
+    /// Anything received before the response (i.e. a late "#Done." from the baud change) is skipped, as is the rest
+    /// of the response (the amp connection resyncs before use).
+    fn verify_baud(port: &mut Box<dyn SerialPort>) -> Result<()> {
+        port.clear(serialport::ClearBuffer::All)?;
+        port.write_all(BAUD_VERIFY_ENQUIRY)?;
+
+        let mut buffer = Vec::with_capacity(64);
+
+        // the response status line, i.e. "#>11PR01", ends at the next prompt
+        while !(buffer.windows(BAUD_VERIFY_RESPONSE.len()).any(|w| w == BAUD_VERIFY_RESPONSE) && buffer.ends_with(b"\r\n#")) {
+            if buffer.len() >= 256 {
+                bail!("unexpected response: {}", preview_payload(&buffer, 50));
+            }
+
+            let mut ch = [0; 1];
+            match port.read(&mut ch) {
+                Ok(0) => bail!("port closed"),
+                Ok(_) => buffer.push(ch[0]),
+                Err(err) if err.kind() == io::ErrorKind::TimedOut => bail!("timed out waiting for a response (received: {})", preview_payload(&buffer, 50)),
+                Err(err) => return Err(err.into())
+            }
+        }
+
+        debug!("amp responded after baud adjustment: {}", preview_payload(&buffer, 50));
+
+        Ok(())
+    }
+
     /// Detect the current baud rate of the amp.
     /// 
     /// Sets the baud rate of the serial port to each of the supported values and then
@@ -156,4 +222,221 @@ impl Write for AmpSerialPort {
     }
 }
 
-impl Port for AmpSerialPort {}
\ No newline at end of file
+impl Port for AmpSerialPort {
+    /// Re-detect the baud rate of the amp (i.e. after it was power-cycled and reverted to its default rate)
+    /// and re-apply the configured baud adjustment.
+    fn redetect_baud(&mut self) -> Result<Option<u32>> {
+        let detected_baud = AmpSerialPort::detect_baud(&mut self.port)
+            .context("failed to detect baud")?;
+
+        let baud = AmpSerialPort::apply_adjust_baud(&mut self.port, self.adjust_baud, self.verify_adjust_baud, detected_baud)?
+            .unwrap_or(detected_baud);
+
+        Ok(Some(baud))
+    }
+
+    fn clear_input(&mut self) -> Result<()> {
+        self.port.clear(serialport::ClearBuffer::Input).context("failed to clear serial input buffer")
+    }
+
+    /// Serial ports are opened exclusively (TIOCEXCL), so reopening the device fails with EBUSY while this port is open.
+    /// Closing a clone of the port clears the exclusive lock of the device (TIOCNXCL), leaving this port open.
+    fn release_handle(&self) -> Result<Option<ReleasePortFn>> {
+        let clone = self.port.try_clone().context("failed to clone serial port")?;
+
+        Ok(Some(Box::new(move || drop(clone))))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use common::zone::ZoneId;
+    use serialport::TTYPort;
+
+    use super::*;
+
+    #[test]
+    fn test_open_settle() {
+        let (mut master, slave) = TTYPort::pair().unwrap();
+
+        let mut config = SerialPortConfig::with_device(&slave.name().unwrap());
+        drop(slave); // reopened by AmpSerialPort
+        config.open_settle = Duration::from_millis(250);
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+
+        // echo back everything written (as the amp does), so that baud rate detection succeeds
+        let echo = std::thread::spawn({
+            let events = events.clone();
+
+            move || {
+                master.set_timeout(Duration::from_secs(2)).unwrap();
+
+                let mut buf = [0; BAUD_DETECT_TEST_DATA.len()];
+                master.read_exact(&mut buf).unwrap();
+                events.lock().unwrap().push("write".to_string());
+
+                master.write_all(&buf).unwrap();
+
+                master // closing the master early would fail reads on the slave
+            }
+        });
+
+        let _port = AmpSerialPort::with_sleep(&config, |delay| {
+            events.lock().unwrap().push(format!("sleep {:?}", delay));
+        }).unwrap();
+
+        echo.join().unwrap();
+
+        assert_eq!(*events.lock().unwrap(), vec!["sleep 250ms".to_string(), "write".to_string()]);
+    }
+
+    /// a serial port connected to an amp that echoes everything written, but only when the baud rates match
+    #[derive(Clone)]
+    struct MockSerialPort {
+        baud: u32,
+        amp_baud: Arc<Mutex<u32>>,
+        echo: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl Read for MockSerialPort {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let mut echo = self.echo.lock().unwrap();
+
+            if echo.is_empty() {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+
+            let n = buf.len().min(echo.len());
+            buf[..n].copy_from_slice(&echo.drain(..n).collect::<Vec<_>>());
+
+            Ok(n)
+        }
+    }
+
+    impl Write for MockSerialPort {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let mut amp_baud = self.amp_baud.lock().unwrap();
+
+            // garbage in, nothing echoed
+            if self.baud != *amp_baud {
+                return Ok(buf.len());
+            }
+
+            self.echo.lock().unwrap().extend_from_slice(buf);
+
+            // baud set command, the amp switches immediately
+            if let Some(baud) = str::from_utf8(buf).ok().and_then(|cmd| cmd.strip_prefix('<')?.strip_suffix('\r')?.parse().ok()) {
+                *amp_baud = baud;
+            }
+
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    }
+
+    impl SerialPort for MockSerialPort {
+        fn name(&self) -> Option<String> { None }
+        fn baud_rate(&self) -> serialport::Result<u32> { Ok(self.baud) }
+        fn data_bits(&self) -> serialport::Result<serialport::DataBits> { Ok(serialport::DataBits::Eight) }
+        fn flow_control(&self) -> serialport::Result<serialport::FlowControl> { Ok(serialport::FlowControl::None) }
+        fn parity(&self) -> serialport::Result<serialport::Parity> { Ok(serialport::Parity::None) }
+        fn stop_bits(&self) -> serialport::Result<serialport::StopBits> { Ok(serialport::StopBits::One) }
+        fn timeout(&self) -> Duration { Duration::ZERO }
+        fn set_baud_rate(&mut self, baud_rate: u32) -> serialport::Result<()> { self.baud = baud_rate; Ok(()) }
+        fn set_data_bits(&mut self, _: serialport::DataBits) -> serialport::Result<()> { Ok(()) }
+        fn set_flow_control(&mut self, _: serialport::FlowControl) -> serialport::Result<()> { Ok(()) }
+        fn set_parity(&mut self, _: serialport::Parity) -> serialport::Result<()> { Ok(()) }
+        fn set_stop_bits(&mut self, _: serialport::StopBits) -> serialport::Result<()> { Ok(()) }
+        fn set_timeout(&mut self, _: Duration) -> serialport::Result<()> { Ok(()) }
+        fn write_request_to_send(&mut self, _: bool) -> serialport::Result<()> { Ok(()) }
+        fn write_data_terminal_ready(&mut self, _: bool) -> serialport::Result<()> { Ok(()) }
+        fn read_clear_to_send(&mut self) -> serialport::Result<bool> { Ok(true) }
+        fn read_data_set_ready(&mut self) -> serialport::Result<bool> { Ok(true) }
+        fn read_ring_indicator(&mut self) -> serialport::Result<bool> { Ok(false) }
+        fn read_carrier_detect(&mut self) -> serialport::Result<bool> { Ok(true) }
+        fn bytes_to_read(&self) -> serialport::Result<u32> { Ok(self.echo.lock().unwrap().len() as u32) }
+        fn bytes_to_write(&self) -> serialport::Result<u32> { Ok(0) }
+        fn clear(&self, _: serialport::ClearBuffer) -> serialport::Result<()> { self.echo.lock().unwrap().clear(); Ok(()) }
+        fn try_clone(&self) -> serialport::Result<Box<dyn SerialPort>> { Ok(Box::new(self.clone())) }
+        fn set_break(&self) -> serialport::Result<()> { Ok(()) }
+        fn clear_break(&self) -> serialport::Result<()> { Ok(()) }
+    }
+
+    #[test]
+    fn test_redetect_baud() {
+        // the port was adjusted to the max baud, then the amp was power-cycled and reverted to 9600
+        let amp_baud = Arc::new(Mutex::new(9600));
+
+        let mut port = AmpSerialPort {
+            port: Box::new(MockSerialPort { baud: 230400, amp_baud: amp_baud.clone(), echo: Default::default() }),
+            adjust_baud: AdjustBaudConfig::Max,
+            verify_adjust_baud: false,
+            previous_baud: None
+        };
+
+        assert_eq!(port.redetect_baud().unwrap(), Some(230400));
+        assert_eq!(*amp_baud.lock().unwrap(), 230400);
+        assert_eq!(port.port.baud_rate().unwrap(), 230400);
+
+        // without an adjustment, the detected baud is kept
+        *amp_baud.lock().unwrap() = 9600;
+        port.adjust_baud = AdjustBaudConfig::Off;
+
+        assert_eq!(port.redetect_baud().unwrap(), Some(9600));
+        assert_eq!(port.port.baud_rate().unwrap(), 9600);
+    }
+
+    #[test]
+    fn test_verify_adjust_baud() {
+        // an emulated amp on the other end of a pty (which ignores baud rates, so the amp responds at any rate)
+        let (master, slave) = TTYPort::pair().unwrap();
+
+        let mut config = SerialPortConfig::with_device(&slave.name().unwrap());
+        drop(slave); // reopened by AmpSerialPort
+        config.baud = BaudConfig::Rate(Baud::try_from(9600_i64).unwrap());
+        config.adjust_baud = AdjustBaudConfig::Rate(Baud::try_from(57600_i64).unwrap());
+        config.verify_adjust_baud = true;
+        config.reset_baud = false;
+
+        let emu = Arc::new(Mutex::new(mwhaemu::emu::Amp::new(1)));
+        std::thread::spawn({
+            let emu = emu.clone();
+            let mut master = master;
+
+            move || {
+                master.set_timeout(Duration::from_secs(10)).unwrap();
+                mwhaemu::serial::run(emu, master)
+            }
+        });
+
+        let mut port = AmpSerialPort::new(&config).unwrap();
+        assert_eq!(port.port.baud_rate().unwrap(), 57600);
+
+        // an adjustment to the current rate isn't sent (or verified)
+        assert_eq!(AmpSerialPort::apply_adjust_baud(&mut port.port, port.adjust_baud, true, 57600).unwrap(), None);
+
+        // the connection is usable at the new rate
+        let mut amp = crate::amp::Amp::new(Box::new(port), 1).unwrap();
+        assert_eq!(amp.zone_enquiry(ZoneId::Zone { amp: 1, zone: 1 }).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_verify_adjust_baud_no_response() {
+        // the amp switches baud and echoes, but never responds to enquiries (i.e. the switch left it at an unknown rate)
+        let amp_baud = Arc::new(Mutex::new(9600));
+        let mut port: Box<dyn SerialPort> = Box::new(MockSerialPort { baud: 9600, amp_baud: amp_baud.clone(), echo: Default::default() });
+
+        let err = AmpSerialPort::apply_adjust_baud(&mut port, AdjustBaudConfig::Max, true, 9600).unwrap_err();
+        assert!(format!("{err:#}").contains("amp did not respond after adjusting baud rate to 230400"), "{err:#}");
+
+        // without verification the adjustment is assumed to have worked
+        *amp_baud.lock().unwrap() = 9600;
+        port.set_baud_rate(9600).unwrap();
+        assert_eq!(AmpSerialPort::apply_adjust_baud(&mut port, AdjustBaudConfig::Max, false, 9600).unwrap(), Some(230400));
+    }
+}
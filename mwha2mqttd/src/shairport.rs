@@ -1,23 +1,71 @@
-use std::{collections::HashMap, sync::{mpsc::Sender, Arc, Mutex}, cmp::min};
+use std::collections::HashMap;
 
-use common::{ids::SourceId, mqtt::{MqttConnectionManager, PayloadDecodeError}, zone::{ZoneAttribute, ZoneId, ranges}};
+use common::{ids::SourceId, mqtt::{MqttConnectionManager, PayloadDecodeError}, zone::{AttributeRanges, ZoneAttribute, ZoneId}};
 use rumqttc::Publish;
 
 use anyhow::Result;
 
-use crate::{config::{SourceConfig, ZoneConfig, ShairportConfig}, AmpControlChannelMessage, amp::ZoneStatus};
+use crate::{channel::ControlSender, config::{SourceConfig, ZoneConfig, ShairportConfig}, worker::AmpControlChannelMessage, amp::{SharedZonesStatus, ZoneStatus}};
 
 
+/// tolerance when comparing AirPlay volumes against the mute sentinel, as exact float comparisons are fragile
+const MUTE_DB_EPSILON: f32 = 0.01;
 
+/// returns true if the AirPlay volume (in dB) is the mute sentinel
+fn is_airplay_mute(db: f32, mute_db: f32) -> bool {
+    (db - mute_db).abs() <= MUTE_DB_EPSILON
+}
 
+/// get the adjustments for a zone listening to an AirPlay source when the AirPlay volume (in dB) changes
+fn airplay_volume_adjustments(airplay_volume: f32, zone: &ZoneStatus, zone_config: &ZoneConfig, shairport_config: &ShairportConfig, ranges: &AttributeRanges) -> Vec<ZoneAttribute> {
+    let muted = zone.matches(ZoneAttribute::Mute(true));
+    let follow_mute = zone_config.shairport.follow_mute;
 
-pub fn install_source_shairport_handlers(shairport_config: &ShairportConfig, zones_config: &HashMap<ZoneId, ZoneConfig>, sources_config: &HashMap<SourceId, SourceConfig>,
-                                         mqtt: &mut MqttConnectionManager, zones_status: Arc<Mutex<Vec<ZoneStatus>>>, send: Sender<AmpControlChannelMessage>) -> Result<()>
+    match airplay_volume {
+        db if is_airplay_mute(db, shairport_config.mute_db) => {
+            // AirPlay mute (according to Shairport docs)
+            if !follow_mute {
+                log::info!("zone {}: ignoring AirPlay mute (follow_mute is disabled)", zone.zone_id);
+                return vec![];
+            }
+
+            vec![ZoneAttribute::Mute(true)]
+        },
+        db if (-30.0..=0.0).contains(&db) => {
+            let max_vol = zone_config.shairport.max_volume.unwrap_or(shairport_config.max_zone_volume) as f32;
+            let vol_offset = zone_config.shairport.volume_offset.unwrap_or(shairport_config.zone_volume_offset) as f32;
+
+            // 0.0 = max, -30.0 = min
+            let vol = ZoneAttribute::Volume(((1.0 - (db / -30.0)) * max_vol + vol_offset) as u8).clamped_in(ranges);
+
+            let mut adjustments = vec![];
+
+            if muted && follow_mute {
+                adjustments.push(ZoneAttribute::Mute(false));
+            }
+
+            log::info!("zone {}: adjusting volume to {vol:?}", zone.zone_id);
+
+            adjustments.push(vol);
+
+            adjustments
+        },
+        other_db => {
+            log::error!("airplay_volume out of range: {other_db}");
+
+            vec![]
+        }
+    }
+}
+
+pub fn install_source_shairport_handlers(shairport_config: &ShairportConfig, zones_config: &HashMap<ZoneId, ZoneConfig>, sources_config: &HashMap<SourceId, SourceConfig>, ranges: &AttributeRanges,
+                                         mqtt: &mut MqttConnectionManager, zones_status: SharedZonesStatus, send: ControlSender) -> Result<()>
 {
     for (source_id, source_config) in sources_config {
         if let Some(volume_topic) = &source_config.shairport.volume_topic {
             let handler = {
                 let shairport_config = shairport_config.clone();
+                let ranges = ranges.clone();
                 let volume_topic = volume_topic.clone();
                 let source_id = source_id.clone();
                 let zones_status = zones_status.clone();
@@ -35,44 +83,18 @@ pub fn install_source_shairport_handlers(shairport_config: &ShairportConfig, zon
                                 Some(Ok(airplay_volume)) => {
                                     log::info!("source {source_id}: AirPlay volume changed to {airplay_volume}");
 
-                                    for zone in zones_status.lock().expect("lock zone_statuses").iter() {
+                                    for zone in zones_status.snapshot().iter() {
                                         let send_attr = |attr: ZoneAttribute| {
-                                            send.send(AmpControlChannelMessage::ChangeZoneAttribute(zone.zone_id, attr)).unwrap(); // TODO: handler error
+                                            send.send(AmpControlChannelMessage::ChangeZoneAttribute(zone.zone_id, attr));
                                         };
 
-                                        if !zone.matches(ZoneAttribute::Source((&source_id).into())) {
+                                        if !zone.matches(ZoneAttribute::from(source_id)) {
                                              continue; // only zones listening to this AirPlay source get their volume adjusted
                                         }
 
-                                        let muted = zone.matches(ZoneAttribute::Mute(true));
-
-                                        let zone_config = zones_config.get(&zone.zone_id);
-
-                                        if let Some(zone_config) = zone_config {
-                                            match airplay_volume {
-                                                db if db == -144.0 => {
-                                                    // AirPlay mute (according to Shairport docs)
-                                                    send_attr(ZoneAttribute::Mute(true));
-                                                },
-                                                db if db >= -30.00 && db <= 0.0 => {
-                                                    let max_vol = zone_config.shairport.max_volume.unwrap_or(shairport_config.max_zone_volume) as f32;
-                                                    let vol_offset = zone_config.shairport.volume_offset.unwrap_or(shairport_config.zone_volume_offset) as f32;
-
-                                                    // 0.0 = max, -30.0 = min
-                                                    let mut vol = ((1.0 - (db / -30.0)) * max_vol + vol_offset) as u8;
-                                                    vol = min(vol, *ranges::VOLUME.end()); // clamp
-
-                                                    if muted {
-                                                        send_attr(ZoneAttribute::Mute(false))
-                                                    }
-
-                                                    log::info!("zone {} on source {source_id}: adjusting volume to {vol}", zone.zone_id);
-        
-                                                    send_attr(ZoneAttribute::Volume(vol));
-                                                },
-                                                other_db => {
-                                                    log::error!("airplay_volume out of range: {other_db}")
-                                                }
+                                        if let Some(zone_config) = zones_config.get(&zone.zone_id) {
+                                            for attr in airplay_volume_adjustments(airplay_volume, zone, zone_config, &shairport_config, &ranges) {
+                                                send_attr(attr);
                                             }
                                         }
                                     }
@@ -92,4 +114,57 @@ pub fn install_source_shairport_handlers(shairport_config: &ShairportConfig, zon
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::config::ZoneShairportConfig;
+
+    use super::*;
+
+    #[test]
+    fn test_is_airplay_mute() {
+        let mute_db = ShairportConfig::default().mute_db;
+
+        assert!(is_airplay_mute(-144.0, mute_db));
+        assert!(is_airplay_mute(-144.005, mute_db));
+        assert!(is_airplay_mute(-143.995, mute_db));
+        assert!(is_airplay_mute("-144.000000".parse().unwrap(), mute_db));
+
+        assert!(!is_airplay_mute(-143.9, mute_db));
+        assert!(!is_airplay_mute(-144.1, mute_db));
+        assert!(!is_airplay_mute(-30.0, mute_db));
+
+        // custom sentinel
+        assert!(is_airplay_mute(-100.0, -100.0));
+        assert!(!is_airplay_mute(-144.0, -100.0));
+    }
+
+    #[test]
+    fn test_follow_mute() {
+        let shairport_config = ShairportConfig::default();
+
+        let zone = |muted| ZoneStatus {
+            zone_id: ZoneId::Zone { amp: 1, zone: 1 },
+            attributes: vec![ZoneAttribute::Mute(muted), ZoneAttribute::Volume(10), ZoneAttribute::Source(1)]
+        };
+
+        let zone_config = |follow_mute| ZoneConfig {
+            name: "Study".to_string(),
+            always_publish: false,
+            poll: true,
+            shairport: ZoneShairportConfig { follow_mute, ..Default::default() }
+        };
+
+        // following mute (the default)
+        assert!(ZoneShairportConfig::default().follow_mute);
+        assert_eq!(airplay_volume_adjustments(-144.0, &zone(false), &zone_config(true), &shairport_config, &AttributeRanges::default()), vec![ZoneAttribute::Mute(true)]);
+        assert_eq!(airplay_volume_adjustments(0.0, &zone(true), &zone_config(true), &shairport_config, &AttributeRanges::default()), vec![ZoneAttribute::Mute(false), ZoneAttribute::Volume(38)]);
+
+        // not following mute, but still tracking volume
+        assert_eq!(airplay_volume_adjustments(-144.0, &zone(false), &zone_config(false), &shairport_config, &AttributeRanges::default()), vec![]);
+        assert_eq!(airplay_volume_adjustments(0.0, &zone(true), &zone_config(false), &shairport_config, &AttributeRanges::default()), vec![ZoneAttribute::Volume(38)]);
+        assert_eq!(airplay_volume_adjustments(-15.0, &zone(false), &zone_config(false), &shairport_config, &AttributeRanges::default()), vec![ZoneAttribute::Volume(19)]);
+    }
+}
@@ -0,0 +1,2980 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+
+use common::ids::SourceId;
+use common::mqtt::BacklogClient;
+use common::zone::BalanceTrim;
+use common::zone::VolumePercent;
+use common::zone::ZoneAttribute;
+use common::zone::ZoneAttributeDiscriminants;
+use common::zone::ZoneId;
+use common::zone::ZoneTopic;
+use common::zone::ZoneTopicFormat;
+use crossbeam_channel::RecvTimeoutError;
+use crossbeam_channel::TryRecvError;
+
+use anyhow::Result;
+use humantime_serde::re::humantime;
+use rumqttc::QoS;
+use serde_json::Value;
+use strum::IntoEnumIterator;
+use serde_json::json;
+
+use crate::amp::AmpController;
+use crate::amp::ReleasePortFn;
+use crate::amp::SharedZonesStatus;
+use crate::amp::ZoneStatus;
+use crate::channel::ControlReceiver;
+use crate::config::AmpConfig;
+use crate::config::BoolPayload;
+use crate::config::Config;
+use crate::config::OfflinePlaceholder;
+use crate::config::PublishConfig;
+use crate::config::SourceConfig;
+
+
+pub enum AmpControlChannelMessage {
+    ChangeZoneAttribute(ZoneId, ZoneAttribute),
+    /// change a zone attribute even if the zone status indicates the value is already set
+    ForceZoneAttribute(ZoneId, ZoneAttribute),
+    /// enable/disable status publishing for a configured zone (or the configured zones of an amp/system zone)
+    SetZoneEnabled(ZoneId, bool),
+    /// re-detect (and re-adjust) the baud rate of the amp connection, i.e. after the amp was power-cycled
+    RedetectBaud,
+    Poison
+}
+
+impl AmpControlChannelMessage {
+    /// The zone, attribute and whether it's forced, if the message is a zone attribute adjustment.
+    pub fn adjustment(&self) -> Option<(ZoneId, ZoneAttribute, bool)> {
+        match *self {
+            Self::ChangeZoneAttribute(zone_id, attr) => Some((zone_id, attr, false)),
+            Self::ForceZoneAttribute(zone_id, attr) => Some((zone_id, attr, true)),
+            _ => None
+        }
+    }
+}
+
+/// A queued zone attribute adjustment.
+#[derive(Clone, Copy, Debug)]
+struct Adjustment {
+    zone_id: ZoneId,
+    attr: ZoneAttribute,
+    force: bool,
+}
+
+
+/// Applies a source's default volume to zones that switch to that source.
+///
+/// The default is not applied if the zone's volume was adjusted within the configured window,
+/// so that a manual adjustment made alongside a source change isn't immediately overridden.
+pub struct SourceDefaultVolumes {
+    defaults: HashMap<u8, u8>,
+    manual_window: Duration,
+    last_volume_adjustments: HashMap<ZoneId, Instant>,
+}
+
+impl SourceDefaultVolumes {
+    pub fn new(sources: &HashMap<SourceId, SourceConfig>, manual_window: Duration) -> Self {
+        let defaults = sources.iter()
+            .filter_map(|(id, source)| source.default_volume.map(|volume| (u8::from(id), volume)))
+            .collect();
+
+        Self {
+            defaults,
+            manual_window,
+            last_volume_adjustments: HashMap::new()
+        }
+    }
+
+    /// Record a volume adjustment for a zone (or all the zones of a virtual amp/system zone).
+    pub fn volume_adjusted(&mut self, zone_id: ZoneId, now: Instant) {
+        for zone_id in zone_id.to_zones() {
+            self.last_volume_adjustments.insert(zone_id, now);
+        }
+    }
+
+    /// Returns the volume adjustment to apply if the zone has switched to a source that has a default volume.
+    pub fn source_changed(&self, previous: &ZoneStatus, current: &ZoneStatus, now: Instant) -> Option<ZoneAttribute> {
+        let source = match current.get(ZoneAttributeDiscriminants::Source) {
+            Some(ZoneAttribute::Source(source)) => source,
+            _ => return None
+        };
+
+        if previous.matches(ZoneAttribute::Source(source)) {
+            return None; // source hasn't changed
+        }
+
+        let volume = *self.defaults.get(&source)?;
+
+        if let Some(adjusted) = self.last_volume_adjustments.get(&current.zone_id) {
+            if now.saturating_duration_since(*adjusted) < self.manual_window {
+                return None; // volume was manually adjusted recently, leave it be
+            }
+        }
+
+        Some(ZoneAttribute::Volume(volume))
+    }
+}
+
+
+/// Returns the new keypad connected state if it changed between the previous and current zone status.
+pub fn keypad_transition(previous: &ZoneStatus, current: &ZoneStatus) -> Option<bool> {
+    match (previous.get(ZoneAttributeDiscriminants::KeypadConnected), current.get(ZoneAttributeDiscriminants::KeypadConnected)) {
+        (Some(previous), Some(ZoneAttribute::KeypadConnected(connected))) if previous != ZoneAttribute::KeypadConnected(connected) => Some(connected),
+        _ => None
+    }
+}
+
+
+/// Trusts recently set zone attribute values over polled values, until the hardware has settled.
+///
+/// For `window` after an attribute is set, polled values of that attribute are replaced with the value set,
+/// so that transient values read back from the hardware aren't published. A zero `window` disables this.
+pub struct SettleWindow {
+    window: Duration,
+    recent: HashMap<(ZoneId, ZoneAttributeDiscriminants), (ZoneAttribute, Instant)>,
+}
+
+impl SettleWindow {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            recent: HashMap::new()
+        }
+    }
+
+    /// Record an attribute set on a zone (or all the zones of a virtual amp/system zone).
+    pub fn attribute_set(&mut self, zone_id: ZoneId, attr: ZoneAttribute, now: Instant) {
+        if self.window.is_zero() {
+            return;
+        }
+
+        for zone_id in zone_id.to_zones() {
+            self.recent.insert((zone_id, ZoneAttributeDiscriminants::from(attr)), (attr, now));
+        }
+    }
+
+    /// Replace unsettled polled values with the values recently set.
+    pub fn apply(&mut self, statuses: &mut [ZoneStatus], now: Instant) {
+        let window = self.window;
+        self.recent.retain(|_, (_, set_at)| now.saturating_duration_since(*set_at) < window);
+
+        for status in statuses {
+            for attr in status.attributes.iter_mut() {
+                let key = (status.zone_id, ZoneAttributeDiscriminants::from(*attr));
+
+                let Some((set, _)) = self.recent.get(&key) else {
+                    continue;
+                };
+
+                if attr == set {
+                    // settled
+                    self.recent.remove(&key);
+
+                } else {
+                    log::debug!("zone {}: ignoring unsettled {:?} (set to {:?})", status.zone_id, attr, set);
+                    *attr = *set;
+                }
+            }
+        }
+    }
+}
+
+
+/// Limits how often a value is published to each topic.
+///
+/// Values offered within `min_interval` of the previous publish to the same topic are held back,
+/// with later values replacing earlier ones, and are published once the interval has elapsed.
+pub struct PublishThrottle {
+    min_interval: Duration,
+    last_published: HashMap<String, (Instant, Value)>,
+    pending: HashMap<String, Value>,
+}
+
+impl PublishThrottle {
+    pub fn new(min_interval: Duration) -> Self {
+        Self {
+            min_interval,
+            last_published: HashMap::new(),
+            pending: HashMap::new()
+        }
+    }
+
+    /// Offer a value for publishing. Returns the value if it can be published immediately.
+    pub fn offer(&mut self, topic: String, value: Value, now: Instant) -> Option<(String, Value)> {
+        if let Some((published_at, _)) = self.last_published.get(&topic) {
+            if now.saturating_duration_since(*published_at) < self.min_interval {
+                self.pending.insert(topic, value);
+                return None;
+            }
+        }
+
+        self.pending.remove(&topic);
+        self.last_published.insert(topic.clone(), (now, value.clone()));
+
+        Some((topic, value))
+    }
+
+    /// Forget a topic (i.e. after it has been cleared), dropping any held back value.
+    pub fn forget(&mut self, topic: &str) {
+        self.pending.remove(topic);
+        self.last_published.remove(topic);
+    }
+
+    /// Returns the held back values that can now be published.
+    pub fn due(&mut self, now: Instant) -> Vec<(String, Value)> {
+        let due_topics = self.pending.keys()
+            .filter(|topic| self.last_published.get(*topic).is_none_or(|(published_at, _)| now.saturating_duration_since(*published_at) >= self.min_interval))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        due_topics.into_iter().filter_map(|topic| {
+            let value = self.pending.remove(&topic)?;
+
+            // the value flapped back to what was last published, no need to publish it again
+            if self.last_published.get(&topic).is_some_and(|(_, published)| *published == value) {
+                return None;
+            }
+
+            self.offer(topic, value, now)
+        }).collect()
+    }
+}
+
+
+/// Limits the rate of inbound zone set commands, per zone, dropping the excess.
+///
+/// Shared by the MQTT set topic handlers, so that a misbehaving client can't queue adjustments faster than the amp can apply them.
+#[derive(Clone)]
+pub struct SetRateLimiter {
+    max_per_second: u32,
+
+    /// the start of the current one second window, and the number of sets allowed in it, for each zone
+    windows: Arc<Mutex<HashMap<ZoneId, (Instant, u32)>>>,
+}
+
+impl SetRateLimiter {
+    /// `max_per_second` of 0 allows every set.
+    pub fn new(max_per_second: u32) -> Self {
+        Self {
+            max_per_second,
+            windows: Default::default()
+        }
+    }
+
+    /// Returns true if a set for the zone is allowed now.
+    pub fn allow(&self, zone_id: ZoneId, now: Instant) -> bool {
+        if self.max_per_second == 0 {
+            return true;
+        }
+
+        let mut windows = self.windows.lock().unwrap();
+        let (window_start, count) = windows.entry(zone_id).or_insert((now, 0));
+
+        if now.saturating_duration_since(*window_start) >= Duration::from_secs(1) {
+            *window_start = now;
+            *count = 0;
+        }
+
+        if *count >= self.max_per_second {
+            return false;
+        }
+
+        *count += 1;
+        true
+    }
+}
+
+
+/// Schedules periodic full republishes of zone status, regardless of whether it has changed.
+pub struct Heartbeat {
+    interval: Duration,
+    last: Option<Instant>,
+}
+
+impl Heartbeat {
+    /// A zero `interval` disables the heartbeat.
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last: None
+        }
+    }
+
+    /// Returns true (and restarts the interval) if a full republish is due.
+    pub fn due(&mut self, now: Instant) -> bool {
+        if self.interval.is_zero() {
+            return false;
+        }
+
+        if self.last.is_some_and(|last| now.saturating_duration_since(last) < self.interval) {
+            return false;
+        }
+
+        self.last = Some(now);
+
+        true
+    }
+}
+
+
+/// Whether a publish carries authoritative state, or is ephemeral (i.e. events and heartbeat republishes of unchanged values).
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PublishClass {
+    Status,
+    Diagnostic,
+}
+
+/// Downgrades diagnostic publishes to `AtMostOnce` while the outgoing publish backlog exceeds `threshold`.
+///
+/// Status publishes are always `AtLeastOnce`. A zero `threshold` disables downgrading.
+pub struct CongestionPolicy {
+    threshold: usize,
+}
+
+impl CongestionPolicy {
+    pub fn new(threshold: usize) -> Self {
+        Self { threshold }
+    }
+
+    pub fn qos(&self, class: PublishClass, backlog: usize) -> QoS {
+        match class {
+            PublishClass::Diagnostic if self.threshold != 0 && backlog > self.threshold => QoS::AtMostOnce,
+            _ => QoS::AtLeastOnce
+        }
+    }
+}
+
+
+/// Destination of the worker's MQTT publishes.
+pub trait Publisher: Send {
+    fn publish(&mut self, topic: String, qos: QoS, retain: bool, payload: String) -> Result<(), rumqttc::ClientError>;
+
+    /// Estimated number of publishes yet to be sent.
+    fn backlog(&self) -> usize { 0 }
+}
+
+impl Publisher for BacklogClient {
+    fn publish(&mut self, topic: String, qos: QoS, retain: bool, payload: String) -> Result<(), rumqttc::ClientError> {
+        BacklogClient::publish(self, topic, qos, retain, payload)
+    }
+
+    fn backlog(&self) -> usize {
+        BacklogClient::backlog(self)
+    }
+}
+
+
+/// processes incoming zone attribute adjustments and periodically polls the amp for status updates
+struct AmpWorker {
+    amp: Box<dyn AmpController>,
+    mqtt: Box<dyn Publisher>,
+    topic_base: String,
+    zone_topic_format: ZoneTopicFormat,
+
+    poll_interval: Duration,
+
+    /// zones configured for publish (excludes amp and system zones)
+    configured_zone_ids: HashSet<ZoneId>,
+
+    /// configured zones that are currently enabled for publish
+    zone_ids: HashSet<ZoneId>,
+
+    /// amps of the zones configured for publish (for bulk query)
+    amp_ids: HashSet<ZoneId>,
+
+    /// number of amps covered by a system zone enquiry (see `AmpConfig::amp_count`)
+    amp_count: u8,
+
+    /// zones whose attributes are published on every poll, even if unchanged
+    always_publish_zone_ids: HashSet<ZoneId>,
+
+    /// configured zones excluded from polls (their status is the values last set)
+    unpolled_zone_ids: HashSet<ZoneId>,
+
+    zones_status: SharedZonesStatus,
+    previous_statuses: HashMap<ZoneId, ZoneStatus>,
+
+    /// whether each zone responded to the last poll
+    available: HashMap<ZoneId, bool>,
+
+    /// what the attribute topics of unavailable zones are set to
+    offline_placeholder: OfflinePlaceholder,
+
+    bool_payload: BoolPayload,
+
+    default_volumes: SourceDefaultVolumes,
+
+    throttle: PublishThrottle,
+
+    settle: SettleWindow,
+
+    heartbeat: Heartbeat,
+
+    keypad_events: bool,
+
+    /// set on a zone when a keypad is connected to it
+    keypad_connect_state: Vec<ZoneAttribute>,
+
+    /// also publish zone balance as `balance-left`/`balance-right` trims
+    balance_trims: bool,
+
+    /// also publish zone volume as a `volume-percent`
+    volume_percent: bool,
+
+    /// whether to listen for unsolicited zone status from the amp while waiting between polls
+    unsolicited_status: bool,
+
+    /// apply adjustments received while applying others before refreshing the status, see `update_batch`
+    batch_adjustments: bool,
+
+    /// skip adjustments that match the last polled zone status, unless forced
+    skip_unchanged_sets: bool,
+
+    /// publish amp errors to `status/last-error`
+    publish_last_error: bool,
+
+    /// whether `status/last-error` may hold an error that needs clearing
+    /// (initially, a retained error may be left over from a previous run)
+    last_error_published: bool,
+
+    /// never set zone attributes on the amp (only poll and publish)
+    readonly: bool,
+
+    /// sources that zone `Source` adjustments may not select
+    rejected_sources: HashSet<u8>,
+
+    /// zone `Source` adjustments above this are rejected
+    source_count: u8,
+
+    /// whether the amp has responded to a poll yet
+    amp_ready: bool,
+
+    /// adjustments received before the amp was ready, at most one per zone attribute
+    early_adjustments: Vec<Adjustment>,
+
+    congestion: CongestionPolicy,
+
+    /// run once after the first poll that any zone responds to
+    after_first_poll: Option<FirstPollHook>,
+
+    last_poll: LastPoll,
+
+    /// set once the worker has been replaced by the watchdog, and should stop
+    retired: Arc<AtomicBool>,
+
+    /// number of set commands and enquiries issued to the amp
+    commands_total: u64,
+
+    /// whether to publish `commands_total`, and the value last published
+    publish_commands_total: bool,
+    published_commands_total: Option<u64>,
+
+    /// whether diagnostic topics (`status/diag/...`) are retained
+    retain_diagnostics: bool,
+}
+
+/// Run by the worker after its first poll that any zone responds to (i.e. to publish metadata).
+pub type FirstPollHook = Box<dyn FnOnce() + Send>;
+
+impl AmpWorker {
+    fn new(config: &AmpConfig, publish_config: &PublishConfig, amp: Box<dyn AmpController>, mqtt: Box<dyn Publisher>, topic_base: &str, zones_status: SharedZonesStatus) -> Self {
+        // get the zones specifically configured for publish (ignore amp and system zones)
+        let zone_ids = config.zones.keys().filter_map(|z| match z {
+            ZoneId::Zone { amp, zone } => Some(ZoneId::Zone { amp: *amp, zone: *zone }),
+            _ => None,
+        }).collect::<HashSet<_>>();
+
+        let unpolled_zone_ids = config.zones.iter().filter(|(_, zone)| !zone.poll).map(|(id, _)| *id).collect::<HashSet<_>>();
+
+        // coalesce polled zone ids into amp ids (for bulk query)
+        let amp_ids = zone_ids.difference(&unpolled_zone_ids).flat_map(ZoneId::to_amps).collect::<HashSet<_>>();
+
+        Self {
+            amp,
+            mqtt,
+            topic_base: topic_base.to_string(),
+            zone_topic_format: publish_config.zone_topic_format,
+            poll_interval: config.poll_interval,
+            configured_zone_ids: zone_ids.clone(),
+            zone_ids,
+            amp_ids,
+            amp_count: config.amp_count(),
+            always_publish_zone_ids: config.zones.iter().filter(|(_, zone)| zone.always_publish).map(|(id, _)| *id).collect(),
+            unpolled_zone_ids,
+            zones_status,
+            previous_statuses: HashMap::new(),
+            available: HashMap::new(),
+            offline_placeholder: publish_config.offline_placeholder,
+            bool_payload: publish_config.bool_payload,
+            default_volumes: SourceDefaultVolumes::new(&config.sources(), config.manual_volume_window),
+            throttle: PublishThrottle::new(publish_config.min_interval),
+            settle: SettleWindow::new(config.settle_window),
+            heartbeat: Heartbeat::new(publish_config.republish_interval),
+            keypad_events: publish_config.keypad_events,
+            keypad_connect_state: Vec::new(),
+            balance_trims: publish_config.balance_trims,
+            volume_percent: publish_config.volume_percent,
+            unsolicited_status: config.unsolicited_status,
+            batch_adjustments: config.batch_adjustments,
+            skip_unchanged_sets: config.skip_unchanged_sets,
+            publish_last_error: publish_config.last_error,
+            last_error_published: publish_config.last_error,
+            readonly: config.readonly,
+            rejected_sources: match config.reject_disabled_source_selects {
+                true => config.sources().iter().filter(|(_, source)| !source.enabled).map(|(id, _)| u8::from(id)).collect(),
+                false => HashSet::new()
+            },
+            source_count: config.source_count,
+            amp_ready: false,
+            early_adjustments: Vec::new(),
+            congestion: CongestionPolicy::new(publish_config.congestion_threshold),
+            after_first_poll: None,
+            last_poll: LastPoll::default(),
+            retired: Arc::new(AtomicBool::new(false)),
+            commands_total: 0,
+            publish_commands_total: publish_config.commands_total,
+            published_commands_total: None,
+            retain_diagnostics: publish_config.retain_diagnostics,
+        }
+    }
+
+    /// Wait for incoming zone attribute adjustments, or until the poll interval elapses.
+    ///
+    /// Returns `None` if the worker should stop.
+    fn receive_adjustments(&mut self, recv: &ControlReceiver) -> Option<Vec<Adjustment>> {
+        // wait for an incoming zone attribute adjustment with a timeout.
+        // if a timeout occurs do a zone status refresh anyway (poll the amp)
+        let msg = match self.unsolicited_status {
+            true => self.listen_unsolicited(recv),
+            false => match recv.recv_timeout(self.poll_interval) {
+                Ok(msg) => Some(msg),
+                Err(RecvTimeoutError::Timeout) => None, // timeout waiting for message, refresh zone status anyway
+                Err(other) => panic!("recv_timeout error: {:?}", other)
+            }
+        };
+
+        self.drain_adjustments(msg, recv)
+    }
+
+    /// Collect `msg` and any other queued zone attribute adjustments, without waiting.
+    ///
+    /// Returns `None` if the worker should stop.
+    fn drain_adjustments(&mut self, mut msg: Option<AmpControlChannelMessage>, recv: &ControlReceiver) -> Option<Vec<Adjustment>> {
+        let mut adjustments = HashMap::<_, Adjustment>::new();
+
+        // drain the channel.
+        // mqtt can deliver faster than the serialport can handle and multiple adjustments may have come while processing the last request.
+        // there is no point adjusting the same attribute multiple times.
+        // newer attribute adjustments queued for the same zone overwrite earlier ones (but stay forced if any were forced).
+        loop {
+            let adjustment = match msg {
+                Some(msg @ (AmpControlChannelMessage::ChangeZoneAttribute(..) | AmpControlChannelMessage::ForceZoneAttribute(..))) => {
+                    msg.adjustment().map(|(zone_id, attr, force)| Adjustment { zone_id, attr, force })
+                },
+                Some(AmpControlChannelMessage::SetZoneEnabled(zone_id, enabled)) => {
+                    self.set_zone_enabled(zone_id, enabled);
+                    None
+                },
+                Some(AmpControlChannelMessage::RedetectBaud) => {
+                    self.redetect_baud();
+                    None
+                },
+                Some(AmpControlChannelMessage::Poison) => { return None },
+                None => break
+            };
+
+            if let Some(adjustment) = adjustment {
+                let key = (adjustment.zone_id, std::mem::discriminant(&adjustment.attr));
+                let force = adjustment.force || adjustments.get(&key).is_some_and(|a| a.force);
+
+                adjustments.insert(key, Adjustment { force, ..adjustment });
+            }
+
+            msg = try_receive(recv);
+        }
+
+        Some(adjustments.into_values().collect())
+    }
+
+    /// wait for an incoming message until the poll interval elapses, processing unsolicited zone status from the amp in the meantime.
+    ///
+    /// the amp read timeout bounds how long an incoming message may wait.
+    fn listen_unsolicited(&mut self, recv: &ControlReceiver) -> Option<AmpControlChannelMessage> {
+        let deadline = Instant::now() + self.poll_interval;
+
+        loop {
+            match recv.try_recv() {
+                Ok(msg) => return Some(msg),
+                Err(TryRecvError::Empty) => {},
+                Err(other) => panic!("try_recv error: {:?}", other)
+            }
+
+            if Instant::now() >= deadline {
+                return None;
+            }
+
+            let statuses = match self.amp.unsolicited_statuses() {
+                Ok(statuses) => statuses,
+                Err(e) => {
+                    // fall back to a poll, which refreshes the status (or reports the amp as not responding)
+                    log::error!("failed to read unsolicited zone status: {:#}", e);
+                    self.publish_error(&e);
+                    return None;
+                }
+            };
+
+            let statuses = statuses.into_iter()
+                .filter(|z| self.zone_ids.contains(&z.zone_id))
+                .collect::<Vec<_>>();
+
+            if !statuses.is_empty() {
+                self.process_statuses(statuses, false);
+            }
+        }
+    }
+
+    /// re-detect the baud rate of the amp connection and publish the result.
+    ///
+    /// runs on the worker thread, so no other commands are interleaved with detection.
+    fn redetect_baud(&mut self) {
+        match self.amp.redetect_baud() {
+            Ok(Some(baud)) => {
+                log::info!("baud rate re-detected, now {}", baud);
+                self.publish(format!("{}status/amp/baud", self.topic_base), json!(baud), PublishClass::Status);
+            },
+            Ok(None) => log::warn!("baud rate re-detection is only supported for serial port connections"),
+            Err(e) => log::error!("failed to re-detect baud rate: {:#}", e)
+        }
+    }
+
+    /// returns true if the last polled status of every zone covered by `zone_id` already matches `attr`
+    fn status_matches(&self, zone_id: ZoneId, attr: ZoneAttribute) -> bool {
+        zone_id.to_zones().iter()
+            .all(|z| self.previous_statuses.get(z).is_some_and(|status| status.matches(attr)))
+    }
+
+    /// apply zone attribute adjustments
+    ///
+    /// adjustments that match the last polled zone status are skipped if `skip_unchanged_sets` is enabled, unless forced.
+    fn apply_adjustments(&mut self, adjustments: &[Adjustment]) {
+        let now = Instant::now();
+
+        for &Adjustment { zone_id, attr, force } in adjustments {
+            if self.readonly {
+                log::warn!("adjust {} = {:?} ignored, writes to the amp are disabled (readonly)", zone_id, attr);
+                continue;
+            }
+
+            if let ZoneAttribute::Source(source) = attr {
+                if source > self.source_count {
+                    log::warn!("adjust {} = {:?} rejected, source {} is above the configured source count ({})", zone_id, attr, source, self.source_count);
+                    continue;
+                }
+
+                if self.rejected_sources.contains(&source) {
+                    log::warn!("adjust {} = {:?} rejected, source {} is disabled", zone_id, attr, source);
+                    continue;
+                }
+            }
+
+            if self.skip_unchanged_sets && !force && self.status_matches(zone_id, attr) {
+                log::debug!("adjust {} = {:?} (unchanged, skipped)", zone_id, attr);
+                continue;
+            }
+
+            log::debug!("adjust {} = {:?}", zone_id, attr);
+
+            if !self.set_zone_attribute(zone_id, attr) {
+                continue;
+            }
+
+            self.settle.attribute_set(zone_id, attr, now);
+            self.echo_unpolled(zone_id, attr);
+
+            if let ZoneAttribute::Volume(_) = attr {
+                self.default_volumes.volume_adjusted(zone_id, now);
+            }
+        }
+    }
+
+    /// publish an attribute set on the amp as the status of any unpolled zones it applies to
+    fn echo_unpolled(&mut self, zone_id: ZoneId, attr: ZoneAttribute) {
+        let statuses = zone_id.to_zones().into_iter()
+            .filter(|z| self.zone_ids.contains(z) && self.unpolled_zone_ids.contains(z))
+            .map(|z| {
+                let mut status = self.previous_statuses.get(&z).cloned().unwrap_or(ZoneStatus { zone_id: z, attributes: Vec::new() });
+
+                status.attributes.retain(|a| ZoneAttributeDiscriminants::from(*a) != ZoneAttributeDiscriminants::from(attr));
+                status.attributes.push(attr);
+
+                status
+            })
+            .collect::<Vec<_>>();
+
+        if !statuses.is_empty() {
+            self.process_statuses(statuses, false);
+        }
+    }
+
+    /// set a zone attribute on the amp, returning whether it was set.
+    ///
+    /// failures (i.e. a timeout or an invalid value) are logged and published, rather than stopping the worker
+    fn set_zone_attribute(&mut self, zone_id: ZoneId, attr: ZoneAttribute) -> bool {
+        self.commands_total += 1;
+
+        match self.amp.set_zone_attribute(zone_id, attr) {
+            Ok(()) => true,
+            Err(e) => {
+                let e = e.context(format!("failed to set {} = {:?}", zone_id, attr));
+
+                log::error!("{:#}", e);
+                self.publish_error(&e);
+                false
+            }
+        }
+    }
+
+    /// publish the number of commands issued to the amp, if it has changed since it was last published
+    fn publish_commands_total(&mut self) {
+        if !self.publish_commands_total || self.published_commands_total == Some(self.commands_total) {
+            return;
+        }
+
+        self.publish_diagnostic(format!("{}status/diag/commands_total", self.topic_base), json!(self.commands_total));
+        self.published_commands_total = Some(self.commands_total);
+    }
+
+    /// get zone statuses from active amps
+    fn poll(&mut self) -> Result<Vec<ZoneStatus>> {
+        // query all amps at once if more than one amp is active, and every amp the system enquiry covers is active.
+        // otherwise only the active amps are queried
+        let enquiry_ids = match self.amp_ids.len() {
+            n if n > 1 && n == usize::from(self.amp_count) => vec![ZoneId::System],
+            _ => ZoneId::System.to_amps().into_iter().filter(|id| self.amp_ids.contains(id)).collect()
+        };
+
+        let mut statuses = Vec::new();
+
+        for id in enquiry_ids {
+            self.commands_total += 1;
+            let enquiry_result = self.amp.zone_enquiry(id)?;
+
+            // exclude disabled and unpolled zones
+            statuses.extend(enquiry_result.into_iter().filter(|z| self.zone_ids.contains(&z.zone_id) && !self.unpolled_zone_ids.contains(&z.zone_id)));
+        }
+
+        Ok(statuses)
+    }
+
+    fn publish(&mut self, topic: String, value: Value, class: PublishClass) {
+        log::debug!("set {} = {}", topic, value);
+
+        let qos = self.congestion.qos(class, self.mqtt.backlog());
+
+        self.mqtt.publish(topic, qos, true, self.bool_payload.encode(&value)).unwrap(); // TODO: handle error more gracefully
+    }
+
+    /// publish a diagnostic topic, retained only if configured
+    fn publish_diagnostic(&mut self, topic: String, value: Value) {
+        log::debug!("set {} = {}", topic, value);
+
+        let qos = self.congestion.qos(PublishClass::Diagnostic, self.mqtt.backlog());
+
+        self.mqtt.publish(topic, qos, self.retain_diagnostics, self.bool_payload.encode(&value)).unwrap(); // TODO: handle error more gracefully
+    }
+
+    /// clear a retained topic
+    fn clear(&mut self, topic: String) {
+        log::debug!("clear {}", topic);
+
+        self.throttle.forget(&topic);
+        self.mqtt.publish(topic, QoS::AtLeastOnce, true, String::new()).unwrap(); // TODO: handle error more gracefully
+    }
+
+    /// publish an amp error to `status/last-error`, if enabled
+    fn publish_error(&mut self, err: &anyhow::Error) {
+        if !self.publish_last_error { return }
+
+        self.publish(format!("{}status/last-error", self.topic_base), json!({
+            "error": format!("{:#}", err),
+            "timestamp": humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+        }), PublishClass::Status);
+
+        self.last_error_published = true;
+    }
+
+    /// clear `status/last-error` once the amp responds again
+    fn clear_error(&mut self) {
+        if !self.last_error_published { return }
+
+        self.clear(format!("{}status/last-error", self.topic_base));
+        self.last_error_published = false;
+    }
+
+    /// enable/disable status publishing for configured zones.
+    ///
+    /// disabling a zone clears its retained status topics. re-enabled zones have their full status published on the next poll.
+    fn set_zone_enabled(&mut self, zone_id: ZoneId, enabled: bool) {
+        let zone_ids = zone_id.to_zones().into_iter()
+            .filter(|z| self.configured_zone_ids.contains(z))
+            .collect::<Vec<_>>();
+
+        for zone_id in zone_ids {
+            if enabled {
+                if self.zone_ids.insert(zone_id) {
+                    log::info!("zone {}: status publishing enabled", zone_id);
+                }
+
+                continue;
+            }
+
+            if !self.zone_ids.remove(&zone_id) {
+                continue; // already disabled
+            }
+
+            log::info!("zone {}: status publishing disabled", zone_id);
+
+            self.previous_statuses.remove(&zone_id);
+            self.available.remove(&zone_id);
+
+            let topics = ZoneAttributeDiscriminants::iter()
+                .map(|attr| attr.mqtt_topic_name(ZoneTopic::Status, &self.topic_base, self.zone_topic_format, &zone_id))
+                .chain([ZoneTopic::Status.zone_topic_name(&self.topic_base, self.zone_topic_format, &zone_id, "available")])
+                .chain(self.balance_trim_topics(&zone_id).into_iter().flatten())
+                .chain(self.volume_percent_topic(&zone_id))
+                .collect::<Vec<_>>();
+
+            for topic in topics {
+                self.clear(topic);
+            }
+        }
+
+        self.amp_ids = self.zone_ids.difference(&self.unpolled_zone_ids).flat_map(ZoneId::to_amps).collect();
+    }
+
+    /// publish zone attributes that have changed since the previous poll
+    fn publish_changes(&mut self, statuses: &[ZoneStatus], complete: bool) {
+        for (topic, value, class) in self.status_publishes(statuses, complete, Instant::now()) {
+            self.publish(topic, value, class);
+        }
+    }
+
+    /// get the zone status topics and values to publish.
+    ///
+    /// includes only changed attributes, unless a heartbeat republish is due (unchanged attributes are then diagnostic)
+    /// or the zone is configured to always publish.
+    /// the heartbeat is only checked for a poll of all zones (`complete`), so every zone is republished when it's due.
+    fn status_publishes(&mut self, statuses: &[ZoneStatus], complete: bool, now: Instant) -> Vec<(String, Value, PublishClass)> {
+        // previously throttled values that are now due
+        let mut publishes = self.throttle.due(now).into_iter()
+            .map(|(topic, value)| (topic, value, PublishClass::Status))
+            .collect::<Vec<_>>();
+
+        let republish = complete && self.heartbeat.due(now);
+
+        for zone_status in statuses {
+            let previous_status = self.previous_statuses.get(&zone_status.zone_id);
+            let always_publish = self.always_publish_zone_ids.contains(&zone_status.zone_id);
+
+            for (discriminant, attr) in zone_status.iter() {
+                // don't publish if zone attribute hasn't changed
+                let unchanged = previous_status.is_some_and(|prev_status| prev_status.matches(attr));
+                if !republish && !always_publish && unchanged {
+                    continue;
+                }
+
+                let class = if unchanged && !always_publish { PublishClass::Diagnostic } else { PublishClass::Status };
+
+                let topic = discriminant.mqtt_topic_name(ZoneTopic::Status, &self.topic_base, self.zone_topic_format, &zone_status.zone_id);
+
+                let value = {
+                    use ZoneAttribute::*;
+
+                    match attr {
+                        PublicAnnouncement(b) | Power(b) | Mute(b) | DoNotDisturb(b) | KeypadConnected(b) => json!(b),
+                        Volume(v) | Treble(v) | Bass(v) | Balance(v) | Source(v) => json!(v)
+                    }
+                };
+
+                publishes.extend(self.throttle.offer(topic, value, now).map(|(topic, value)| (topic, value, class)));
+
+                if let (ZoneAttribute::Balance(balance), Some(topics)) = (attr, self.balance_trim_topics(&zone_status.zone_id)) {
+                    let (left, right) = BalanceTrim::from_balance(balance);
+
+                    for (topic, trim) in topics.into_iter().zip([left, right]) {
+                        publishes.extend(self.throttle.offer(topic, json!(trim), now).map(|(topic, value)| (topic, value, class)));
+                    }
+                }
+
+                if let (ZoneAttribute::Volume(volume), Some(topic)) = (attr, self.volume_percent_topic(&zone_status.zone_id)) {
+                    let VolumePercent(percent) = VolumePercent::from_volume(volume);
+
+                    publishes.extend(self.throttle.offer(topic, json!(percent), now).map(|(topic, value)| (topic, value, class)));
+                }
+            }
+        }
+
+        publishes
+    }
+
+    /// the zone's `balance-left` and `balance-right` status topics, if balance trims are enabled
+    fn balance_trim_topics(&self, zone_id: &ZoneId) -> Option<[String; 2]> {
+        if !self.balance_trims { return None }
+
+        Some(["balance-left", "balance-right"].map(|attr_name| ZoneTopic::Status.zone_topic_name(&self.topic_base, self.zone_topic_format, zone_id, attr_name)))
+    }
+
+    /// the zone's `volume-percent` status topic, if volume percent is enabled
+    fn volume_percent_topic(&self, zone_id: &ZoneId) -> Option<String> {
+        if !self.volume_percent { return None }
+
+        Some(ZoneTopic::Status.zone_topic_name(&self.topic_base, self.zone_topic_format, zone_id, "volume-percent"))
+    }
+
+    /// mark zones that didn't respond to the poll as unavailable (and vice versa), publishing any changes
+    fn update_availability(&mut self, statuses: &[ZoneStatus]) {
+        let responded = statuses.iter().map(|s| s.zone_id).collect::<HashSet<_>>();
+
+        let mut publishes = Vec::new();
+        let mut unavailable = Vec::new();
+
+        for &zone_id in self.zone_ids.difference(&self.unpolled_zone_ids) {
+            let available = responded.contains(&zone_id);
+
+            if self.available.insert(zone_id, available) == Some(available) {
+                continue;
+            }
+
+            if available {
+                log::info!("zone {}: available", zone_id);
+            } else {
+                log::warn!("zone {}: not responding, marking as unavailable", zone_id);
+                unavailable.push(zone_id);
+            }
+
+            publishes.push((ZoneTopic::Status.zone_topic_name(&self.topic_base, self.zone_topic_format, &zone_id, "available"), json!(available)));
+        }
+
+        for (topic, value) in publishes {
+            self.publish(topic, value, PublishClass::Status);
+        }
+
+        for zone_id in unavailable {
+            self.publish_offline_placeholders(zone_id);
+        }
+    }
+
+    /// replace the attribute topics of an unavailable zone with the configured placeholder.
+    ///
+    /// the zone's cached status is dropped, so that its full status is published once it responds again.
+    fn publish_offline_placeholders(&mut self, zone_id: ZoneId) {
+        if self.offline_placeholder == OfflinePlaceholder::Keep { return }
+
+        self.previous_statuses.remove(&zone_id);
+
+        let topics = ZoneAttributeDiscriminants::iter()
+            .map(|attr| attr.mqtt_topic_name(ZoneTopic::Status, &self.topic_base, self.zone_topic_format, &zone_id))
+            .chain(self.balance_trim_topics(&zone_id).into_iter().flatten())
+            .chain(self.volume_percent_topic(&zone_id))
+            .collect::<Vec<_>>();
+
+        for topic in topics {
+            match self.offline_placeholder {
+                OfflinePlaceholder::Keep => {},
+                OfflinePlaceholder::Null => {
+                    self.throttle.forget(&topic);
+                    self.publish(topic, Value::Null, PublishClass::Status);
+                },
+                OfflinePlaceholder::Empty => self.clear(topic),
+            }
+        }
+    }
+
+    /// publish (non-retained) events for zones with keypads that have connected/disconnected since the previous poll
+    fn publish_keypad_events(&mut self, statuses: &[ZoneStatus]) {
+        for zone_status in statuses {
+            let Some(previous_status) = self.previous_statuses.get(&zone_status.zone_id) else {
+                continue; // first poll, nothing to compare against
+            };
+
+            if let Some(connected) = keypad_transition(previous_status, zone_status) {
+                let topic = format!("{}event/zone/{}/keypad", self.topic_base, self.zone_topic_format.format(&zone_status.zone_id));
+                let value = json!(if connected { "connected" } else { "disconnected" });
+
+                log::debug!("event {} = {}", topic, value);
+
+                let qos = self.congestion.qos(PublishClass::Diagnostic, self.mqtt.backlog());
+
+                self.mqtt.publish(topic, qos, false, value.to_string()).unwrap(); // TODO: handle error more gracefully
+            }
+        }
+    }
+
+    /// apply the keypad connect state to zones with keypads that have connected since the previous poll
+    fn apply_keypad_connect_state(&mut self, statuses: &[ZoneStatus]) {
+        if self.readonly || self.keypad_connect_state.is_empty() { return }
+
+        for zone_status in statuses {
+            let Some(previous_status) = self.previous_statuses.get(&zone_status.zone_id) else {
+                continue; // first poll, nothing to compare against
+            };
+
+            if keypad_transition(previous_status, zone_status) != Some(true) {
+                continue;
+            }
+
+            log::info!("zone {}: keypad connected, applying {:?}", zone_status.zone_id, self.keypad_connect_state);
+
+            for attr in self.keypad_connect_state.clone() {
+                self.set_zone_attribute(zone_status.zone_id, attr);
+            }
+        }
+    }
+
+    /// apply the default volume of any newly selected sources
+    fn apply_source_default_volumes(&mut self, statuses: &[ZoneStatus]) {
+        if self.readonly { return }
+
+        let now = Instant::now();
+
+        for zone_status in statuses {
+            let Some(previous_status) = self.previous_statuses.get(&zone_status.zone_id) else {
+                continue; // first poll, nothing to compare against
+            };
+
+            if let Some(attr) = self.default_volumes.source_changed(previous_status, zone_status, now) {
+                log::info!("zone {}: source changed, applying source default {:?}", zone_status.zone_id, attr);
+
+                self.set_zone_attribute(zone_status.zone_id, attr);
+            }
+        }
+    }
+
+    /// apply adjustments, then poll the amp and publish any changes
+    ///
+    /// adjustments received before the amp first responds to a poll are buffered until it does.
+    fn update(&mut self, adjustments: &[Adjustment]) {
+        if self.adjust(adjustments) {
+            self.refresh();
+        }
+    }
+
+    /// like `update`, but adjustments received while the batch is being applied join the batch, and the status is
+    /// refreshed once the batch is complete (rather than after every set of adjustments received).
+    ///
+    /// the batch ends once no more adjustments are queued, or once the poll interval has elapsed, so that a steady
+    /// stream of adjustments doesn't hold off polling.
+    /// returns false if the worker should stop.
+    fn update_batch(&mut self, adjustments: Vec<Adjustment>, recv: &ControlReceiver) -> bool {
+        let deadline = Instant::now() + self.poll_interval;
+        let mut adjustments = adjustments;
+
+        loop {
+            if !self.adjust(&adjustments) {
+                return true;
+            }
+
+            if Instant::now() >= deadline {
+                break;
+            }
+
+            adjustments = match self.drain_adjustments(try_receive(recv), recv) {
+                Some(adjustments) if adjustments.is_empty() => break,
+                Some(adjustments) => adjustments,
+                None => return false
+            };
+        }
+
+        self.refresh();
+
+        true
+    }
+
+    /// apply adjustments, or buffer them until the amp first responds to a poll.
+    ///
+    /// returns false if the status doesn't need refreshing: the amp isn't ready yet, or it just became ready with
+    /// nothing to apply, and its status has been published from the poll that found it ready.
+    fn adjust(&mut self, adjustments: &[Adjustment]) -> bool {
+        if self.amp_ready {
+            self.apply_adjustments(adjustments);
+            return true;
+        }
+
+        for &adjustment in adjustments {
+            coalesce_adjustment(&mut self.early_adjustments, adjustment);
+        }
+
+        let statuses = match self.poll() {
+            Ok(statuses) => statuses,
+            Err(e) => {
+                log::warn!("amp not ready, {} adjustment(s) buffered: {:#}", self.early_adjustments.len(), e);
+                self.publish_error(&e.context("amp not ready"));
+                return false;
+            }
+        };
+
+        self.amp_ready = true;
+
+        let adjustments = std::mem::take(&mut self.early_adjustments);
+
+        if adjustments.is_empty() {
+            self.polled(statuses);
+            return false;
+        }
+
+        self.apply_adjustments(&adjustments);
+
+        true
+    }
+
+    /// poll the amp and publish any changes
+    fn refresh(&mut self) {
+        let statuses = match self.poll() {
+            Ok(statuses) => statuses,
+            // the amp may recover (i.e. a timeout, garbled response or port error), try again next cycle
+            Err(e) => {
+                let e = e.context("failed to poll amp");
+
+                log::error!("{:#}", e);
+                self.publish_error(&e);
+                return;
+            }
+        };
+
+        self.polled(statuses);
+    }
+
+    /// publish the zone statuses from a successful poll
+    fn polled(&mut self, statuses: Vec<ZoneStatus>) {
+        self.clear_error();
+
+        self.process_statuses(statuses, true);
+
+        self.publish_commands_total();
+
+        self.last_poll.completed();
+    }
+
+    /// the startup sequence run on the calling thread by `spawn_amp_worker`, before the worker thread is spawned
+    /// (see `WorkerHooks`).
+    fn startup(&mut self, config: &Config, after_first_poll: Option<FirstPollHook>, initial_sync: bool, apply_startup: bool) {
+        self.after_first_poll = after_first_poll;
+        self.keypad_connect_state = config.keypad_connect.attributes();
+
+        if apply_startup {
+            self.queue_startup_state(&config.startup.attributes());
+        }
+
+        if initial_sync {
+            self.initial_sync();
+        }
+    }
+
+    /// queue the startup state to be set on every configured zone once the amp is ready.
+    ///
+    /// the adjustments are forced, as the amp's state before startup is unknown.
+    fn queue_startup_state(&mut self, attrs: &[ZoneAttribute]) {
+        if attrs.is_empty() { return }
+
+        let mut zone_ids = self.configured_zone_ids.iter().copied().collect::<Vec<_>>();
+        zone_ids.sort();
+
+        log::info!("applying startup state {:?} to {} zone(s) once the amp is ready", attrs, zone_ids.len());
+
+        for adjustment in zone_ids.into_iter().flat_map(|zone_id| attrs.iter().map(move |&attr| Adjustment { zone_id, attr, force: true })) {
+            coalesce_adjustment(&mut self.early_adjustments, adjustment);
+        }
+    }
+
+    /// poll and publish the status of all zones once, before the worker thread is spawned.
+    ///
+    /// any adjustments queued before the amp was ready (i.e. the startup state) are applied first.
+    /// if the amp doesn't respond, the worker's regular polling takes over.
+    fn initial_sync(&mut self) {
+        let result = self.poll().and_then(|statuses| {
+            self.amp_ready = true;
+
+            if self.early_adjustments.is_empty() {
+                return Ok(statuses);
+            }
+
+            let adjustments = std::mem::take(&mut self.early_adjustments);
+            self.apply_adjustments(&adjustments);
+
+            self.poll()
+        });
+
+        match result {
+            Ok(statuses) => self.polled(statuses),
+            Err(e) => {
+                log::warn!("initial sync failed, amp not ready: {:#}", e);
+                self.publish_error(&e.context("amp not ready"));
+            }
+        }
+    }
+
+    /// publish and cache zone statuses, either from a poll of all zones (`complete`) or received unsolicited
+    fn process_statuses(&mut self, mut statuses: Vec<ZoneStatus>, complete: bool) {
+        self.settle.apply(&mut statuses, Instant::now());
+
+        // zones missing from unsolicited status aren't unavailable
+        if complete {
+            self.update_availability(&statuses);
+        }
+
+        self.publish_changes(&statuses, complete);
+
+        if self.keypad_events {
+            self.publish_keypad_events(&statuses);
+        }
+
+        if complete && !statuses.is_empty() {
+            if let Some(hook) = self.after_first_poll.take() {
+                hook();
+            }
+        }
+
+        self.apply_source_default_volumes(&statuses);
+        self.apply_keypad_connect_state(&statuses);
+
+        for zone_status in &statuses {
+            self.previous_statuses.insert(zone_status.zone_id, zone_status.clone());
+        }
+
+        if !complete {
+            // merge into the previous snapshot
+            let updated = statuses.iter().map(|z| z.zone_id).collect::<HashSet<_>>();
+
+            statuses.extend(self.zones_status.snapshot().iter()
+                .filter(|z| !updated.contains(&z.zone_id))
+                .cloned());
+        }
+
+        self.zones_status.update(statuses);
+    }
+
+    fn run(mut self, recv: ControlReceiver) {
+        // a retired worker may have been wedged for some time, don't let it act on anything else
+        let retired = self.retired.clone();
+        let retired = || retired.load(Ordering::SeqCst);
+
+        while !retired() {
+            let Some(adjustments) = self.receive_adjustments(&recv) else {
+                return
+            };
+
+            if retired() { return }
+
+            match self.batch_adjustments {
+                true => if !self.update_batch(adjustments, &recv) { return },
+                false => self.update(&adjustments)
+            }
+        }
+    }
+
+    /// run the worker on a new thread
+    fn spawn<F>(self, recv: ControlReceiver, on_panic: F) -> AmpWorkerHandle
+    where
+        F: FnOnce() + Send + 'static
+    {
+        let last_poll = self.last_poll.clone();
+        let retired = self.retired.clone();
+
+        let thread = thread::spawn(move || {
+            if let Err(panic) = panic::catch_unwind(AssertUnwindSafe(|| self.run(recv))) {
+                on_panic();
+                panic::resume_unwind(panic);
+            }
+        });
+
+        AmpWorkerHandle { thread, last_poll, retired, release_port: None }
+    }
+}
+
+/// add `adjustment` to `adjustments`, replacing any earlier adjustment of the same zone attribute (which stays forced
+/// if either was forced)
+fn coalesce_adjustment(adjustments: &mut Vec<Adjustment>, adjustment: Adjustment) {
+    let queued = adjustments.iter_mut()
+        .find(|a| a.zone_id == adjustment.zone_id && std::mem::discriminant(&a.attr) == std::mem::discriminant(&adjustment.attr));
+
+    match queued {
+        Some(queued) => *queued = Adjustment { force: queued.force || adjustment.force, ..adjustment },
+        None => adjustments.push(adjustment)
+    }
+}
+
+/// receive a queued control message, without waiting
+fn try_receive(recv: &ControlReceiver) -> Option<AmpControlChannelMessage> {
+    match recv.try_recv() {
+        Ok(msg) => Some(msg),
+        Err(TryRecvError::Empty) => None,
+        Err(other) => panic!("try_recv error: {:?}", other)
+    }
+}
+
+/// The time of the worker's last completed poll.
+#[derive(Clone)]
+pub struct LastPoll(Arc<Mutex<Instant>>);
+
+impl Default for LastPoll {
+    fn default() -> Self {
+        // a worker that never completes a poll counts as stalled from when it started
+        Self(Arc::new(Mutex::new(Instant::now())))
+    }
+}
+
+impl LastPoll {
+    fn completed(&self) {
+        *self.0.lock().unwrap() = Instant::now();
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.0.lock().unwrap().elapsed()
+    }
+}
+
+/// A running amp worker.
+pub struct AmpWorkerHandle {
+    thread: JoinHandle<()>,
+    last_poll: LastPoll,
+    retired: Arc<AtomicBool>,
+
+    /// releases the worker's hold on the amp port, so a replacement worker can reopen it
+    release_port: Option<ReleasePortFn>,
+}
+
+impl AmpWorkerHandle {
+    /// Time since the worker last completed a poll.
+    pub fn since_last_poll(&self) -> Duration {
+        self.last_poll.elapsed()
+    }
+
+    /// Release the worker's hold on the amp port, so that it can be reopened while the worker is (wedged) using it.
+    pub fn release_port(&mut self) {
+        if let Some(release) = self.release_port.take() {
+            release();
+        }
+    }
+
+    /// Abandon the worker (it may be wedged, so can't be joined). It stops if it ever wakes up.
+    pub fn retire(self) {
+        self.retired.store(true, Ordering::SeqCst);
+    }
+
+    pub fn join(self) -> thread::Result<()> {
+        self.thread.join()
+    }
+}
+
+/// Restarts the amp worker if it stops completing polls, i.e. if it's wedged mid-read.
+pub struct Watchdog {
+    stop: crossbeam_channel::Sender<()>,
+    thread: JoinHandle<AmpWorkerHandle>,
+}
+
+impl Watchdog {
+    /// Watch `worker`, calling `restart` to replace it if no poll completes within `timeout` (`Duration::ZERO` never restarts).
+    ///
+    /// If `restart` fails it's tried again on the next check.
+    pub fn spawn<R>(worker: AmpWorkerHandle, timeout: Duration, mut restart: R) -> Self
+    where
+        R: FnMut(Duration) -> Result<AmpWorkerHandle> + Send + 'static
+    {
+        let (stop, stop_recv) = crossbeam_channel::bounded(1);
+
+        let thread = thread::spawn(move || {
+            let mut worker = worker;
+
+            if timeout.is_zero() {
+                let _ = stop_recv.recv();
+                return worker;
+            }
+
+            while let Err(RecvTimeoutError::Timeout) = stop_recv.recv_timeout(timeout / 4) {
+                let stalled = worker.since_last_poll();
+
+                if stalled < timeout { continue }
+
+                log::error!("amp worker hasn't completed a poll in {:?}, restarting", stalled);
+
+                // the replacement reopens the port, which the stalled worker may still hold open
+                worker.release_port();
+
+                match restart(stalled) {
+                    Ok(replacement) => std::mem::replace(&mut worker, replacement).retire(),
+                    Err(err) => log::error!("failed to restart amp worker: {:#}", err)
+                }
+            }
+
+            worker
+        });
+
+        Self { stop, thread }
+    }
+
+    /// Stop watching, returning the current worker.
+    pub fn stop(self) -> AmpWorkerHandle {
+        let _ = self.stop.send(());
+
+        self.thread.join().expect("watchdog thread panicked")
+    }
+}
+
+/// callbacks run on the worker thread
+pub struct WorkerHooks<F> {
+    /// poll and publish the status of all zones on the calling thread before spawning the worker,
+    /// so that the status topics are populated by the time `spawn_amp_worker` returns
+    pub initial_sync: bool,
+
+    /// apply the configured startup state (`config.startup`) to all configured zones once the amp is ready
+    pub apply_startup: bool,
+
+    /// run once the first poll that any zone responds to has been published
+    pub after_first_poll: Option<FirstPollHook>,
+
+    /// called if the worker thread panics, so that the daemon can shut down rather than carry on without a worker.
+    /// the panic is then propagated to the `JoinHandle`.
+    pub on_panic: F,
+}
+
+/// spawn a worker thread that processes incoming zone attribute adjustments and periodically polls the amp for status updates
+pub fn spawn_amp_worker<A, F>(config: &Config, amp: A, mqtt: BacklogClient, topic_base: &str, recv: ControlReceiver, zones_status: SharedZonesStatus, hooks: WorkerHooks<F>) -> AmpWorkerHandle
+    where
+        A: AmpController + 'static,
+        F: FnOnce() + Send + 'static
+{
+    let WorkerHooks { initial_sync, apply_startup, after_first_poll, on_panic } = hooks;
+
+    let release_port = amp.release_handle().unwrap_or_else(|err| {
+        log::warn!("the amp port can't be released if the worker stalls, restarting it may fail: {:#}", err);
+        None
+    });
+
+    let mut worker = AmpWorker::new(&config.amp, &config.publish, Box::new(amp), Box::new(mqtt), topic_base, zones_status);
+    worker.startup(config, after_first_poll, initial_sync, apply_startup);
+
+    let mut handle = worker.spawn(recv, on_panic);
+    handle.release_port = release_port;
+
+    handle
+}
+
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use common::mqtt::PublishBacklog;
+    use common::mqtt::PublishJson;
+
+    use crate::amp::AmpError;
+    use crate::config::MetadataTiming;
+
+    use super::*;
+
+    const STUDY: ZoneId = ZoneId::Zone { amp: 1, zone: 1 };
+    const LIVING_ROOM: ZoneId = ZoneId::Zone { amp: 1, zone: 2 };
+
+    /// records publishes as (topic, retain, payload), and the QoS last used for each topic
+    #[derive(Clone, Default)]
+    pub(crate) struct Published {
+        publishes: Arc<Mutex<Vec<(String, bool, String)>>>,
+        qos: Arc<Mutex<HashMap<String, QoS>>>,
+
+        /// simulated outgoing publish backlog
+        backlog: Arc<AtomicUsize>,
+    }
+
+    impl Published {
+        pub(crate) fn take(&self) -> Vec<(String, bool, String)> {
+            std::mem::take(&mut self.publishes.lock().unwrap())
+        }
+
+        pub(crate) fn qos(&self, topic: &str) -> Option<QoS> {
+            self.qos.lock().unwrap().get(topic).copied()
+        }
+
+        pub(crate) fn set_backlog(&self, backlog: usize) {
+            self.backlog.store(backlog, Ordering::SeqCst);
+        }
+    }
+
+    impl Publisher for Published {
+        fn publish(&mut self, topic: String, qos: QoS, retain: bool, payload: String) -> Result<(), rumqttc::ClientError> {
+            self.qos.lock().unwrap().insert(topic.clone(), qos);
+            self.publishes.lock().unwrap().push((topic, retain, payload));
+            Ok(())
+        }
+
+        fn backlog(&self) -> usize {
+            self.backlog.load(Ordering::SeqCst)
+        }
+    }
+
+    impl PublishJson for Published {
+        fn publish_json<S>(&mut self, topic: S, qos: QoS, retain: bool, value: Value) -> Result<(), rumqttc::ClientError>
+        where
+            S: Into<String>
+        {
+            Publisher::publish(self, topic.into(), qos, retain, value.to_string())
+        }
+
+        fn clear_retained<S>(&mut self, topic: S, qos: QoS) -> Result<(), rumqttc::ClientError>
+        where
+            S: Into<String>
+        {
+            Publisher::publish(self, topic.into(), qos, true, String::new())
+        }
+    }
+
+    /// an in-memory amp, that records the attributes set
+    #[derive(Clone, Default)]
+    pub(crate) struct MockAmp {
+        pub(crate) zones: Arc<Mutex<HashMap<ZoneId, ZoneStatus>>>,
+        pub(crate) sets: Arc<Mutex<Vec<(ZoneId, ZoneAttribute)>>>,
+        pub(crate) enquiries: Arc<Mutex<Vec<ZoneId>>>,
+        pub(crate) unsolicited: Arc<Mutex<Vec<ZoneStatus>>>,
+
+        /// fail every command (i.e. the amp is still starting up)
+        pub(crate) not_ready: Arc<AtomicBool>,
+
+        /// block zone enquiries (i.e. a read that never completes)
+        pub(crate) wedged: Arc<AtomicBool>,
+
+        /// the port the amp is connected via, if opened with `ExclusivePort::open`
+        port: Option<ExclusivePort>,
+    }
+
+    /// a port that, like a serial port, can't be opened again while it's open (until it's released)
+    #[derive(Clone, Default)]
+    struct ExclusivePort(Arc<AtomicBool>);
+
+    impl ExclusivePort {
+        /// connect `amp` via the port
+        fn open(&self, amp: &mut MockAmp) -> anyhow::Result<()> {
+            if self.0.swap(true, Ordering::SeqCst) {
+                anyhow::bail!("port is busy");
+            }
+
+            amp.port = Some(self.clone());
+
+            Ok(())
+        }
+    }
+
+    impl MockAmp {
+        /// an amp with the given zones, all powered on at volume 20 listening to source 1
+        pub(crate) fn with_zones(zone_ids: &[ZoneId]) -> Self {
+            use ZoneAttribute::*;
+
+            let amp = Self::default();
+
+            amp.zones.lock().unwrap().extend(zone_ids.iter().map(|&zone_id| (zone_id, ZoneStatus {
+                zone_id,
+                attributes: vec![
+                    PublicAnnouncement(false), Power(true), Mute(false), DoNotDisturb(false), Volume(20),
+                    Treble(7), Bass(7), Balance(10), Source(1), KeypadConnected(true)
+                ]
+            })));
+
+            amp
+        }
+
+        pub(crate) fn set(&self, zone_id: ZoneId, attr: ZoneAttribute) {
+            let mut zones = self.zones.lock().unwrap();
+
+            for zone_id in zone_id.to_zones() {
+                if let Some(status) = zones.get_mut(&zone_id) {
+                    status.attributes.retain(|a| std::mem::discriminant(a) != std::mem::discriminant(&attr));
+                    status.attributes.push(attr);
+                }
+            }
+        }
+    }
+
+    impl AmpController for MockAmp {
+        fn zone_enquiry(&mut self, id: ZoneId) -> anyhow::Result<Vec<ZoneStatus>> {
+            while self.wedged.load(Ordering::SeqCst) {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+
+            if self.not_ready.load(Ordering::SeqCst) {
+                return Err(AmpError::Timeout.into());
+            }
+
+            self.enquiries.lock().unwrap().push(id);
+
+            let zones = self.zones.lock().unwrap();
+
+            Ok(id.to_zones().iter().filter_map(|z| zones.get(z).cloned()).collect())
+        }
+
+        fn set_zone_attribute(&mut self, id: ZoneId, attr: ZoneAttribute) -> anyhow::Result<()> {
+            attr.validate()?;
+
+            if self.not_ready.load(Ordering::SeqCst) {
+                return Err(AmpError::Timeout.into());
+            }
+
+            self.sets.lock().unwrap().push((id, attr));
+            self.set(id, attr);
+
+            Ok(())
+        }
+
+        fn unsolicited_statuses(&mut self) -> anyhow::Result<Vec<ZoneStatus>> {
+            if self.not_ready.load(Ordering::SeqCst) {
+                return Err(AmpError::Timeout.into());
+            }
+
+            let statuses = std::mem::take(&mut *self.unsolicited.lock().unwrap());
+
+            if statuses.is_empty() {
+                std::thread::sleep(Duration::from_millis(1)); // stand-in for the port read timeout
+            }
+
+            Ok(statuses)
+        }
+
+        fn release_handle(&self) -> anyhow::Result<Option<ReleasePortFn>> {
+            Ok(self.port.clone().map(|port| Box::new(move || port.0.store(false, Ordering::SeqCst)) as ReleasePortFn))
+        }
+    }
+
+    /// a worker for `amp`, publishing to the returned `Published`
+    fn test_worker(config: &Config, amp: impl AmpController + 'static) -> (AmpWorker, Published) {
+        let published = Published::default();
+        let worker = AmpWorker::new(&config.amp, &config.publish, Box::new(amp), Box::new(published.clone()), "mwha/", SharedZonesStatus::default());
+
+        (worker, published)
+    }
+
+    /// spawn a worker for `amp` on its own thread, publishing to `published`
+    pub(crate) fn spawn_test_worker(config: &Config, amp: MockAmp, published: &Published, recv: ControlReceiver) -> AmpWorkerHandle {
+        AmpWorker::new(&config.amp, &config.publish, Box::new(amp), Box::new(published.clone()), "mwha/", SharedZonesStatus::default())
+            .spawn(recv, || {})
+    }
+
+    #[test]
+    fn test_source_default_volume() {
+        let status = |source, volume| ZoneStatus {
+            zone_id: STUDY,
+            attributes: vec![ZoneAttribute::Volume(volume), ZoneAttribute::Source(source)]
+        };
+
+        let sources = HashMap::from([
+            (SourceId::try_from(2).unwrap(), SourceConfig { default_volume: Some(10), ..Default::default() }),
+            (SourceId::try_from(3).unwrap(), SourceConfig::default()),
+        ]);
+
+        let mut default_volumes = SourceDefaultVolumes::new(&sources, Duration::from_secs(5));
+
+        let now = Instant::now();
+
+        // switching to a source with a default volume applies it
+        assert_eq!(default_volumes.source_changed(&status(1, 20), &status(2, 20), now), Some(ZoneAttribute::Volume(10)));
+
+        // no source change, or a source without a default, does nothing
+        assert_eq!(default_volumes.source_changed(&status(2, 20), &status(2, 20), now), None);
+        assert_eq!(default_volumes.source_changed(&status(2, 20), &status(3, 20), now), None);
+
+        // a recent manual volume adjustment (including via the amp virtual zone) suppresses the default...
+        default_volumes.volume_adjusted(ZoneId::Amp(1), now);
+        assert_eq!(default_volumes.source_changed(&status(1, 20), &status(2, 20), now + Duration::from_secs(1)), None);
+
+        // ...until the window has passed
+        assert_eq!(default_volumes.source_changed(&status(1, 20), &status(2, 20), now + Duration::from_secs(5)), Some(ZoneAttribute::Volume(10)));
+    }
+
+    #[test]
+    fn test_publish_throttle() {
+        let topic = || "mwha/status/zone/11/volume".to_string();
+        let ms = Duration::from_millis;
+
+        let mut throttle = PublishThrottle::new(ms(250));
+
+        let now = Instant::now();
+
+        // first value is published immediately
+        assert_eq!(throttle.offer(topic(), json!(1), now), Some((topic(), json!(1))));
+
+        // rapidly alternating values within the window are held back
+        assert_eq!(throttle.offer(topic(), json!(2), now + ms(10)), None);
+        assert_eq!(throttle.offer(topic(), json!(1), now + ms(20)), None);
+        assert_eq!(throttle.offer(topic(), json!(2), now + ms(30)), None);
+        assert_eq!(throttle.due(now + ms(100)), vec![]);
+
+        // other topics aren't affected
+        assert!(throttle.offer("mwha/status/zone/12/volume".to_string(), json!(5), now + ms(30)).is_some());
+
+        // the last value wins once the window elapses
+        assert_eq!(throttle.due(now + ms(260)), vec![(topic(), json!(2))]);
+        assert_eq!(throttle.due(now + ms(270)), vec![]);
+
+        // values that flap back to the last published value within the window aren't republished
+        assert_eq!(throttle.offer(topic(), json!(3), now + ms(300)), None);
+        assert_eq!(throttle.offer(topic(), json!(2), now + ms(310)), None);
+        assert_eq!(throttle.due(now + ms(520)), vec![]);
+
+        // a zero interval disables throttling
+        let mut throttle = PublishThrottle::new(Duration::ZERO);
+        assert!(throttle.offer(topic(), json!(1), now).is_some());
+        assert!(throttle.offer(topic(), json!(2), now).is_some());
+    }
+
+    #[test]
+    fn test_keypad_transition() {
+        let status = |connected| ZoneStatus {
+            zone_id: STUDY,
+            attributes: vec![ZoneAttribute::Power(true), ZoneAttribute::KeypadConnected(connected)]
+        };
+
+        let statuses = [status(false), status(false), status(true), status(true), status(true)];
+
+        let events = statuses.windows(2)
+            .filter_map(|w| keypad_transition(&w[0], &w[1]))
+            .collect::<Vec<_>>();
+
+        // exactly one event for a single change
+        assert_eq!(events, vec![true]);
+
+        assert_eq!(keypad_transition(&status(true), &status(false)), Some(false));
+    }
+
+    #[test]
+    fn test_force_set() {
+        let mut config = crate::config::tests::config_from_str(crate::config::tests::TEST_CONFIG);
+        config.amp.skip_unchanged_sets = true;
+        let (amp, emu) = crate::amp::tests::emulated_amp(mwhaemu::emu::Amp::new(1), 1);
+
+        let (mut worker, _) = test_worker(&config, amp);
+
+        let volume = |emu: &Arc<Mutex<mwhaemu::emu::Amp>>| emu.lock().unwrap().zones[&STUDY].volume;
+        let adjust = |force| [Adjustment { zone_id: STUDY, attr: ZoneAttribute::Volume(10), force }];
+
+        worker.apply_adjustments(&adjust(false));
+        for status in worker.poll().unwrap() {
+            worker.previous_statuses.insert(status.zone_id, status);
+        }
+        assert_eq!(volume(&emu), 10);
+
+        // the amp changes behind the worker's back (i.e. power blip), so the cached status is stale
+        emu.lock().unwrap().zone_set(STUDY, ZoneAttribute::Volume(0));
+
+        // a normal set matching the cached status is suppressed
+        worker.apply_adjustments(&adjust(false));
+        assert_eq!(volume(&emu), 0);
+
+        // a forced set is always written to the amp
+        worker.apply_adjustments(&adjust(true));
+        assert_eq!(volume(&emu), 10);
+
+        // normal sets are only suppressed if configured
+        emu.lock().unwrap().zone_set(STUDY, ZoneAttribute::Volume(0));
+        worker.skip_unchanged_sets = false;
+
+        worker.apply_adjustments(&adjust(false));
+        assert_eq!(volume(&emu), 10);
+    }
+
+    #[test]
+    fn test_zone_availability() {
+        let config = crate::config::tests::TEST_CONFIG.replace(r#"12 = "Living Room""#, r#"12 = "Living Room"
+            21 = "Garage""#);
+        let config = crate::config::tests::config_from_str(&config);
+
+        // two amps configured, but only one present
+        let (amp, _emu) = crate::amp::tests::emulated_amp(mwhaemu::emu::Amp::new(1), 2);
+
+        let (mut worker, _) = test_worker(&config, amp);
+
+        let statuses = worker.poll().unwrap();
+        worker.update_availability(&statuses);
+
+        assert_eq!(worker.available, HashMap::from([
+            (STUDY, true),
+            (LIVING_ROOM, true),
+            (ZoneId::Zone { amp: 2, zone: 1 }, false),
+        ]));
+    }
+
+    #[test]
+    fn test_bool_payload() {
+        let status_topics = |config: &Config| {
+            let (mut worker, published) = test_worker(config, MockAmp::with_zones(&[STUDY]));
+            worker.update(&[]);
+
+            published.take().into_iter()
+                .filter(|(topic, _, _)| topic.starts_with("mwha/status/zone/11/"))
+                .map(|(topic, _, payload)| (topic, payload))
+                .collect::<HashMap<_, _>>()
+        };
+
+        let config = crate::config::tests::config_from_str(crate::config::tests::TEST_CONFIG);
+        assert_eq!(config.publish.bool_payload, BoolPayload::Json);
+
+        let json = status_topics(&config);
+        assert_eq!(json["mwha/status/zone/11/power"], "true");
+        assert_eq!(json["mwha/status/zone/11/mute"], "false");
+        assert_eq!(json["mwha/status/zone/11/available"], "true");
+
+        let config = crate::config::tests::config_from_str(&crate::config::tests::TEST_CONFIG.replace("[shairport]", "[publish]\nbool_payload = \"on-off\"\n[shairport]"));
+        assert_eq!(config.publish.bool_payload, BoolPayload::OnOff);
+
+        let on_off = status_topics(&config);
+        assert_eq!(on_off["mwha/status/zone/11/power"], "ON");
+        assert_eq!(on_off["mwha/status/zone/11/mute"], "OFF");
+        assert_eq!(on_off["mwha/status/zone/11/available"], "ON");
+
+        // only booleans are affected
+        assert_eq!(on_off["mwha/status/zone/11/volume"], "20");
+        assert_eq!(on_off.len(), json.len());
+    }
+
+    #[test]
+    fn test_offline_placeholder() {
+        let status_topics = |published: &Published| published.take().into_iter()
+            .filter(|(topic, _, _)| topic.starts_with("mwha/status/zone/11/"))
+            .map(|(topic, _, payload)| (topic, payload))
+            .collect::<HashMap<_, _>>();
+
+        for (placeholder, expected) in [(OfflinePlaceholder::Null, "null"), (OfflinePlaceholder::Empty, "")] {
+            let mut config = crate::config::tests::config_from_str(crate::config::tests::TEST_CONFIG);
+            config.publish.offline_placeholder = placeholder;
+
+            let amp = MockAmp::with_zones(&[STUDY]);
+
+            let (mut worker, published) = test_worker(&config, amp.clone());
+
+            worker.update(&[]);
+            assert_eq!(status_topics(&published)["mwha/status/zone/11/volume"], "20");
+
+            // zone stops responding
+            let status = amp.zones.lock().unwrap().remove(&STUDY).unwrap();
+            worker.update(&[]);
+
+            let offline = status_topics(&published);
+            assert_eq!(offline["mwha/status/zone/11/available"], "false");
+            assert_eq!(offline.len(), 11); // 10 attributes + available
+            assert!(offline.iter().filter(|(topic, _)| !topic.ends_with("/available")).all(|(_, payload)| payload == expected), "{placeholder:?}");
+
+            // still offline, nothing new published
+            worker.update(&[]);
+            assert!(status_topics(&published).is_empty());
+
+            // zone responds again, real values are restored
+            amp.zones.lock().unwrap().insert(STUDY, status);
+            worker.update(&[]);
+
+            let online = status_topics(&published);
+            assert_eq!(online["mwha/status/zone/11/available"], "true");
+            assert_eq!(online["mwha/status/zone/11/volume"], "20");
+            assert_eq!(online["mwha/status/zone/11/power"], "true");
+            assert_eq!(online.len(), 11);
+        }
+
+        // placeholders are off by default
+        let config = crate::config::tests::config_from_str(crate::config::tests::TEST_CONFIG);
+        assert_eq!(config.publish.offline_placeholder, OfflinePlaceholder::Keep);
+    }
+
+    #[test]
+    fn test_heartbeat_republish() {
+        let mut config = crate::config::tests::config_from_str(crate::config::tests::TEST_CONFIG);
+        config.publish.republish_interval = Duration::from_secs(60);
+
+        let (amp, _emu) = crate::amp::tests::emulated_amp(mwhaemu::emu::Amp::new(1), 1);
+
+        let (mut worker, _) = test_worker(&config, amp);
+
+        let statuses = worker.poll().unwrap();
+        let publish_count = |worker: &mut AmpWorker, complete, now| {
+            let count = worker.status_publishes(&statuses, complete, now).len();
+            for zone_status in &statuses {
+                worker.previous_statuses.insert(zone_status.zone_id, zone_status.clone());
+            }
+            count
+        };
+
+        let now = Instant::now();
+        let all = statuses.len() * 10;
+
+        assert_eq!(publish_count(&mut worker, true, now), all);
+
+        // unchanged values aren't republished until the heartbeat is due
+        assert_eq!(publish_count(&mut worker, true, now + Duration::from_secs(30)), 0);
+        assert_eq!(publish_count(&mut worker, true, now + Duration::from_secs(60)), all);
+        assert_eq!(publish_count(&mut worker, true, now + Duration::from_secs(90)), 0);
+
+        // a partial update (i.e. unsolicited status) doesn't consume the heartbeat, the next poll republishes every zone
+        assert_eq!(publish_count(&mut worker, false, now + Duration::from_secs(120)), 0);
+        assert_eq!(publish_count(&mut worker, true, now + Duration::from_secs(121)), all);
+
+        // disabled
+        let mut heartbeat = Heartbeat::new(Duration::ZERO);
+        assert!(!heartbeat.due(now));
+    }
+
+    #[test]
+    fn test_always_publish() {
+        let mut config = crate::config::tests::config_from_str(crate::config::tests::TEST_CONFIG);
+        config.amp.zones.get_mut(&STUDY).unwrap().always_publish = true;
+
+        let amp = MockAmp::with_zones(&[STUDY, LIVING_ROOM]);
+
+        let (mut worker, _) = test_worker(&config, amp);
+
+        let statuses = worker.poll().unwrap();
+        let published_zones = |worker: &mut AmpWorker| {
+            let zones = worker.status_publishes(&statuses, true, Instant::now()).into_iter()
+                .map(|(topic, _, class)| {
+                    assert_eq!(class, PublishClass::Status);
+                    topic.split('/').nth(3).unwrap().to_string()
+                })
+                .collect::<HashSet<_>>();
+            for zone_status in &statuses {
+                worker.previous_statuses.insert(zone_status.zone_id, zone_status.clone());
+            }
+            zones
+        };
+
+        assert_eq!(published_zones(&mut worker), HashSet::from(["11".to_string(), "12".to_string()]));
+
+        // unchanged values are only republished for the always-publish zone
+        assert_eq!(published_zones(&mut worker), HashSet::from(["11".to_string()]));
+        assert_eq!(published_zones(&mut worker), HashSet::from(["11".to_string()]));
+    }
+
+    #[test]
+    fn test_unpolled_zone() {
+        let mut config = crate::config::tests::config_from_str(crate::config::tests::TEST_CONFIG);
+        for zone in config.amp.zones.values_mut() {
+            zone.poll = false;
+        }
+        config.amp.skip_unchanged_sets = true;
+
+        let amp = MockAmp::with_zones(&[STUDY, LIVING_ROOM]);
+
+        let (mut worker, published) = test_worker(&config, amp.clone());
+
+        // the zones are never enquired, and publish nothing until set
+        worker.update(&[]);
+        assert!(amp.enquiries.lock().unwrap().is_empty());
+        assert!(published.take().is_empty());
+
+        // sets are written to the amp, and echoed as the zone status
+        worker.update(&[Adjustment { zone_id: LIVING_ROOM, attr: ZoneAttribute::Volume(30), force: false }]);
+        assert_eq!(amp.sets.lock().unwrap().clone(), vec![(LIVING_ROOM, ZoneAttribute::Volume(30))]);
+        assert_eq!(published.take(), vec![("mwha/status/zone/12/volume".to_string(), true, "30".to_string())]);
+        assert!(amp.enquiries.lock().unwrap().is_empty());
+
+        // an unchanged set is skipped (if configured), as for polled zones
+        worker.update(&[Adjustment { zone_id: LIVING_ROOM, attr: ZoneAttribute::Volume(30), force: false }]);
+        assert_eq!(amp.sets.lock().unwrap().len(), 1);
+
+        // polled zones on the same amp are still enquired, without the unpolled zone's status
+        config.amp.zones.get_mut(&STUDY).unwrap().poll = true;
+        let (mut worker, published) = test_worker(&config, amp.clone());
+
+        worker.update(&[]);
+        let enquiries = amp.enquiries.lock().unwrap().clone();
+        assert!(!enquiries.is_empty() && enquiries.iter().all(|id| *id == ZoneId::Amp(1)));
+        assert!(published.take().iter().all(|(topic, _, _)| !topic.starts_with("mwha/status/zone/12/")));
+    }
+
+    #[test]
+    fn test_poll_active_amps() {
+        const KITCHEN: ZoneId = ZoneId::Zone { amp: 2, zone: 1 };
+
+        let enquiries = |zones: &str, unpolled: &[ZoneId]| {
+            let mut config = crate::config::tests::config_from_str(&crate::config::tests::TEST_CONFIG.replace("12 = \"Living Room\"", zones));
+            for zone_id in unpolled {
+                config.amp.zones.get_mut(zone_id).unwrap().poll = false;
+            }
+
+            let amp = MockAmp::with_zones(&[]);
+
+            let (mut worker, _) = test_worker(&config, amp.clone());
+            worker.poll().unwrap();
+
+            let enquiries = amp.enquiries.lock().unwrap().clone();
+            enquiries
+        };
+
+        // a system enquiry covers every amp up to the highest configured, so is only used when they're all active
+        assert_eq!(enquiries("21 = \"Kitchen\"", &[]), vec![ZoneId::System]);
+        assert_eq!(enquiries("31 = \"Garage\"", &[]), vec![ZoneId::Amp(1), ZoneId::Amp(3)]);
+        assert_eq!(enquiries("21 = \"Kitchen\"", &[KITCHEN]), vec![ZoneId::Amp(1)]);
+        assert_eq!(enquiries("12 = \"Living Room\"", &[]), vec![ZoneId::Amp(1)]);
+    }
+
+    #[test]
+    fn test_worker_panic_is_detectable() {
+        let config = crate::config::tests::config_from_str(crate::config::tests::TEST_CONFIG);
+
+        let (amp, _emu) = crate::amp::tests::emulated_amp(mwhaemu::emu::Amp::new(1), 1);
+        let (mqtt, connection) = rumqttc::Client::new(rumqttc::MqttOptions::new("test", "localhost", 1883), 100);
+
+        // publishing fails without an event loop, panicking the worker on its first poll
+        drop(connection);
+
+        let (_send, recv) = crate::channel::control_channel(0, crate::config::ChannelOverflow::DropSuperseded);
+        let (panicked_send, panicked_recv) = crossbeam_channel::unbounded();
+
+        let worker = spawn_amp_worker(&config, amp, BacklogClient::new(mqtt, PublishBacklog::default()), "mwha/", recv, SharedZonesStatus::default(), WorkerHooks {
+            initial_sync: false,
+            apply_startup: false,
+            after_first_poll: None,
+            on_panic: move || panicked_send.send(()).unwrap()
+        });
+
+        assert!(panicked_recv.recv_timeout(Duration::from_secs(5)).is_ok());
+        assert!(worker.join().is_err());
+    }
+
+    #[test]
+    fn test_zone_enabled() {
+        let mut config = crate::config::tests::config_from_str(crate::config::tests::TEST_CONFIG);
+        config.publish.balance_trims = true;
+        config.publish.volume_percent = true;
+
+        let (amp, _emu) = crate::amp::tests::emulated_amp(mwhaemu::emu::Amp::new(1), 1);
+
+        let (mut worker, published) = test_worker(&config, amp);
+
+        let poll = |worker: &mut AmpWorker| {
+            let statuses = worker.poll().unwrap();
+            worker.publish_changes(&statuses, true);
+            for zone_status in &statuses {
+                worker.previous_statuses.insert(zone_status.zone_id, zone_status.clone());
+            }
+            published.take()
+        };
+
+        let study_topics = |published: &[(String, bool, String)]| published.iter()
+            .filter(|(topic, _, _)| topic.starts_with("mwha/status/zone/11/"))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let polled = study_topics(&poll(&mut worker)).len();
+        assert_eq!(polled, 13); // 10 attributes + balance trims + volume percent
+
+        // disabling clears the retained status topics, each once
+        worker.set_zone_enabled(STUDY, false);
+
+        let cleared = published.take();
+        assert!(cleared.iter().all(|(topic, retain, payload)| topic.starts_with("mwha/status/zone/11/") && *retain && payload.is_empty()));
+
+        let cleared_topics = cleared.iter().map(|(topic, _, _)| topic.as_str()).collect::<HashSet<_>>();
+        assert_eq!(cleared.len(), cleared_topics.len());
+        assert_eq!(cleared_topics.len(), 14); // 10 attributes + balance trims + volume percent + available
+        assert!(cleared_topics.contains("mwha/status/zone/11/volume-percent"));
+
+        // and stops further publishes
+        worker.heartbeat = Heartbeat::new(Duration::from_nanos(1));
+        assert!(study_topics(&poll(&mut worker)).is_empty());
+
+        // re-enabling resumes them (in full)
+        worker.heartbeat = Heartbeat::new(Duration::ZERO);
+        worker.set_zone_enabled(STUDY, true);
+        assert_eq!(study_topics(&poll(&mut worker)).len(), polled);
+        assert!(study_topics(&poll(&mut worker)).is_empty());
+
+        // unconfigured zones can't be enabled
+        worker.set_zone_enabled(ZoneId::Zone { amp: 1, zone: 3 }, true);
+        assert!(!worker.zone_ids.contains(&ZoneId::Zone { amp: 1, zone: 3 }));
+    }
+
+    #[test]
+    fn test_congestion_downgrades_diagnostics() {
+        let mut config = crate::config::tests::config_from_str(crate::config::tests::TEST_CONFIG);
+        config.publish.congestion_threshold = 10;
+        config.publish.keypad_events = true;
+
+        let (amp, emu) = crate::amp::tests::emulated_amp(mwhaemu::emu::Amp::new(1), 1);
+
+        let (mut worker, published) = test_worker(&config, amp);
+
+        let poll = |worker: &mut AmpWorker| {
+            let statuses = worker.poll().unwrap();
+            worker.publish_changes(&statuses, true);
+            worker.publish_keypad_events(&statuses);
+            for zone_status in &statuses {
+                worker.previous_statuses.insert(zone_status.zone_id, zone_status.clone());
+            }
+        };
+
+        let volume_topic = "mwha/status/zone/11/volume";
+        let power_topic = "mwha/status/zone/11/power";
+        let keypad_topic = "mwha/event/zone/11/keypad";
+
+        poll(&mut worker);
+
+        // the broker falls behind
+        published.set_backlog(50);
+
+        // changed values are authoritative status
+        emu.lock().unwrap().zone_set(STUDY, ZoneAttribute::Volume(20));
+        emu.lock().unwrap().zone_set(STUDY, ZoneAttribute::KeypadConnected(true));
+        poll(&mut worker);
+
+        assert_eq!(published.qos(volume_topic), Some(QoS::AtLeastOnce));
+        assert_eq!(published.qos(keypad_topic), Some(QoS::AtMostOnce));
+
+        // heartbeat republishes of unchanged values are diagnostic
+        worker.heartbeat = Heartbeat::new(Duration::from_nanos(1));
+        poll(&mut worker);
+
+        assert_eq!(published.qos(power_topic), Some(QoS::AtMostOnce));
+
+        // once the backlog clears, everything is AtLeastOnce again
+        published.set_backlog(0);
+        poll(&mut worker);
+
+        assert_eq!(published.qos(power_topic), Some(QoS::AtLeastOnce));
+
+        // disabled
+        let policy = CongestionPolicy::new(0);
+        assert_eq!(policy.qos(PublishClass::Diagnostic, 1000), QoS::AtLeastOnce);
+    }
+
+    #[test]
+    fn test_metadata_timing() {
+        let topics = |metadata| {
+            let mut config = crate::config::tests::config_from_str(crate::config::tests::TEST_CONFIG);
+            config.publish.metadata = metadata;
+
+            let (amp, _emu) = crate::amp::tests::emulated_amp(mwhaemu::emu::Amp::new(1), 1);
+
+            let (mut worker, published) = test_worker(&config, amp);
+
+            // as in `run`
+            let connected = crate::ConnectedStatus::default();
+            worker.startup(&config, crate::first_poll_metadata(published.clone(), &config, "mwha/", &connected), true, true);
+            if config.publish.metadata == MetadataTiming::Startup {
+                crate::publish_online(&mut published.clone(), &config, "mwha/", &connected).unwrap();
+            }
+
+            worker.update(&[]);
+
+            published.take().into_iter().map(|(topic, _, _)| topic).collect::<Vec<_>>()
+        };
+
+        let position = |topics: &[String], topic: &str| topics.iter().position(|t| t == topic).unwrap();
+
+        // metadata is published once the initial sync has published zone status
+        let topics_startup = topics(MetadataTiming::Startup);
+        assert!(position(&topics_startup, "mwha/connected") > position(&topics_startup, "mwha/status/zone/12/volume"));
+
+        // zone status is published before any metadata
+        let topics_first_poll = topics(MetadataTiming::FirstPoll);
+        assert!(position(&topics_first_poll, "mwha/connected") > position(&topics_first_poll, "mwha/status/zone/11/volume"));
+        assert!(position(&topics_first_poll, "mwha/status/zone/11/name") > position(&topics_first_poll, "mwha/status/zone/12/volume"));
+    }
+
+    #[test]
+    fn test_initial_sync() {
+        let config = crate::config::tests::config_from_str(crate::config::tests::TEST_CONFIG);
+
+        let amp = MockAmp::with_zones(&[STUDY, LIVING_ROOM]);
+        let published = Published::default();
+        let zones_status = SharedZonesStatus::default();
+
+        let mut worker = AmpWorker::new(&config.amp, &config.publish, Box::new(amp.clone()), Box::new(published.clone()), "mwha/", zones_status.clone());
+
+        // the amp isn't responding yet, the regular polls take over
+        amp.not_ready.store(true, Ordering::SeqCst);
+        worker.startup(&config, None, true, true);
+        assert!(!worker.amp_ready);
+        assert!(published.take().is_empty());
+
+        amp.not_ready.store(false, Ordering::SeqCst);
+        worker.startup(&config, None, true, true);
+        crate::publish_online(&mut published.clone(), &config, "mwha/", &crate::ConnectedStatus::default()).unwrap();
+
+        assert!(worker.amp_ready);
+        assert_eq!(zones_status.snapshot().len(), 2);
+
+        // every zone status topic is populated before `connected` goes online
+        let published = published.take();
+        let connected = published.iter().position(|(topic, _, payload)| topic == "mwha/connected" && payload == "2").unwrap();
+        let status_topics = published.iter().enumerate()
+            .filter(|(_, (topic, _, _))| topic.starts_with("mwha/status/zone/") && !topic.ends_with("/name"))
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+
+        assert_eq!(status_topics.len(), 2 * 11); // 10 attributes + available
+        assert!(status_topics.iter().all(|i| *i < connected));
+    }
+
+    #[test]
+    fn test_startup_state() {
+        let config = crate::config::tests::config_from_str(&crate::config::tests::TEST_CONFIG.replace("[shairport]", r#"[startup]
+            power = false
+            volume = 5
+            source = 1
+
+            [shairport]"#));
+
+        let startup = vec![ZoneAttribute::Power(false), ZoneAttribute::Volume(5), ZoneAttribute::Source(1)];
+        assert_eq!(config.startup.attributes(), startup);
+
+        let expected_sets = [STUDY, LIVING_ROOM].into_iter()
+            .flat_map(|zone_id| startup.iter().map(move |&attr| (zone_id, attr)))
+            .collect::<Vec<_>>();
+
+        // applied by the initial sync, including attributes already matching the amp (source 1), and published
+        let amp = MockAmp::with_zones(&[STUDY, LIVING_ROOM, ZoneId::Zone { amp: 1, zone: 3 }]);
+
+        let (mut worker, published) = test_worker(&config, amp.clone());
+        worker.startup(&config, None, true, true);
+
+        assert_eq!(*amp.sets.lock().unwrap(), expected_sets);
+
+        let publishes = published.take();
+        assert!(publishes.contains(&("mwha/status/zone/11/power".to_string(), true, "false".to_string())));
+        assert!(publishes.contains(&("mwha/status/zone/12/volume".to_string(), true, "5".to_string())));
+
+        // only once
+        worker.update(&[]);
+        assert_eq!(amp.sets.lock().unwrap().len(), expected_sets.len());
+
+        // without an initial sync, applied once the amp is ready
+        let amp = MockAmp::with_zones(&[STUDY, LIVING_ROOM]);
+        amp.not_ready.store(true, Ordering::SeqCst);
+
+        let (mut worker, _) = test_worker(&config, amp.clone());
+        worker.startup(&config, None, false, true);
+
+        worker.update(&[]);
+        assert!(amp.sets.lock().unwrap().is_empty());
+
+        amp.not_ready.store(false, Ordering::SeqCst);
+        worker.update(&[]);
+        assert_eq!(*amp.sets.lock().unwrap(), expected_sets);
+
+        // nothing configured, nothing applied
+        let config = crate::config::tests::config_from_str(crate::config::tests::TEST_CONFIG);
+        assert!(config.startup.attributes().is_empty());
+    }
+
+    #[test]
+    fn test_keypad_connect_state() {
+        let config = crate::config::tests::config_from_str(&crate::config::tests::TEST_CONFIG.replace("[shairport]", r#"[keypad_connect]
+            power = true
+            volume = 15
+
+            [shairport]"#));
+
+        assert_eq!(config.keypad_connect.attributes(), vec![ZoneAttribute::Power(true), ZoneAttribute::Volume(15)]);
+
+        let (amp, emu) = crate::amp::tests::emulated_amp(mwhaemu::emu::Amp::new(1), 1);
+
+        let (mut worker, _) = test_worker(&config, amp);
+        worker.startup(&config, None, false, false);
+
+        let study = |emu: &Arc<Mutex<mwhaemu::emu::Amp>>| {
+            let zone = emu.lock().unwrap().zones[&STUDY].clone();
+            (zone.power, zone.volume)
+        };
+
+        worker.update(&[]);
+        assert_eq!(study(&emu), (false, 0));
+
+        // applied on connect
+        emu.lock().unwrap().zone_set(STUDY, ZoneAttribute::KeypadConnected(true));
+        worker.update(&[]);
+        assert_eq!(study(&emu), (true, 15));
+
+        // exactly once, while the keypad stays connected
+        emu.lock().unwrap().zone_set(STUDY, ZoneAttribute::Volume(25));
+        worker.update(&[]);
+        worker.update(&[]);
+        assert_eq!(study(&emu), (true, 25));
+
+        // not on disconnect, but again on reconnect
+        emu.lock().unwrap().zone_set(STUDY, ZoneAttribute::Power(false));
+        emu.lock().unwrap().zone_set(STUDY, ZoneAttribute::KeypadConnected(false));
+        worker.update(&[]);
+        assert_eq!(study(&emu), (false, 25));
+
+        emu.lock().unwrap().zone_set(STUDY, ZoneAttribute::KeypadConnected(true));
+        worker.update(&[]);
+        assert_eq!(study(&emu), (true, 15));
+
+        // other zones are unaffected
+        assert_eq!(emu.lock().unwrap().zones[&LIVING_ROOM].volume, 0);
+    }
+
+    #[test]
+    fn test_mock_poll_and_publish() {
+        let config = crate::config::tests::config_from_str(crate::config::tests::TEST_CONFIG);
+        let amp = MockAmp::with_zones(&[STUDY, LIVING_ROOM, ZoneId::Zone { amp: 1, zone: 3 }]);
+
+        let (mut worker, published) = test_worker(&config, amp.clone());
+
+        // unconfigured zones aren't published
+        worker.update(&[]);
+        let topics = published.take().into_iter().map(|(topic, _, _)| topic).collect::<Vec<_>>();
+        assert_eq!(topics.iter().filter(|t| t.starts_with("mwha/status/zone/11/")).count(), 11); // 10 attributes + available
+        assert_eq!(topics.iter().filter(|t| t.starts_with("mwha/status/zone/12/")).count(), 11);
+        assert!(!topics.iter().any(|t| t.starts_with("mwha/status/zone/13/")));
+
+        // nothing changed
+        worker.update(&[]);
+        assert!(published.take().is_empty());
+
+        // only changes are published
+        amp.set(STUDY, ZoneAttribute::Treble(10));
+        worker.update(&[]);
+        assert_eq!(published.take(), vec![("mwha/status/zone/11/treble".to_string(), true, "10".to_string())]);
+    }
+
+    #[test]
+    fn test_topic_base() {
+        let config = crate::config::tests::config_from_str(crate::config::tests::TEST_CONFIG);
+
+        for (base, expected) in [("mwha/", "mwha/status/zone/11/volume"), ("home/audio/", "home/audio/status/zone/11/volume"), ("", "status/zone/11/volume")] {
+            let amp = MockAmp::with_zones(&[STUDY]);
+            let published = Published::default();
+
+            let mut worker = AmpWorker::new(&config.amp, &config.publish, Box::new(amp.clone()), Box::new(published.clone()), base, SharedZonesStatus::default());
+            worker.update(&[]);
+
+            let topics = published.take().into_iter().map(|(topic, _, _)| topic).collect::<Vec<_>>();
+            assert!(topics.contains(&expected.to_string()), "{:?}", topics);
+            assert!(topics.iter().all(|topic| topic.starts_with(&format!("{}status/", base))), "{:?}", topics);
+        }
+    }
+
+    #[test]
+    fn test_balance_trims() {
+        let mut config = crate::config::tests::config_from_str(crate::config::tests::TEST_CONFIG);
+        config.publish.balance_trims = true;
+
+        let amp = MockAmp::with_zones(&[STUDY]);
+
+        let (mut worker, published) = test_worker(&config, amp.clone());
+        worker.update(&[]);
+        published.take();
+
+        // trims are published alongside the combined balance
+        amp.set(STUDY, ZoneAttribute::Balance(7));
+        worker.update(&[]);
+        assert_eq!(published.take(), vec![
+            ("mwha/status/zone/11/balance".to_string(), true, "7".to_string()),
+            ("mwha/status/zone/11/balance-left".to_string(), true, "3".to_string()),
+            ("mwha/status/zone/11/balance-right".to_string(), true, "0".to_string()),
+        ]);
+
+        // a trim set maps to the combined balance
+        worker.update(&[Adjustment { zone_id: STUDY, attr: BalanceTrim::Right(4).to_balance(), force: false }]);
+        assert_eq!(published.take(), vec![
+            ("mwha/status/zone/11/balance".to_string(), true, "14".to_string()),
+            ("mwha/status/zone/11/balance-left".to_string(), true, "0".to_string()),
+            ("mwha/status/zone/11/balance-right".to_string(), true, "4".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn test_volume_percent() {
+        let mut config = crate::config::tests::config_from_str(crate::config::tests::TEST_CONFIG);
+        config.publish.volume_percent = true;
+
+        let amp = MockAmp::with_zones(&[STUDY]);
+
+        let (mut worker, published) = test_worker(&config, amp.clone());
+        worker.update(&[]);
+        published.take();
+
+        // the percentage is published alongside the raw volume
+        amp.set(STUDY, ZoneAttribute::Volume(19));
+        worker.update(&[]);
+        assert_eq!(published.take(), vec![
+            ("mwha/status/zone/11/volume".to_string(), true, "19".to_string()),
+            ("mwha/status/zone/11/volume-percent".to_string(), true, "50".to_string()),
+        ]);
+
+        // a percent set maps to the raw volume
+        worker.update(&[Adjustment { zone_id: STUDY, attr: VolumePercent(100).to_volume(), force: false }]);
+        assert_eq!(published.take(), vec![
+            ("mwha/status/zone/11/volume".to_string(), true, "38".to_string()),
+            ("mwha/status/zone/11/volume-percent".to_string(), true, "100".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn test_batch_adjustments() {
+        /// an amp that delivers the next set of a burst each time one is applied, i.e. the burst arrives faster than
+        /// the worker applies it
+        struct BurstAmp {
+            amp: MockAmp,
+            burst: Vec<AmpControlChannelMessage>,
+            send: crate::channel::ControlSender,
+        }
+
+        impl AmpController for BurstAmp {
+            fn zone_enquiry(&mut self, id: ZoneId) -> anyhow::Result<Vec<ZoneStatus>> {
+                self.amp.zone_enquiry(id)
+            }
+
+            fn set_zone_attribute(&mut self, id: ZoneId, attr: ZoneAttribute) -> anyhow::Result<()> {
+                if !self.burst.is_empty() {
+                    self.send.send(self.burst.remove(0));
+                }
+
+                self.amp.set_zone_attribute(id, attr)
+            }
+        }
+
+        for batch_adjustments in [true, false] {
+            let mut config = crate::config::tests::config_from_str(crate::config::tests::TEST_CONFIG);
+            config.amp.batch_adjustments = batch_adjustments;
+            config.amp.poll_interval = Duration::from_secs(60);
+
+            let amp = MockAmp::with_zones(&[STUDY]);
+            let (send, recv) = crate::channel::control_channel(0, crate::config::ChannelOverflow::DropSuperseded);
+
+            // a burst of 10 sets, the first already queued
+            let mut burst = (1..=10).map(|volume| AmpControlChannelMessage::ChangeZoneAttribute(STUDY, ZoneAttribute::Volume(volume))).collect::<Vec<_>>();
+            send.send(burst.remove(0));
+
+            let burst_amp = BurstAmp { amp: amp.clone(), burst, send: send.clone() };
+
+            let (mut worker, _) = test_worker(&config, burst_amp);
+            worker.update(&[]);
+            amp.enquiries.lock().unwrap().clear();
+
+            // run the worker until the burst has been applied
+            while amp.sets.lock().unwrap().len() < 10 {
+                let adjustments = worker.receive_adjustments(&recv).unwrap();
+
+                match worker.batch_adjustments {
+                    true => assert!(worker.update_batch(adjustments, &recv)),
+                    false => worker.update(&adjustments)
+                }
+            }
+
+            assert_eq!(amp.sets.lock().unwrap().last(), Some(&(STUDY, ZoneAttribute::Volume(10))));
+
+            // batched, the status is refreshed once. otherwise, after each set
+            let refreshes = amp.enquiries.lock().unwrap().len();
+            assert_eq!(refreshes, if batch_adjustments { 1 } else { 10 });
+        }
+    }
+
+    #[test]
+    fn test_commands_total() {
+        let mut config = crate::config::tests::config_from_str(crate::config::tests::TEST_CONFIG);
+        config.publish.commands_total = true;
+
+        let amp = MockAmp::with_zones(&[STUDY]);
+
+        let (mut worker, published) = test_worker(&config, amp.clone());
+
+        let commands_total = |published: &Published| published.take().into_iter()
+            .filter(|(topic, _, _)| topic == "mwha/status/diag/commands_total")
+            .map(|(_, retain, payload)| { assert!(retain); payload.parse::<u64>().unwrap() })
+            .next_back();
+
+        // the first update's poll finds the amp ready, and its status is published without polling again
+        worker.update(&[]);
+        let before = commands_total(&published).unwrap();
+        assert_eq!(before, 1);
+
+        // N sets, plus the poll
+        let sets = [ZoneAttribute::Volume(5), ZoneAttribute::Treble(3), ZoneAttribute::Bass(9)];
+        let adjustments = sets.iter().map(|&attr| Adjustment { zone_id: STUDY, attr, force: false }).collect::<Vec<_>>();
+
+        worker.update(&adjustments);
+        assert_eq!(commands_total(&published), Some(before + sets.len() as u64 + 1));
+    }
+
+    #[test]
+    fn test_retain_diagnostics() {
+        for retain_diagnostics in [true, false] {
+            let mut config = crate::config::tests::config_from_str(crate::config::tests::TEST_CONFIG);
+            config.publish.commands_total = true;
+            config.publish.retain_diagnostics = retain_diagnostics;
+
+            let amp = MockAmp::with_zones(&[STUDY]);
+
+            let (mut worker, published) = test_worker(&config, amp.clone());
+            worker.update(&[]);
+
+            let (diagnostics, status): (Vec<_>, Vec<_>) = published.take().into_iter()
+                .partition(|(topic, _, _)| topic.starts_with("mwha/status/diag/"));
+
+            assert!(!diagnostics.is_empty());
+            assert!(diagnostics.iter().all(|(_, retain, _)| *retain == retain_diagnostics));
+
+            // zone status is always retained
+            assert!(status.iter().any(|(topic, _, _)| topic.starts_with("mwha/status/zone/11/")));
+            assert!(status.iter().all(|(_, retain, _)| *retain));
+        }
+    }
+
+    #[test]
+    fn test_mock_coalesce_adjustments() {
+        let config = crate::config::tests::config_from_str(crate::config::tests::TEST_CONFIG);
+        let amp = MockAmp::with_zones(&[STUDY, LIVING_ROOM]);
+
+        let (mut worker, published) = test_worker(&config, amp.clone());
+
+        worker.update(&[]);
+        published.take();
+
+        // a burst of adjustments, queued faster than the worker can apply them
+        let (send, recv) = crate::channel::control_channel(0, crate::config::ChannelOverflow::DropSuperseded);
+        for volume in [5, 10, 15] {
+            send.send(AmpControlChannelMessage::ChangeZoneAttribute(STUDY, ZoneAttribute::Volume(volume)));
+        }
+        send.send(AmpControlChannelMessage::ChangeZoneAttribute(STUDY, ZoneAttribute::Mute(true)));
+
+        let adjustments = worker.receive_adjustments(&recv).unwrap();
+        worker.update(&adjustments);
+
+        // only the latest value of each attribute is applied
+        let mut sets = amp.sets.lock().unwrap().clone();
+        sets.sort_by_key(|(_, attr)| attr.to_string());
+        assert_eq!(sets, vec![(STUDY, ZoneAttribute::Mute(true)), (STUDY, ZoneAttribute::Volume(15))]);
+
+        let mut publishes = published.take();
+        publishes.sort();
+        assert_eq!(publishes, vec![
+            ("mwha/status/zone/11/mute".to_string(), true, "true".to_string()),
+            ("mwha/status/zone/11/volume".to_string(), true, "15".to_string()),
+        ]);
+
+        // adjustments that match the polled status aren't applied, if configured
+        worker.skip_unchanged_sets = true;
+        worker.update(&[Adjustment { zone_id: STUDY, attr: ZoneAttribute::Volume(15), force: false }]);
+        assert_eq!(amp.sets.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_settle_window() {
+        let mut config = crate::config::tests::config_from_str(crate::config::tests::TEST_CONFIG);
+        config.amp.settle_window = Duration::from_secs(60);
+
+        let amp = MockAmp::with_zones(&[STUDY]);
+
+        let (mut worker, published) = test_worker(&config, amp.clone());
+
+        worker.update(&[]);
+        published.take();
+
+        // the volume is set, but the hardware is still ramping up when polled
+        worker.apply_adjustments(&[Adjustment { zone_id: STUDY, attr: ZoneAttribute::Volume(30), force: false }]);
+        amp.set(STUDY, ZoneAttribute::Volume(25));
+        worker.update(&[]);
+
+        // the transient value isn't published, the set value is
+        assert_eq!(published.take(), vec![("mwha/status/zone/11/volume".to_string(), true, "30".to_string())]);
+
+        // settled
+        amp.set(STUDY, ZoneAttribute::Volume(30));
+        worker.update(&[]);
+        assert!(published.take().is_empty());
+
+        // once settled, polled values are trusted again (i.e. keypad adjustments)
+        amp.set(STUDY, ZoneAttribute::Volume(10));
+        worker.update(&[]);
+        assert_eq!(published.take(), vec![("mwha/status/zone/11/volume".to_string(), true, "10".to_string())]);
+
+        // polled values are trusted once the window elapses, even if they never settle
+        let now = Instant::now();
+        let mut settle = SettleWindow::new(Duration::from_secs(1));
+        settle.attribute_set(STUDY, ZoneAttribute::Volume(30), now);
+
+        let poll = |settle: &mut SettleWindow, now| {
+            let mut statuses = vec![ZoneStatus { zone_id: STUDY, attributes: vec![ZoneAttribute::Volume(25)] }];
+            settle.apply(&mut statuses, now);
+            statuses[0].get(ZoneAttributeDiscriminants::Volume)
+        };
+
+        assert_eq!(poll(&mut settle, now), Some(ZoneAttribute::Volume(30)));
+        assert_eq!(poll(&mut settle, now + Duration::from_secs(2)), Some(ZoneAttribute::Volume(25)));
+    }
+
+    #[test]
+    fn test_unsolicited_status() {
+        let mut config = crate::config::tests::config_from_str(&crate::config::tests::TEST_CONFIG.replace("[shairport]", "[publish]\nlast_error = true\n[shairport]"));
+        config.amp.unsolicited_status = true;
+
+        let amp = MockAmp::with_zones(&[STUDY, LIVING_ROOM]);
+        let published = Published::default();
+        let zones_status = SharedZonesStatus::default();
+
+        let mut worker = AmpWorker::new(&config.amp, &config.publish, Box::new(amp.clone()), Box::new(published.clone()), "mwha/", zones_status.clone());
+
+        worker.update(&[]);
+        published.take();
+
+        // the amp reports a change between polls
+        let mut status = amp.zones.lock().unwrap()[&STUDY].clone();
+        status.attributes.retain(|a| !matches!(a, ZoneAttribute::Volume(_)));
+        status.attributes.push(ZoneAttribute::Volume(33));
+        amp.unsolicited.lock().unwrap().push(status);
+
+        let (_send, recv) = crate::channel::control_channel(0, crate::config::ChannelOverflow::DropSuperseded);
+        assert!(worker.receive_adjustments(&recv).unwrap().is_empty());
+
+        // the cache is updated, without dropping the other zones
+        let snapshot = zones_status.snapshot();
+        assert_eq!(snapshot.len(), 2);
+        assert!(snapshot.iter().any(|z| z.zone_id == STUDY && z.matches(ZoneAttribute::Volume(33))));
+        assert!(snapshot.iter().any(|z| z.zone_id == LIVING_ROOM));
+
+        assert_eq!(published.take(), vec![("mwha/status/zone/11/volume".to_string(), true, "33".to_string())]);
+
+        // a failed read is published as an error, and the worker falls back to polling
+        amp.not_ready.store(true, Ordering::SeqCst);
+        assert!(worker.receive_adjustments(&recv).unwrap().is_empty());
+        assert!(published.take().iter().any(|(topic, _, _)| topic == "mwha/status/last-error"));
+    }
+
+    #[test]
+    fn test_readonly() {
+        let mut config = crate::config::tests::config_from_str(crate::config::tests::TEST_CONFIG);
+        config.amp.readonly = true;
+
+        let amp = MockAmp::with_zones(&[STUDY]);
+
+        let (mut worker, published) = test_worker(&config, amp.clone());
+
+        worker.update(&[]);
+        published.take();
+
+        let (send, recv) = crate::channel::control_channel(0, crate::config::ChannelOverflow::DropSuperseded);
+        send.send(AmpControlChannelMessage::ChangeZoneAttribute(STUDY, ZoneAttribute::Volume(15)));
+        send.send(AmpControlChannelMessage::ForceZoneAttribute(STUDY, ZoneAttribute::Power(false)));
+
+        let adjustments = worker.receive_adjustments(&recv).unwrap();
+        worker.update(&adjustments);
+
+        // nothing is written to the amp, but polling continues
+        assert!(amp.sets.lock().unwrap().is_empty());
+        assert!(published.take().is_empty());
+
+        amp.set(STUDY, ZoneAttribute::Volume(25));
+        worker.update(&[]);
+        assert_eq!(published.take(), vec![("mwha/status/zone/11/volume".to_string(), true, "25".to_string())]);
+    }
+
+    #[test]
+    fn test_early_adjustments_buffered() {
+        let config = crate::config::tests::config_from_str(crate::config::tests::TEST_CONFIG);
+
+        let amp = MockAmp::with_zones(&[STUDY]);
+        amp.not_ready.store(true, Ordering::SeqCst);
+
+        let (mut worker, published) = test_worker(&config, amp.clone());
+
+        // a retained set is delivered while the amp is still connecting, and then repeatedly changed
+        worker.update(&[Adjustment { zone_id: STUDY, attr: ZoneAttribute::Volume(30), force: false }]);
+        worker.update(&[Adjustment { zone_id: STUDY, attr: ZoneAttribute::Mute(true), force: false }]);
+
+        for volume in 0..100 {
+            worker.update(&[Adjustment { zone_id: STUDY, attr: ZoneAttribute::Volume(volume % 30 + 1), force: false }]);
+        }
+
+        assert!(amp.sets.lock().unwrap().is_empty());
+        assert!(published.take().is_empty());
+
+        // only the latest adjustment of each zone attribute is kept
+        assert_eq!(worker.early_adjustments.len(), 2);
+
+        // once the amp responds, the buffered adjustments are applied in order
+        amp.not_ready.store(false, Ordering::SeqCst);
+        worker.update(&[]);
+
+        assert_eq!(*amp.sets.lock().unwrap(), vec![(STUDY, ZoneAttribute::Volume(10)), (STUDY, ZoneAttribute::Mute(true))]);
+
+        let snapshot = worker.zones_status.snapshot();
+        assert!(snapshot[0].matches(ZoneAttribute::Volume(10)) && snapshot[0].matches(ZoneAttribute::Mute(true)));
+    }
+
+    #[test]
+    fn test_emulated_set() {
+        let config = crate::config::tests::config_from_str(crate::config::tests::TEST_CONFIG);
+        let (amp, emu) = crate::amp::tests::emulated_amp(mwhaemu::emu::Amp::new(1), 1);
+
+        let (mut worker, _) = test_worker(&config, amp);
+
+        worker.update(&[
+            Adjustment { zone_id: STUDY, attr: ZoneAttribute::Volume(25), force: false },
+            Adjustment { zone_id: STUDY, attr: ZoneAttribute::Power(true), force: false },
+        ]);
+
+        let state = emu.lock().unwrap().dump();
+        assert_eq!(state["zones"]["11"]["volume"], 25);
+        assert_eq!(state["zones"]["11"]["power"], true);
+        assert_eq!(state["zones"]["12"]["volume"], 0);
+    }
+
+    #[test]
+    fn test_watchdog_restarts_wedged_worker() {
+        let mut config = crate::config::tests::config_from_str(crate::config::tests::TEST_CONFIG);
+        config.amp.poll_interval = Duration::from_millis(10);
+
+        let (send, recv) = crate::channel::control_channel(0, crate::config::ChannelOverflow::DropSuperseded);
+
+        let spawn = {
+            let config = config.clone();
+            let recv = recv.clone();
+
+            move |amp: &MockAmp| AmpWorker::new(&config.amp, &config.publish, Box::new(amp.clone()), Box::new(Published::default()), "mwha/", SharedZonesStatus::default())
+                .spawn(recv.clone(), || {})
+        };
+
+        let wedged_amp = MockAmp::with_zones(&[STUDY]);
+        let replacement_amp = MockAmp::with_zones(&[STUDY]);
+
+        let worker = spawn(&wedged_amp);
+        wedged_amp.wedged.store(true, Ordering::SeqCst);
+
+        let (restarted_send, restarted_recv) = crossbeam_channel::unbounded();
+
+        let watchdog = Watchdog::spawn(worker, Duration::from_millis(100), {
+            let replacement_amp = replacement_amp.clone();
+
+            move |stalled| {
+                restarted_send.send(stalled).unwrap();
+                Ok(spawn(&replacement_amp))
+            }
+        });
+
+        let stalled = restarted_recv.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(stalled >= Duration::from_millis(100));
+
+        // the replacement worker takes over
+        send.send(AmpControlChannelMessage::ChangeZoneAttribute(STUDY, ZoneAttribute::Volume(30)));
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while replacement_amp.sets.lock().unwrap().is_empty() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(*replacement_amp.sets.lock().unwrap(), vec![(STUDY, ZoneAttribute::Volume(30))]);
+
+        // only restarted once, as the replacement keeps polling
+        let worker = watchdog.stop();
+        assert!(restarted_recv.try_recv().is_err());
+
+        // the retired worker stops once unwedged, without acting on anything
+        wedged_amp.wedged.store(false, Ordering::SeqCst);
+        std::thread::sleep(Duration::from_millis(50));
+
+        send.send(AmpControlChannelMessage::Poison);
+        worker.join().unwrap();
+
+        assert!(wedged_amp.sets.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_watchdog_releases_wedged_worker_port() {
+        let mut config = crate::config::tests::config_from_str(crate::config::tests::TEST_CONFIG);
+        config.amp.poll_interval = Duration::from_millis(10);
+
+        let (mqtt, _connection) = rumqttc::Client::new(rumqttc::MqttOptions::new("test", "localhost", 1883), 100);
+        let (send, recv) = crate::channel::control_channel(0, crate::config::ChannelOverflow::DropSuperseded);
+
+        let spawn = move |amp: MockAmp| spawn_amp_worker(&config, amp, BacklogClient::new(mqtt.clone(), PublishBacklog::default()), "mwha/", recv.clone(), SharedZonesStatus::default(), WorkerHooks {
+            initial_sync: false,
+            apply_startup: false,
+            after_first_poll: None,
+            on_panic: || {}
+        });
+
+        let port = ExclusivePort::default();
+
+        let mut wedged_amp = MockAmp::with_zones(&[STUDY]);
+        port.open(&mut wedged_amp).unwrap();
+        wedged_amp.wedged.store(true, Ordering::SeqCst);
+
+        let worker = spawn(wedged_amp.clone());
+
+        // the wedged worker holds the port open
+        assert!(port.open(&mut MockAmp::default()).is_err());
+
+        let (restarted_send, restarted_recv) = crossbeam_channel::unbounded();
+
+        let watchdog = Watchdog::spawn(worker, Duration::from_millis(100), {
+            let port = port.clone();
+            let spawn = spawn.clone();
+
+            move |_stalled| {
+                let mut amp = MockAmp::with_zones(&[STUDY]);
+                port.open(&mut amp)?;
+
+                restarted_send.send(()).unwrap();
+                Ok(spawn(amp))
+            }
+        });
+
+        // the port is released before the replacement opens it
+        assert!(restarted_recv.recv_timeout(Duration::from_secs(5)).is_ok());
+
+        let worker = watchdog.stop();
+
+        wedged_amp.wedged.store(false, Ordering::SeqCst);
+        send.send(AmpControlChannelMessage::Poison);
+        worker.join().unwrap();
+    }
+
+    #[test]
+    fn test_reject_disabled_source_selects() {
+        let config = crate::config::tests::TEST_CONFIG.replace("[amp.sources]", "[amp.sources]\n2 = { name = \"Unused\", enabled = false }");
+        let mut config = crate::config::tests::config_from_str(&config);
+
+        let select = |config: &Config, source| {
+            let amp = MockAmp::with_zones(&[STUDY]);
+
+            let (mut worker, _) = test_worker(config, amp.clone());
+            worker.update(&[Adjustment { zone_id: STUDY, attr: ZoneAttribute::Source(source), force: true }]);
+
+            let sets = amp.sets.lock().unwrap().clone();
+            sets
+        };
+
+        // disabled sources can still be selected by default
+        assert_eq!(select(&config, 2), vec![(STUDY, ZoneAttribute::Source(2))]);
+
+        config.amp.reject_disabled_source_selects = true;
+        assert!(select(&config, 2).is_empty());
+        assert_eq!(select(&config, 3), vec![(STUDY, ZoneAttribute::Source(3))]);
+    }
+
+    #[test]
+    fn test_source_count() {
+        let mut config = crate::config::tests::config_from_str(crate::config::tests::TEST_CONFIG);
+        config.amp.source_count = 3;
+
+        let amp = MockAmp::with_zones(&[STUDY]);
+
+        let (mut worker, _) = test_worker(&config, amp.clone());
+        worker.update(&[
+            Adjustment { zone_id: STUDY, attr: ZoneAttribute::Source(4), force: true },
+            Adjustment { zone_id: STUDY, attr: ZoneAttribute::Source(3), force: true },
+        ]);
+
+        // selects above the source count are rejected
+        assert_eq!(amp.sets.lock().unwrap().clone(), vec![(STUDY, ZoneAttribute::Source(3))]);
+    }
+
+    #[test]
+    fn test_set_rate_limiter() {
+        let now = Instant::now();
+        let limiter = SetRateLimiter::new(5);
+
+        // a burst beyond the limit is dropped
+        let allowed = (0..20).filter(|_| limiter.allow(STUDY, now)).count();
+        assert_eq!(allowed, 5);
+
+        // other zones have their own limit
+        assert!(limiter.allow(LIVING_ROOM, now));
+
+        // the limit resets each second
+        assert!(!limiter.allow(STUDY, now + Duration::from_millis(999)));
+        assert!(limiter.allow(STUDY, now + Duration::from_secs(1)));
+
+        // unlimited
+        let limiter = SetRateLimiter::new(0);
+        assert!((0..1000).all(|_| limiter.allow(STUDY, now)));
+    }
+
+    #[test]
+    fn test_last_error() {
+        const TOPIC: &str = "mwha/status/last-error";
+
+        let config = crate::config::tests::config_from_str(&crate::config::tests::TEST_CONFIG.replace("[shairport]", "[publish]\nlast_error = true\n[shairport]"));
+
+        let amp = MockAmp::with_zones(&[STUDY]);
+
+        let (mut worker, published) = test_worker(&config, amp.clone());
+
+        let last_error = |publishes: &[(String, bool, String)]| publishes.iter()
+            .filter(|(topic, _, _)| topic == TOPIC)
+            .map(|(_, retain, payload)| (*retain, payload.clone()))
+            .collect::<Vec<_>>();
+
+        // a stale error from a previous run is cleared by the first successful poll
+        worker.update(&[]);
+        assert_eq!(last_error(&published.take()), vec![(true, String::new())]);
+
+        // poll failures are published, retained, with a timestamp
+        amp.not_ready.store(true, Ordering::SeqCst);
+        worker.update(&[]);
+
+        let errors = last_error(&published.take());
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].0);
+
+        let error: Value = serde_json::from_str(&errors[0].1).unwrap();
+        assert!(error["error"].as_str().unwrap().contains("timed out"));
+        assert!(humantime::parse_rfc3339(error["timestamp"].as_str().unwrap()).is_ok());
+
+        // and cleared once the amp responds again, only once
+        amp.not_ready.store(false, Ordering::SeqCst);
+        worker.update(&[]);
+        assert_eq!(last_error(&published.take()), vec![(true, String::new())]);
+
+        worker.update(&[]);
+        assert!(last_error(&published.take()).is_empty());
+
+        // disabled by default
+        let config = crate::config::tests::config_from_str(crate::config::tests::TEST_CONFIG);
+        let (mut worker, published) = test_worker(&config, amp.clone());
+
+        amp.not_ready.store(true, Ordering::SeqCst);
+        worker.update(&[]);
+        amp.not_ready.store(false, Ordering::SeqCst);
+        worker.update(&[]);
+        assert!(last_error(&published.take()).is_empty());
+    }
+
+    #[test]
+    fn test_set_error() {
+        let config = crate::config::tests::config_from_str(&crate::config::tests::TEST_CONFIG.replace("[shairport]", "[publish]\nlast_error = true\n[shairport]"));
+
+        let amp = MockAmp::with_zones(&[STUDY]);
+
+        let (mut worker, published) = test_worker(&config, amp.clone());
+
+        worker.update(&[]);
+        published.take();
+
+        let last_errors = |publishes: Vec<(String, bool, String)>| publishes.into_iter()
+            .filter(|(topic, _, _)| topic == "mwha/status/last-error")
+            .map(|(_, _, payload)| payload)
+            .collect::<Vec<_>>();
+
+        // a failed set is published (and cleared by the following successful poll), rather than stopping the worker
+        worker.update(&[Adjustment { zone_id: STUDY, attr: ZoneAttribute::Volume(99), force: false }]);
+
+        let errors = last_errors(published.take());
+        assert_eq!(errors.len(), 2);
+        assert!(serde_json::from_str::<Value>(&errors[0]).unwrap()["error"].as_str().unwrap().contains("failed to set 11 = Volume(99)"), "{}", errors[0]);
+        assert_eq!(errors[1], "");
+
+        assert!(amp.sets.lock().unwrap().is_empty());
+
+        // and later sets still apply
+        worker.update(&[Adjustment { zone_id: STUDY, attr: ZoneAttribute::Volume(30), force: false }]);
+        assert_eq!(*amp.sets.lock().unwrap(), vec![(STUDY, ZoneAttribute::Volume(30))]);
+        assert!(last_errors(published.take()).is_empty());
+    }
+}
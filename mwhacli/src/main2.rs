@@ -4,6 +4,7 @@ use std::sync::mpsc::channel;
 
 use client::Client;
 use common::mqtt::{MqttConfig, MqttConnectionManager};
+use common::zone::ZoneTopicFormat;
 use anyhow::Result;
 use anyhow::Context;
 use simplelog::LevelFilter;
@@ -41,6 +42,8 @@ fn main() -> Result<()> {
         ca_certs: None,
         client_certs: None,
         client_key: None,
+        connect_retries: 0,
+        connect_backoff: std::time::Duration::from_secs(1),
     };
 
     println!("Connecting to MQTT");
@@ -51,9 +54,9 @@ fn main() -> Result<()> {
 
     let (updates_send, updates_recv) = crossbeam_channel::unbounded();
 
-    let client = Client::new();
+    let client = Client::new(&topic_base, ZoneTopicFormat::default());
     println!("Subscribing");
-    client.setup_status_handlers(mqtt_cm, updates_send);
+    client.setup_status_handlers(mqtt_cm, updates_send)?;
 
     loop {
         let update = updates_recv.recv()?;
@@ -0,0 +1,448 @@
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use common::zone::{ZoneAttribute, ZoneAttributeDiscriminants, ZoneId};
+
+
+pub mod emu {
+    use common::zone::MAX_ZONES_PER_AMP;
+
+    use super::*;
+    use std::{collections::{BTreeMap, HashMap}, io::{Read, Write}, str};
+
+    use serde::Serialize;
+    use serde_json::{Value, json};
+
+    #[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+    pub struct Zone {
+        pub public_announcement: bool,
+        pub power: bool,
+        pub mute: bool,
+        pub do_not_disturb: bool,
+        pub volume: u8,
+        pub treble: u8,
+        pub bass: u8,
+        pub balance: u8,
+        pub source: u8,
+        pub keypad_connected: bool
+    }
+
+    impl Default for Zone {
+        fn default() -> Self {
+            Self {
+                public_announcement: false,
+                power: false,
+                mute: false,
+                do_not_disturb: false,
+                volume: 0,
+                treble: 7,
+                bass: 7,
+                balance: 10,
+                source: 1,
+                keypad_connected:false
+            }
+        }
+    }
+
+    impl Zone {
+        fn set(&mut self, attribute: ZoneAttribute) {
+            match attribute {
+                ZoneAttribute::PublicAnnouncement(b) => self.public_announcement = b,
+                ZoneAttribute::Power(b) => self.power = b,
+                ZoneAttribute::Mute(b) => self.mute = b,
+                ZoneAttribute::DoNotDisturb(b) => self.do_not_disturb = b,
+                ZoneAttribute::Volume(v) => self.volume = v,
+                ZoneAttribute::Treble(v) => self.treble = v,
+                ZoneAttribute::Bass(v) => self.bass = v,
+                ZoneAttribute::Balance(v) => self.balance = v,
+                ZoneAttribute::Source(v) => self.source = v,
+                ZoneAttribute::KeypadConnected(b) => self.keypad_connected = b,
+            }
+        }
+    }
+
+    pub struct Amp {
+        pub zones: HashMap<ZoneId, Zone>,
+
+        /// whether the system zone enquiry (`?00`, all zones on all amps) is supported
+        pub system_enquiry: bool
+    }
+
+    impl Amp {
+        pub fn new(amps: u8) -> Self {
+            // create the zones -- 6 zones per amp
+            let mut zones = Vec::with_capacity((amps * 6).into());
+            {
+                for amp in 1..=amps {
+                    for zone in 1..=MAX_ZONES_PER_AMP {
+                        zones.push((ZoneId::Zone { amp, zone }, Zone::default()))
+                    }
+                }
+            }
+            
+            Self {
+                zones: zones.into_iter().collect(),
+                system_enquiry: false
+            }
+        }
+    
+        /// set the attributes of one or more zones. nop if a zone doesn't exist.
+        pub fn zone_set(&mut self, zone: ZoneId, attribute: ZoneAttribute) {
+            for zone in zone.to_zones() {
+                if let Some(zone) = self.zones.get_mut(&zone) {
+                    zone.set(attribute)
+                }
+            }
+        }
+
+        /// get the staus of one or more zones. nop if a zone doesn't exist.
+        pub fn zone_enquiry(&mut self, zone: ZoneId) -> Vec<(ZoneId, &Zone)> {
+            zone.to_zones().into_iter().filter_map(|id| {
+                self.zones.get(&id).map(|zone| (id, zone))
+            }).collect()
+        }
+    
+        pub fn set_pa_state(&mut self, pa: bool) {
+            for zone in self.zones.values_mut() {
+                zone.public_announcement = pa;
+            } 
+        }
+
+        /// the full state of the emulated amp(s) as JSON, with zones keyed by zone id (i.e. for test assertions)
+        pub fn dump(&self) -> Value {
+            let zones = self.zones.iter()
+                .map(|(id, zone)| (id.to_string(), zone))
+                .collect::<BTreeMap<_, _>>();
+
+            json!({
+                "system_enquiry": self.system_enquiry,
+                "zones": zones
+            })
+        }
+    }
+}
+
+
+pub mod serial {
+    use super::*;
+
+    use anyhow::{Context, bail};
+
+    use regex::Regex;
+
+    use std::{io::{Read, Write}, str};
+
+    pub fn run<S: Read + Write>(amp: Arc<Mutex<emu::Amp>>, mut stream: S) -> Result<()> {
+        enum Command {
+            ZoneEnquriry(ZoneId),
+            ZoneAttributeEnquiry(ZoneId, ZoneAttributeDiscriminants),
+            ZoneSet(ZoneId, ZoneAttribute),
+            /// the stream has no baud rate, so this is only acknowledged
+            BaudSet(u32)
+        }
+
+        const BAUD_RATES: &[u32] = &[9600, 19200, 38400, 57600, 115200, 230400];
+
+        fn parse_command(buffer: &[u8]) -> Result<Option<Command>> {
+            let cmd = str::from_utf8(buffer)?.to_uppercase();
+
+            if cmd.len() == 0 { return Ok(None) }
+
+            // TODO: convert to static
+            let zone_enquiry_re = Regex::new(r"^\?(\d\d)$").unwrap();
+            let zone_attr_enquiry_re = Regex::new(r"\?(\d\d)(\w\w)").unwrap();
+            let zone_set_re = Regex::new(r"<(\d\d)(\w\w)(\d\d)").unwrap();
+            let baud_set_re = Regex::new(r"^<(\d+)$").unwrap();
+
+            macro_rules! capture_group {
+                ( $captures:ident, $i:expr ) => {
+                    $captures.get($i).expect(concat!("capture group ", $i)).as_str()
+                }
+            }
+
+            fn zone_id(captures: &regex::Captures, allow_system: bool) -> Result<ZoneId> {
+                let zone = capture_group!(captures, 1)
+                    .parse().context("expected a valid zone id")?;
+
+                if let (ZoneId::System, false) = (zone, allow_system) {
+                    bail!("system zone not supported")
+                }
+
+                Ok(zone)
+            }
+
+            let cmd = if let Some(captures) = zone_enquiry_re.captures(&cmd) {
+                // zone enquiry (system zone support is optional, and checked when the command is handled)
+                let zone = zone_id(&captures, true)?;
+
+                Command::ZoneEnquriry(zone)
+
+            } else if let Some(captures) = zone_attr_enquiry_re.captures(&cmd) {
+                // zone attribute enquiry
+                let zone = zone_id(&captures, false)?;
+
+                let attr = match ZoneAttributeDiscriminants::from_protocol_code(capture_group!(captures, 2)) {
+                    Some(attr) => attr,
+                    None => return Ok(None) // unknown attribute results in a nop
+                };
+
+                Command::ZoneAttributeEnquiry(zone, attr)
+
+            } else if let Some(captures) = baud_set_re.captures(&cmd) {
+                // baud set (checked before zone sets, which an all-digit baud rate would otherwise match)
+                let baud: u32 = capture_group!(captures, 1)
+                    .parse().context("expected a valid baud rate")?;
+
+                if !BAUD_RATES.contains(&baud) {
+                    bail!("unsupported baud rate: {}", baud)
+                }
+
+                Command::BaudSet(baud)
+
+            } else if let Some(captures) = zone_set_re.captures(&cmd) {
+                // zone set
+                let zone = zone_id(&captures, false)?;
+
+                let attr = capture_group!(captures, 2);
+
+                let value: u8 = capture_group!(captures, 3)
+                    .parse().context("expected a valid value")?;
+
+                let attr = match attr {
+                    "PR" | "MU" | "DT" => {
+                        let value = match value {
+                            0 => false,
+                            1 => true,
+                            _ => return Ok(None) // invalid bool results in a nop
+                        };
+
+                        match attr {
+                            "PR" => ZoneAttribute::Power(value),
+                            "MU" => ZoneAttribute::Mute(value),
+                            "DT" => ZoneAttribute::DoNotDisturb(value),
+                            _ => unreachable!()
+                        }
+                    },
+                    "VO" => ZoneAttribute::Volume(value),
+                    "TR" => ZoneAttribute::Treble(value),
+                    "BS" => ZoneAttribute::Bass(value),
+                    "BL" => ZoneAttribute::Balance(value),
+                    "CH" => ZoneAttribute::Source(value),
+                    _ => return Ok(None) // unknown attribute results in a nop
+                };
+
+                if let Err(err) = attr.validate() {
+                    // out of range values result in a nop
+                    log::warn!("serial command \"{}\": warning: {}. nop.", cmd, err);
+                    return Ok(None)
+                }
+
+                Command::ZoneSet(zone, attr)
+
+            } else {
+                bail!("unknown command: {}", cmd)
+            };
+
+            Ok(Some(cmd))
+        }
+        
+        let mut cmd_buffer = Vec::with_capacity(256);
+
+        // commands are at most this many bytes (excluding the CR)
+        const MAX_COMMAND_LEN: usize = 70;
+
+        loop {
+            // the rest of an over-long command is echoed but discarded, and the command results in a command error
+            let mut overflowed = false;
+
+            loop {
+                let mut ch = [0; 1];
+                let n = stream.read(&mut ch)?;
+
+                if n == 0 {
+                    return Ok(());
+                }
+
+                match ch[0] {
+                    // printable ASCII
+                    0x20..=0x7F => {
+                        // echo the byte back and append to buffer
+                        stream.write_all(&ch)?; 
+
+                        if cmd_buffer.len() == MAX_COMMAND_LEN {
+                            overflowed = true;
+                        } else {
+                            cmd_buffer.extend_from_slice(&ch);
+                        }
+                    },
+
+                    // Backspace, delete a byte from the cmd buffer and write control chars
+                    0x08 if !cmd_buffer.is_empty() => {
+                        stream.write_all(b"\x08\x20\x08")?;
+                        cmd_buffer.pop();
+                    }
+
+                    // CR
+                    0x0D => break, // handle command
+
+                    // ESC
+                    0x1B => {
+                        // clear the cmd buffer and handle (will result in a nop)
+                        cmd_buffer.clear();
+                        break
+                    }
+
+                    _ => {}  // ignore
+                }
+            }
+
+            {
+                let mut amp = amp.lock().unwrap();
+
+                let cmd = match overflowed {
+                    true => Err(anyhow::anyhow!("command longer than {} bytes", MAX_COMMAND_LEN)),
+                    false => parse_command(&cmd_buffer).and_then(|cmd| match cmd {
+                        Some(Command::ZoneEnquriry(ZoneId::System)) if !amp.system_enquiry => bail!("system zone enquiry not supported"),
+                        cmd => Ok(cmd)
+                    })
+                };
+
+                match cmd {
+                    Ok(cmd) => {
+                        match cmd {
+                            Some(Command::ZoneEnquriry(zone)) => {
+                                for (id, zone) in amp.zone_enquiry(zone) {
+                                    write!(stream, "\r\n#>{}{:02}{:02}{:02}{:02}{:02}{:02}{:02}{:02}{:02}{:02}",
+                                        id,
+                                        zone.public_announcement as u8,
+                                        zone.power as u8,
+                                        zone.mute as u8,
+                                        zone.do_not_disturb as u8,
+                                        zone.volume,
+                                        zone.treble,
+                                        zone.bass,
+                                        zone.balance,
+                                        zone.source,
+                                        zone.keypad_connected as u8
+                                    )?
+                                }
+                            },
+                            Some(Command::ZoneAttributeEnquiry(zone, attr)) => {
+                                for (id, zone) in amp.zone_enquiry(zone) {
+                                    let value = match attr {
+                                        ZoneAttributeDiscriminants::PublicAnnouncement => zone.public_announcement as u8,
+                                        ZoneAttributeDiscriminants::Power => zone.power as u8,
+                                        ZoneAttributeDiscriminants::Mute => zone.mute as u8,
+                                        ZoneAttributeDiscriminants::DoNotDisturb => zone.do_not_disturb as u8,
+                                        ZoneAttributeDiscriminants::Volume => zone.volume,
+                                        ZoneAttributeDiscriminants::Treble => zone.treble,
+                                        ZoneAttributeDiscriminants::Bass => zone.bass,
+                                        ZoneAttributeDiscriminants::Balance => zone.balance,
+                                        ZoneAttributeDiscriminants::Source => zone.source,
+                                        ZoneAttributeDiscriminants::KeypadConnected => zone.keypad_connected as u8,
+                                    };
+
+                                    write!(stream, "\r\n#>{}{}{:02}", id, attr.protocol_code(), value)?;
+                                }
+                            }
+                            Some(Command::ZoneSet(zone, attribute)) => {
+                                amp.zone_set(zone, attribute)
+                            },
+                            Some(Command::BaudSet(baud)) => {
+                                log::info!("serial baud rate set to {}", baud);
+                                stream.write_all(b"\r\n#Done.")?;
+                            },
+                            None => {}
+                        }
+                    },
+                    Err(err) => {
+                        let cmd = common::payload::preview_payload(&cmd_buffer, cmd_buffer.len());
+                        println!("serial command \"{}\": error: {:#}", cmd, err);
+                        
+                        stream.write_all(b"\r\n#\r\nCommand Error.")?;
+                    }
+                };
+            }
+
+            cmd_buffer.clear();
+
+            stream.write_all(b"\r\n#")?;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read, Write};
+
+    use super::*;
+
+    /// a stream that reads from a fixed input and records everything written
+    struct TestStream {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for TestStream {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for TestStream {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn run(input: &[u8]) -> Vec<u8> {
+        let mut stream = TestStream { input: Cursor::new(input.to_vec()), output: Vec::new() };
+
+        serial::run(Arc::new(Mutex::new(emu::Amp::new(1))), &mut stream).unwrap();
+
+        stream.output
+    }
+
+    #[test]
+    fn test_command_overflow() {
+        // the maximum length command is parsed (as an unknown command)
+        let cmd = [b'A'; 70];
+        assert_eq!(run(&[&cmd[..], b"\r"].concat()), [&cmd[..], b"\r\n#\r\nCommand Error.\r\n#"].concat());
+
+        // longer commands are echoed in full, with a single command error
+        let cmd = [b'?'; 75];
+        assert_eq!(run(&[&cmd[..], b"\r"].concat()), [&cmd[..], b"\r\n#\r\nCommand Error.\r\n#"].concat());
+
+        // a valid command prefix doesn't make an over-long command valid
+        let cmd = format!("<11VO10{}", " ".repeat(70));
+        assert_eq!(run(format!("{cmd}\r").as_bytes()), [cmd.as_bytes(), b"\r\n#\r\nCommand Error.\r\n#"].concat());
+
+        // commands after an over-long command are handled normally
+        let output = run(&[&[b'?'; 75][..], b"\r?11\r"].concat());
+        assert!(output.ends_with(&[&b"\r\n#\r\nCommand Error.\r\n#"[..], &run(b"?11\r")].concat()));
+    }
+
+    #[test]
+    fn test_baud_set() {
+        assert_eq!(run(b"<57600\r"), b"<57600\r\n#Done.\r\n#");
+        assert_eq!(run(b"<230400\r"), b"<230400\r\n#Done.\r\n#");
+
+        // unsupported rates are an error
+        assert_eq!(run(b"<1234\r"), b"<1234\r\n#\r\nCommand Error.\r\n#");
+    }
+
+    #[test]
+    fn test_attribute_enquiry() {
+        assert_eq!(run(b"?11VO\r"), b"?11VO\r\n#>11VO00\r\n#");
+        assert_eq!(run(b"?11BS\r"), b"?11BS\r\n#>11BS07\r\n#");
+        assert_eq!(run(b"?11LS\r"), b"?11LS\r\n#>11LS00\r\n#");
+
+        // unknown attributes are a nop
+        assert_eq!(run(b"?11XX\r"), b"?11XX\r\n#");
+    }
+}
@@ -1,16 +1,26 @@
+use std::cell::Cell;
+
+use client::demo::DemoStatus;
 use gtk::glib::Object;
 use gtk::prelude::*;
 use gtk::subclass::prelude::*;
 use gtk::{gio, glib};
+use strum::IntoEnumIterator;
 
 // use crate::config::VERSION;
 use crate::MainWindow;
+use crate::preferences_dialog::PreferencesDialog;
+use crate::settings::{settings_path, ConnectionSettings};
+use crate::zone_actions::ZoneAction;
 
 mod imp {
     use super::*;
 
     #[derive(Debug, Default)]
-    pub struct MwhaMixerApplication {}
+    pub struct MwhaMixerApplication {
+        /// show fake zones from a `DemoStatus` rather than the daemon's status
+        pub demo: Cell<bool>,
+    }
 
     #[glib::object_subclass]
     impl ObjectSubclass for MwhaMixerApplication {
@@ -24,6 +34,12 @@ mod imp {
             self.parent_constructed();
             self.obj().setup_gactions();
             self.obj().set_accels_for_action("app.quit", &["<primary>q"]);
+            self.obj().set_accels_for_action("app.preferences", &["<primary>comma"]);
+
+            // zone actions apply to the zone control that has focus
+            for action in ZoneAction::iter() {
+                self.obj().set_accels_for_action(&action.detailed_name(), action.accels());
+            }
         }
     }
 
@@ -39,6 +55,11 @@ mod imp {
                 window
             } else {
                 let window = MainWindow::new(&*application);
+
+                if self.demo.get() {
+                    application.setup_demo(&window);
+                }
+
                 window.upcast()
             };
 
@@ -64,6 +85,22 @@ impl MwhaMixerApplication {
             .build()
     }
 
+    /// Show fake zones instead of the daemon's status, for developing the UI without an amp or broker.
+    pub fn set_demo(&self, demo: bool) {
+        self.imp().demo.set(demo);
+    }
+
+    fn setup_demo(&self, window: &MainWindow) {
+        let demo = DemoStatus::new(2);
+
+        for update in demo.status_updates() {
+            window.apply_status_update(update);
+        }
+
+        // sets are ignored, the status never changes
+        window.connect_publish(move |topic, payload| demo.publish(topic, payload));
+    }
+
     fn setup_gactions(&self) {
         let quit_action = gio::ActionEntry::builder("quit")
             .activate(move |app: &Self, _, _| app.quit())
@@ -71,7 +108,25 @@ impl MwhaMixerApplication {
         let about_action = gio::ActionEntry::builder("about")
             .activate(move |app: &Self, _, _| app.show_about())
             .build();
-        self.add_action_entries([quit_action, about_action]);
+        let preferences_action = gio::ActionEntry::builder("preferences")
+            .activate(move |app: &Self, _, _| app.show_preferences())
+            .build();
+        self.add_action_entries([quit_action, about_action, preferences_action]);
+    }
+
+    fn show_preferences(&self) {
+        let Some(window) = self.active_window() else {
+            return;
+        };
+
+        let settings = ConnectionSettings::load(&settings_path()).unwrap_or_else(|e| {
+            eprintln!("failed to load settings from {}: {}", settings_path().display(), e);
+            ConnectionSettings::default()
+        });
+
+        let preferences = PreferencesDialog::new(&window, &settings);
+
+        preferences.present();
     }
 
     fn show_about(&self) {
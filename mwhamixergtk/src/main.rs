@@ -1,6 +1,11 @@
 mod application;
 mod main_window;
+mod pa;
+mod preferences_dialog;
+mod settings;
+mod zone_actions;
 mod zone_control;
+mod zone_groups;
 
 use self::application::MwhaMixerApplication;
 use self::main_window::MainWindow;
@@ -29,9 +34,17 @@ fn main() {
     // desktop features such as file opening and single-instance applications.
     let app = MwhaMixerApplication::new("com.zegelin.mwhamixergtk", &gio::ApplicationFlags::empty());
 
+    // `--demo` shows fake zones, without connecting to a broker (i.e. for UI development and screenshots).
+    // GApplication rejects options it doesn't know, so it's removed before the rest are passed on.
+    let mut args = std::env::args().collect::<Vec<_>>();
+    let demo = args.iter().any(|arg| arg == "--demo");
+    args.retain(|arg| arg != "--demo");
+
+    app.set_demo(demo);
+
     // Run the application. This function will block until the application
     // exits. Upon return, we have our exit code to return to the shell. (This
     // is the code you see when you do `echo $?` after running a command in a
     // terminal.
-    std::process::exit(app.run().into());
+    std::process::exit(app.run_with_args(&args).into());
 }
\ No newline at end of file
@@ -1,21 +1,48 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+
+use client::{zone_set_message, StatusUpdate, ZoneMeta};
+use common::zone::{ZoneAttribute, ZoneId, ZoneTopicFormat};
 use gtk::glib::Object;
 use gtk::prelude::*;
 use gtk::subclass::prelude::*;
 use gtk::{gio, glib};
 
-mod imp {
-    use crate::zone_control::ZoneControl;
+use crate::pa::PaStatus;
+use crate::settings::{settings_path, ConnectionSettings};
+use crate::zone_control::ZoneControl;
+use crate::zone_groups::{ZoneGroups, ZoneSection};
 
+mod imp {
     use super::*;
 
-    #[derive(Debug, Default, gtk::CompositeTemplate)]
+    #[derive(Default, gtk::CompositeTemplate)]
     #[template(resource = "/com/zegelin/mwhamixergtk/main_window.ui.xml")]
     pub struct MainWindow {
         #[template_child]
         pub header_bar: TemplateChild<gtk::HeaderBar>,
 
+        #[template_child]
+        pub pa_indicator: TemplateChild<gtk::Label>,
+
+        #[template_child]
+        pub header_area: TemplateChild<gtk::Box>,
+
         #[template_child]
         pub zone_list: TemplateChild<gtk::Box>,
+
+        pub zone_groups: RefCell<ZoneGroups>,
+
+        /// the zone box of each amp section
+        pub amp_sections: RefCell<BTreeMap<u8, gtk::Box>>,
+
+        pub zone_controls: RefCell<HashMap<ZoneId, ZoneControl>>,
+
+        pub topic_base: RefCell<String>,
+
+        pub pa_status: RefCell<PaStatus>,
+
+        pub publish_handlers: RefCell<Vec<Box<dyn Fn(&str, &str)>>>,
     }
 
     #[glib::object_subclass]
@@ -37,10 +64,16 @@ mod imp {
         fn constructed(&self) {
             self.parent_constructed();
 
-            for i in 0..6 {
-                let zc = ZoneControl::new();
+            let obj = self.obj();
 
-                self.zone_list.append(&zc);
+            let settings = ConnectionSettings::load(&settings_path()).unwrap_or_default();
+            self.topic_base.replace(settings.topic_base);
+
+            let zones = (1..=6).map(|zone| ZoneId::Zone { amp: 1, zone })
+                .chain([ZoneId::Amp(1), ZoneId::System]);
+
+            for zone in zones {
+                obj.add_zone(zone, &ZoneMeta::from_zone_id(&zone));
             }
         }
 
@@ -63,4 +96,107 @@ impl MainWindow {
 
         o
     }
+
+    /// Add a control for the zone to its amp section (or the header, for the system zone),
+    /// creating the section if required.
+    pub fn add_zone(&self, zone: ZoneId, meta: &[ZoneMeta]) {
+        let imp = self.imp();
+
+        let Some((section, position)) = imp.zone_groups.borrow_mut().insert(zone, meta) else {
+            return;
+        };
+
+        let container = match section {
+            ZoneSection::Header => imp.header_area.get(),
+            ZoneSection::Amp(amp) => self.amp_section(amp),
+        };
+
+        let control = ZoneControl::new(zone);
+        control.set_public_announcement(imp.pa_status.borrow().is_zone_active(&zone));
+
+        control.connect_set_attribute(glib::clone!(@weak self as window => move |zone, attr| {
+            let (topic, payload) = zone_set_message(&window.imp().topic_base.borrow(), ZoneTopicFormat::default(), &zone, attr);
+            window.publish(&topic, &payload);
+        }));
+
+        insert_child_at(&container, &control, position);
+
+        imp.zone_controls.borrow_mut().insert(zone, control);
+    }
+
+    /// Reflect a zone status update.
+    pub fn update_zone_attribute(&self, zone: ZoneId, attr: &ZoneAttribute) {
+        let imp = self.imp();
+
+        if let Some(control) = imp.zone_controls.borrow().get(&zone) {
+            control.update_attribute(attr);
+        }
+
+        let changed = imp.pa_status.borrow_mut().update(zone, attr);
+        if let Some(active) = changed {
+            imp.pa_indicator.set_visible(active);
+        }
+    }
+
+    /// Reflect an update from the status subscriptions (or the demo status producer).
+    pub fn apply_status_update(&self, update: StatusUpdate) {
+        match update {
+            StatusUpdate::AvailableZones(zones) => {
+                for zone in zones {
+                    self.add_zone(zone, &ZoneMeta::from_zone_id(&zone));
+                }
+            },
+            StatusUpdate::ZoneAttribute(zone, attr) => self.update_zone_attribute(zone, &attr),
+            _ => {} // TODO: zone names and connection state aren't shown yet
+        }
+    }
+
+    /// Register a handler for messages (topic, payload) the window wants published.
+    pub fn connect_publish<F: Fn(&str, &str) + 'static>(&self, f: F) {
+        self.imp().publish_handlers.borrow_mut().push(Box::new(f));
+    }
+
+    fn publish(&self, topic: &str, payload: &str) {
+        for handler in self.imp().publish_handlers.borrow().iter() {
+            handler(topic, payload);
+        }
+    }
+
+    fn amp_section(&self, amp: u8) -> gtk::Box {
+        let imp = self.imp();
+
+        if let Some(zones) = imp.amp_sections.borrow().get(&amp) {
+            return zones.clone();
+        }
+
+        let zones = gtk::Box::new(gtk::Orientation::Vertical, 0);
+
+        let expander = gtk::Expander::builder()
+            .label(format!("Amp {}", amp))
+            .expanded(true)
+            .child(&zones)
+            .build();
+
+        let position = imp.zone_groups.borrow().amp_position(amp);
+        insert_child_at(&imp.zone_list, &expander, position);
+
+        imp.amp_sections.borrow_mut().insert(amp, zones.clone());
+
+        zones
+    }
+}
+
+fn insert_child_at(container: &gtk::Box, child: &impl IsA<gtk::Widget>, position: usize) {
+    let sibling = match position {
+        0 => None,
+        n => {
+            let mut sibling = container.first_child();
+            for _ in 1..n {
+                sibling = sibling.and_then(|s| s.next_sibling());
+            }
+            sibling
+        }
+    };
+
+    container.insert_child_after(child, sibling.as_ref());
 }
\ No newline at end of file
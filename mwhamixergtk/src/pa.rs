@@ -0,0 +1,71 @@
+use std::collections::HashSet;
+
+use common::zone::{ZoneAttribute, ZoneId};
+
+/// Tracks which zones report `public-announcement`, for the header bar PA indicator.
+/// PA is active if any zone is in PA mode.
+///
+/// PA is triggered by the amp's 12V input, so it's only reported as status (there's no set topic).
+#[derive(Debug, Default)]
+pub struct PaStatus {
+    zones: HashSet<ZoneId>,
+}
+
+impl PaStatus {
+    /// Apply a zone status update, returning the new PA state if it changed.
+    pub fn update(&mut self, zone: ZoneId, attr: &ZoneAttribute) -> Option<bool> {
+        let ZoneAttribute::PublicAnnouncement(active) = *attr else {
+            return None;
+        };
+
+        let was_active = self.is_active();
+
+        if active {
+            self.zones.insert(zone);
+        } else {
+            self.zones.remove(&zone);
+        }
+
+        (self.is_active() != was_active).then_some(self.is_active())
+    }
+
+    pub fn is_active(&self) -> bool {
+        !self.zones.is_empty()
+    }
+
+    pub fn is_zone_active(&self, zone: &ZoneId) -> bool {
+        self.zones.contains(zone)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ZoneAttribute::*;
+
+    #[test]
+    fn test_pa_status() {
+        let mut status = PaStatus::default();
+
+        let z11 = ZoneId::Zone { amp: 1, zone: 1 };
+        let z12 = ZoneId::Zone { amp: 1, zone: 2 };
+
+        // other attributes are ignored
+        assert_eq!(status.update(z11, &Mute(true)), None);
+        assert!(!status.is_active());
+
+        assert_eq!(status.update(z11, &PublicAnnouncement(false)), None);
+        assert_eq!(status.update(z11, &PublicAnnouncement(true)), Some(true));
+        assert_eq!(status.update(z12, &PublicAnnouncement(true)), None);
+        assert!(status.is_zone_active(&z12));
+
+        // active until every zone has left PA mode
+        assert_eq!(status.update(z11, &PublicAnnouncement(false)), None);
+        assert!(status.is_active());
+        assert!(!status.is_zone_active(&z11));
+        assert_eq!(status.update(z12, &PublicAnnouncement(false)), Some(false));
+        assert!(!status.is_active());
+    }
+}
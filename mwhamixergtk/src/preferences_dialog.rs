@@ -0,0 +1,106 @@
+use gtk::glib::Object;
+use gtk::prelude::*;
+use gtk::subclass::prelude::*;
+use gtk::glib;
+
+use crate::settings::{settings_path, ConnectionSettings};
+
+mod imp {
+    use super::*;
+
+    #[derive(Debug, Default, gtk::CompositeTemplate)]
+    #[template(resource = "/com/zegelin/mwhamixergtk/preferences_dialog.ui.xml")]
+    pub struct PreferencesDialog {
+        #[template_child]
+        pub url_entry: TemplateChild<gtk::Entry>,
+
+        #[template_child]
+        pub username_entry: TemplateChild<gtk::Entry>,
+
+        #[template_child]
+        pub password_entry: TemplateChild<gtk::PasswordEntry>,
+
+        #[template_child]
+        pub topic_base_entry: TemplateChild<gtk::Entry>,
+
+        #[template_child]
+        pub cancel_button: TemplateChild<gtk::Button>,
+
+        #[template_child]
+        pub save_button: TemplateChild<gtk::Button>,
+    }
+
+    #[glib::object_subclass]
+    impl ObjectSubclass for PreferencesDialog {
+        const NAME: &'static str = "PreferencesDialog";
+        type Type = super::PreferencesDialog;
+        type ParentType = gtk::Window;
+
+        fn class_init(klass: &mut Self::Class) {
+            klass.bind_template();
+        }
+
+        fn instance_init(obj: &glib::subclass::InitializingObject<Self>) {
+            obj.init_template();
+        }
+    }
+
+    impl ObjectImpl for PreferencesDialog {
+        fn constructed(&self) {
+            self.parent_constructed();
+
+            let obj = self.obj();
+
+            self.cancel_button.connect_clicked(glib::clone!(@weak obj => move |_| obj.close()));
+
+            self.save_button.connect_clicked(glib::clone!(@weak obj => move |_| {
+                match obj.settings().save(&settings_path()) {
+                    Ok(()) => obj.close(),
+                    Err(e) => eprintln!("failed to save settings to {}: {}", settings_path().display(), e)
+                }
+            }));
+        }
+    }
+
+    impl WidgetImpl for PreferencesDialog {}
+    impl WindowImpl for PreferencesDialog {}
+}
+
+glib::wrapper! {
+    pub struct PreferencesDialog(ObjectSubclass<imp::PreferencesDialog>)
+        @extends gtk::Widget, gtk::Window;
+}
+
+impl PreferencesDialog {
+    pub fn new<P: glib::IsA<gtk::Window>>(parent: &P, settings: &ConnectionSettings) -> Self {
+        let dialog: Self = Object::builder()
+            .property("transient-for", parent)
+            .property("modal", true)
+            .build();
+
+        dialog.set_settings(settings);
+
+        dialog
+    }
+
+    fn set_settings(&self, settings: &ConnectionSettings) {
+        let imp = self.imp();
+
+        imp.url_entry.set_text(&settings.url);
+        imp.username_entry.set_text(settings.username.as_deref().unwrap_or_default());
+        imp.password_entry.set_text(settings.password.as_deref().unwrap_or_default());
+        imp.topic_base_entry.set_text(&settings.topic_base);
+    }
+
+    /// The settings as currently entered.
+    pub fn settings(&self) -> ConnectionSettings {
+        let imp = self.imp();
+
+        ConnectionSettings {
+            url: imp.url_entry.text().to_string(),
+            username: Some(imp.username_entry.text().to_string()),
+            password: Some(imp.password_entry.text().to_string()),
+            topic_base: imp.topic_base_entry.text().to_string(),
+        }.normalized()
+    }
+}
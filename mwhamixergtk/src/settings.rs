@@ -0,0 +1,10 @@
+use std::path::PathBuf;
+
+use gtk::glib;
+
+pub use client::settings::ConnectionSettings;
+
+/// The settings file, `$XDG_CONFIG_HOME/mwhamixergtk/settings.toml`.
+pub fn settings_path() -> PathBuf {
+    glib::user_config_dir().join("mwhamixergtk").join("settings.toml")
+}
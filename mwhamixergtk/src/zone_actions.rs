@@ -0,0 +1,73 @@
+use common::zone::ZoneAttribute;
+use strum_macros::{EnumIter, IntoStaticStr};
+
+/// Per-zone actions, installed on each `ZoneControl` in the `zone` action group.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumIter, IntoStaticStr)]
+#[strum(serialize_all = "kebab-case")]
+pub enum ZoneAction {
+    ToggleMute,
+    VolumeUp,
+    VolumeDown,
+}
+
+impl ZoneAction {
+    pub const GROUP: &'static str = "zone";
+
+    pub fn name(&self) -> &'static str {
+        self.into()
+    }
+
+    /// The detailed action name, i.e. `zone.toggle-mute`.
+    pub fn detailed_name(&self) -> String {
+        format!("{}.{}", Self::GROUP, self.name())
+    }
+
+    pub fn accels(&self) -> &'static [&'static str] {
+        match self {
+            ZoneAction::ToggleMute => &["m"],
+            ZoneAction::VolumeUp => &["Up"],
+            ZoneAction::VolumeDown => &["Down"],
+        }
+    }
+
+    /// The attribute to set, given the zone's current mute and volume status.
+    pub fn attribute(&self, mute: bool, volume: u8) -> ZoneAttribute {
+        match self {
+            ZoneAction::ToggleMute => ZoneAttribute::Mute(!mute),
+            ZoneAction::VolumeUp => ZoneAttribute::Volume(volume.saturating_add(1)).clamped(),
+            ZoneAction::VolumeDown => ZoneAttribute::Volume(volume.saturating_sub(1)).clamped(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use client::zone_set_message;
+    use common::zone::{ZoneId, ZoneTopicFormat};
+
+    use super::*;
+
+    #[test]
+    fn test_action_names() {
+        assert_eq!(ZoneAction::ToggleMute.detailed_name(), "zone.toggle-mute");
+        assert_eq!(ZoneAction::VolumeUp.detailed_name(), "zone.volume-up");
+        assert_eq!(ZoneAction::VolumeDown.detailed_name(), "zone.volume-down");
+    }
+
+    #[test]
+    fn test_action_topics() {
+        let zone = ZoneId::Zone { amp: 1, zone: 2 };
+
+        let message = |action: ZoneAction, mute, volume| zone_set_message("mwha/", ZoneTopicFormat::default(), &zone, &action.attribute(mute, volume));
+
+        assert_eq!(message(ZoneAction::ToggleMute, false, 20), ("mwha/set/zone/12/mute".to_string(), "true".to_string()));
+        assert_eq!(message(ZoneAction::ToggleMute, true, 20), ("mwha/set/zone/12/mute".to_string(), "false".to_string()));
+
+        assert_eq!(message(ZoneAction::VolumeUp, false, 20), ("mwha/set/zone/12/volume".to_string(), "21".to_string()));
+        assert_eq!(message(ZoneAction::VolumeDown, false, 20), ("mwha/set/zone/12/volume".to_string(), "19".to_string()));
+
+        // volume stays within range
+        assert_eq!(message(ZoneAction::VolumeDown, false, 0).1, "0");
+        assert_eq!(message(ZoneAction::VolumeUp, false, 38).1, "38");
+    }
+}
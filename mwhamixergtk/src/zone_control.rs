@@ -1,14 +1,31 @@
+use std::cell::{Cell, RefCell};
+
+use common::zone::{ZoneAttribute, ZoneId};
 use gtk::glib::Object;
 use gtk::prelude::*;
 use gtk::subclass::prelude::*;
 use gtk::{gio, glib};
+use strum::IntoEnumIterator;
+
+use crate::zone_actions::ZoneAction;
 
 mod imp {
     use super::*;
 
-    #[derive(Debug, Default, gtk::CompositeTemplate)]
+    #[derive(Default, gtk::CompositeTemplate)]
     #[template(resource = "/com/zegelin/mwhamixergtk/zone_control.ui.xml")]
     pub struct ZoneControl {
+        #[template_child]
+        pub pa_indicator: TemplateChild<gtk::Label>,
+
+        pub zone: Cell<Option<ZoneId>>,
+
+        /// last known status, used by the zone actions
+        pub mute: Cell<bool>,
+        pub volume: Cell<u8>,
+
+        pub set_attribute_handlers: RefCell<Vec<Box<dyn Fn(ZoneId, &ZoneAttribute)>>>,
+
         // #[template_child]
         // pub header_bar: TemplateChild<gtk::HeaderBar>,
 
@@ -45,7 +62,61 @@ glib::wrapper! {
 }
 
 impl ZoneControl {
-    pub fn new() -> Self {
-        Object::builder().build()
+    pub fn new(zone: ZoneId) -> Self {
+        let control: Self = Object::builder().build();
+
+        control.imp().zone.set(Some(zone));
+        control.setup_actions();
+
+        control
+    }
+
+    fn setup_actions(&self) {
+        let group = gio::SimpleActionGroup::new();
+
+        for zone_action in ZoneAction::iter() {
+            let action = gio::SimpleAction::new(zone_action.name(), None);
+            action.connect_activate(glib::clone!(@weak self as control => move |_, _| control.activate_zone_action(zone_action)));
+
+            group.add_action(&action);
+        }
+
+        self.insert_action_group(ZoneAction::GROUP, Some(&group));
+    }
+
+    fn activate_zone_action(&self, action: ZoneAction) {
+        let imp = self.imp();
+
+        let Some(zone) = imp.zone.get() else {
+            return;
+        };
+
+        let attr = action.attribute(imp.mute.get(), imp.volume.get());
+
+        for handler in imp.set_attribute_handlers.borrow().iter() {
+            handler(zone, &attr);
+        }
+    }
+
+    /// Register a handler for attribute changes requested via the zone actions.
+    pub fn connect_set_attribute<F: Fn(ZoneId, &ZoneAttribute) + 'static>(&self, f: F) {
+        self.imp().set_attribute_handlers.borrow_mut().push(Box::new(f));
+    }
+
+    /// Reflect a zone status update.
+    pub fn update_attribute(&self, attr: &ZoneAttribute) {
+        let imp = self.imp();
+
+        match *attr {
+            ZoneAttribute::Mute(mute) => imp.mute.set(mute),
+            ZoneAttribute::Volume(volume) => imp.volume.set(volume),
+            ZoneAttribute::PublicAnnouncement(active) => self.set_public_announcement(active),
+            _ => {}
+        }
+    }
+
+    /// Show or hide the indicator for when the zone is in PA mode.
+    pub fn set_public_announcement(&self, active: bool) {
+        self.imp().pa_indicator.set_visible(active);
     }
 }
\ No newline at end of file
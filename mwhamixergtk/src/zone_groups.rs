@@ -0,0 +1,122 @@
+use std::collections::BTreeMap;
+
+use client::{ZoneMeta, ZoneKind};
+use common::zone::ZoneId;
+
+/// Where a zone's control is placed in the main window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ZoneSection {
+    /// the header area, for the system (PA) zone
+    Header,
+
+    /// the expandable section for an amp, holding the amp zone and its physical zones
+    Amp(u8),
+}
+
+impl ZoneSection {
+    /// The section for a zone with the given metadata, or `None` if the metadata doesn't say which amp the zone is on.
+    pub fn from_meta<'a>(meta: impl IntoIterator<Item = &'a ZoneMeta>) -> Option<Self> {
+        let mut section = None;
+
+        for m in meta {
+            match m {
+                ZoneMeta::Kind(ZoneKind::System) | ZoneMeta::Amp(None) => return Some(ZoneSection::Header),
+                ZoneMeta::Amp(Some(amp)) => section = Some(ZoneSection::Amp(*amp)),
+                _ => {}
+            }
+        }
+
+        section
+    }
+}
+
+/// Zones grouped by section, ordered by amp and then by zone index (amp zones first).
+#[derive(Debug, Default)]
+pub struct ZoneGroups {
+    sections: BTreeMap<ZoneSection, BTreeMap<Option<u8>, ZoneId>>,
+}
+
+impl ZoneGroups {
+    /// Add a zone, returning its section and position within that section,
+    /// or `None` if its section can't be determined or it has already been added.
+    pub fn insert(&mut self, zone: ZoneId, meta: &[ZoneMeta]) -> Option<(ZoneSection, usize)> {
+        let section = ZoneSection::from_meta(meta)?;
+
+        let index = meta.iter().find_map(|m| match m {
+            ZoneMeta::Index(index) => Some(*index),
+            _ => None
+        }).flatten();
+
+        let zones = self.sections.entry(section).or_default();
+
+        if zones.values().any(|z| *z == zone) {
+            return None;
+        }
+
+        zones.insert(index, zone);
+
+        Some((section, zones.range(..index).count()))
+    }
+
+    /// The position of the section amongst the amp sections (the header isn't counted).
+    pub fn amp_position(&self, amp: u8) -> usize {
+        self.sections.keys().filter(|s| matches!(s, ZoneSection::Amp(a) if *a < amp)).count()
+    }
+
+    pub fn zones(&self, section: ZoneSection) -> Vec<ZoneId> {
+        self.sections.get(&section).map(|zones| zones.values().copied().collect()).unwrap_or_default()
+    }
+
+    pub fn sections(&self) -> Vec<ZoneSection> {
+        self.sections.keys().copied().collect()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_section_from_meta() {
+        let section = |zone: ZoneId| ZoneSection::from_meta(&ZoneMeta::from_zone_id(&zone));
+
+        assert_eq!(section(ZoneId::System), Some(ZoneSection::Header));
+        assert_eq!(section(ZoneId::Amp(2)), Some(ZoneSection::Amp(2)));
+        assert_eq!(section(ZoneId::Zone { amp: 3, zone: 4 }), Some(ZoneSection::Amp(3)));
+
+        assert_eq!(ZoneSection::from_meta(&[ZoneMeta::Name("Kitchen".to_string())]), None);
+    }
+
+    #[test]
+    fn test_groups() {
+        let mut groups = ZoneGroups::default();
+
+        let mut insert = |zone: ZoneId| groups.insert(zone, &ZoneMeta::from_zone_id(&zone));
+
+        // positions reflect the order at the time of insertion
+        assert_eq!(insert(ZoneId::Zone { amp: 2, zone: 3 }), Some((ZoneSection::Amp(2), 0)));
+        assert_eq!(insert(ZoneId::Zone { amp: 2, zone: 1 }), Some((ZoneSection::Amp(2), 0)));
+        assert_eq!(insert(ZoneId::Zone { amp: 2, zone: 6 }), Some((ZoneSection::Amp(2), 2)));
+        assert_eq!(insert(ZoneId::Amp(2)), Some((ZoneSection::Amp(2), 0)));
+        assert_eq!(insert(ZoneId::Zone { amp: 1, zone: 1 }), Some((ZoneSection::Amp(1), 0)));
+        assert_eq!(insert(ZoneId::System), Some((ZoneSection::Header, 0)));
+
+        // duplicates are ignored
+        assert_eq!(insert(ZoneId::Zone { amp: 2, zone: 3 }), None);
+
+        assert_eq!(groups.sections(), vec![ZoneSection::Header, ZoneSection::Amp(1), ZoneSection::Amp(2)]);
+        assert_eq!(groups.zones(ZoneSection::Header), vec![ZoneId::System]);
+        assert_eq!(groups.zones(ZoneSection::Amp(2)), vec![
+            ZoneId::Amp(2),
+            ZoneId::Zone { amp: 2, zone: 1 },
+            ZoneId::Zone { amp: 2, zone: 3 },
+            ZoneId::Zone { amp: 2, zone: 6 },
+        ]);
+        assert_eq!(groups.zones(ZoneSection::Amp(3)), vec![]);
+
+        assert_eq!(groups.amp_position(1), 0);
+        assert_eq!(groups.amp_position(2), 1);
+        assert_eq!(groups.amp_position(3), 2);
+    }
+}